@@ -0,0 +1,90 @@
+//! Golden tests for diagrams: each diagram of a corpus directory is rendered and compared to the
+//! output it's expected to have, written next to it.
+
+use std::path::{Path, PathBuf};
+
+use crate::spec::{render_spec_str, Error, Format, Overrides};
+
+/// A diagram of a corpus whose rendered output is not the expected one, see `check_corpus`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorpusMismatch {
+    pub diagram: PathBuf,
+
+    /// The content of the expected output of the diagram, `None` if there's none yet.
+    pub expected: Option<String>,
+
+    /// The diagram as rendered now followed by its warnings as `warning: ...`, or the error that
+    /// prevented rendering it as `error: ...`.
+    pub actual: String,
+}
+
+/// The file with the expected output of a diagram of a corpus, that is the diagram with the
+/// `.expected.txt` extension, like `flow.expected.txt` for `flow.toml`.
+pub fn expected_path(diagram: &Path) -> PathBuf {
+    diagram.with_extension("expected.txt")
+}
+
+/// Render each TOML and JSON diagram in `dir` and compare it to its expected output, see
+/// `expected_path`, returning the ones that don't match in the order of their names. Warnings
+/// are part of the output, after the diagram, and diagrams that can't be rendered are expected
+/// to fail with the same error.
+///
+/// With `bless` the expected outputs that don't match are overwritten with the actual ones,
+/// which is how they're created in the first place. Diagrams are rendered with seed 0 unless
+/// `overrides` has another one, so that the outputs are the same on every run.
+pub fn check_corpus(
+    dir: &Path,
+    overrides: &Overrides,
+    bless: bool,
+) -> Result<Vec<CorpusMismatch>, Error> {
+    let io = |e: std::io::Error| Error::Io(format!("{}: {}", dir.display(), e));
+    let mut diagrams = vec![];
+    for entry in std::fs::read_dir(dir).map_err(io)? {
+        let path = entry.map_err(io)?.path();
+        let format = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .and_then(Format::from_extension);
+        if let Some(format) = format {
+            diagrams.push((path, format));
+        }
+    }
+    diagrams.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let overrides = Overrides {
+        seed: overrides.seed.or(Some(0)),
+        ..overrides.clone()
+    };
+
+    let mut mismatches = vec![];
+    for (diagram, format) in diagrams {
+        let io = |e: std::io::Error| Error::Io(format!("{}: {}", diagram.display(), e));
+        let input = std::fs::read_to_string(&diagram).map_err(io)?;
+        let actual = match render_spec_str(&input, format, &overrides) {
+            Ok(rendered) => {
+                let mut out = format!("{}\n", rendered);
+                for w in &rendered.warnings {
+                    out.push_str(&format!("warning: {}\n", w));
+                }
+                out
+            }
+            Err(e) => format!("error: {}\n", e),
+        };
+
+        let expected_path = expected_path(&diagram);
+        let expected = std::fs::read_to_string(&expected_path).ok();
+        if expected.as_ref() == Some(&actual) {
+            continue;
+        }
+
+        if bless {
+            std::fs::write(&expected_path, &actual).map_err(io)?;
+        }
+        mismatches.push(CorpusMismatch {
+            diagram,
+            expected,
+            actual,
+        });
+    }
+    Ok(mismatches)
+}
@@ -0,0 +1,512 @@
+//! A parser for the subset of the D2 language that maps to diagrams: shapes become the nodes of a
+//! layered diagram, connections become edges and a few keywords become the matching options.
+
+use serde_json::{json, Value};
+
+use crate::spec::Error;
+
+/// Keywords that set properties of shapes and connections rather than declaring shapes.
+const RESERVED: &[&str] = &[
+    "label",
+    "shape",
+    "icon",
+    "width",
+    "height",
+    "style",
+    "near",
+    "tooltip",
+    "link",
+    "direction",
+    "constraint",
+    "class",
+    "classes",
+    "vars",
+    "source-arrowhead",
+    "target-arrowhead",
+    "grid-rows",
+    "grid-columns",
+];
+
+/// The connections between shapes, longest first so that `<->` isn't taken for `<-`.
+const ARROWS: &[&str] = &["<->", "->", "<-", "--"];
+
+/// How deep maps can be nested, like the recursion limit of serde_json, so that malicious inputs
+/// can't overflow the stack.
+const MAX_DEPTH: usize = 128;
+
+/// A statement like `a -> b: label { style.stroke-dash: 3 }`.
+#[derive(Debug)]
+struct Stmt {
+    // the keys of the statement, each a path of dot separated keys like `a.style.fill`, and the
+    // connections between consecutive ones.
+    keys: Vec<Vec<String>>,
+    arrows: Vec<&'static str>,
+    value: Option<String>,
+    body: Vec<Stmt>,
+    line: usize,
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+
+    // number of maps being parsed, see `MAX_DEPTH`.
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        s.chars()
+            .enumerate()
+            .all(|(i, c)| self.chars.get(self.pos + i) == Some(&c))
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error(&self, msg: &str) -> Error {
+        Error::Parse(format!("{} in D2 diagram on line {}", msg, self.line))
+    }
+
+    /// Skip spaces and comments up to the end of the line.
+    fn skip_blanks(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == '#' {
+                while self.peek().is_some_and(|c| c != '\n') {
+                    self.pos += 1;
+                }
+            } else if c.is_whitespace() && c != '\n' {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Parse the statements up to the closing brace of the current map if `nested`, or up to
+    /// the end of the diagram otherwise.
+    fn stmts(&mut self, nested: bool) -> Result<Vec<Stmt>, Error> {
+        let mut stmts = vec![];
+        loop {
+            self.skip_blanks();
+            match self.peek() {
+                None if nested => return Err(self.error(r#"expected "}""#)),
+                None => break,
+                Some('\n') => {
+                    self.pos += 1;
+                    self.line += 1;
+                }
+                Some(';') => self.pos += 1,
+                Some('}') if nested => {
+                    self.pos += 1;
+                    break;
+                }
+                Some('}') => return Err(self.error(r#"unexpected "}""#)),
+                _ => stmts.push(self.stmt()?),
+            }
+        }
+        Ok(stmts)
+    }
+
+    fn stmt(&mut self) -> Result<Stmt, Error> {
+        let line = self.line;
+        let mut keys = vec![self.key_path()?];
+        let mut arrows = vec![];
+        loop {
+            self.skip_blanks();
+            match ARROWS.iter().find(|a| self.starts_with(a)) {
+                Some(a) => {
+                    self.pos += a.len();
+                    arrows.push(*a);
+                    keys.push(self.key_path()?);
+                }
+                None => break,
+            }
+        }
+
+        let mut value = None;
+        if self.eat(':') {
+            self.skip_blanks();
+            if !matches!(self.peek(), None | Some('{' | '}' | ';' | '\n')) {
+                value = Some(self.value()?);
+            }
+        }
+
+        self.skip_blanks();
+        let body = if self.eat('{') {
+            if self.depth == MAX_DEPTH {
+                return Err(self.error(&format!(
+                    "maps are nested more than {} levels deep",
+                    MAX_DEPTH
+                )));
+            }
+            self.depth += 1;
+            let body = self.stmts(true)?;
+            self.depth -= 1;
+            body
+        } else {
+            vec![]
+        };
+
+        self.skip_blanks();
+        match self.peek() {
+            None | Some('}' | ';' | '\n') => Ok(Stmt {
+                keys,
+                arrows,
+                value,
+                body,
+                line,
+            }),
+            Some(c) => Err(self.error(&format!("unexpected character {:?}", c))),
+        }
+    }
+
+    fn key_path(&mut self) -> Result<Vec<String>, Error> {
+        let mut path = vec![self.key()?];
+        while self.eat('.') {
+            path.push(self.key()?);
+        }
+        Ok(path)
+    }
+
+    fn key(&mut self) -> Result<String, Error> {
+        self.skip_blanks();
+        match self.peek() {
+            Some(q @ ('"' | '\'')) => return self.quoted(q),
+            Some('(') => return Err(self.error("references to connections are not supported")),
+            _ => {}
+        }
+
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if ":.;{}\n#".contains(c) || ARROWS.iter().any(|a| self.starts_with(a)) {
+                break;
+            }
+            self.pos += 1;
+        }
+        let key = self.chars[start..self.pos]
+            .iter()
+            .collect::<String>()
+            .trim()
+            .to_string();
+        if key.is_empty() {
+            return Err(match self.peek() {
+                Some(c) => self.error(&format!("expected a key, found {:?}", c)),
+                None => self.error("expected a key, found the end of the diagram"),
+            });
+        }
+        Ok(key)
+    }
+
+    fn value(&mut self) -> Result<String, Error> {
+        match self.peek() {
+            Some(q @ ('"' | '\'')) => return self.quoted(q),
+            Some('|') => return Err(self.error("block strings are not supported")),
+            _ => {}
+        }
+
+        let start = self.pos;
+        while self.peek().is_some_and(|c| !";{}\n#".contains(c)) {
+            self.pos += 1;
+        }
+        Ok(self.chars[start..self.pos]
+            .iter()
+            .collect::<String>()
+            .trim()
+            .to_string())
+    }
+
+    fn quoted(&mut self, quote: char) -> Result<String, Error> {
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            match self.peek() {
+                Some('\\') if quote == '"' => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some('n') => s.push('\n'),
+                        Some(c) => s.push(c),
+                        None => break,
+                    }
+                }
+                Some(c) if c == quote => {
+                    self.pos += 1;
+                    return Ok(s);
+                }
+                Some('\n') | None => break,
+                Some(c) => s.push(c),
+            }
+            self.pos += 1;
+        }
+        Err(self.error("unterminated string"))
+    }
+}
+
+/// Flatten the properties in `body` to their paths below `prefix` and their values, like
+/// `style.stroke-dash` for `style: { stroke-dash: 3 }`.
+fn properties(
+    prefix: &[String],
+    body: &[Stmt],
+    props: &mut Vec<(Vec<String>, Option<String>)>,
+) -> Result<(), Error> {
+    for stmt in body {
+        if !stmt.arrows.is_empty() {
+            return Err(containers(stmt.line));
+        }
+        let path = prefix
+            .iter()
+            .chain(&stmt.keys[0])
+            .cloned()
+            .collect::<Vec<_>>();
+        props.push((path.clone(), stmt.value.clone()));
+        properties(&path, &stmt.body, props)?;
+    }
+    Ok(())
+}
+
+fn containers(line: usize) -> Error {
+    Error::Parse(format!(
+        "containers are not supported in D2 diagrams, found one on line {}",
+        line
+    ))
+}
+
+fn is_reserved(key: &str) -> bool {
+    RESERVED.contains(&key)
+}
+
+/// Convert the D2 diagram `input` to a diagram in the JSON format.
+///
+/// Shapes become the nodes of a layered diagram, laid out from left to right with
+/// `direction: right` or `left`, and are labelled with their label or their key. Connections keep
+/// the labels of their arrowheads, their label is drawn next to their source like a source
+/// arrowhead label and they're dashed if they have a `style.stroke-dash`. `--` connections are
+/// not directed while `<->` ones have arrowheads at both ends. Containers, references to
+/// connections, block strings and the other keywords are not supported.
+pub(crate) fn d2_to_json(input: &str) -> Result<Value, Error> {
+    let stmts = Parser {
+        chars: input.chars().collect(),
+        pos: 0,
+        line: 1,
+        depth: 0,
+    }
+    .stmts(false)?;
+
+    let mut nodes: Vec<(String, Option<String>)> = vec![];
+    let node = |nodes: &mut Vec<(String, Option<String>)>, id: &str| match nodes
+        .iter()
+        .position(|(n, _)| n == id)
+    {
+        Some(i) => i,
+        None => {
+            nodes.push((id.to_string(), None));
+            nodes.len() - 1
+        }
+    };
+    let mut edges = vec![];
+    let mut horizontal = false;
+
+    for stmt in &stmts {
+        if stmt.arrows.is_empty() {
+            let path = &stmt.keys[0];
+            if path[0] == "direction" {
+                horizontal = match stmt.value.as_deref() {
+                    Some("right" | "left") => true,
+                    Some("down" | "up") => false,
+                    _ => {
+                        return Err(Error::Parse(format!(
+                            r#"direction must be "right", "left", "down" or "up" on line {}"#,
+                            stmt.line
+                        )))
+                    }
+                };
+                continue;
+            }
+            // the label, style and variables of the whole diagram
+            if is_reserved(&path[0]) {
+                continue;
+            }
+
+            // `a.label: x` sets a property of `a` while `a.b` would be a shape inside `a`
+            let mut props = vec![];
+            if path.len() == 1 {
+                props.push((vec!["label".to_string()], stmt.value.clone()));
+                properties(&[], &stmt.body, &mut props)?;
+            } else {
+                props.push((path[1..].to_vec(), stmt.value.clone()));
+                properties(&path[1..], &stmt.body, &mut props)?;
+            }
+
+            let i = node(&mut nodes, &path[0]);
+            for (k, v) in props {
+                if !is_reserved(&k[0]) {
+                    return Err(containers(stmt.line));
+                }
+                if k == ["label"] && v.is_some() {
+                    nodes[i].1 = v;
+                }
+            }
+            continue;
+        }
+
+        if stmt.keys.iter().any(|path| path.len() > 1) {
+            return Err(containers(stmt.line));
+        }
+
+        let mut props = vec![(vec!["label".to_string()], stmt.value.clone())];
+        properties(&[], &stmt.body, &mut props)?;
+        let prop = |path: &[&str]| {
+            props
+                .iter()
+                .rev()
+                .find(|(k, _)| k.iter().map(String::as_str).eq(path.iter().copied()))
+                .and_then(|(_, v)| v.clone())
+        };
+        let label = prop(&["label"]);
+        let source = prop(&["source-arrowhead", "label"]).or_else(|| prop(&["source-arrowhead"]));
+        let target = prop(&["target-arrowhead", "label"]).or_else(|| prop(&["target-arrowhead"]));
+        let dashed = prop(&["style", "stroke-dash"])
+            .and_then(|d| d.parse::<f64>().ok())
+            .is_some_and(|d| d > 0.0);
+
+        for (w, arrow) in stmt.keys.windows(2).zip(&stmt.arrows) {
+            let (a, b) = (&w[0][0], &w[1][0]);
+            node(&mut nodes, a);
+            node(&mut nodes, b);
+
+            // the label is drawn next to the source of the edge like in DOT diagrams, unless
+            // the source arrowhead has a label of its own
+            let (from, to, from_head, to_head) = if *arrow == "<-" {
+                (b, a, &target, &source)
+            } else {
+                (a, b, &source, &target)
+            };
+            let from_label = from_head.as_ref().or(label.as_ref());
+            let to_label = to_head.as_ref();
+            let mut e = json!({ "from": from, "to": to });
+            if let Some(l) = from_label {
+                e["from_label"] = json!(l);
+            }
+            if let Some(l) = to_label {
+                e["to_label"] = json!(l);
+            }
+            if dashed {
+                e["dashed"] = json!(true);
+            }
+            match *arrow {
+                "--" => e["directed"] = json!(false),
+                "<->" => e["bidirectional"] = json!(true),
+                _ => {}
+            }
+            edges.push(e);
+        }
+    }
+
+    let nodes = nodes
+        .into_iter()
+        .map(|(id, label)| {
+            let text = label.unwrap_or_else(|| id.clone());
+            json!({ "id": id, "text": text })
+        })
+        .collect::<Vec<_>>();
+
+    Ok(json!({
+        "kind": "layered",
+        "orientation": if horizontal { "horizontal" } else { "vertical" },
+        "directed": true,
+        "nodes": nodes,
+        "edges": edges,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::Format;
+
+    #[test]
+    fn test_d2_to_json() {
+        let d2 = r#"
+# a comment
+direction: right
+web server: "Web\nserver" { shape: rectangle }
+web server -> api -> db: queries
+db.label: Database
+api <- worker: jobs {
+  source-arrowhead.label: 1
+  style: { stroke-dash: 3 }
+}
+worker -- db; cache
+cache <-> db
+"#;
+        assert_eq!(
+            d2_to_json(d2).unwrap(),
+            json!({
+                "kind": "layered",
+                "orientation": "horizontal",
+                "directed": true,
+                "nodes": [
+                    { "id": "web server", "text": "Web\nserver" },
+                    { "id": "api", "text": "api" },
+                    { "id": "db", "text": "Database" },
+                    { "id": "worker", "text": "worker" },
+                    { "id": "cache", "text": "cache" },
+                ],
+                "edges": [
+                    { "from": "web server", "to": "api", "from_label": "queries" },
+                    { "from": "api", "to": "db", "from_label": "queries" },
+                    {
+                        "from": "worker",
+                        "to": "api",
+                        "from_label": "jobs",
+                        "to_label": "1",
+                        "dashed": true,
+                    },
+                    { "from": "worker", "to": "db", "directed": false },
+                    { "from": "cache", "to": "db", "bidirectional": true },
+                ],
+            })
+        );
+
+        assert_eq!(Format::guess("# a\nweb server -> api"), Format::D2);
+        assert_eq!(Format::guess("a <- b: reads"), Format::D2);
+        assert_eq!(Format::guess("text: a -> b"), Format::Yaml);
+        assert_eq!(Format::guess("direction: right\na -> b"), Format::D2);
+        assert_eq!(Format::guess("text: |-\n  a -> b"), Format::Yaml);
+
+        assert_eq!(
+            d2_to_json("a -> b\ncloud: { a }"),
+            Err(Error::Parse(
+                "containers are not supported in D2 diagrams, found one on line 2".to_string()
+            ))
+        );
+        assert_eq!(
+            d2_to_json("a -> \"b"),
+            Err(Error::Parse(
+                "unterminated string in D2 diagram on line 1".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        assert_eq!(
+            d2_to_json(&format!("a -> b\n{}", "a: {".repeat(20_000))),
+            Err(Error::Parse(
+                "maps are nested more than 128 levels deep in D2 diagram on line 2".to_string()
+            ))
+        );
+    }
+}
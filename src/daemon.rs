@@ -0,0 +1,274 @@
+use std::io::{self, Read, Write};
+use std::num::NonZeroUsize;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ascii_diagrams::{render_spec_str, Format, Limits, Overrides};
+
+/// Longest diagram a client can send, in bytes.
+pub const MAX_REQUEST_LEN: usize = 1 << 20;
+
+/// How long a client can take to send its whole diagram before it's dropped.
+pub const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many clients can wait for a worker to render their diagram before new ones are turned
+/// away.
+pub const MAX_PENDING: usize = 64;
+
+/// The limits diagrams are rendered with unless overridden on the command line, small enough
+/// that rendering a diagram takes at most a few seconds and megabytes. Routing the lines takes
+/// time proportional to the size of the canvas times the number of edges, and then they're
+/// tweaked for at most `max_tweak_time`.
+pub const DEFAULT_LIMITS: Limits = Limits {
+    max_blocks: 50,
+    max_edges: 100,
+    max_text_length: 1_000,
+    max_canvas_cells: 10_000,
+    max_tweak_time: Some(Duration::from_secs(2)),
+};
+
+/// Serve the clients connecting to `listener` until it fails, rendering as many diagrams at a
+/// time as there are cores.
+///
+/// A client writes a diagram and then shuts down its side of the connection. The format of the
+/// diagram is guessed from its content, see `Format::guess`, unless the first line is a header
+/// like `format: toml` with any of the extensions `--format` takes, which is not part of the
+/// diagram. The reply is the rendered diagram followed by its warnings as
+/// `warning: ...` lines, or `error: ...` if the diagram cannot be rendered, after which the
+/// connection is closed. Clients connecting while `MAX_PENDING` others are waiting for a worker
+/// are replied `error: the server is busy, try again later` right away.
+pub fn serve(listener: UnixListener, overrides: Overrides) -> io::Result<()> {
+    let workers = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    serve_with(listener, overrides, workers, MAX_PENDING, READ_TIMEOUT)
+}
+
+fn serve_with(
+    listener: UnixListener,
+    overrides: Overrides,
+    workers: usize,
+    pending: usize,
+    read_timeout: Duration,
+) -> io::Result<()> {
+    let (sender, receiver) = mpsc::sync_channel::<UnixStream>(pending);
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..workers {
+        let receiver = Arc::clone(&receiver);
+        let overrides = overrides.clone();
+        thread::spawn(move || loop {
+            let stream = match receiver.lock().unwrap().recv() {
+                Ok(stream) => stream,
+                Err(_) => return,
+            };
+            // a panicking render must not take the worker down with it
+            let handled = AssertUnwindSafe(|| handle(stream, &overrides, read_timeout));
+            match panic::catch_unwind(handled) {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => eprintln!("cannot serve client: {}", e),
+                Err(_) => eprintln!("cannot serve client: rendering panicked"),
+            }
+        });
+    }
+
+    for stream in listener.incoming() {
+        if let Err(TrySendError::Full(mut stream)) = sender.try_send(stream?) {
+            let _ = stream.write_all(b"error: the server is busy, try again later\n");
+        }
+    }
+    Ok(())
+}
+
+fn handle(mut stream: UnixStream, overrides: &Overrides, read_timeout: Duration) -> io::Result<()> {
+    // the timeout of the socket applies to each read, a client sending a byte at a time would
+    // never time out without a deadline for the whole diagram
+    let deadline = Instant::now() + read_timeout;
+
+    // one more byte than allowed to tell whether the diagram is too long
+    let mut input = vec![];
+    let mut buf = [0; 8192];
+    while input.len() <= MAX_REQUEST_LEN {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "the client took too long to send its diagram",
+            ));
+        }
+        stream.set_read_timeout(Some(left))?;
+
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        input.extend_from_slice(&buf[..n]);
+    }
+
+    stream.write_all(reply(&input, overrides).as_bytes())
+}
+
+/// The reply to a client that sent `input`, see `serve`.
+fn reply(input: &[u8], overrides: &Overrides) -> String {
+    if input.len() > MAX_REQUEST_LEN {
+        return format!(
+            "error: the diagram is longer than {} bytes\n",
+            MAX_REQUEST_LEN
+        );
+    }
+    let text = match std::str::from_utf8(input) {
+        Ok(text) => text,
+        Err(e) => return format!("error: {}\n", e),
+    };
+
+    let (format, text) = match text.split_once('\n') {
+        Some((header, diagram)) if header.starts_with("format:") => {
+            match crate::parse_format(header["format:".len()..].trim()) {
+                Ok(format) => (format, diagram),
+                Err(e) => return format!("error: {}\n", e),
+            }
+        }
+        _ => (Format::guess(text), text),
+    };
+
+    match render_spec_str(text, format, overrides) {
+        Ok(rendered) => {
+            let mut out = format!("{}\n", rendered);
+            for w in &rendered.warnings {
+                out.push_str(&format!("warning: {}\n", w));
+            }
+            out
+        }
+        Err(e) => format!("error: {}\n", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Shutdown;
+
+    #[test]
+    fn test_serve() {
+        let path =
+            std::env::temp_dir().join(format!("ascii-diagrams-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let overrides = Overrides {
+            seed: Some(0),
+            limits: DEFAULT_LIMITS,
+            ..Overrides::default()
+        };
+        thread::spawn(move || serve(listener, overrides));
+
+        let request = |input: &[u8]| {
+            let mut stream = UnixStream::connect(&path).unwrap();
+            stream.write_all(input).unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+            let mut out = String::new();
+            stream.read_to_string(&mut out).unwrap();
+            out
+        };
+
+        assert_eq!(
+            request(
+                br#"{"compact": true,
+                     "blocks": [{"text": "hi", "position": {"row": 0, "column": 0}}],
+                     "edges": [{"from": "hi", "to": "bye"}]}"#
+            ),
+            r#"+----+
+|    |
+| hi |
+|    |
++----+
+warning: id "bye" not found
+"#
+        );
+
+        let huge =
+            r#"blocks = [ { text = "a", width = 100000000, position = { row = 0, column = 0 } } ]"#;
+        assert_eq!(
+            request(huge.as_bytes()),
+            "error: the diagram would be drawn on more than 10000 characters\n"
+        );
+
+        assert_eq!(
+            request(format!("a: {}", "[".repeat(40_000)).as_bytes()),
+            "error: recursion limit exceeded at line 1 column 259\n"
+        );
+
+        // a D2 diagram whose content alone reads like YAML
+        let d2 = request(b"format: d2\nx: hi");
+        assert!(d2.contains("| hi |") && !d2.contains("error"), "{}", d2);
+        assert_eq!(
+            request(b"format: xml\n<a/>"),
+            "error: unrecognized diagram format \"xml\", valid extensions: toml, json, yaml, yml, dot, gv, d2, puml, plantuml, pu\n"
+        );
+
+        assert_eq!(
+            request(&vec![b' '; MAX_REQUEST_LEN + 1]),
+            format!(
+                "error: the diagram is longer than {} bytes\n",
+                MAX_REQUEST_LEN
+            )
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_busy() {
+        let path =
+            std::env::temp_dir().join(format!("ascii-diagrams-busy-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        thread::spawn(move || serve_with(listener, Overrides::default(), 1, 0, READ_TIMEOUT));
+        thread::sleep(Duration::from_millis(100));
+
+        // the only worker waits for this client to send its diagram
+        let mut waiting = UnixStream::connect(&path).unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        let mut out = String::new();
+        stream.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "error: the server is busy, try again later\n");
+
+        waiting.write_all(b"{}").unwrap();
+        waiting.shutdown(Shutdown::Write).unwrap();
+        let mut out = String::new();
+        waiting.read_to_string(&mut out).unwrap();
+        assert!(!out.starts_with("error"), "{}", out);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_deadline() {
+        let path =
+            std::env::temp_dir().join(format!("ascii-diagrams-slow-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let timeout = Duration::from_millis(300);
+        thread::spawn(move || serve_with(listener, Overrides::default(), 1, 1, timeout));
+
+        // every byte comes well within the timeout, but the diagram as a whole doesn't
+        let mut stream = UnixStream::connect(&path).unwrap();
+        let start = Instant::now();
+        for _ in 0..20 {
+            if stream.write_all(b" ").is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        let _ = stream.shutdown(Shutdown::Write);
+        let mut out = String::new();
+        let _ = stream.read_to_string(&mut out);
+        assert_eq!(out, "");
+        assert!(start.elapsed() < Duration::from_millis(900));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,363 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::render::{into_string, PlacedBlocks, RenderOptions};
+use crate::{Block, Edge, LogicalPos};
+
+/// Source of the ids of the blocks, shared by all the diagrams so that an id is never valid in
+/// a diagram other than the one that returned it.
+static NEXT_BLOCK_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// An opaque handle to a block of a `Diagram`, returned by `Diagram::add_block`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BlockId(usize);
+
+/// Why a change to a `Diagram` was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagramError {
+    /// The block is not part of the diagram.
+    UnknownBlock(BlockId),
+
+    /// There's already a block at the given position.
+    PositionTaken(LogicalPos),
+
+    /// The blocks are already connected by an edge in the same direction.
+    DuplicateEdge(BlockId, BlockId),
+
+    /// An edge cannot connect a block to itself.
+    SelfLoop(BlockId),
+}
+
+impl fmt::Display for DiagramError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiagramError::UnknownBlock(id) => write!(f, "block {} is not in the diagram", id.0),
+            DiagramError::PositionTaken(LogicalPos { row, col }) => write!(
+                f,
+                "there's already a block at row {} and column {}",
+                row, col
+            ),
+            DiagramError::DuplicateEdge(from, to) => {
+                write!(f, "blocks {} and {} are already connected", from.0, to.0)
+            }
+            DiagramError::SelfLoop(id) => write!(f, "block {} cannot be connected to itself", id.0),
+        }
+    }
+}
+
+impl std::error::Error for DiagramError {}
+
+/// A diagram made of `Block`s and of `Edge`s between them, referenced by `BlockId`s rather than
+/// by their index as in `render`. Changes that would make the diagram invalid are rejected.
+///
+/// The diagram can be changed and rendered again any number of times. The placement of the
+/// blocks is reused across renders as long as neither the blocks nor the options changed, so
+/// that changing only the edges is cheaper than rendering from scratch.
+#[derive(Debug, Clone)]
+pub struct Diagram {
+    config: RenderOptions,
+
+    // ordered by id, that is in insertion order.
+    blocks: BTreeMap<BlockId, Block>,
+
+    // the endpoints of the edges are the ids of the blocks, not their indices.
+    edges: Vec<Edge>,
+
+    // the blocks as placed by the last render, cleared when they or the config change.
+    placed: Option<PlacedBlocks>,
+}
+
+impl Diagram {
+    pub fn new(config: RenderOptions) -> Self {
+        Diagram {
+            config,
+            blocks: BTreeMap::new(),
+            edges: vec![],
+            placed: None,
+        }
+    }
+
+    /// Add a block to the diagram, fail if there's already a block with the same z at its
+    /// position, see `Block::with_z`.
+    pub fn add_block(&mut self, block: Block) -> Result<BlockId, DiagramError> {
+        let position = block.position();
+        if self
+            .blocks
+            .values()
+            .any(|b| b.position() == position && b.z() == block.z())
+        {
+            return Err(DiagramError::PositionTaken(position));
+        }
+
+        let id = BlockId(NEXT_BLOCK_ID.fetch_add(1, Ordering::Relaxed));
+        self.blocks.insert(id, block);
+        self.placed = None;
+        Ok(id)
+    }
+
+    /// Remove a block from the diagram alongside all the edges attached to it and return it.
+    pub fn remove_block(&mut self, id: BlockId) -> Result<Block, DiagramError> {
+        let block = self
+            .blocks
+            .remove(&id)
+            .ok_or(DiagramError::UnknownBlock(id))?;
+
+        self.edges.retain(|e| e.from != id.0 && e.to != id.0);
+        self.placed = None;
+        Ok(block)
+    }
+
+    /// Move a block to the given position, fail if there's already another block with the same z
+    /// there.
+    pub fn move_block(
+        &mut self,
+        id: BlockId,
+        position: impl Into<LogicalPos>,
+    ) -> Result<(), DiagramError> {
+        let position = position.into();
+        let z = match self.blocks.get(&id) {
+            Some(b) => b.z(),
+            None => return Err(DiagramError::UnknownBlock(id)),
+        };
+        if self
+            .blocks
+            .iter()
+            .any(|(other, b)| *other != id && b.position() == position && b.z() == z)
+        {
+            return Err(DiagramError::PositionTaken(position));
+        }
+
+        self.blocks.get_mut(&id).unwrap().set_position(position);
+        self.placed = None;
+        Ok(())
+    }
+
+    /// Connect the given blocks with an edge and return it, so that it can be adjusted with the
+    /// `Edge::set_*` methods.
+    pub fn add_edge(&mut self, from: BlockId, to: BlockId) -> Result<&mut Edge, DiagramError> {
+        for id in [from, to] {
+            if !self.blocks.contains_key(&id) {
+                return Err(DiagramError::UnknownBlock(id));
+            }
+        }
+        if from == to {
+            return Err(DiagramError::SelfLoop(from));
+        }
+        if self.edges.iter().any(|e| (e.from, e.to) == (from.0, to.0)) {
+            return Err(DiagramError::DuplicateEdge(from, to));
+        }
+
+        self.edges.push(Edge::new(from.0, to.0));
+        Ok(self.edges.last_mut().unwrap())
+    }
+
+    /// Remove the edge going from `from` to `to` and return it.
+    pub fn remove_edge(&mut self, from: BlockId, to: BlockId) -> Option<Edge> {
+        let i = self
+            .edges
+            .iter()
+            .position(|e| (e.from, e.to) == (from.0, to.0))?;
+        Some(self.edges.remove(i))
+    }
+
+    pub fn block(&self, id: BlockId) -> Option<&Block> {
+        self.blocks.get(&id)
+    }
+
+    /// The blocks of the diagram alongside their ids, in the order they were added.
+    pub fn blocks(&self) -> impl Iterator<Item = (BlockId, &Block)> {
+        self.blocks.iter().map(|(id, b)| (*id, b))
+    }
+
+    pub fn config(&self) -> &RenderOptions {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: RenderOptions) {
+        if config != self.config {
+            self.config = config;
+            self.placed = None;
+        }
+    }
+
+    /// Render the diagram, see `render_to_string`.
+    pub fn render(&mut self) -> Vec<String> {
+        if self.blocks.is_empty() {
+            return vec![];
+        }
+
+        let (blocks, edges) = self.indexed();
+        let config = &self.config;
+        self.placed
+            .get_or_insert_with(|| PlacedBlocks::new(&blocks, config))
+            .render(&blocks, &edges, config)
+            .into_iter()
+            .map(into_string)
+            .collect()
+    }
+
+    /// The blocks and the edges of the diagram with the endpoints of the edges turned into
+    /// indices of the blocks, as expected by `render`.
+    fn indexed(&self) -> (Vec<Block>, Vec<Edge>) {
+        let index = self
+            .blocks
+            .keys()
+            .enumerate()
+            .map(|(i, id)| (id.0, i))
+            .collect::<HashMap<_, _>>();
+
+        let blocks = self.blocks.values().cloned().collect();
+        let edges = self
+            .edges
+            .iter()
+            .map(|e| {
+                let mut e = e.clone();
+                e.from = index[&e.from];
+                e.to = index[&e.to];
+                e
+            })
+            .collect();
+
+        (blocks, edges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::render::render_to_string;
+
+    fn config() -> RenderOptions {
+        RenderOptions {
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_diagram() {
+        let mut diagram = Diagram::new(config());
+        let a = diagram.add_block(Block::from_str((0, 0), "a")).unwrap();
+        let b = diagram.add_block(Block::from_str((0, 1), "b")).unwrap();
+        diagram.add_edge(a, b).unwrap().set_directed(true);
+
+        let blocks = [Block::from_str((0, 0), "a"), Block::from_str((0, 1), "b")];
+        let edges = [Edge::new(0, 1).with_directed(true)];
+        assert_eq!(diagram.render(), render_to_string(&blocks, edges, config()));
+
+        assert_eq!(
+            diagram.block(b).map(Block::position),
+            Some(LogicalPos::new(0, 1))
+        );
+        assert_eq!(
+            diagram.blocks().map(|(id, _)| id).collect::<Vec<_>>(),
+            vec![a, b]
+        );
+    }
+
+    #[test]
+    fn test_diagram_validation() {
+        let mut diagram = Diagram::new(config());
+        let a = diagram.add_block(Block::from_str((0, 0), "a")).unwrap();
+        let b = diagram.add_block(Block::from_str((0, 1), "b")).unwrap();
+
+        assert_eq!(
+            diagram.add_block(Block::from_str((0, 1), "c")),
+            Err(DiagramError::PositionTaken(LogicalPos::new(0, 1)))
+        );
+
+        assert!(diagram.add_edge(a, b).is_ok());
+        assert_eq!(
+            diagram.add_edge(a, b).map(|_| ()),
+            Err(DiagramError::DuplicateEdge(a, b))
+        );
+        assert!(diagram.add_edge(b, a).is_ok());
+        assert_eq!(
+            diagram.add_edge(a, a).map(|_| ()),
+            Err(DiagramError::SelfLoop(a))
+        );
+
+        // ids are never valid in other diagrams
+        let mut other = Diagram::new(config());
+        let c = other.add_block(Block::from_str((0, 0), "c")).unwrap();
+        assert_eq!(
+            diagram.add_edge(a, c).map(|_| ()),
+            Err(DiagramError::UnknownBlock(c))
+        );
+    }
+
+    #[test]
+    fn test_diagram_changes() {
+        let mut diagram = Diagram::new(config());
+        let a = diagram.add_block(Block::from_str((0, 0), "a")).unwrap();
+        let b = diagram.add_block(Block::from_str((0, 1), "b")).unwrap();
+        let c = diagram.add_block(Block::from_str((1, 0), "c")).unwrap();
+        diagram.add_edge(a, b).unwrap();
+        diagram.add_edge(c, b).unwrap();
+        diagram.render();
+
+        // changing only the edges reuses the placement of the blocks
+        assert!(diagram.remove_edge(c, b).is_some());
+        assert!(diagram.remove_edge(c, b).is_none());
+        assert!(diagram.placed.is_some());
+        let blocks = [
+            Block::from_str((0, 0), "a"),
+            Block::from_str((0, 1), "b"),
+            Block::from_str((1, 0), "c"),
+        ];
+        assert_eq!(
+            diagram.render(),
+            render_to_string(&blocks, [(0, 1)].iter().copied(), config())
+        );
+        assert_eq!(diagram.render(), diagram.render());
+
+        assert_eq!(
+            diagram.move_block(c, (0, 1)),
+            Err(DiagramError::PositionTaken(LogicalPos::new(0, 1)))
+        );
+        diagram.move_block(c, (1, 1)).unwrap();
+        assert!(diagram.placed.is_none());
+        diagram.add_edge(a, c).unwrap();
+        let blocks = [
+            Block::from_str((0, 0), "a"),
+            Block::from_str((0, 1), "b"),
+            Block::from_str((1, 1), "c"),
+        ];
+        assert_eq!(
+            diagram.render(),
+            render_to_string(&blocks, [(0, 1), (0, 2)].iter().copied(), config())
+        );
+
+        // removing a block removes its edges too
+        assert_eq!(
+            diagram.remove_block(b).map(|b| b.position()),
+            Ok(LogicalPos::new(0, 1))
+        );
+        assert_eq!(
+            diagram.remove_block(b).map(|_| ()),
+            Err(DiagramError::UnknownBlock(b))
+        );
+        let blocks = [Block::from_str((0, 0), "a"), Block::from_str((1, 1), "c")];
+        assert_eq!(
+            diagram.render(),
+            render_to_string(&blocks, [(0, 1)].iter().copied(), config())
+        );
+
+        diagram.set_config(RenderOptions {
+            hmargin: 2,
+            ..config()
+        });
+        assert!(diagram.placed.is_none());
+        let config = RenderOptions {
+            hmargin: 2,
+            ..config()
+        };
+        assert_eq!(
+            diagram.render(),
+            render_to_string(&blocks, [(0, 1)].iter().copied(), config)
+        );
+    }
+}
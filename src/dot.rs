@@ -0,0 +1,467 @@
+//! A parser for the subset of the Graphviz DOT language that maps to diagrams: nodes become
+//! blocks, edges become edges and a few attributes become the matching options.
+
+use serde_json::{json, Map, Value};
+
+use crate::spec::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    // identifiers, numerals and quoted strings alike, quotes removed.
+    Id(String),
+    Punct(&'static str),
+}
+
+/// Split `input` in tokens, skipping whitespace and comments.
+fn tokenize(input: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = vec![];
+    let mut chars = input.char_indices().peekable();
+    let mut line_start = true;
+    while let Some((i, c)) = chars.next() {
+        let rest = &input[i..];
+        if c == '\n' {
+            line_start = true;
+            continue;
+        }
+        if c.is_whitespace() {
+            continue;
+        }
+
+        // preprocessor output lines start with #
+        if (c == '#' && line_start) || rest.starts_with("//") {
+            while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+            continue;
+        }
+        line_start = false;
+        if let Some(comment) = rest.strip_prefix("/*") {
+            let end = comment
+                .find("*/")
+                .ok_or_else(|| Error::Parse("unterminated comment".to_string()))?;
+            while chars.next_if(|&(j, _)| j < i + end + 4).is_some() {}
+            continue;
+        }
+
+        if let Some(p) = ["->", "--"].iter().find(|p| rest.starts_with(**p)) {
+            chars.next();
+            tokens.push(Token::Punct(p));
+            continue;
+        }
+        if let Some(p) = ["{", "}", "[", "]", "=", ";", ","]
+            .iter()
+            .find(|p| rest.starts_with(**p))
+        {
+            tokens.push(Token::Punct(p));
+            continue;
+        }
+
+        if c == '"' {
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, '"')) => s.push('"'),
+                        Some((_, '\n')) => {}
+                        // \n, \l and \r end lines centered, left and right aligned
+                        Some((_, 'n' | 'l' | 'r')) => s.push('\n'),
+                        Some((_, c)) => {
+                            s.push('\\');
+                            s.push(c);
+                        }
+                        None => break,
+                    },
+                    Some((_, '"')) => {
+                        tokens.push(Token::Id(s));
+                        break;
+                    }
+                    Some((_, c)) => s.push(c),
+                    None => return Err(Error::Parse("unterminated string".to_string())),
+                }
+            }
+            continue;
+        }
+        if c == '<' {
+            return Err(Error::Parse(
+                "HTML strings are not supported in DOT diagrams".to_string(),
+            ));
+        }
+
+        if c.is_alphanumeric() || c == '_' || c == '.' || c == '-' {
+            let mut s = c.to_string();
+            while let Some((_, c)) =
+                chars.next_if(|&(_, c)| c.is_alphanumeric() || c == '_' || c == '.')
+            {
+                s.push(c);
+            }
+            tokens.push(Token::Id(s));
+            continue;
+        }
+
+        return Err(Error::Parse(format!(
+            "unexpected character {:?} in DOT diagram on line {}",
+            c,
+            1 + input[..i].matches('\n').count()
+        )));
+    }
+    Ok(tokens)
+}
+
+/// The nodes and edges of a DOT graph, in the order they're first mentioned.
+#[derive(Debug, Default)]
+struct Graph {
+    directed: bool,
+    attrs: Map<String, Value>,
+    nodes: Vec<(String, Map<String, Value>)>,
+    edges: Vec<(String, String, Map<String, Value>)>,
+}
+
+impl Graph {
+    fn node(&mut self, id: &str) -> &mut Map<String, Value> {
+        let i = match self.nodes.iter().position(|(n, _)| n == id) {
+            Some(i) => i,
+            None => {
+                self.nodes.push((id.to_string(), Map::new()));
+                self.nodes.len() - 1
+            }
+        };
+        &mut self.nodes[i].1
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eat(&mut self, p: &'static str) -> bool {
+        if self.peek() == Some(&Token::Punct(p)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, p: &'static str) -> Result<(), Error> {
+        if self.eat(p) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("{:?}", p)))
+        }
+    }
+
+    fn id(&mut self) -> Result<String, Error> {
+        match self.peek() {
+            Some(Token::Id(id)) => {
+                let id = id.clone();
+                self.pos += 1;
+                Ok(id)
+            }
+            _ => Err(self.unexpected("an identifier")),
+        }
+    }
+
+    fn keyword(&mut self, kw: &str) -> bool {
+        match self.peek() {
+            Some(Token::Id(id)) if id.eq_ignore_ascii_case(kw) => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> Error {
+        let found = match self.peek() {
+            Some(Token::Id(id)) => format!("{:?}", id),
+            Some(Token::Punct(p)) => format!("{:?}", p),
+            None => "the end of the diagram".to_string(),
+        };
+        Error::Parse(format!(
+            "expected {} in DOT diagram, found {}",
+            expected, found
+        ))
+    }
+
+    /// Parse `[k = v, ...]`, possibly repeated, into `attrs`.
+    fn attr_lists(&mut self, attrs: &mut Map<String, Value>) -> Result<(), Error> {
+        while self.eat("[") {
+            while !self.eat("]") {
+                let k = self.id()?;
+                self.expect("=")?;
+                let v = self.id()?;
+                attrs.insert(k, Value::String(v));
+                let _ = self.eat(",") || self.eat(";");
+            }
+        }
+        Ok(())
+    }
+
+    fn graph(&mut self) -> Result<Graph, Error> {
+        let mut graph = Graph::default();
+        let _ = self.keyword("strict");
+        if self.keyword("digraph") {
+            graph.directed = true;
+        } else if !self.keyword("graph") {
+            return Err(self.unexpected(r#""graph" or "digraph""#));
+        }
+        if let Some(Token::Id(_)) = self.peek() {
+            self.pos += 1;
+        }
+        self.expect("{")?;
+        self.stmts(&mut graph)?;
+        if self.peek().is_some() {
+            return Err(self.unexpected("the end of the diagram"));
+        }
+        Ok(graph)
+    }
+
+    /// Parse the statements up to the closing brace of the graph, flattening the subgraphs into
+    /// `graph`. Subgraphs are tracked by how many are open rather than by recursing, so that
+    /// deeply nested ones can't overflow the stack.
+    fn stmts(&mut self, graph: &mut Graph) -> Result<(), Error> {
+        let mut subgraphs = 0_usize;
+        loop {
+            if self.eat("}") {
+                if subgraphs == 0 {
+                    break;
+                }
+                subgraphs -= 1;
+                continue;
+            }
+            if self.eat(";") {
+                continue;
+            }
+            if self.keyword("subgraph") {
+                if let Some(Token::Id(_)) = self.peek() {
+                    self.pos += 1;
+                }
+                self.expect("{")?;
+                subgraphs += 1;
+                continue;
+            }
+            if self.eat("{") {
+                subgraphs += 1;
+                continue;
+            }
+            if self.keyword("graph") {
+                let mut attrs = Map::new();
+                self.attr_lists(&mut attrs)?;
+                graph.attrs.extend(attrs);
+                continue;
+            }
+
+            // default attributes of nodes and edges don't matter for diagrams
+            if self.keyword("node") || self.keyword("edge") {
+                self.attr_lists(&mut Map::new())?;
+                continue;
+            }
+
+            let id = self.id()?;
+            if self.eat("=") {
+                let v = self.id()?;
+                graph.attrs.insert(id, Value::String(v));
+                continue;
+            }
+
+            let mut chain = vec![id];
+            while self.eat("->") || self.eat("--") {
+                chain.push(self.id()?);
+            }
+            let mut attrs = Map::new();
+            self.attr_lists(&mut attrs)?;
+            if chain.len() == 1 {
+                graph.node(&chain[0]).extend(attrs);
+            } else {
+                for n in &chain {
+                    graph.node(n);
+                }
+                for w in chain.windows(2) {
+                    graph
+                        .edges
+                        .push((w[0].clone(), w[1].clone(), attrs.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Convert the DOT graph `input` to a diagram in the JSON format.
+///
+/// If all the nodes have a `pos` attribute, written as `"column,row"`, the nodes become blocks at
+/// those positions. Otherwise the diagram is a layered one, with `rankdir=LR` or `RL` laying it out
+/// from left to right. Nodes are labelled with their `label` or their id, edges with their
+/// `taillabel` and `headlabel` and a `label` is drawn like a `taillabel`. Edges with
+/// `style=dashed` are dashed and the ones with `dir=none` are not directed.
+pub(crate) fn dot_to_json(input: &str) -> Result<Value, Error> {
+    let tokens = tokenize(input)?;
+    let graph = Parser { tokens, pos: 0 }.graph()?;
+
+    let attr = |attrs: &Map<String, Value>, k: &str| {
+        attrs.get(k).and_then(Value::as_str).map(str::to_string)
+    };
+
+    let positions = graph
+        .nodes
+        .iter()
+        .filter_map(|(id, attrs)| Some((id, attr(attrs, "pos")?)))
+        .map(|(id, pos)| {
+            let coords = pos
+                .trim_end_matches('!')
+                .split(',')
+                .map(|c| c.trim().parse::<i32>())
+                .collect::<Result<Vec<_>, _>>();
+            match coords.as_deref() {
+                Ok([column, row]) => Ok(json!({ "column": column, "row": row })),
+                _ => Err(Error::Parse(format!(
+                    r#"the pos of node "{}" must be written as "column,row", not "{}""#,
+                    id, pos
+                ))),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if !positions.is_empty() && positions.len() != graph.nodes.len() {
+        return Err(Error::Parse(
+            "either all nodes of a DOT diagram must have a pos or none of them".to_string(),
+        ));
+    }
+
+    let edges = graph
+        .edges
+        .iter()
+        .map(|(from, to, attrs)| {
+            let mut e = json!({ "from": from, "to": to });
+            if let Some(l) = attr(attrs, "taillabel").or_else(|| attr(attrs, "label")) {
+                e["from_label"] = json!(l);
+            }
+            if let Some(l) = attr(attrs, "headlabel") {
+                e["to_label"] = json!(l);
+            }
+            if attr(attrs, "style").as_deref() == Some("dashed") {
+                e["dashed"] = json!(true);
+            }
+            if attr(attrs, "dir").as_deref() == Some("none") {
+                e["directed"] = json!(false);
+            }
+            e
+        })
+        .collect::<Vec<_>>();
+
+    let nodes = graph.nodes.iter().map(|(id, attrs)| {
+        let text = attr(attrs, "label").unwrap_or_else(|| id.clone());
+        json!({ "id": id, "text": text })
+    });
+
+    let mut diagram = json!({ "directed": graph.directed, "edges": edges });
+    if positions.is_empty() {
+        let horizontal = matches!(attr(&graph.attrs, "rankdir").as_deref(), Some("LR" | "RL"));
+        diagram["kind"] = json!("layered");
+        diagram["orientation"] = json!(if horizontal { "horizontal" } else { "vertical" });
+        diagram["nodes"] = nodes.collect();
+    } else {
+        diagram["blocks"] = nodes
+            .zip(positions)
+            .map(|(mut n, position)| {
+                n["position"] = position;
+                n
+            })
+            .collect();
+    }
+    Ok(diagram)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::Format;
+
+    #[test]
+    fn test_dot_to_json() {
+        let dot = r#"
+// a comment
+digraph pipeline {
+    rankdir = LR;
+    node [shape = box];
+    lex [label = "lexer\nscanner"];
+    lex -> parse -> check [label = "ast"];
+    subgraph cluster_backend { check -> emit [style = dashed, dir = none] }
+    /* emit loops back */
+    emit -> lex;
+}
+"#;
+        assert_eq!(
+            dot_to_json(dot).unwrap(),
+            json!({
+                "kind": "layered",
+                "orientation": "horizontal",
+                "directed": true,
+                "nodes": [
+                    { "id": "lex", "text": "lexer\nscanner" },
+                    { "id": "parse", "text": "parse" },
+                    { "id": "check", "text": "check" },
+                    { "id": "emit", "text": "emit" },
+                ],
+                "edges": [
+                    { "from": "lex", "to": "parse", "from_label": "ast" },
+                    { "from": "parse", "to": "check", "from_label": "ast" },
+                    { "from": "check", "to": "emit", "dashed": true, "directed": false },
+                    { "from": "emit", "to": "lex" },
+                ],
+            })
+        );
+
+        let positioned = r#"graph { a [pos = "0,0"]; b [pos = "1,0"]; a -- b }"#;
+        assert_eq!(
+            dot_to_json(positioned).unwrap(),
+            json!({
+                "directed": false,
+                "blocks": [
+                    { "id": "a", "text": "a", "position": { "column": 0, "row": 0 } },
+                    { "id": "b", "text": "b", "position": { "column": 1, "row": 0 } },
+                ],
+                "edges": [{ "from": "a", "to": "b" }],
+            })
+        );
+
+        assert_eq!(
+            dot_to_json(r#"graph { a [pos = "0,0"]; a -- b }"#),
+            Err(Error::Parse(
+                "either all nodes of a DOT diagram must have a pos or none of them".to_string()
+            ))
+        );
+        assert_eq!(
+            dot_to_json("digraph { a -> }"),
+            Err(Error::Parse(
+                r#"expected an identifier in DOT diagram, found "}""#.to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_guess() {
+        assert_eq!(Format::guess("// a -> b\ndigraph { a -> b }"), Format::Dot);
+        assert_eq!(Format::guess("/* a\n = b */ graph { a -- b }"), Format::Dot);
+        assert_eq!(Format::guess("# a\nstrict digraph { a -> b }"), Format::Dot);
+        assert_eq!(Format::guess("# a\ngraph = 1"), Format::Toml);
+    }
+
+    #[test]
+    fn test_nested_subgraphs() {
+        let depth = 100_000;
+        let dot = format!(
+            "digraph {{ {} a -> b {} }}",
+            "subgraph { {".repeat(depth),
+            "} }".repeat(depth)
+        );
+        let json = dot_to_json(&dot).unwrap();
+        assert_eq!(json["edges"], json!([{ "from": "a", "to": "b" }]));
+
+        assert!(dot_to_json(&format!("digraph {{ {} a }}", "{".repeat(depth))).is_err());
+    }
+}
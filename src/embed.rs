@@ -0,0 +1,376 @@
+/// Language tag that marks a source block as a diagram to render.
+pub const DIAGRAM_TAG: &str = "ascii-diagram";
+
+/// Tag that marks the rendered output of a diagram so that it can be found and updated on the
+/// next run.
+pub const OUTPUT_TAG: &str = "ascii-diagram-output";
+
+/// The kinds of documents diagrams can be embedded in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DocumentKind {
+    /// ```` ```ascii-diagram ```` fenced code blocks.
+    Markdown,
+
+    /// `#+BEGIN_SRC ascii-diagram` source blocks.
+    Org,
+
+    /// `.. ascii-diagram::` directives.
+    Rst,
+}
+
+impl DocumentKind {
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext {
+            "md" | "markdown" => Some(DocumentKind::Markdown),
+            "org" => Some(DocumentKind::Org),
+            "rst" => Some(DocumentKind::Rst),
+            _ => None,
+        }
+    }
+
+    /// Parse the diagram source block starting at line `i`, if any. Return the diagram and the
+    /// index of the first line after the block.
+    fn parse_source(self, lines: &[&str], i: usize) -> Option<(String, usize)> {
+        let line = lines[i];
+
+        match self {
+            DocumentKind::Markdown => {
+                let fence = markdown_fence(line)?;
+                if line[fence.len()..].trim() != DIAGRAM_TAG {
+                    return None;
+                }
+
+                let end = find_markdown_fence_end(lines, i + 1, fence)?;
+                Some((lines[i + 1..end].join("\n"), end + 1))
+            }
+            DocumentKind::Org => {
+                let mut words = line.split_whitespace();
+                if !words.next()?.eq_ignore_ascii_case("#+begin_src")
+                    || words.next()? != DIAGRAM_TAG
+                {
+                    return None;
+                }
+
+                let end = (i + 1..lines.len())
+                    .find(|&j| lines[j].trim().eq_ignore_ascii_case("#+end_src"))?;
+                Some((lines[i + 1..end].join("\n"), end + 1))
+            }
+            DocumentKind::Rst => {
+                if line.trim() != format!(".. {}::", DIAGRAM_TAG) {
+                    return None;
+                }
+
+                let end = find_rst_indented_block_end(lines, i + 1, indentation(line));
+                Some((dedent(&lines[i + 1..end]), end))
+            }
+        }
+    }
+
+    /// Parse the code or example block starting at line `i` that is not a diagram, if any. Return
+    /// the index of the first line after the block, which is the end of the document if it's
+    /// never closed. Diagrams shown inside such blocks, for example to document the syntax, are
+    /// left alone.
+    fn parse_verbatim(self, lines: &[&str], i: usize) -> Option<usize> {
+        let line = lines[i];
+
+        match self {
+            DocumentKind::Markdown => {
+                let fence = markdown_fence(line)?;
+                Some(find_markdown_fence_end(lines, i + 1, fence).map_or(lines.len(), |j| j + 1))
+            }
+            DocumentKind::Org => {
+                let begin = line.split_whitespace().next()?.to_ascii_lowercase();
+                let end = format!("#+end_{}", begin.strip_prefix("#+begin_")?);
+
+                let end =
+                    (i + 1..lines.len()).find(|&j| lines[j].trim().eq_ignore_ascii_case(&end));
+                Some(end.map_or(lines.len(), |j| j + 1))
+            }
+            // directives can be nested, diagrams inside them are rendered too
+            DocumentKind::Rst => None,
+        }
+    }
+
+    /// Parse the output of a previous run starting at line `i`, if any. Return the index of the
+    /// first line after the output.
+    fn parse_output(self, lines: &[&str], i: usize) -> Option<usize> {
+        let line = lines.get(i)?;
+
+        match self {
+            DocumentKind::Markdown => {
+                let fence = markdown_fence(line)?;
+                if line[fence.len()..].trim() != format!("text {}", OUTPUT_TAG) {
+                    return None;
+                }
+
+                Some(find_markdown_fence_end(lines, i + 1, fence)? + 1)
+            }
+            DocumentKind::Org => {
+                if !line.trim().eq_ignore_ascii_case("#+results:")
+                    || !lines
+                        .get(i + 1)?
+                        .trim()
+                        .eq_ignore_ascii_case("#+begin_example")
+                {
+                    return None;
+                }
+
+                let end = (i + 2..lines.len())
+                    .find(|&j| lines[j].trim().eq_ignore_ascii_case("#+end_example"))?;
+                Some(end + 1)
+            }
+            DocumentKind::Rst => {
+                if line.trim() != format!(".. {}", OUTPUT_TAG) {
+                    return None;
+                }
+
+                let j = skip_blank_lines(lines, i + 1);
+                if lines.get(j)?.trim() != "::" {
+                    return None;
+                }
+
+                Some(find_rst_indented_block_end(lines, j + 1, indentation(line)))
+            }
+        }
+    }
+
+    /// Format the rendered diagram in a way that's displayed verbatim. `tagged` controls whether
+    /// the output should be tagged with `OUTPUT_TAG` so it can be updated later.
+    fn format_output(self, rendered: &str, indent: &str, tagged: bool) -> Vec<String> {
+        let mut out = vec![];
+
+        match self {
+            DocumentKind::Markdown => {
+                if tagged {
+                    out.push(format!("{}```text {}", indent, OUTPUT_TAG));
+                } else {
+                    out.push(format!("{}```text", indent));
+                }
+                out.extend(rendered.lines().map(|l| format!("{}{}", indent, l)));
+                out.push(format!("{}```", indent));
+            }
+            DocumentKind::Org => {
+                if tagged {
+                    out.push(format!("{}#+RESULTS:", indent));
+                }
+                out.push(format!("{}#+begin_example", indent));
+                out.extend(rendered.lines().map(|l| format!("{}{}", indent, l)));
+                out.push(format!("{}#+end_example", indent));
+            }
+            DocumentKind::Rst => {
+                if tagged {
+                    out.push(format!("{}.. {}", indent, OUTPUT_TAG));
+                    out.push(String::new());
+                }
+                out.push(format!("{}::", indent));
+                out.push(String::new());
+                out.extend(rendered.lines().map(|l| format!("{}    {}", indent, l)));
+            }
+        }
+
+        out
+    }
+}
+
+/// Render all the diagrams embedded in `doc` with `render`.
+///
+/// If `replace` is true then the diagram sources are replaced by their rendered output, otherwise
+/// the output is appended after each source, replacing the output of previous runs if present.
+pub fn embed(
+    doc: &str,
+    kind: DocumentKind,
+    replace: bool,
+    mut render: impl FnMut(&str) -> Result<String, String>,
+) -> Result<String, String> {
+    let lines = doc.lines().collect::<Vec<_>>();
+    let mut out = vec![];
+
+    let mut i = 0;
+    while i < lines.len() {
+        let (diagram, end) = match kind.parse_source(&lines, i) {
+            Some(s) => s,
+            None => {
+                let end = kind.parse_verbatim(&lines, i).unwrap_or(i + 1);
+                out.extend(lines[i..end].iter().map(|l| l.to_string()));
+                i = end;
+                continue;
+            }
+        };
+
+        let rendered = render(&diagram)?;
+        let indent = &lines[i][..indentation(lines[i])];
+
+        if !replace {
+            out.extend(lines[i..end].iter().map(|l| l.to_string()));
+            out.push(String::new());
+        }
+        out.extend(kind.format_output(&rendered, indent, !replace));
+
+        i = end;
+        if let Some(prev_end) = kind.parse_output(&lines, skip_blank_lines(&lines, end)) {
+            i = prev_end;
+        }
+    }
+
+    let mut out = out.join("\n");
+    if doc.ends_with('\n') {
+        out.push('\n');
+    }
+
+    Ok(out)
+}
+
+/// Return the opening fence of a markdown code block, if `line` is one.
+fn markdown_fence(line: &str) -> Option<&str> {
+    ["```", "~~~"].iter().find_map(|f| {
+        let n = line.len() - line.trim_start_matches(&f[..1]).len();
+        if n >= 3 {
+            Some(&line[..n])
+        } else {
+            None
+        }
+    })
+}
+
+fn find_markdown_fence_end(lines: &[&str], start: usize, fence: &str) -> Option<usize> {
+    (start..lines.len()).find(|&j| {
+        let l = lines[j].trim_end();
+        l.starts_with(fence) && l.trim_start_matches(&fence[..1]).is_empty()
+    })
+}
+
+/// Find the end of the rST block made of the lines indented more than `indent` starting at
+/// `start`. Trailing blank lines are not considered part of the block.
+fn find_rst_indented_block_end(lines: &[&str], start: usize, indent: usize) -> usize {
+    let mut end = start;
+    for (j, l) in lines.iter().enumerate().skip(start) {
+        if l.trim().is_empty() {
+            continue;
+        }
+        if indentation(l) <= indent {
+            break;
+        }
+        end = j + 1;
+    }
+    end
+}
+
+fn skip_blank_lines(lines: &[&str], mut i: usize) -> usize {
+    while i < lines.len() && lines[i].trim().is_empty() {
+        i += 1;
+    }
+    i
+}
+
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+/// Remove the common indentation from all the given lines.
+fn dedent(lines: &[&str]) -> String {
+    let indent = lines
+        .iter()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| indentation(l))
+        .min()
+        .unwrap_or(0);
+
+    lines
+        .iter()
+        .map(|l| l.get(indent..).unwrap_or(""))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fake_render(diagram: &str) -> Result<String, String> {
+        Ok(format!("<{}>", diagram.trim()))
+    }
+
+    #[test]
+    fn test_embed_markdown() {
+        let doc = "# title\n\n```ascii-diagram\nfoo\n```\n\ntext\n";
+
+        let appended = embed(doc, DocumentKind::Markdown, false, fake_render).unwrap();
+        assert_eq!(
+            appended,
+            "# title\n\n```ascii-diagram\nfoo\n```\n\n```text ascii-diagram-output\n<foo>\n```\n\ntext\n"
+        );
+        assert_eq!(
+            embed(&appended, DocumentKind::Markdown, false, fake_render).unwrap(),
+            appended
+        );
+
+        assert_eq!(
+            embed(doc, DocumentKind::Markdown, true, fake_render).unwrap(),
+            "# title\n\n```text\n<foo>\n```\n\ntext\n"
+        );
+    }
+
+    #[test]
+    fn test_embed_nested_fences() {
+        // the syntax of diagrams documented in a longer fence, then an actual diagram
+        let doc = "````markdown\n```ascii-diagram\nfoo\n```\n````\n\n~~~\n```ascii-diagram\n~~~\n\n```ascii-diagram\nbar\n```\n";
+
+        assert_eq!(
+            embed(doc, DocumentKind::Markdown, true, fake_render).unwrap(),
+            "````markdown\n```ascii-diagram\nfoo\n```\n````\n\n~~~\n```ascii-diagram\n~~~\n\n```text\n<bar>\n```\n"
+        );
+
+        // an unclosed fence runs until the end of the document
+        let doc = "```\n```ascii-diagram\nfoo\n";
+        assert_eq!(
+            embed(doc, DocumentKind::Markdown, true, fake_render).unwrap(),
+            doc
+        );
+
+        let doc = "#+begin_example\n#+BEGIN_SRC ascii-diagram\nfoo\n#+END_SRC\n#+end_example\n";
+        assert_eq!(
+            embed(doc, DocumentKind::Org, true, fake_render).unwrap(),
+            doc
+        );
+    }
+
+    #[test]
+    fn test_embed_org() {
+        let doc = "* title\n#+BEGIN_SRC ascii-diagram\nfoo\n#+END_SRC\ntext\n";
+
+        let appended = embed(doc, DocumentKind::Org, false, fake_render).unwrap();
+        assert_eq!(
+            appended,
+            "* title\n#+BEGIN_SRC ascii-diagram\nfoo\n#+END_SRC\n\n#+RESULTS:\n#+begin_example\n<foo>\n#+end_example\ntext\n"
+        );
+        assert_eq!(
+            embed(&appended, DocumentKind::Org, false, fake_render).unwrap(),
+            appended
+        );
+
+        assert_eq!(
+            embed(doc, DocumentKind::Org, true, fake_render).unwrap(),
+            "* title\n#+begin_example\n<foo>\n#+end_example\ntext\n"
+        );
+    }
+
+    #[test]
+    fn test_embed_rst() {
+        let doc = "Title\n=====\n\n.. ascii-diagram::\n\n   foo\n   bar\n\ntext\n";
+
+        let appended = embed(doc, DocumentKind::Rst, false, fake_render).unwrap();
+        assert_eq!(
+            appended,
+            "Title\n=====\n\n.. ascii-diagram::\n\n   foo\n   bar\n\n.. ascii-diagram-output\n\n::\n\n    <foo\n    bar>\n\ntext\n"
+        );
+        assert_eq!(
+            embed(&appended, DocumentKind::Rst, false, fake_render).unwrap(),
+            appended
+        );
+
+        assert_eq!(
+            embed(doc, DocumentKind::Rst, true, fake_render).unwrap(),
+            "Title\n=====\n\n::\n\n    <foo\n    bar>\n\ntext\n"
+        );
+    }
+}
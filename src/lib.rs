@@ -1,50 +1,676 @@
-/// (row, column)
-pub type LogicalPoint = (LogicalCoord, LogicalCoord);
+use std::ops::{Add, Sub};
+
 pub type LogicalCoord = i32;
 
+/// A position in the logical space where each block occupies the exact same amount of space both
+/// horizontally and vertically, as opposed to the canvas space where `CanvasPoint`s live.
+///
+/// It can be converted from and into a (row, column) tuple.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct LogicalPos {
+    pub row: LogicalCoord,
+    pub col: LogicalCoord,
+}
+
+impl LogicalPos {
+    pub fn new(row: LogicalCoord, col: LogicalCoord) -> Self {
+        LogicalPos { row, col }
+    }
+
+    /// The number of rows and columns to cross to go from this position to `other`.
+    pub fn manhattan_distance(self, other: LogicalPos) -> LogicalCoord {
+        (self.row - other.row).abs() + (self.col - other.col).abs()
+    }
+}
+
+impl Add for LogicalPos {
+    type Output = LogicalPos;
+
+    fn add(self, o: LogicalPos) -> LogicalPos {
+        LogicalPos::new(self.row + o.row, self.col + o.col)
+    }
+}
+
+impl Sub for LogicalPos {
+    type Output = LogicalPos;
+
+    fn sub(self, o: LogicalPos) -> LogicalPos {
+        LogicalPos::new(self.row - o.row, self.col - o.col)
+    }
+}
+
+impl From<(LogicalCoord, LogicalCoord)> for LogicalPos {
+    fn from((row, col): (LogicalCoord, LogicalCoord)) -> Self {
+        LogicalPos { row, col }
+    }
+}
+
+impl From<LogicalPos> for (LogicalCoord, LogicalCoord) {
+    fn from(p: LogicalPos) -> Self {
+        (p.row, p.col)
+    }
+}
+
+/// The shape of the outline of a `Block`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shape {
+    /// A plain rectangle.
+    Rectangle,
+
+    /// A rectangle with a folded top right corner, usually used for annotations.
+    Note,
+
+    /// A rectangle whose sides are square brackets, usually used for external systems or users
+    /// like in context diagrams.
+    External,
+}
+
+/// How the text of a `Block` that doesn't fit in its explicit size is handled. In both cases
+/// the text that still doesn't fit is cut and terminated by an ellipsis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Wrap lines at word boundaries.
+    Wrap,
+
+    /// Truncate lines that are too long.
+    Truncate,
+}
+
+/// The direction the text of a `Block` is written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextDirection {
+    LeftToRight,
+
+    /// Characters are drawn from right to left and lines are aligned to the right.
+    RightToLeft,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Block {
     text: Vec<Vec<u8>>,
 
-    // these coordinates are in a logical space where each block occupies the exact same amount of
-    // space both horizontally and vertically.
-    column: LogicalCoord,
-    row: LogicalCoord,
+    // optional text rendered below a separator at the bottom of the block, empty if there's none.
+    footer: Vec<Vec<u8>>,
+
+    shape: Shape,
+
+    // short text drawn over the top left corner of the border, empty if there's none.
+    corner_text: Vec<u8>,
+
+    pos: LogicalPos,
 
-    // these are the dimensions in canvas space of the text contained in the block.
+    // these are the dimensions in canvas space of the text contained in the block. The width
+    // accounts for the footer too while the height does not.
     text_width: usize,
     text_height: usize,
+
+    // explicit dimensions in canvas space of the block that override the ones derived from the
+    // text, if any. The height does not include the footer.
+    width: Option<usize>,
+    height: Option<usize>,
+    overflow: Overflow,
+
+    text_direction: TextDirection,
+
+    // whether the text is drawn one character per row, each line being a column.
+    vertical: bool,
+
+    // constraints for `optimize_layout`.
+    pinned: bool,
+    order: Option<i32>,
+
+    // drawing order of the blocks at the same position, see `with_z`.
+    z: i32,
+
+    // whether a shadow is drawn right below and to the right of the block, see `with_shadow`.
+    shadow: bool,
+
+    // character filling the empty space inside the block, if any.
+    fill: Option<u8>,
+
+    // rectangle of the canvas the block is kept at, if any, see `with_rect`.
+    rect: Option<CanvasRect>,
+
+    // whether the outline is dashed, see `with_dashed`.
+    dashed: bool,
 }
 
 impl Block {
-    pub fn new((row, column): LogicalPoint, t: &[u8]) -> Self {
-        let mut text_width = 0;
-        let mut text = vec![vec![]];
-        for c in t {
-            if *c == b'\n' {
-                text_width = text_width.max(text.last().unwrap().len());
-                text.push(vec![]);
-                continue;
-            }
-
-            if *c == b' ' || c.is_ascii_graphic() {
-                text.last_mut().unwrap().push(*c);
-            }
-        }
-        text_width = text_width.max(text.last().unwrap().len());
+    /// Create a block at the given (row, column) with the given text. Only printable ASCII
+    /// characters are kept, see `Block::from_str`.
+    #[deprecated(note = "use `Block::from_str` instead")]
+    pub fn new(position: impl Into<LogicalPos>, t: &[u8]) -> Self {
+        Block::from_bytes(position.into(), t)
+    }
 
+    /// Create a block at the given (row, column) with the given text, possibly on multiple lines.
+    /// Only printable ASCII characters are kept, other characters are dropped. Tabs are dropped
+    /// too rather than expanded, expand them beforehand to keep the text aligned, like diagram
+    /// specs do with their `tab_width`.
+    pub fn from_str(position: impl Into<LogicalPos>, t: &str) -> Self {
+        Block::from_bytes(position.into(), t.as_bytes())
+    }
+
+    fn from_bytes(pos: LogicalPos, t: &[u8]) -> Self {
+        let (text, text_width) = parse_text(t);
         let text_height = text.len();
 
         Self {
-            column,
-            row,
+            pos,
             text,
+            footer: vec![],
+            shape: Shape::Rectangle,
+            corner_text: vec![],
             text_height,
             text_width,
+            width: None,
+            height: None,
+            overflow: Overflow::Wrap,
+            text_direction: TextDirection::LeftToRight,
+            vertical: false,
+            pinned: false,
+            order: None,
+            z: 0,
+            shadow: false,
+            fill: None,
+            rect: None,
+            dashed: false,
+        }
+    }
+
+    pub fn position(&self) -> LogicalPos {
+        self.pos
+    }
+
+    /// Move the block to the given position.
+    pub fn set_position(&mut self, position: impl Into<LogicalPos>) {
+        self.pos = position.into();
+    }
+
+    /// Move the block to the given position.
+    pub fn with_position(mut self, position: impl Into<LogicalPos>) -> Self {
+        self.set_position(position);
+        self
+    }
+
+    /// The lines of the text of the block.
+    pub fn text(&self) -> &[Vec<u8>] {
+        &self.text
+    }
+
+    /// Replace the text of the block, the same characters as in `Block::from_str` are kept.
+    pub fn set_text(&mut self, t: impl AsRef<[u8]>) {
+        self.text = parse_text(t.as_ref()).0;
+        self.update_text_size();
+    }
+
+    /// Replace the text of the block, the same characters as in `Block::from_str` are kept.
+    pub fn with_text(mut self, t: impl AsRef<[u8]>) -> Self {
+        self.set_text(t);
+        self
+    }
+
+    /// The lines of the footer of the block, empty if there's none.
+    pub fn footer(&self) -> &[Vec<u8>] {
+        &self.footer
+    }
+
+    /// Set the footer of the block, that is some text rendered below a separator at the bottom of
+    /// the block.
+    pub fn with_footer(mut self, t: impl AsRef<[u8]>) -> Self {
+        self.footer = parse_text(t.as_ref()).0;
+        self.update_text_size();
+        self
+    }
+
+    /// The explicit (width, height) of the block set with `with_width` and `with_height`, if
+    /// any.
+    pub fn size(&self) -> (Option<usize>, Option<usize>) {
+        (self.width, self.height)
+    }
+
+    /// Set or clear the explicit width and height of the block, see `with_width` and
+    /// `with_height`.
+    pub fn set_size(&mut self, width: Option<usize>, height: Option<usize>) {
+        self.width = width;
+        self.height = height;
+    }
+
+    pub fn shape(&self) -> Shape {
+        self.shape
+    }
+
+    pub fn overflow(&self) -> Overflow {
+        self.overflow
+    }
+
+    pub fn text_direction(&self) -> TextDirection {
+        self.text_direction
+    }
+
+    /// Whether the text is drawn vertically, see `with_vertical_text`.
+    pub fn is_vertical(&self) -> bool {
+        self.vertical
+    }
+
+    /// Whether `optimize_layout` can move the block, see `with_pinned`.
+    pub fn is_pinned(&self) -> bool {
+        self.pinned
+    }
+
+    /// The position of the block in the reading order of its row, see `with_order`.
+    pub fn order(&self) -> Option<i32> {
+        self.order
+    }
+
+    /// The drawing order of the block among the ones at the same position, see `with_z`.
+    pub fn z(&self) -> i32 {
+        self.z
+    }
+
+    /// Whether the block casts a shadow, see `with_shadow`.
+    pub fn has_shadow(&self) -> bool {
+        self.shadow
+    }
+
+    /// The character filling the empty space inside the block, see `with_fill`.
+    pub fn fill(&self) -> Option<u8> {
+        self.fill
+    }
+
+    /// Whether the outline of the block is dashed, see `with_dashed`.
+    pub fn is_dashed(&self) -> bool {
+        self.dashed
+    }
+
+    /// The rectangle of the canvas the block is kept at, see `with_rect`.
+    pub fn rect(&self) -> Option<CanvasRect> {
+        self.rect
+    }
+
+    /// The text drawn over the top left corner of the border, empty if there's none.
+    pub fn corner_text(&self) -> &[u8] {
+        &self.corner_text
+    }
+
+    /// Set the shape used to draw the outline of the block.
+    pub fn with_shape(mut self, shape: Shape) -> Self {
+        self.shape = shape;
+        self
+    }
+
+    /// Set the width of the block, borders included, regardless of its text. Text that doesn't
+    /// fit is wrapped and then truncated with an ellipsis.
+    pub fn with_width(mut self, width: usize) -> Self {
+        self.width = Some(width);
+        self
+    }
+
+    /// Set the height of the block, borders included but footer excluded, regardless of its
+    /// text. Lines that don't fit are dropped and the last visible one ends with an ellipsis.
+    pub fn with_height(mut self, height: usize) -> Self {
+        self.height = Some(height);
+        self
+    }
+
+    /// Set how text that doesn't fit in the explicit size of the block is handled.
+    pub fn with_overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Whether the text of the block doesn't fit in its explicit size, if any, and so it has to
+    /// be wrapped or truncated. Note that the padding is sacrificed before the text.
+    pub fn overflows(&self) -> bool {
+        // -2 for the borders
+        let overflows_width = self.width.is_some_and(|w| self.text_width + 2 > w.max(3));
+        let overflows_height = self.height.is_some_and(|h| self.text_height + 2 > h.max(3));
+
+        overflows_width || overflows_height
+    }
+
+    /// Set the direction the text and the footer of the block are written in.
+    pub fn with_text_direction(mut self, text_direction: TextDirection) -> Self {
+        self.text_direction = text_direction;
+        self
+    }
+
+    /// Draw the text of the block vertically, one character per row. Each line of the text
+    /// becomes a column, columns are laid out following the text direction. The footer is not
+    /// affected.
+    pub fn with_vertical_text(mut self, vertical: bool) -> Self {
+        self.vertical = vertical;
+        self.update_text_size();
+        self
+    }
+
+    /// Prevent `optimize_layout` from moving the block.
+    pub fn with_pinned(mut self, pinned: bool) -> Self {
+        self.pinned = pinned;
+        self
+    }
+
+    /// Set the position of the block in the reading order of its row. `optimize_layout` never
+    /// moves blocks with an order to another row, nor out of sequence with the other ordered
+    /// blocks in the same row, from left to right.
+    pub fn with_order(mut self, order: i32) -> Self {
+        self.order = Some(order);
+        self
+    }
+
+    /// Set the drawing order of the block among the ones at the same position, which is 0 by
+    /// default. Blocks at the same position overlap on purpose if they have different z: the
+    /// one with the lowest z fills the cell like any other block, while the others are drawn over
+    /// it in increasing z order at their own size, in the top right corner of the cell. That's
+    /// useful for decorations like badges. Edges are attached to the cell anyway.
+    pub fn with_z(mut self, z: i32) -> Self {
+        self.z = z;
+        self
+    }
+
+    /// Draw a shadow one cell below and to the right of the block, like this:
+    ///
+    /// ```text
+    /// +-----+
+    /// | box |#
+    /// +-----+#
+    ///  #######
+    /// ```
+    ///
+    /// The shadow takes an extra column and row of margin and lines go through it only to reach
+    /// the block.
+    pub fn with_shadow(mut self, shadow: bool) -> Self {
+        self.shadow = shadow;
+        self
+    }
+
+    /// Fill the empty space inside the block, around the text, with `c` to make it stand out,
+    /// like the cells of a heatmap. Only printable ASCII characters are kept.
+    pub fn with_fill(mut self, c: u8) -> Self {
+        self.fill = Some(c).filter(u8::is_ascii_graphic);
+        self
+    }
+
+    /// Draw the outline of the block dashed, like for something planned or removed:
+    ///
+    /// ```text
+    /// +- - -+
+    /// : box :
+    /// +- - -+
+    /// ```
+    pub fn with_dashed(mut self, dashed: bool) -> Self {
+        self.dashed = dashed;
+        self
+    }
+
+    /// Keep the block at the given rectangle of the canvas, borders and footer included, like
+    /// one taken from the `Layout` of a previous rendering. The block takes the size of the
+    /// rectangle and its column and row are moved right and down as needed to start where the
+    /// rectangle does, so it stays put as long as the columns and rows before it don't grow and
+    /// the other blocks in its column and row aren't larger.
+    pub fn with_rect(mut self, rect: CanvasRect) -> Self {
+        self.rect = Some(rect);
+        self
+    }
+
+    /// Set a short text to draw over the top left corner of the border of the block, like a
+    /// reference number. Only the first line of `t` is kept.
+    pub fn with_corner_text(mut self, t: impl AsRef<[u8]>) -> Self {
+        let (mut text, _) = parse_text(t.as_ref());
+        self.corner_text = text.swap_remove(0);
+        self
+    }
+
+    /// Compute the dimensions of the text from the text itself, the footer and the direction.
+    fn update_text_size(&mut self) {
+        let lines_width = self.text.iter().map(Vec::len).max().unwrap_or(0);
+        let footer_width = self.footer.iter().map(Vec::len).max().unwrap_or(0);
+
+        // columns are separated by a space
+        let (text_width, text_height) = if self.vertical {
+            (2 * self.text.len() - 1, lines_width)
+        } else {
+            (lines_width, self.text.len())
+        };
+
+        self.text_width = text_width.max(footer_width);
+        self.text_height = text_height;
+    }
+}
+
+/// An `Edge` connects two `Block`s, referenced by their index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edge {
+    from: usize,
+    to: usize,
+
+    // short texts placed next to the endpoints of the edge, empty if there's none.
+    from_label: Vec<u8>,
+    to_label: Vec<u8>,
+
+    // whether an arrowhead is drawn at the `to` endpoint.
+    directed: bool,
+
+    // whether an arrowhead is drawn at both endpoints, see `with_bidirectional`.
+    bidirectional: bool,
+
+    // name of the group of edges this one can share segments with, empty if there's none.
+    group: Vec<u8>,
+
+    // corners of the polyline the edge is drawn along instead of routing it, empty if there's
+    // none, see `with_route`.
+    route: Vec<CanvasPoint>,
+
+    // whether the line is dashed, see `with_dashed`.
+    dashed: bool,
+}
+
+impl Edge {
+    pub fn new(from: usize, to: usize) -> Self {
+        Self {
+            from,
+            to,
+            from_label: vec![],
+            to_label: vec![],
+            directed: false,
+            bidirectional: false,
+            group: vec![],
+            route: vec![],
+            dashed: false,
+        }
+    }
+
+    /// The corners of the polyline the edge is drawn along, see `with_route`.
+    pub fn route(&self) -> &[CanvasPoint] {
+        &self.route
+    }
+
+    /// Set the label placed next to the `from` endpoint, like a cardinality or a role. Only the
+    /// first line of `t` is kept.
+    pub fn with_from_label(mut self, t: impl AsRef<[u8]>) -> Self {
+        self.set_from_label(t);
+        self
+    }
+
+    /// Set the label placed next to the `to` endpoint, like a cardinality or a role. Only the
+    /// first line of `t` is kept.
+    pub fn with_to_label(mut self, t: impl AsRef<[u8]>) -> Self {
+        self.set_to_label(t);
+        self
+    }
+
+    /// Draw an arrowhead pointing to the `to` block.
+    pub fn with_directed(mut self, directed: bool) -> Self {
+        self.set_directed(directed);
+        self
+    }
+
+    /// Draw arrowheads pointing to both blocks, like for a two-way relationship, whether the edge
+    /// is directed or not.
+    pub fn with_bidirectional(mut self, bidirectional: bool) -> Self {
+        self.bidirectional = bidirectional;
+        self
+    }
+
+    /// Let the edge share segments with the other edges of the given group, so that edges
+    /// going to the same block can be drawn as a single trunk with branches. The points where
+    /// the edges of a group branch off are marked with `*`.
+    pub fn with_group(mut self, group: impl AsRef<[u8]>) -> Self {
+        self.set_group(group);
+        self
+    }
+
+    /// Draw the line of the edge dashed, like for something planned or removed. Vertical segments
+    /// are drawn with `:` and every other character of horizontal ones is left blank, except where
+    /// other edges pass.
+    pub fn with_dashed(mut self, dashed: bool) -> Self {
+        self.dashed = dashed;
+        self
+    }
+
+    /// Draw the edge along the polyline with the given corners, from the `from` block to the `to`
+    /// one, like the points of an `EdgeRoute` of a previous rendering. The route is used only if
+    /// it still starts and ends on the borders of the blocks and doesn't go through any of them,
+    /// otherwise the edge is routed as usual but still starting and ending where the route does
+    /// if possible, which keeps the diagram similar to the previous rendering. Other edges are
+    /// routed around it.
+    pub fn with_route(mut self, points: impl IntoIterator<Item = CanvasPoint>) -> Self {
+        self.route = points.into_iter().collect();
+        self
+    }
+
+    /// Set the label placed next to the `from` endpoint, see `with_from_label`.
+    pub fn set_from_label(&mut self, t: impl AsRef<[u8]>) {
+        let (mut text, _) = parse_text(t.as_ref());
+        self.from_label = text.swap_remove(0);
+    }
+
+    /// Set the label placed next to the `to` endpoint, see `with_to_label`.
+    pub fn set_to_label(&mut self, t: impl AsRef<[u8]>) {
+        let (mut text, _) = parse_text(t.as_ref());
+        self.to_label = text.swap_remove(0);
+    }
+
+    /// Whether to draw an arrowhead pointing to the `to` block.
+    pub fn set_directed(&mut self, directed: bool) {
+        self.directed = directed;
+    }
+
+    /// Set the group of edges this one can share segments with, see `with_group`. An empty
+    /// group means no group.
+    pub fn set_group(&mut self, group: impl AsRef<[u8]>) {
+        self.group = group.as_ref().to_vec();
+    }
+}
+
+impl From<(usize, usize)> for Edge {
+    fn from((from, to): (usize, usize)) -> Self {
+        Edge::new(from, to)
+    }
+}
+
+/// Split the given text in lines keeping only printable ASCII characters. Return the lines
+/// alongside the width of the longest one.
+fn parse_text(t: &[u8]) -> (Vec<Vec<u8>>, usize) {
+    let mut width = 0;
+    let mut text = vec![vec![]];
+    for c in t {
+        if *c == b'\n' {
+            width = width.max(text.last().unwrap().len());
+            text.push(vec![]);
+            continue;
+        }
+
+        if *c == b' ' || c.is_ascii_graphic() {
+            text.last_mut().unwrap().push(*c);
         }
     }
+    width = width.max(text.last().unwrap().len());
+
+    (text, width)
 }
 
+#[cfg(feature = "spec")]
+mod corpus;
+#[cfg(feature = "spec")]
+mod d2;
+mod diagram;
+#[cfg(feature = "spec")]
+mod dot;
+#[cfg(feature = "spec")]
+mod plantuml;
 mod render;
+#[cfg(feature = "spec")]
+mod spans;
+#[cfg(feature = "spec")]
+mod spec;
+#[cfg(feature = "spec")]
+mod sync;
+
+#[cfg(feature = "spec")]
+pub use corpus::{check_corpus, expected_path, CorpusMismatch};
+pub use diagram::{BlockId, Diagram, DiagramError};
+#[allow(deprecated)]
+pub use render::render;
+pub use render::{
+    add_guides, add_headers, add_offsets, add_polylines, add_stubs, asciicast, auto_margins,
+    collisions, compact_margins, detours, draw_order, edge_intrusions, estimate_size, fit_to_width,
+    hide_elements, logical_grid, optimize_layout, overlapping_blocks, overwritten_cells, packet,
+    render_layout, render_to, render_to_string, render_with_layout, route_fixture, score,
+    score_layout, sparkline, suggest, try_render_layout, write_rows, Attachment, BlockRect, Canvas,
+    CanvasPoint, CanvasRect, Collision, CollisionKind, Detour, EdgeRoute, Element, Intrusion,
+    Layout, LayoutMismatch, LogicalGrid, Metrics, Overlap, Polyline, PolylineError, PolylinePoint,
+    RenderOptions, RouteError, RouteFailure, Suggestion, CUT_MARKER,
+};
+#[cfg(feature = "spec")]
+pub use spans::Span;
+#[cfg(feature = "spec")]
+pub use spec::{
+    render_spec_all, render_spec_diff, render_spec_str, Error, Format, Limit, Limits,
+    LinkedDiagram, Overrides, RenderedDiagram, Viewport, FRAME_SEPARATOR,
+};
+#[cfg(feature = "spec")]
+pub use sync::sync_spec_str;
 
-pub use render::{render, RenderOptions};
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_logical_pos() {
+        let p = LogicalPos::from((1, -2));
+        assert_eq!(p, LogicalPos { row: 1, col: -2 });
+        assert_eq!(<(i32, i32)>::from(p), (1, -2));
+
+        assert_eq!(p + LogicalPos::new(2, 3), LogicalPos::new(3, 1));
+        assert_eq!(p - LogicalPos::new(2, 3), LogicalPos::new(-1, -5));
+        assert_eq!(p.manhattan_distance(LogicalPos::new(-1, 0)), 4);
+    }
+
+    #[test]
+    fn test_block_accessors() {
+        let mut block = Block::from_str((1, 2), "ab\ncde")
+            .with_footer(b"footer")
+            .with_width(10);
+        assert_eq!(block.position(), LogicalPos::new(1, 2));
+        assert_eq!(block.text(), &[b"ab".to_vec(), b"cde".to_vec()][..]);
+        assert_eq!(block.size(), (Some(10), None));
+        assert_eq!((block.text_width, block.text_height), (6, 2));
+
+        block.set_position((0, -1));
+        block.set_text(b"a much longer line");
+        block.set_size(None, Some(5));
+        assert_eq!(block.position(), LogicalPos::new(0, -1));
+        assert_eq!(block.footer(), &[b"footer".to_vec()][..]);
+        assert_eq!(block.size(), (None, Some(5)));
+        assert_eq!((block.text_width, block.text_height), (18, 1));
+
+        // the text size follows the direction the text is written in
+        let block = Block::from_str((0, 0), "abc").with_vertical_text(true);
+        let block = block.with_text(b"ab\nc");
+        assert!(block.is_vertical());
+        assert_eq!((block.text_width, block.text_height), (3, 2));
+        let block = block.with_vertical_text(false);
+        assert_eq!((block.text_width, block.text_height), (2, 2));
+    }
+}
@@ -1,14 +1,26 @@
-use std::collections::{BTreeSet, HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fs::File;
 use std::io;
 use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use serde::Deserialize;
 use structopt::StructOpt;
 
-use ascii_diagrams::{render, Block, LogicalCoord, RenderOptions};
+use ascii_diagrams::{
+    asciicast, check_corpus, expected_path, render_spec_all, render_spec_diff, render_spec_str,
+    sync_spec_str, write_rows, Format, Limits, LogicalGrid, Overrides, RenderedDiagram, Viewport,
+    FRAME_SEPARATOR,
+};
+
+#[cfg(unix)]
+mod daemon;
+mod embed;
+mod pandoc;
+mod view;
+
+/// Seconds between the strokes of `--to asciicast`.
+const CAST_DELAY: f64 = 0.05;
 
 macro_rules! die {
     ( $ ( $ args : tt ) * ) => {
@@ -29,20 +41,6 @@ macro_rules! try_or_die {
         }
     };
 }
-
-// serde doesn't support literals as default values yet, have to use functions instead...
-//
-// See https://github.com/serde-rs/serde/issues/368
-const fn default_hmargin() -> usize {
-    5
-}
-const fn default_vmargin() -> usize {
-    3
-}
-const fn default_padding() -> usize {
-    1
-}
-
 /// Render a diagram using only ASCII characters.
 ///
 /// This is useful to embed diagrams directly as text instead of using images.
@@ -88,17 +86,26 @@ const fn default_padding() -> usize {
 /// ```
 ///
 /// I also think these diagrams are quite neat to look at.
-#[derive(Debug, StructOpt)]
+#[derive(Debug, Clone, StructOpt)]
 struct Opts {
-    /// Input diagram to render in either TOML or JSON.
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+
+    /// Input diagram to render in either TOML or JSON, or `-` to read it from stdin.
     #[structopt(name = "INPUT", parse(from_os_str))]
-    diagram: PathBuf,
+    diagram: Option<PathBuf>,
 
     /// Output file where to save the final ascii diagram. If nothing is passed stdout will be
     /// used.
     #[structopt(name = "OUTPUT", parse(from_os_str))]
     output: Option<PathBuf>,
 
+    /// Format of the input diagram, given as one of its extensions like "toml" or "json". By
+    /// default it's taken from the extension of INPUT, or guessed from the content of the
+    /// diagram when it's read from stdin.
+    #[structopt(long)]
+    format: Option<String>,
+
     /// Seed to use for the rendering algorithm.
     #[structopt(long)]
     seed: Option<u64>,
@@ -106,228 +113,826 @@ struct Opts {
     /// Maximum number of tweaks to find the best arrangement of lines.
     #[structopt(long, default_value = "100")]
     max_tweaks: usize,
-}
 
-#[derive(Deserialize)]
-struct Spec {
-    blocks: Vec<SpecBlock>,
-    edges: Vec<SpecEdge>,
+    /// Transliterate non-ASCII characters to their closest ASCII representation instead of
+    /// dropping them. What was transliterated is reported on stderr.
+    #[structopt(long)]
+    ascii_only: bool,
 
-    #[serde(default = "default_hmargin")]
-    horizontal_margin: usize,
+    /// Analyze the rendered diagram and print on stderr some changes to the diagram that would
+    /// make it easier to read.
+    #[structopt(long)]
+    suggest: bool,
 
-    #[serde(default = "default_vmargin")]
-    vertical_margin: usize,
+    /// Move the blocks along their rows and columns, or swap whole rows and columns, to minimize
+    /// edge crossings and length. Useful when only the adjacency of the blocks matters, not their
+    /// exact positions. Blocks with `pinned = true` or `external = true` are never moved and
+    /// blocks with an `order` are kept in sequence within their row.
+    #[structopt(long)]
+    optimize_layout: bool,
 
-    #[serde(default = "default_padding")]
-    padding: usize,
-}
+    /// Increase the margins of the diagram until there are no crossings and no lines running
+    /// right next to blocks, or until a fixed budget of attempts is exhausted. The chosen margins
+    /// are printed on stderr.
+    #[structopt(long)]
+    auto_margins: bool,
 
-#[derive(Deserialize)]
-struct SpecBlock {
-    id: Option<String>,
-    text: String,
-    position: SpecPosition,
-}
+    /// Render only the edges with at least one of the given tags. Can be repeated.
+    #[structopt(long = "edge-tag", name = "TAG", number_of_values = 1)]
+    edge_tags: Vec<String>,
+
+    /// Do not render the edges with any of the given tags. Can be repeated.
+    #[structopt(long = "exclude-edge-tag", name = "EXCLUDED_TAG", number_of_values = 1)]
+    exclude_edge_tags: Vec<String>,
 
-#[derive(Deserialize)]
-struct SpecEdge {
-    from: String,
+    /// Draw the edges with any of the given tags dashed, like the legacy ones. Can be repeated.
+    #[structopt(long = "dashed-edge-tag", name = "DASHED_TAG", number_of_values = 1)]
+    dashed_edge_tags: Vec<String>,
+
+    /// Print a report about the rendered diagram on stderr. Can be repeated. `size` reports the
+    /// size of the diagram and its widest line, `grid` the block in each cell of the logical grid
+    /// alongside the width of each column and the height of each row, in characters.
+    #[structopt(long, possible_values = &["size", "grid"], number_of_values = 1)]
+    report: Vec<String>,
+
+    /// Warn about diagrams wider than the given number of columns, like the width of the page
+    /// or of the code blocks of the documents they're embedded in.
+    #[structopt(long)]
+    width_budget: Option<usize>,
+
+    /// Print the fully resolved diagram as JSON instead of rendering it, that is the blocks with
+    /// their ids, normalized text and final positions, the edges between the resolved ids,
+    /// including the inferred ones, and all the options with their defaults applied.
+    #[structopt(long)]
+    dump_resolved: bool,
+
+    /// Output format: "ascii" renders the diagram, "layout-json" emits its geometry as JSON, that
+    /// is the rectangles of the blocks and the routes of the edges in canvas coordinates, and
+    /// "asciicast" emits an asciinema cast drawing the diagram progressively, the blocks first and
+    /// then the edges.
+    #[structopt(
+        long,
+        default_value = "ascii",
+        possible_values = &["ascii", "layout-json", "asciicast"]
+    )]
     to: String,
+
+    /// Render the diagram using the routes in a layout JSON previously emitted by
+    /// `--to layout-json` instead of finding them again. Fail if the layout doesn't match the
+    /// diagram anymore, for example because blocks were added or their text changed. To keep
+    /// only some of it copy the points of the edges to their `route` and the rectangles of the
+    /// blocks to their `rect` in the diagram instead, the rest is laid out again.
+    #[structopt(long, parse(from_os_str))]
+    from_layout: Option<PathBuf>,
+
+    /// Layout JSON of a previous rendering of the diagram, emitted with `--to layout-json`. The
+    /// edges are drawn along their previous routes where they still fit or at least attached
+    /// where they were, so that small changes to the diagram produce small diffs of its
+    /// rendering.
+    #[structopt(long = "prev", parse(from_os_str))]
+    prev_layout: Option<PathBuf>,
+
+    /// Directory where to cache the routes of the edges, so that rendering again a diagram that
+    /// didn't change doesn't need to find them again. Entries are keyed by the resolved diagram,
+    /// see `--dump-resolved`, and the version of this program.
+    #[structopt(long, parse(from_os_str))]
+    cache_dir: Option<PathBuf>,
+
+    /// Shrink the margins of the rendered diagram by removing the empty columns and rows, or the
+    /// ones lines merely run across, while keeping at least one in each margin. It doesn't apply
+    /// to `--to layout-json`.
+    #[structopt(long)]
+    compact_margins: bool,
+
+    /// Mark the corners of the logical cells of the diagram with `.` to tell which row and column
+    /// each block is in. Marks are drawn only on empty space and they don't affect the edges.
+    #[structopt(long)]
+    guides: bool,
+
+    /// Emit only a region of the rendered diagram given as `x,y,width,height` in characters.
+    /// Lines cut at the border of the region are marked with `~`.
+    #[structopt(long)]
+    viewport: Option<Viewport>,
+
+    /// Split diagrams wider than the given number of characters in pages of at most that width,
+    /// from left to right. Lines cut between pages are marked with `~` and listed below each page.
+    #[structopt(long)]
+    split_width: Option<usize>,
+
+    /// Render the diagram step by step for slides or walkthroughs, one frame per step. Blocks
+    /// appear in the order they're declared, or by their `step` if any has one, and edges with
+    /// their blocks or at their own `step`. Frames are separated by form feeds, or written to
+    /// numbered files next to OUTPUT if given, like `diagram-1.txt`, `diagram-2.txt` and so on.
+    #[structopt(long)]
+    frames: bool,
+
+    /// Shrink diagrams wider than the terminal by reducing the horizontal margin and then by
+    /// wrapping the text of the widest blocks. Without it there's only a warning. It has no effect
+    /// when not writing to a terminal.
+    #[structopt(long)]
+    fit: bool,
+
+    /// Reject diagrams with more than this many blocks, to render diagrams from untrusted
+    /// sources.
+    #[structopt(long)]
+    max_blocks: Option<usize>,
+
+    /// Reject diagrams with more than this many edges.
+    #[structopt(long)]
+    max_edges: Option<usize>,
+
+    /// Reject diagrams with a text longer than this many characters.
+    #[structopt(long)]
+    max_text_length: Option<usize>,
+
+    /// Reject diagrams that would be drawn on more than this many characters.
+    #[structopt(long)]
+    max_canvas_cells: Option<usize>,
+
+    /// Stop tweaking the lines after this many milliseconds, keeping the best arrangement found
+    /// so far. The result might differ between runs then.
+    #[structopt(long)]
+    max_tweak_millis: Option<u64>,
+
+    /// Wrap the diagram in a ``` fenced code block, to post it in chats like Slack and Discord.
+    /// Diagrams wider than `--fence-width` are shrunk like with `--fit`, and it's an error if they
+    /// are still too wide since chats would wrap their lines.
+    #[structopt(long)]
+    fence: bool,
+
+    /// Widest diagram that fits in a fenced code block with `--fence`. The default fits in a
+    /// message on Slack and on Discord on desktop.
+    #[structopt(long, default_value = "80")]
+    fence_width: usize,
+
+    /// Width of the terminal the diagram is written to, if any.
+    #[structopt(skip)]
+    terminal_width: Option<usize>,
 }
 
-#[derive(Deserialize)]
-struct SpecPosition {
-    row: LogicalCoord,
-    column: LogicalCoord,
+#[derive(Debug, Clone, StructOpt)]
+enum Command {
+    /// Run as a pandoc JSON filter.
+    ///
+    /// Code blocks with the `ascii-diagram` class are replaced by the rendered diagram. The
+    /// diagram format is taken from the `format` attribute of the code block if present,
+    /// otherwise it's guessed from its content.
+    PandocFilter {
+        /// Output format pandoc is targeting, passed by pandoc itself. Diagrams are emitted as
+        /// raw `<pre>` blocks for HTML formats, as `verbatim` blocks for LaTeX ones, as SVG
+        /// images for docx, odt and pptx and as code blocks for all the others.
+        #[structopt(name = "FORMAT")]
+        format: Option<String>,
+    },
+
+    /// Render the diagrams embedded in a Markdown, Org or reStructuredText document.
+    ///
+    /// Diagrams are written in ```` ```ascii-diagram ```` code blocks in Markdown,
+    /// `#+BEGIN_SRC ascii-diagram` blocks in Org and `.. ascii-diagram::` directives in
+    /// reStructuredText. The rendered output is appended after each diagram and updated on
+    /// subsequent runs.
+    Embed {
+        /// Document containing the diagrams, its kind is detected from the extension.
+        #[structopt(name = "DOCUMENT", parse(from_os_str))]
+        document: PathBuf,
+
+        /// Replace the diagrams with their rendered output instead of appending it.
+        #[structopt(long)]
+        replace: bool,
+
+        /// Update the document in place instead of writing the result to stdout.
+        #[structopt(long)]
+        in_place: bool,
+    },
+
+    /// Show a diagram in an interactive viewer in the alternate screen of the terminal.
+    ///
+    /// The arrow keys (or h, j, k, l) pan diagrams larger than the terminal, page up and page
+    /// down scroll by a screen and home goes back to the top left corner. `+` and `-` increase
+    /// and decrease the margins, re-rendering the diagram. `s` and `S` cycle the seeds while `t`
+    /// and `T` double and halve the tweaks, the status line shows the score of the diagram. `w`
+    /// quits printing the command to render the diagram with the chosen seed and tweaks, `q`
+    /// quits.
+    View {
+        #[structopt(name = "DIAGRAM", parse(from_os_str))]
+        diagram: PathBuf,
+    },
+
+    /// Render two versions of a diagram as one, like a visual changelog.
+    ///
+    /// Blocks and edges removed in the new version are drawn dashed, the others as they are in
+    /// the new version. A legend with what was added and removed follows the diagram.
+    Diff {
+        #[structopt(name = "OLD", parse(from_os_str))]
+        old: PathBuf,
+
+        #[structopt(name = "NEW", parse(from_os_str))]
+        new: PathBuf,
+    },
+
+    /// Render a diagram followed by the diagrams detailing its blocks, like the levels of a C4
+    /// model.
+    ///
+    /// Each diagram is titled with the breadcrumbs leading to it from the top one, following the
+    /// blocks with `details`, and diagrams are separated by form feeds. Warnings are prefixed by
+    /// the name of the diagram they're about.
+    RenderAll {
+        #[structopt(name = "DIAGRAM", parse(from_os_str))]
+        diagram: PathBuf,
+    },
+
+    /// Check that the diagrams in a directory still render as expected, like golden tests.
+    ///
+    /// Each TOML and JSON diagram is compared to the file with the same name and the
+    /// `.expected.txt` extension next to it. Diagrams are rendered with seed 0 unless another one
+    /// is given. The diagrams that don't match are listed on stderr and the exit status is 1.
+    Corpus {
+        #[structopt(name = "DIR", parse(from_os_str))]
+        dir: PathBuf,
+
+        /// Write the expected output of the diagrams that don't match instead of failing, for
+        /// new diagrams and after intended changes.
+        #[structopt(long)]
+        bless: bool,
+    },
+
+    /// Render the diagrams sent by clients over a unix socket, like a service for chat bots and
+    /// web pages.
+    ///
+    /// Each client writes a diagram, in any of the formats the other commands read, and shuts
+    /// down its side of the connection, receiving the rendered diagram followed by its warnings
+    /// as `warning: ...` lines or `error: ...`. The format is guessed from the content of the
+    /// diagram unless its first line is a header like `format: toml`. Diagrams are rendered with
+    /// the limits given on the command line or, by default, at most 50 blocks, 100 edges, texts
+    /// of 1000 characters and 10000 characters in all, with the lines tweaked for at most 2
+    /// seconds, and as many at a time as there are cores. Clients connecting while 64 others are
+    /// waiting are turned away with `error: the server is busy, try again later`.
+    Daemon {
+        #[structopt(name = "SOCKET", parse(from_os_str))]
+        socket: PathBuf,
+    },
+
+    /// Update the positions of the blocks of a diagram after moving them by hand in its rendered
+    /// output.
+    ///
+    /// Blocks are found in the rendered diagram by their text, so they must be moved as a whole
+    /// and to columns and rows that already have blocks, edges can be left as they are. The
+    /// diagram must be rendered with the same options, what was moved is reported on stderr.
+    Sync {
+        /// The rendered diagram where the blocks were moved.
+        #[structopt(name = "RENDERED", parse(from_os_str))]
+        rendered: PathBuf,
+
+        #[structopt(name = "DIAGRAM", parse(from_os_str))]
+        diagram: PathBuf,
+
+        /// Update the diagram in place instead of writing the result to stdout.
+        #[structopt(long)]
+        in_place: bool,
+    },
 }
 
 fn main() {
-    let opts = Opts::from_args();
+    let mut opts = Opts::from_args();
 
-    let mut f = try_or_die!(File::open(&opts.diagram));
-    let mut input_spec = vec![];
-    try_or_die!(f.read_to_end(&mut input_spec));
+    match &opts.cmd {
+        Some(Command::PandocFilter { format: target }) => {
+            let mut input = vec![];
+            try_or_die!(io::stdin().read_to_end(&mut input));
 
-    let spec: Spec = match opts
-        .diagram
-        .extension()
-        .and_then(OsStr::to_str)
-        .unwrap_or("")
-    {
-        "json" => try_or_die!(serde_json::from_slice(&input_spec)),
-        "toml" => try_or_die!(toml::from_slice(&input_spec)),
-        e => {
+            let mut doc = try_or_die!(serde_json::from_slice(&input));
+            try_or_die!(pandoc::filter(
+                &mut doc,
+                target.as_deref(),
+                |format, text| render_to_string(text, parse_format(format)?, &opts)
+            ));
+
+            let stdout = io::stdout();
+            try_or_die!(serde_json::to_writer(stdout.lock(), &doc));
+            return;
+        }
+        Some(Command::Embed {
+            document,
+            replace,
+            in_place,
+        }) => {
+            let ext = document.extension().and_then(OsStr::to_str).unwrap_or("");
+            let kind = match embed::DocumentKind::from_extension(ext) {
+                Some(k) => k,
+                None => die!(
+                    r#"unrecognized document format "{}", valid extensions: md, markdown, org, rst"#,
+                    ext
+                ),
+            };
+
+            let doc = try_or_die!(std::fs::read_to_string(document));
+            let doc = try_or_die!(embed::embed(&doc, kind, *replace, |text| {
+                render_to_string(text, Format::guess(text), &opts)
+            }));
+
+            if *in_place {
+                try_or_die!(std::fs::write(document, doc));
+            } else {
+                try_or_die!(io::stdout().write_all(doc.as_bytes()));
+            }
+            return;
+        }
+        Some(Command::Diff { old, new }) => {
+            let read = |path: &PathBuf| {
+                let input = try_or_die!(std::fs::read_to_string(path));
+                let format = path.extension().and_then(OsStr::to_str).unwrap_or("");
+                (input, try_or_die!(parse_format(format)))
+            };
+            let ((old, old_format), (new, new_format)) = (read(old), read(new));
+
+            let rendered = try_or_die!(render_spec_diff(
+                &old,
+                old_format,
+                &new,
+                new_format,
+                &opts.overrides()
+            ));
+            for w in &rendered.warnings {
+                eprintln!("{}", w);
+            }
+            try_or_die!(write_rows(rendered.rows, io::stdout().lock()));
+            return;
+        }
+        Some(Command::RenderAll { diagram }) => {
+            let input = try_or_die!(std::fs::read_to_string(diagram));
+            let format = diagram.extension().and_then(OsStr::to_str).unwrap_or("");
+            let format = try_or_die!(parse_format(format));
+
+            let diagrams = try_or_die!(render_spec_all(&input, format, &opts.overrides()));
+            let mut rows = vec![];
+            for (i, d) in diagrams.into_iter().enumerate() {
+                for w in &d.rendered.warnings {
+                    match d.name.as_str() {
+                        "" => eprintln!("{}", w),
+                        name => eprintln!("{}: {}", name, w),
+                    }
+                }
+                if i > 0 {
+                    rows.push(FRAME_SEPARATOR.to_vec());
+                }
+                rows.push(d.breadcrumbs.join(" > ").into_bytes());
+                rows.push(vec![]);
+                rows.extend(d.rendered.rows);
+            }
+            try_or_die!(write_rows(rows, io::stdout().lock()));
+            return;
+        }
+        Some(Command::Corpus { dir, bless }) => {
+            let mismatches = try_or_die!(check_corpus(dir, &opts.overrides(), *bless));
+            for m in &mismatches {
+                let expected = expected_path(&m.diagram);
+                match (*bless, &m.expected) {
+                    (true, _) => eprintln!("blessed {}", expected.display()),
+                    (false, Some(_)) => eprintln!(
+                        "{} doesn't match {}",
+                        m.diagram.display(),
+                        expected.display()
+                    ),
+                    (false, None) => {
+                        eprintln!("{} has no {}", m.diagram.display(), expected.display())
+                    }
+                }
+            }
+            if !*bless && !mismatches.is_empty() {
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Daemon { socket }) => {
+            #[cfg(unix)]
+            {
+                let listener = try_or_die!(std::os::unix::net::UnixListener::bind(socket));
+                let overrides = Overrides {
+                    limits: opts.limits(daemon::DEFAULT_LIMITS),
+                    ..opts.overrides()
+                };
+                try_or_die!(daemon::serve(listener, overrides));
+            }
+            #[cfg(not(unix))]
             die!(
-                r#"unrecognized diagram format "{}", valid extensions: toml, json"#,
-                e
+                "cannot listen on {}, unix sockets are not supported",
+                socket.display()
             );
+            return;
         }
-    };
-
-    let canvas = render_diagram(spec, &opts);
+        Some(Command::Sync {
+            rendered,
+            diagram,
+            in_place,
+        }) => {
+            let rendered = try_or_die!(std::fs::read_to_string(rendered));
+            let input = try_or_die!(std::fs::read_to_string(diagram));
+            let format = diagram.extension().and_then(OsStr::to_str).unwrap_or("");
+            let format = try_or_die!(parse_format(format));
+
+            let (synced, messages) =
+                try_or_die!(sync_spec_str(&rendered, &input, format, &opts.overrides()));
+            for m in &messages {
+                eprintln!("{}", m);
+            }
 
-    match opts.output {
-        Some(output) => {
-            let mut f = try_or_die!(File::create(output));
-            for l in canvas {
-                try_or_die!(f.write_all(&l));
-                try_or_die!(writeln!(f, ""));
+            if *in_place {
+                try_or_die!(std::fs::write(diagram, synced));
+            } else {
+                try_or_die!(io::stdout().write_all(synced.as_bytes()));
+            }
+            return;
+        }
+        Some(Command::View { diagram }) => {
+            let input = try_or_die!(std::fs::read_to_string(diagram));
+            let format = diagram.extension().and_then(OsStr::to_str).unwrap_or("");
+            let format = try_or_die!(parse_format(format));
+
+            let first = try_or_die!(render_spec_str(&input, format, &opts.overrides()));
+            let initial = view::View {
+                x: 0,
+                y: 0,
+                hmargin: first.config.hmargin,
+                vmargin: first.config.vmargin,
+                seed: opts.seed.unwrap_or(0),
+                max_tweaks: opts.max_tweaks,
+            };
+            let kept = try_or_die!(view::run(initial.clone(), |v| {
+                let overrides = Overrides {
+                    seed: Some(v.seed),
+                    max_tweaks: v.max_tweaks,
+                    horizontal_margin: Some(v.hmargin),
+                    vertical_margin: Some(v.vmargin),
+                    ..opts.overrides()
+                };
+
+                let rendered =
+                    render_spec_str(&input, format, &overrides).map_err(|e| e.to_string())?;
+                let score = rendered
+                    .score
+                    .ok_or_else(|| "the viewer cannot show resolved diagrams".to_string())?;
+                Ok((rendered.rows, score))
+            }));
+
+            if let Some(v) = kept {
+                println!(
+                    "ascii-diagrams --seed {} --max-tweaks {} {}",
+                    v.seed,
+                    v.max_tweaks,
+                    diagram.display()
+                );
+                if (v.hmargin, v.vmargin) != (initial.hmargin, initial.vmargin) {
+                    println!(
+                        "horizontal_margin = {}, vertical_margin = {}",
+                        v.hmargin, v.vmargin
+                    );
+                }
             }
+            return;
         }
+        None => {}
+    }
+
+    let diagram = match &opts.diagram {
+        Some(d) => d,
+        None => die!("no input diagram given"),
+    };
+
+    let stdin = diagram == Path::new("-");
+    let input_spec = if stdin {
+        let mut input = String::new();
+        try_or_die!(io::stdin().read_to_string(&mut input));
+        input
+    } else {
+        try_or_die!(std::fs::read_to_string(diagram))
+    };
+
+    if opts.output.is_none() {
+        opts.terminal_width = terminal_width();
+    }
+
+    let format = match &opts.format {
+        Some(format) => try_or_die!(parse_format(format)),
+        None if stdin => Format::guess(&input_spec),
         None => {
-            let stdout = io::stdout();
-            let mut stdout = stdout.lock();
-            for l in canvas {
-                try_or_die!(stdout.write_all(&l));
-                try_or_die!(writeln!(stdout, ""));
+            let ext = diagram.extension().and_then(OsStr::to_str).unwrap_or("");
+            try_or_die!(parse_format(ext))
+        }
+    };
+    if opts.to == "asciicast"
+        && (opts.dump_resolved
+            || opts.viewport.is_some()
+            || opts.split_width.is_some()
+            || opts.frames)
+    {
+        die!(
+            "--to asciicast cannot be used with --dump-resolved, --viewport, --split-width or \
+             --frames"
+        );
+    }
+
+    if opts.fence {
+        try_or_die!(fit_fence(&mut opts));
+    }
+
+    let rendered = try_or_die!(render_reporting(&input_spec, format, &opts));
+    let canvas = match &rendered.layout {
+        Some(layout) if opts.to == "asciicast" => {
+            let cast = asciicast(&rendered.rows, layout, CAST_DELAY);
+            cast.lines().map(|l| l.as_bytes().to_vec()).collect()
+        }
+        _ if opts.fence => try_or_die!(fence(rendered.rows, &opts)),
+        _ => rendered.rows,
+    };
+
+    match &opts.output {
+        Some(output) if opts.frames => {
+            let frames = canvas.split(|r| r == FRAME_SEPARATOR);
+            for (i, frame) in frames.enumerate() {
+                let f = try_or_die!(File::create(frame_path(output, i + 1)));
+                try_or_die!(write_rows(frame.to_vec(), io::BufWriter::new(f)));
             }
         }
+        Some(output) => {
+            let f = try_or_die!(File::create(output));
+            try_or_die!(write_rows(canvas, io::BufWriter::new(f)));
+        }
+        None => try_or_die!(write_rows(canvas, io::stdout().lock())),
+    }
+}
+
+/// Shrink the diagram to `--fence-width` like with `--fit`, failing for the outputs that cannot
+/// be wrapped in a fenced code block.
+fn fit_fence(opts: &mut Opts) -> Result<(), String> {
+    if opts.to == "asciicast" || opts.frames {
+        return Err("--fence cannot be used with --to asciicast or --frames".to_string());
+    }
+    opts.terminal_width = Some(opts.fence_width);
+    opts.fit = true;
+    Ok(())
+}
+
+/// Wrap `rows` in a ``` fenced code block, failing if an ascii diagram is wider than
+/// `--fence-width`.
+fn fence(mut rows: Vec<Vec<u8>>, opts: &Opts) -> Result<Vec<Vec<u8>>, String> {
+    let width = rows.iter().map(Vec::len).max().unwrap_or(0);
+    if !opts.dump_resolved && opts.to == "ascii" && width > opts.fence_width {
+        return Err(format!(
+            "the diagram is {} characters wide, more than the {} that fit in a fenced code block, \
+             use --fence-width to allow wider diagrams",
+            width, opts.fence_width
+        ));
     }
+
+    rows.insert(0, b"```".to_vec());
+    rows.push(b"```".to_vec());
+    Ok(rows)
 }
 
-fn render_diagram(spec: Spec, opts: &Opts) -> Vec<Vec<u8>> {
-    let mut id_to_block_id = HashMap::with_capacity(spec.blocks.len());
-    let mut occupied_positions = HashSet::with_capacity(spec.blocks.len());
-    let mut blocks = Vec::with_capacity(spec.blocks.len());
+/// The path of the `n`-th frame written with `--frames`, numbered from 1 after the stem of
+/// `output`.
+fn frame_path(output: &Path, n: usize) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match output.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, n, ext.to_string_lossy()),
+        None => format!("{}-{}", stem, n),
+    };
+    output.with_file_name(name)
+}
+
+/// The width of the terminal stdout is attached to, if any. `COLUMNS` is used when the terminal
+/// cannot be queried.
+fn terminal_width() -> Option<usize> {
+    use std::io::IsTerminal;
+
+    if !io::stdout().is_terminal() {
+        return None;
+    }
+
+    #[cfg(unix)]
+    {
+        // SAFETY: TIOCGWINSZ only fills the given winsize struct
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        let res = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+        if res == 0 && size.ws_col > 0 {
+            return Some(usize::from(size.ws_col));
+        }
+    }
+
+    std::env::var("COLUMNS").ok()?.parse().ok()
+}
 
-    for b in &spec.blocks {
-        let id = b.id.as_ref().unwrap_or_else(|| &b.text);
-        if id_to_block_id.insert(id, blocks.len()).is_some() {
-            println!(r#"duplicate id found: "{}""#, id);
-            continue;
+impl Opts {
+    /// The options that change how diagrams are rendered.
+    fn overrides(&self) -> Overrides {
+        Overrides {
+            seed: self.seed,
+            max_tweaks: self.max_tweaks,
+            horizontal_margin: None,
+            vertical_margin: None,
+            ascii_only: self.ascii_only,
+            suggest: self.suggest,
+            optimize_layout: self.optimize_layout,
+            auto_margins: self.auto_margins,
+            edge_tags: self.edge_tags.clone(),
+            exclude_edge_tags: self.exclude_edge_tags.clone(),
+            dashed_edge_tags: self.dashed_edge_tags.clone(),
+            dump_resolved: self.dump_resolved,
+            layout_json: self.to == "layout-json",
+            from_layout: self.from_layout.clone(),
+            prev_layout: self.prev_layout.clone(),
+            cache_dir: self.cache_dir.clone(),
+            compact_margins: self.compact_margins,
+            guides: self.guides,
+            viewport: self.viewport,
+            split_width: self.split_width,
+            frames: self.frames,
+            fit: self.fit,
+            terminal_width: self.terminal_width,
+            limits: self.limits(Limits::default()),
         }
+    }
 
-        let pos = (b.position.row, b.position.column);
-        if !occupied_positions.insert(pos) {
-            println!(
-                r#"more than one cell present at row {} and column {}"#,
-                b.position.row, b.position.column
-            );
-            continue;
+    /// The limits given on the command line, the ones of `defaults` for the others.
+    fn limits(&self, defaults: Limits) -> Limits {
+        Limits {
+            max_blocks: self.max_blocks.unwrap_or(defaults.max_blocks),
+            max_edges: self.max_edges.unwrap_or(defaults.max_edges),
+            max_text_length: self.max_text_length.unwrap_or(defaults.max_text_length),
+            max_canvas_cells: self.max_canvas_cells.unwrap_or(defaults.max_canvas_cells),
+            max_tweak_time: self
+                .max_tweak_millis
+                .map(Duration::from_millis)
+                .or(defaults.max_tweak_time),
         }
+    }
+}
 
-        blocks.push(Block::new(pos, b.text.as_bytes()));
+/// Parse the format of a diagram given as a file extension, like "toml" or "json".
+fn parse_format(format: &str) -> Result<Format, String> {
+    Format::from_extension(format).ok_or_else(|| {
+        format!(
+            r#"unrecognized diagram format "{}", valid extensions: toml, json, yaml, yml, dot, gv, d2, puml, plantuml, pu"#,
+            format
+        )
+    })
+}
+
+/// Render the diagram `text` written in `format`, reporting the warnings on stderr.
+fn render_reporting(text: &str, format: Format, opts: &Opts) -> Result<RenderedDiagram, String> {
+    let rendered = render_spec_str(text, format, &opts.overrides()).map_err(|e| e.to_string())?;
+    for w in &rendered.warnings {
+        eprintln!("{}", w);
     }
 
-    let mut edges = BTreeSet::new();
-    for e in &spec.edges {
-        let from = match id_to_block_id.get(&e.from) {
-            Some(i) => *i,
-            None => {
-                println!(r#"id "{}" not found"#, e.from);
-                continue;
-            }
-        };
+    // trailing spaces don't count, they don't make lines wrap
+    let widths = rendered
+        .rows
+        .iter()
+        .map(|r| r.len() - r.iter().rev().take_while(|&&c| c == b' ').count());
+    let (widest, width) = widths
+        .enumerate()
+        .fold((0, 0), |(i, w), (j, v)| if v > w { (j, v) } else { (i, w) });
+    if opts.report.iter().any(|r| r == "size") {
+        let canvas_width = rendered.rows.iter().map(Vec::len).max().unwrap_or(0);
+        eprintln!(
+            "size: the canvas is {} columns by {} lines, the widest line is line {} with {} columns",
+            canvas_width,
+            rendered.rows.len(),
+            widest + 1,
+            width
+        );
+    }
+    if let Some(grid) = rendered
+        .grid
+        .as_ref()
+        .filter(|_| opts.report.iter().any(|r| r == "grid"))
+    {
+        for l in grid_report(grid, &rendered.ids) {
+            eprintln!("{}", l);
+        }
+    }
+    if let Some(budget) = opts.width_budget.filter(|&b| width > b) {
+        eprintln!(
+            "the diagram is {} columns wide, more than the budget of {}, line {} is the widest",
+            width,
+            budget,
+            widest + 1
+        );
+    }
 
-        let to = match id_to_block_id.get(&e.to) {
-            Some(i) => *i,
-            None => {
-                println!(r#"id "{}" not found"#, e.to);
-                continue;
-            }
-        };
+    Ok(rendered)
+}
 
-        if edges.contains(&(from, to)) || edges.contains(&(to, from)) {
-            println!(r#"duplicate edges from "{}" to "{}""#, e.from, e.to);
-            continue;
+/// A table with the id of the block in each cell of `grid`, or a dot for empty cells, with the
+/// column and row coordinates as headers followed by their width and height.
+fn grid_report(grid: &LogicalGrid, ids: &[String]) -> Vec<String> {
+    const MAX_ID_LEN: usize = 16;
+
+    let mut table = vec![];
+    let mut header = vec![String::new()];
+    for (c, w) in grid.columns_width.iter().enumerate() {
+        header.push(format!("{} ({})", grid.min_column + c as i32, w));
+    }
+    table.push(header);
+    for (r, (row, h)) in grid.cells.iter().zip(&grid.rows_height).enumerate() {
+        let mut line = vec![format!("{} ({})", grid.min_row + r as i32, h)];
+        for cell in row {
+            let id = match cell {
+                Some(i) if ids[*i].chars().count() > MAX_ID_LEN => {
+                    let id = ids[*i].chars().take(MAX_ID_LEN - 3).collect::<String>();
+                    format!("{}...", id)
+                }
+                Some(i) => ids[*i].clone(),
+                None => ".".to_string(),
+            };
+            line.push(id.replace('\n', " "));
         }
+        table.push(line);
+    }
 
-        edges.insert((from, to));
+    let mut widths = vec![0; grid.columns_width.len() + 1];
+    for line in &table {
+        for (w, cell) in widths.iter_mut().zip(line) {
+            *w = (*w).max(cell.chars().count());
+        }
+    }
+    let mut report = vec![format!(
+        "grid: {}x{} cells, each column and row followed by its width and height",
+        grid.columns_width.len(),
+        grid.rows_height.len()
+    )];
+    for line in table {
+        let cells = line
+            .iter()
+            .zip(&widths)
+            .map(|(cell, w)| format!("{:w$}", cell, w = w))
+            .collect::<Vec<_>>();
+        report.push(cells.join("  ").trim_end().to_string());
     }
+    report
+}
 
-    render(
-        &blocks,
-        edges,
-        RenderOptions {
-            hmargin: spec.horizontal_margin,
-            vmargin: spec.vertical_margin,
-            padding: spec.padding,
-            seed: opts.seed,
-            max_tweaks: opts.max_tweaks,
-        },
-    )
+/// Render the diagram `text` written in `format` to a string.
+fn render_to_string(text: &str, format: Format, opts: &Opts) -> Result<String, String> {
+    Ok(render_reporting(text, format, opts)?.to_string())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use ascii_diagrams::assert_diagram_eq;
+    fn fenced_opts(args: &[&str]) -> Opts {
+        let mut opts = Opts::from_iter(
+            ["ascii-diagrams", "--fence", "--seed", "0"]
+                .iter()
+                .chain(args),
+        );
+        opts.terminal_width = Some(200);
+        opts
+    }
 
     #[test]
-    fn test_basic_toml_diagram() {
-        let diagram = br#"
-edges = [ {from = "one", to = "four"}
-        , {from = "one", to = "0000"}
-        , {from = "two", to = "zero"}
-        , {from = "oooo", to = "zero"}
-        ]
-
-[[blocks]]
-text = "zero"
-position = { row = -1, column = -1 }
-
-[[blocks]]
-text = "one"
-position = { row = -1, column = 0 }
-
-[[blocks]]
-text = "two"
-position = { row = -1, column = 1 }
-
-[[blocks]]
-text = "0000"
-position = { row = 0, column = -1 }
-
-[[blocks]]
-text = "four"
-position = { row = 0, column = 1 }
-
-[[blocks]]
-text = "oooo"
-position = { row = 1, column = -1 }
-
-"#;
-        let diagram = toml::from_slice(diagram).unwrap();
-
-        assert_diagram_eq!(
-            render_diagram(
-                diagram,
-                &Opts {
-                    diagram: PathBuf::new(),
-                    output: None,
-                    seed: Some(42),
-                    max_tweaks: 0,
-                }
-            ),
-            br#"                                           
-         +------------------------+        
-         |                        |        
-     +---+--+     +-----+     +---+--+     
-     |      |     |     |     |      |     
-   +-+ zero |     | one +-+   | two  |     
-   | |      |     |     | |   |      |     
-   | +------+     +--+--+ |   +------+     
-   |                 |    |                
-   |                 |    +-------+        
-   |                 |            |        
-   | +------+        |        +---+--+     
-   | |      |        |        |      |     
-   | | 0000 +--------+        | four |     
-   | |      |                 |      |     
-   | +------+                 +------+     
-   |                                       
-   |                                       
-   |                                       
-   | +------+                              
-   | |      |                              
-   +-+ oooo |                              
-     |      |                              
-     +------+                              
-                                           
-                                           
-                                           "#
+    fn test_fence() {
+        let diagram = r#"blocks = [
+            { text = "a", position = { row = 0, column = 0 } },
+            { text = "b", position = { row = 0, column = 1 } },
+        ]"#;
+
+        let mut opts = fenced_opts(&["--fence-width", "20", "d.toml"]);
+        fit_fence(&mut opts).unwrap();
+        assert_eq!(opts.terminal_width, Some(20));
+        assert!(opts.fit);
+
+        let rendered = render_reporting(diagram, Format::Toml, &opts).unwrap();
+        let fenced = fence(rendered.rows.clone(), &opts).unwrap();
+        assert_eq!(fenced.first().unwrap(), b"```");
+        assert_eq!(fenced.last().unwrap(), b"```");
+        assert_eq!(&fenced[1..fenced.len() - 1], &rendered.rows[..]);
+        assert!(fenced.iter().all(|r| r.len() <= 20));
+
+        let opts = fenced_opts(&["--fence-width", "5", "d.toml"]);
+        assert_eq!(
+            fence(rendered.rows.clone(), &opts),
+            Err(format!(
+                "the diagram is {} characters wide, more than the 5 that fit in a fenced code \
+                 block, use --fence-width to allow wider diagrams",
+                rendered.rows.iter().map(Vec::len).max().unwrap()
+            ))
         );
     }
+
+    #[test]
+    fn test_fence_rejects_non_text() {
+        for args in &[
+            &["--to", "asciicast", "d.toml"][..],
+            &["--frames", "d.toml"],
+        ] {
+            let mut opts = fenced_opts(args);
+            assert_eq!(
+                fit_fence(&mut opts),
+                Err("--fence cannot be used with --to asciicast or --frames".to_string())
+            );
+        }
+    }
 }
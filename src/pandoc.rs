@@ -0,0 +1,380 @@
+use serde_json::{json, Value};
+
+use ascii_diagrams::Format;
+
+/// Class that marks a code block as a diagram to render.
+pub const DIAGRAM_CLASS: &str = "ascii-diagram";
+
+/// Output formats of pandoc that are written as HTML.
+const HTML_FORMATS: &[&str] = &[
+    "html",
+    "html4",
+    "html5",
+    "chunkedhtml",
+    "epub",
+    "epub2",
+    "epub3",
+    "revealjs",
+    "s5",
+    "slidy",
+    "slideous",
+    "dzslides",
+];
+
+/// Output formats of pandoc that are written as LaTeX.
+const LATEX_FORMATS: &[&str] = &["latex", "beamer"];
+
+/// Binary output formats of pandoc, where diagrams are embedded as SVG images.
+const IMAGE_FORMATS: &[&str] = &["docx", "odt", "pptx"];
+
+/// Size of the font of the diagrams drawn as SVG images and the height of their lines, in pixels.
+const SVG_FONT_SIZE: usize = 14;
+const SVG_LINE_HEIGHT: usize = 17;
+
+/// Walk the given pandoc JSON AST and replace the text of every code block marked with
+/// `DIAGRAM_CLASS` with the result of `render`.
+///
+/// `render` receives the diagram format (any extension known to `Format`, like "toml" or "json")
+/// and the text of the code block. If pandoc is targeting HTML or LaTeX, as `target`, the code
+/// block becomes a raw `<pre>` or `verbatim` block with the same attributes so that it can be
+/// styled like the other diagrams. For binary formats like docx the diagram is drawn in an SVG
+/// image instead, otherwise it stays a code block.
+pub fn filter(
+    doc: &mut Value,
+    target: Option<&str>,
+    mut render: impl FnMut(&str, &str) -> Result<String, String>,
+) -> Result<(), String> {
+    walk(doc, target, &mut render)
+}
+
+fn walk(
+    v: &mut Value,
+    target: Option<&str>,
+    render: &mut impl FnMut(&str, &str) -> Result<String, String>,
+) -> Result<(), String> {
+    match v {
+        Value::Array(a) => {
+            for e in a {
+                walk(e, target, render)?;
+            }
+        }
+        Value::Object(o) => {
+            if o.get("t").and_then(Value::as_str) == Some("CodeBlock") {
+                let c = match o.get_mut("c") {
+                    Some(c) => c,
+                    None => return Ok(()),
+                };
+                if let Some(raw) = replace_code_block(c, target, render)? {
+                    *v = raw;
+                }
+                return Ok(());
+            }
+
+            for e in o.values_mut() {
+                walk(e, target, render)?;
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Replace the content of a code block if it's a diagram, and return the block to replace the
+/// whole code block with if `target` is HTML, LaTeX or a binary format. A code block is encoded as
+/// `[[id, [classes], [[key, value]]], text]`.
+fn replace_code_block(
+    c: &mut Value,
+    target: Option<&str>,
+    render: &mut impl FnMut(&str, &str) -> Result<String, String>,
+) -> Result<Option<Value>, String> {
+    let (attrs, text) = match c.as_array_mut().map(|c| c.as_mut_slice()) {
+        Some([attrs, Value::String(text)]) => (attrs, text),
+        _ => return Err("malformed pandoc code block".to_string()),
+    };
+
+    let classes = match attrs.get_mut(1).and_then(Value::as_array_mut) {
+        Some(classes) => classes,
+        None => return Ok(None),
+    };
+    let class_ix = match classes
+        .iter()
+        .position(|c| c.as_str() == Some(DIAGRAM_CLASS))
+    {
+        Some(i) => i,
+        None => return Ok(None),
+    };
+    classes.remove(class_ix);
+
+    let format = attrs
+        .get(2)
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|kv| match kv.as_array().map(|kv| kv.as_slice()) {
+            Some([Value::String(k), Value::String(v)]) if k == "format" => Some(v.clone()),
+            _ => None,
+        })
+        .next()
+        .unwrap_or_else(|| Format::guess(text).extension().to_string());
+
+    *text = render(&format, text)?;
+    let diagram = text.trim_end_matches('\n');
+
+    // the attributes of the code block are kept, but `format` which is only for the filter
+    let id = attrs.get(0).and_then(Value::as_str).unwrap_or("");
+    let classes = attrs
+        .get(1)
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .collect::<Vec<_>>();
+    let pairs = attrs
+        .get(2)
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|kv| match kv.as_array().map(|kv| kv.as_slice()) {
+            Some([Value::String(k), Value::String(v)]) if k != "format" => Some((k, v)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let raw = match target {
+        Some(t) if HTML_FORMATS.contains(&t) => {
+            let mut pre = String::from("<pre");
+            if !id.is_empty() {
+                pre.push_str(&format!(r#" id="{}""#, escape_html(id)));
+            }
+            let classes = std::iter::once(DIAGRAM_CLASS).chain(classes);
+            pre.push_str(&format!(
+                r#" class="{}""#,
+                escape_html(&classes.collect::<Vec<_>>().join(" "))
+            ));
+            for (k, v) in pairs {
+                pre.push_str(&format!(r#" {}="{}""#, escape_html(k), escape_html(v)));
+            }
+            pre.push_str(&format!(">{}</pre>", escape_html(diagram)));
+
+            raw_block("html", pre)
+        }
+        Some(t) if LATEX_FORMATS.contains(&t) => {
+            // the text would end the environment and the rest would be read as LaTeX
+            if diagram.contains("\\end{verbatim}") {
+                return Err(
+                    r"the rendered diagram contains \end{verbatim}, it can't be written in a LaTeX verbatim block"
+                        .to_string(),
+                );
+            }
+            raw_block(
+                "latex",
+                format!("\\begin{{verbatim}}\n{}\n\\end{{verbatim}}", diagram),
+            )
+        }
+        Some(t) if IMAGE_FORMATS.contains(&t) => {
+            let url = format!(
+                "data:image/svg+xml;base64,{}",
+                base64(svg(diagram).as_bytes())
+            );
+            let pairs = pairs.iter().map(|(k, v)| [k, v]).collect::<Vec<_>>();
+            let image = json!({ "t": "Image", "c": [[id, classes, pairs], [], [url, ""]] });
+            json!({ "t": "Para", "c": [image] })
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(Some(raw))
+}
+
+/// A pandoc raw block with the given content in the given format.
+fn raw_block(format: &str, content: String) -> Value {
+    json!({ "t": "RawBlock", "c": [format, content] })
+}
+
+/// Draw the lines of `diagram` as text in an SVG image, in a monospace font so that they line up
+/// like in a terminal.
+fn svg(diagram: &str) -> String {
+    let lines = diagram.lines().collect::<Vec<_>>();
+    let columns = lines.iter().map(|l| l.chars().count()).max().unwrap_or(0);
+
+    // characters of monospace fonts are about 0.6 times as wide as the font size
+    let width = columns * SVG_FONT_SIZE * 3 / 5;
+    let height = lines.len() * SVG_LINE_HEIGHT;
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" font-family="monospace" font-size="{}">"#,
+        width, height, SVG_FONT_SIZE
+    );
+    for (i, l) in lines.iter().enumerate() {
+        if l.trim().is_empty() {
+            continue;
+        }
+        svg.push_str(&format!(
+            r#"<text x="0" y="{}" xml:space="preserve" style="white-space: pre">{}</text>"#,
+            i * SVG_LINE_HEIGHT + SVG_FONT_SIZE,
+            escape_html(l)
+        ));
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+/// Encode `bytes` in base64 with padding, for data URLs.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Escape the characters that have a meaning in HTML text and attributes.
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_replaces_only_diagram_code_blocks() {
+        let mut doc: Value = serde_json::from_str(
+            r#"{
+  "pandoc-api-version": [1, 22],
+  "meta": {},
+  "blocks": [
+    { "t": "CodeBlock", "c": [["", ["rust"], []], "fn main() {}"] },
+    { "t": "CodeBlock", "c": [["d", ["ascii-diagram"], []], "blocks = []"] },
+    { "t": "CodeBlock", "c": [["", ["ascii-diagram"], [["format", "json"]]], "{}"] }
+  ]
+}"#,
+        )
+        .unwrap();
+
+        filter(&mut doc, None, |format, text| {
+            Ok(format!("{}:{}", format, text))
+        })
+        .unwrap();
+
+        assert_eq!(
+            doc["blocks"],
+            serde_json::json!([
+                { "t": "CodeBlock", "c": [["", ["rust"], []], "fn main() {}"] },
+                { "t": "CodeBlock", "c": [["d", [], []], "toml:blocks = []"] },
+                { "t": "CodeBlock", "c": [["", [], [["format", "json"]]], "json:{}"] }
+            ])
+        );
+    }
+
+    #[test]
+    fn test_filter_emits_raw_blocks() {
+        let doc: Value = serde_json::from_str(
+            r#"{
+  "pandoc-api-version": [1, 22],
+  "meta": {},
+  "blocks": [
+    { "t": "CodeBlock", "c": [["", ["rust"], []], "fn main() {}"] },
+    { "t": "CodeBlock", "c": [["d", ["wide", "ascii-diagram"], [["format", "toml"], ["title", "a<b"]]], "blocks = []"] }
+  ]
+}"#,
+        )
+        .unwrap();
+
+        let filtered = |target, diagram: &str| {
+            let mut doc = doc.clone();
+            filter(&mut doc, target, |_, _| Ok(diagram.to_string()))?;
+            Ok::<_, String>(doc["blocks"].clone())
+        };
+        let diagram = "+-+\n|<|\n+-+\n";
+
+        let code =
+            serde_json::json!({ "t": "CodeBlock", "c": [["", ["rust"], []], "fn main() {}"] });
+        assert_eq!(
+            filtered(Some("html5"), diagram),
+            Ok(serde_json::json!([
+                code,
+                {
+                    "t": "RawBlock",
+                    "c": ["html", "<pre id=\"d\" class=\"ascii-diagram wide\" title=\"a&lt;b\">+-+\n|&lt;|\n+-+</pre>"]
+                }
+            ]))
+        );
+        assert_eq!(
+            filtered(Some("latex"), diagram),
+            Ok(serde_json::json!([
+                code,
+                {
+                    "t": "RawBlock",
+                    "c": ["latex", "\\begin{verbatim}\n+-+\n|<|\n+-+\n\\end{verbatim}"]
+                }
+            ]))
+        );
+        assert_eq!(
+            filtered(Some("latex"), "| \\end{verbatim}\\input{secret} |"),
+            Err(r"the rendered diagram contains \end{verbatim}, it can't be written in a LaTeX verbatim block".to_string())
+        );
+        assert_eq!(
+            filtered(Some("markdown"), diagram),
+            Ok(serde_json::json!([
+                code,
+                { "t": "CodeBlock", "c": [["d", ["wide"], [["format", "toml"], ["title", "a<b"]]], diagram] }
+            ]))
+        );
+
+        let svg = format!(
+            "data:image/svg+xml;base64,{}",
+            base64(svg(diagram.trim_end()).as_bytes())
+        );
+        assert_eq!(
+            filtered(Some("docx"), diagram),
+            Ok(serde_json::json!([
+                code,
+                {
+                    "t": "Para",
+                    "c": [{ "t": "Image", "c": [["d", ["wide"], [["title", "a<b"]]], [], [svg, ""]] }]
+                }
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_svg() {
+        assert_eq!(
+            svg("+-+\n\n|<|"),
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="25" height="51" font-family="monospace" font-size="14"><text x="0" y="14" xml:space="preserve" style="white-space: pre">+-+</text><text x="0" y="48" xml:space="preserve" style="white-space: pre">|&lt;|</text></svg>"#
+        );
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64(&[0xff, 0xfe, 0x00]), "//4A");
+    }
+}
@@ -0,0 +1,488 @@
+//! A parser for the subset of PlantUML component and use case diagrams that maps to diagrams:
+//! components, interfaces, use cases and actors become the nodes of a layered diagram and the
+//! relations between them become edges.
+
+use serde_json::{json, Map, Value};
+
+use crate::spec::Error;
+
+/// Keywords declaring an element like `component Foo`, or a group of elements when followed by a
+/// brace like `package Shop {`.
+const ELEMENTS: &[&str] = &[
+    "component",
+    "interface",
+    "usecase",
+    "actor",
+    "person",
+    "node",
+    "database",
+    "queue",
+    "storage",
+    "artifact",
+    "card",
+    "file",
+    "folder",
+    "frame",
+    "cloud",
+    "package",
+    "rectangle",
+    "agent",
+    "boundary",
+    "control",
+    "entity",
+    "collections",
+    "stack",
+    "hexagon",
+];
+
+/// Keywords declaring a group of elements that can't be an element, like `together {`.
+const GROUPS: &[&str] = &["together"];
+
+/// Statements that don't change the blocks and edges of the diagram, like the title and the
+/// skin.
+const IGNORED: &[&str] = &[
+    "skinparam",
+    "title",
+    "hide",
+    "show",
+    "scale",
+    "caption",
+    "header",
+    "footer",
+    "allowmixing",
+];
+
+#[derive(Debug)]
+struct Node {
+    id: String,
+    text: String,
+    external: bool,
+}
+
+/// The nodes and edges of a PlantUML diagram, in the order they're first mentioned. Edges are
+/// between the indices of the nodes since aliases can be declared after they're used.
+#[derive(Debug, Default)]
+struct Diagram {
+    nodes: Vec<Node>,
+    edges: Vec<(usize, usize, Map<String, Value>)>,
+    horizontal: bool,
+}
+
+impl Diagram {
+    /// Find the node whose alias is `id` or whose name is `text`, adding it if it's new.
+    fn node(&mut self, id: &str, text: &str) -> usize {
+        match self.nodes.iter().position(|n| n.id == id || n.text == text) {
+            Some(i) => i,
+            None => {
+                self.nodes.push(Node {
+                    id: id.to_string(),
+                    text: text.to_string(),
+                    external: false,
+                });
+                self.nodes.len() - 1
+            }
+        }
+    }
+}
+
+/// An element as written in a relation or a declaration, like `[Foo]` or `:User:`.
+#[derive(Debug)]
+struct Element {
+    name: String,
+    actor: bool,
+}
+
+/// Parse the element at the start of `s`, returning it and what follows it.
+fn element(s: &str) -> Option<(Element, &str)> {
+    let delimited = |open: char, close: char| {
+        let rest = s.strip_prefix(open)?;
+        let end = rest.find(close)?;
+        Some((unescape(&rest[..end]), &rest[end + close.len_utf8()..]))
+    };
+
+    let (name, rest, actor) = if let Some(interface) = s.strip_prefix("()") {
+        // interfaces are written as `() Name` or `() "Name"`
+        let (e, rest) = element(interface.trim_start())?;
+        (e.name, rest, false)
+    } else if let Some((name, rest)) = delimited('[', ']') {
+        (name, rest, false)
+    } else if let Some((name, rest)) = delimited('(', ')') {
+        (name, rest, false)
+    } else if let Some((name, rest)) = delimited(':', ':') {
+        (name, rest, true)
+    } else if let Some((name, rest)) = delimited('"', '"') {
+        (name, rest, false)
+    } else {
+        let end = s
+            .find(|c: char| !(c.is_alphanumeric() || "_.$".contains(c)))
+            .unwrap_or(s.len());
+        (s[..end].to_string(), &s[end..], false)
+    };
+
+    if name.trim().is_empty() {
+        return None;
+    }
+    Some((
+        Element {
+            name: name.trim().to_string(),
+            actor,
+        },
+        rest,
+    ))
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("\\n", "\n")
+}
+
+/// Parse the quoted string at the start of `s`, if there's one, like the multiplicities in
+/// `A "1" --> "*" B`.
+fn quoted(s: &str) -> (Option<String>, &str) {
+    let s = s.trim_start();
+    match s.strip_prefix('"').and_then(|r| Some((r, r.find('"')?))) {
+        Some((r, end)) => (Some(unescape(&r[..end])), r[end + 1..].trim_start()),
+        None => (None, s),
+    }
+}
+
+/// The relation drawn by an arrow like `-->`, `<..` or `-up-|>`.
+#[derive(Debug, PartialEq, Eq)]
+struct Arrow {
+    // whether the arrow points to the element on its left or on its right.
+    left: bool,
+    right: bool,
+    dashed: bool,
+}
+
+/// Parse the arrow at the start of `s`, returning it and what follows it.
+fn arrow(s: &str) -> Option<(Arrow, &str)> {
+    let followed_by_line = |r: &str| r.starts_with(['-', '.']);
+
+    let mut rest = s;
+    let mut left = false;
+    if let Some(r) = rest.strip_prefix("<|").or_else(|| rest.strip_prefix('<')) {
+        left = true;
+        rest = r;
+    } else if let Some(r) = rest
+        .strip_prefix(['*', 'o', '#', '+'])
+        .filter(|r| followed_by_line(r))
+    {
+        rest = r;
+    }
+
+    // the line, with the direction and the style of the arrow in it like `-up->` or `-[#red]->`
+    let mut dashed = false;
+    let mut len = 0;
+    loop {
+        if let Some(r) = rest.strip_prefix('-') {
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix('.') {
+            dashed = true;
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix('[') {
+            rest = &r[r.find(']')? + 1..];
+            continue;
+        } else {
+            let word = rest
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .filter(|&end| len > 0 && followed_by_line(&rest[end..]))
+                .map(|end| &rest[..end]);
+            match word {
+                Some(
+                    w @ ("up" | "down" | "left" | "right" | "u" | "d" | "l" | "r" | "le" | "ri"
+                    | "do"),
+                ) => {
+                    rest = &rest[w.len()..];
+                    continue;
+                }
+                _ => break,
+            }
+        }
+        len += 1;
+    }
+    if len == 0 {
+        return None;
+    }
+
+    let mut right = false;
+    if let Some(r) = rest.strip_prefix("|>").or_else(|| rest.strip_prefix('>')) {
+        right = true;
+        rest = r;
+    } else if let Some(r) = rest
+        .strip_prefix(['*', 'o', '#', '+'])
+        .filter(|r| r.is_empty() || r.starts_with([' ', '\t', '"']))
+    {
+        rest = r;
+    }
+
+    Some((
+        Arrow {
+            left,
+            right,
+            dashed,
+        },
+        rest,
+    ))
+}
+
+/// Parse a relation like `[A] "1" --> "*" [B] : uses` into `diagram`, return `None` if `line`
+/// isn't one.
+fn relation(diagram: &mut Diagram, line: &str) -> Option<()> {
+    let (a, rest) = element(line)?;
+    let (a_label, rest) = quoted(rest);
+    let (arrow, rest) = arrow(rest)?;
+    let (b_label, rest) = quoted(rest);
+    let (b, rest) = element(rest)?;
+    let rest = rest.trim();
+    let label = match rest.strip_prefix(':') {
+        Some(l) => Some(unescape(l.trim())),
+        None if rest.is_empty() => None,
+        None => return None,
+    };
+
+    let [a, b] = [a, b].map(|e| {
+        let i = diagram.node(&e.name, &e.name);
+        diagram.nodes[i].external |= e.actor;
+        i
+    });
+
+    // the label is drawn next to the source of the edge like in DOT diagrams, unless there's a
+    // multiplicity there
+    let (from, to, from_label, to_label) = if arrow.left && !arrow.right {
+        (b, a, b_label, a_label)
+    } else {
+        (a, b, a_label, b_label)
+    };
+    let mut attrs = Map::new();
+    if let Some(l) = from_label.or(label) {
+        attrs.insert("from_label".to_string(), json!(l));
+    }
+    if let Some(l) = to_label {
+        attrs.insert("to_label".to_string(), json!(l));
+    }
+    if arrow.dashed {
+        attrs.insert("dashed".to_string(), json!(true));
+    }
+    if arrow.left == arrow.right {
+        attrs.insert("directed".to_string(), json!(false));
+    }
+    diagram.edges.push((from, to, attrs));
+    Some(())
+}
+
+/// Parse the declaration of an element like `component [Foo] as F` or `:User: as U` into
+/// `diagram`, return `None` if `line` isn't one.
+fn declaration(diagram: &mut Diagram, line: &str) -> Option<()> {
+    let (keyword, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let keyword = keyword.to_lowercase();
+    let (actor, rest) = if ELEMENTS.contains(&keyword.as_str()) {
+        (
+            matches!(keyword.as_str(), "actor" | "person"),
+            rest.trim_start(),
+        )
+    } else {
+        (false, line)
+    };
+
+    let quoted_name = rest.starts_with('"');
+    let (e, rest) = element(rest)?;
+    let mut rest = rest.trim_start();
+    let (mut id, mut text) = (e.name.clone(), e.name);
+    if let Some(r) = rest.strip_prefix("as ") {
+        // both `component "Long name" as F` and `component F as "Long name"` work
+        let r = r.trim_start();
+        let quoted_alias = r.starts_with('"');
+        let (alias, r) = element(r)?;
+        if quoted_alias && !quoted_name {
+            text = alias.name;
+        } else {
+            id = alias.name;
+        }
+        rest = r.trim_start();
+    }
+
+    // stereotypes and colors don't matter for diagrams
+    if let Some(r) = rest.strip_prefix("<<") {
+        rest = r[r.find(">>")? + 2..].trim_start();
+    }
+    if rest.starts_with('#') {
+        rest = rest.find(char::is_whitespace).map_or("", |i| &rest[i..]);
+    }
+    if !rest.trim().is_empty() {
+        return None;
+    }
+
+    let i = diagram.node(&id, &text);
+    let node = &mut diagram.nodes[i];
+    node.id = id;
+    node.text = text;
+    node.external |= actor || e.actor;
+    Some(())
+}
+
+/// Convert the PlantUML component or use case diagram `input` to a diagram in the JSON format.
+///
+/// Components, interfaces, use cases and the other elements become the nodes of a layered diagram,
+/// laid out from left to right with `left to right direction`, labelled with their name and with
+/// their alias as id. Actors are external nodes. Relations become edges, dashed if they're
+/// dotted like `..>`, labelled with their label or their multiplicities and not directed unless
+/// they have an arrowhead on one side only. Packages and the other groups are flattened and
+/// notes, legends, skin parameters and the like are ignored. Other statements are not supported.
+pub(crate) fn plantuml_to_json(input: &str) -> Result<Value, Error> {
+    let mut diagram = Diagram::default();
+
+    // statements spanning multiple lines are skipped up to the line that ends them
+    let mut skip_until: Option<&str> = None;
+    for (i, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if let Some(end) = skip_until {
+            if line.to_lowercase().replace(' ', "") == end {
+                skip_until = None;
+            }
+            continue;
+        }
+
+        let lower = line.to_lowercase();
+        let first = lower.split_whitespace().next().unwrap_or("");
+        if line.is_empty() || line.starts_with('\'') || line == "}" || line.starts_with('!') {
+            continue;
+        }
+        if line.starts_with("/'") {
+            if !line.ends_with("'/") || line.len() < 4 {
+                skip_until = Some("'/");
+            }
+            continue;
+        }
+        if first.starts_with("@start") || first.starts_with("@end") {
+            continue;
+        }
+        if lower == "left to right direction" || lower == "top to bottom direction" {
+            diagram.horizontal = lower.starts_with("left");
+            continue;
+        }
+        if IGNORED.contains(&first) {
+            if line.ends_with('{') {
+                skip_until = Some("}");
+            }
+            continue;
+        }
+        if first == "note" && !line.contains(':') {
+            skip_until = Some("endnote");
+            continue;
+        }
+        if first == "note" {
+            continue;
+        }
+        if first == "legend" {
+            skip_until = Some("endlegend");
+            continue;
+        }
+        if line.ends_with('{')
+            && (ELEMENTS.contains(&first) || GROUPS.contains(&first) || line == "{")
+        {
+            continue;
+        }
+
+        if relation(&mut diagram, line).is_none() && declaration(&mut diagram, line).is_none() {
+            return Err(Error::Parse(format!(
+                "unsupported statement in PlantUML diagram on line {}: {}",
+                i + 1,
+                line
+            )));
+        }
+    }
+
+    let nodes = diagram
+        .nodes
+        .iter()
+        .map(|n| {
+            let mut node = json!({ "id": n.id, "text": n.text });
+            if n.external {
+                node["external"] = json!(true);
+            }
+            node
+        })
+        .collect::<Vec<_>>();
+    let edges = diagram
+        .edges
+        .iter()
+        .map(|(from, to, attrs)| {
+            let mut e = attrs.clone();
+            e.insert("from".to_string(), json!(diagram.nodes[*from].id));
+            e.insert("to".to_string(), json!(diagram.nodes[*to].id));
+            Value::Object(e)
+        })
+        .collect::<Vec<_>>();
+
+    Ok(json!({
+        "kind": "layered",
+        "orientation": if diagram.horizontal { "horizontal" } else { "vertical" },
+        "directed": true,
+        "nodes": nodes,
+        "edges": edges,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::Format;
+
+    #[test]
+    fn test_plantuml_to_json() {
+        let puml = r#"
+@startuml
+' a comment
+left to right direction
+skinparam component {
+  BackgroundColor white
+}
+actor :Customer: as C
+package "Shop" {
+  component [Web shop] as Web <<frontend>>
+  [Orders\nservice] as Orders
+  database "Orders DB" as DB
+}
+note right of Web
+  a note
+end note
+C --> Web : browses
+Web "1" -right-> "*" Orders
+Orders ..> DB
+DB <-- [Backups]
+Web -- Orders
+@enduml
+"#;
+        assert_eq!(
+            plantuml_to_json(puml).unwrap(),
+            json!({
+                "kind": "layered",
+                "orientation": "horizontal",
+                "directed": true,
+                "nodes": [
+                    { "id": "C", "text": "Customer", "external": true },
+                    { "id": "Web", "text": "Web shop" },
+                    { "id": "Orders", "text": "Orders\nservice" },
+                    { "id": "DB", "text": "Orders DB" },
+                    { "id": "Backups", "text": "Backups" },
+                ],
+                "edges": [
+                    { "from": "C", "to": "Web", "from_label": "browses" },
+                    { "from": "Web", "to": "Orders", "from_label": "1", "to_label": "*" },
+                    { "from": "Orders", "to": "DB", "dashed": true },
+                    { "from": "Backups", "to": "DB" },
+                    { "from": "Web", "to": "Orders", "directed": false },
+                ],
+            })
+        );
+        assert_eq!(Format::guess(puml), Format::PlantUml);
+
+        assert_eq!(
+            plantuml_to_json("@startuml\nclass Foo {\n}\n@enduml"),
+            Err(Error::Parse(
+                "unsupported statement in PlantUML diagram on line 2: class Foo {".to_string()
+            ))
+        );
+    }
+}
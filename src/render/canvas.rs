@@ -1,11 +1,78 @@
-pub type CanvasPoint = (usize, usize);
+/// A point on the canvas as opposed to a position in the logical space where blocks are placed,
+/// see `LogicalPos`. The origin is the top left corner of the canvas.
+///
+/// It can be converted from and into an (x, y) tuple.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct CanvasPoint {
+    pub x: usize,
+    pub y: usize,
+}
+
+impl CanvasPoint {
+    pub fn new(x: usize, y: usize) -> Self {
+        CanvasPoint { x, y }
+    }
+}
+
+impl From<(usize, usize)> for CanvasPoint {
+    fn from((x, y): (usize, usize)) -> Self {
+        CanvasPoint { x, y }
+    }
+}
+
+impl From<CanvasPoint> for (usize, usize) {
+    fn from(pt: CanvasPoint) -> Self {
+        (pt.x, pt.y)
+    }
+}
+
+/// A rectangle on the canvas, borders included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CanvasRect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl CanvasRect {
+    pub fn top_left(&self) -> CanvasPoint {
+        CanvasPoint::new(self.x, self.y)
+    }
+
+    /// Whether the given point is inside the rectangle, borders included.
+    pub fn contains(&self, pt: CanvasPoint) -> bool {
+        (self.x..self.x + self.width).contains(&pt.x)
+            && (self.y..self.y + self.height).contains(&pt.y)
+    }
+
+    /// Whether the two rectangles have at least a point in common.
+    pub fn intersects(&self, o: &CanvasRect) -> bool {
+        self.x < o.x + o.width
+            && o.x < self.x + self.width
+            && self.y < o.y + o.height
+            && o.y < self.y + self.height
+    }
+
+    /// Whether the two rectangles have more than a border in common, that is they intersect but
+    /// they're not merely side by side sharing a border or a corner.
+    pub fn overlaps(&self, o: &CanvasRect) -> bool {
+        self.x + 1 < o.x + o.width
+            && o.x + 1 < self.x + self.width
+            && self.y + 1 < o.y + o.height
+            && o.y + 1 < self.y + self.height
+    }
+}
+
+/// Character that replaces the lines cut by `Canvas::crop`.
+pub const CUT_MARKER: u8 = b'~';
 
 /// A `Canvas` is the surface where we can draw shapes using ASCII characters.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Canvas {
-    pub canvas: Vec<Vec<u8>>,
-    pub width: usize,
-    pub height: usize,
+    canvas: Vec<Vec<u8>>,
+    width: usize,
+    height: usize,
 }
 
 impl Canvas {
@@ -17,6 +84,30 @@ impl Canvas {
         }
     }
 
+    /// Create a canvas from the given rows, shorter rows are padded with spaces.
+    pub fn from_rows(mut canvas: Vec<Vec<u8>>) -> Self {
+        let width = canvas.iter().map(Vec::len).max().unwrap_or(0);
+        for row in &mut canvas {
+            row.resize(width, b' ');
+        }
+
+        Self {
+            height: canvas.len(),
+            canvas,
+            width,
+        }
+    }
+
+    /// The number of characters in each row of the canvas.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// The number of rows of the canvas.
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
     pub fn draw_rect_outline(&mut self, x: usize, y: usize, width: usize, height: usize) {
         for xx in 0..width {
             self.canvas[y][x + xx] = b'-';
@@ -34,31 +125,289 @@ impl Canvas {
         self.canvas[y + height - 1][x + width - 1] = b'+';
     }
 
+    /// Draw the outline of a note, that is a rectangle whose top right corner is folded like
+    /// this:
+    ///
+    /// ```text
+    /// +----+\
+    /// |    |_\
+    /// |      |
+    /// +------+
+    /// ```
+    pub fn draw_note_outline(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        self.draw_rect_outline(x, y, width, height);
+
+        let fx = x + width - 3;
+        self.canvas[y][fx..fx + 3].copy_from_slice(b"+\\ ");
+        self.canvas[y + 1][fx..fx + 3].copy_from_slice(b"|_\\");
+    }
+
+    /// Draw the outline of something external, that is a rectangle whose sides are square brackets
+    /// like this:
+    ///
+    /// ```text
+    /// +------+
+    /// [      ]
+    /// [      ]
+    /// +------+
+    /// ```
+    pub fn draw_external_outline(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        self.draw_rect_outline(x, y, width, height);
+
+        for yy in y + 1..y + height - 1 {
+            self.canvas[yy][x] = b'[';
+            self.canvas[yy][x + width - 1] = b']';
+        }
+    }
+
+    /// Turn the outline of the rectangle at `(x, y)` of the given dimensions into a dashed one,
+    /// like this:
+    ///
+    /// ```text
+    /// +- - -+
+    /// :     :
+    /// +- - -+
+    /// ```
+    ///
+    /// Only the plain parts of the outline are changed, not the corners nor the points where lines
+    /// are attached.
+    pub fn dash_rect_outline(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        for xx in (2..width - 1).step_by(2) {
+            for yy in [y, y + height - 1] {
+                if self.canvas[yy][x + xx] == b'-' {
+                    self.canvas[yy][x + xx] = b' ';
+                }
+            }
+        }
+
+        for yy in 1..height - 1 {
+            for xx in [x, x + width - 1] {
+                if self.canvas[y + yy][xx] == b'|' {
+                    self.canvas[y + yy][xx] = b':';
+                }
+            }
+        }
+    }
+
+    /// Fill the rectangle at `(x, y)` of the given dimensions with `c`.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, c: u8) {
+        for row in &mut self.canvas[y..y + height] {
+            for cell in &mut row[x..x + width] {
+                *cell = c;
+            }
+        }
+    }
+
+    /// Clear the rectangle at `(x, y)` of the given dimensions, i.e. fill it with spaces.
+    pub fn clear_rect(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        self.fill_rect(x, y, width, height, b' ');
+    }
+
     pub fn draw_text(&mut self, x: usize, y: usize, text: &[u8]) {
         self.canvas[y][x..x + text.len()].copy_from_slice(text);
     }
 
+    /// Draw a vertical line from `y0` to `y1`.
+    ///
+    /// The corners and junctions of other lines are never hidden and the ends of the line are
+    /// drawn as corners only if there's something they join horizontally, see
+    /// `draw_vertical_line_end`.
     pub fn draw_vertical_line(&mut self, x: usize, (y0, y1): (usize, usize)) {
         let (y0, y1) = minmax(y0, y1);
         for y in (y0..y1).skip(1) {
-            self.canvas[y][x] = b'|';
+            if self.canvas[y][x] != b'+' {
+                self.canvas[y][x] = b'|';
+            }
         }
-        self.canvas[y0][x] = b'+';
-        self.canvas[y1][x] = b'+';
+        self.draw_vertical_line_end(x, y0);
+        self.draw_vertical_line_end(x, y1);
     }
 
+    /// Draw a horizontal line from `x0` to `x1`.
+    ///
+    /// The corners and junctions of other lines are never hidden and the ends of the line are
+    /// drawn as corners only if there's something they join vertically, see
+    /// `draw_horizontal_line_end`.
     pub fn draw_horizontal_line(&mut self, y: usize, (x0, x1): (usize, usize)) {
         let (x0, x1) = minmax(x0, x1);
         for x in (x0..x1).skip(1) {
+            if self.canvas[y][x] != b'+' {
+                self.canvas[y][x] = b'-';
+            }
+        }
+        self.draw_horizontal_line_end(x0, y);
+        self.draw_horizontal_line_end(x1, y);
+    }
+
+    /// The end of a vertical line is a corner if it turns into a horizontal line or if it joins
+    /// something else like the border of a block, otherwise the line merely continues (for now)
+    /// possibly along another vertical line.
+    ///
+    /// Note that when drawing a polyline the following segment takes care of turning the end of the
+    /// previous one into a corner.
+    fn draw_vertical_line_end(&mut self, x: usize, y: usize) {
+        let joins = |c| c == b'-' || c == b'+';
+        let left = x > 0 && joins(self.canvas[y][x - 1]);
+        let right = x + 1 < self.width && joins(self.canvas[y][x + 1]);
+
+        if left || right || !matches!(self.canvas[y][x], b' ' | b'|') {
+            self.canvas[y][x] = b'+';
+        } else {
+            self.canvas[y][x] = b'|';
+        }
+    }
+
+    /// The horizontal counterpart of `draw_vertical_line_end`.
+    fn draw_horizontal_line_end(&mut self, x: usize, y: usize) {
+        let joins = |c| c == b'|' || c == b'+';
+        let up = y > 0 && joins(self.canvas[y - 1][x]);
+        let down = y + 1 < self.height && joins(self.canvas[y + 1][x]);
+
+        if up || down || !matches!(self.canvas[y][x], b' ' | b'-') {
+            self.canvas[y][x] = b'+';
+        } else {
             self.canvas[y][x] = b'-';
         }
-        self.canvas[y][x0] = b'+';
-        self.canvas[y][x1] = b'+';
     }
 
-    pub fn at(&self, (x, y): CanvasPoint) -> u8 {
+    /// Draw a diagonal line from `(x0, y0)` to `(x1, y1)` using `\\` when going down to the right
+    /// and `/` when going up to the right. The line must be at 45 degrees.
+    ///
+    /// Crossings with other diagonals are drawn as `X` and corners and junctions are never hidden.
+    /// The ends of the line are drawn as `+` if they land on something else, otherwise they're
+    /// part of the line like the rest of it.
+    pub fn draw_diagonal_line(&mut self, p0: CanvasPoint, p1: CanvasPoint) {
+        let ((x0, y0), (x1, y1)) = (p0.into(), p1.into());
+        assert_eq!(
+            minmax(x0, x1).1 - minmax(x0, x1).0,
+            minmax(y0, y1).1 - minmax(y0, y1).0,
+            "diagonal lines must be at 45 degrees"
+        );
+
+        // normalize the line so that it always goes to the right
+        let ((x0, y0), (x1, y1)) = minmax((x0, y0), (x1, y1));
+        let down = y1 >= y0;
+        let (c, crossing) = if down { (b'\\', b'/') } else { (b'/', b'\\') };
+
+        let len = x1 - x0;
+        for i in 0..=len {
+            let x = x0 + i;
+            let y = if down { y0 + i } else { y0 - i };
+
+            let cur = self.canvas[y][x];
+            self.canvas[y][x] = if i == 0 || i == len {
+                if cur == b' ' || cur == c {
+                    c
+                } else {
+                    b'+'
+                }
+            } else if cur == crossing || cur == b'X' {
+                b'X'
+            } else if cur == b'+' {
+                b'+'
+            } else {
+                c
+            };
+        }
+    }
+
+    pub fn at(&self, CanvasPoint { x, y }: CanvasPoint) -> u8 {
         self.canvas[y][x]
     }
+
+    /// Get the character at the given point, if it's inside the canvas.
+    pub fn get(&self, CanvasPoint { x, y }: CanvasPoint) -> Option<u8> {
+        self.canvas.get(y)?.get(x).copied()
+    }
+
+    /// Set the character at the given point, it must be inside the canvas.
+    pub fn set(&mut self, CanvasPoint { x, y }: CanvasPoint, c: u8) {
+        self.canvas[y][x] = c;
+    }
+
+    /// Iterate over the points of the rectangle at `(x, y)` of the given dimensions alongside their
+    /// characters, row by row.
+    pub fn iter_region(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> impl Iterator<Item = (CanvasPoint, u8)> + '_ {
+        (y..y + height).flat_map(move |yy| {
+            (x..x + width).map(move |xx| (CanvasPoint::new(xx, yy), self.canvas[yy][xx]))
+        })
+    }
+
+    /// Extract the rectangle at `(x, y)` of the given dimensions, clamped to the canvas. Lines that
+    /// continue outside of the rectangle are marked with a `CUT_MARKER` where they're cut.
+    pub fn crop(&self, x: usize, y: usize, width: usize, height: usize) -> Canvas {
+        let x = x.min(self.width);
+        let y = y.min(self.height);
+        let width = width.min(self.width - x);
+        let height = height.min(self.height - y);
+
+        let mut cropped = Canvas::from_rows(
+            self.canvas[y..y + height]
+                .iter()
+                .map(|row| row[x..x + width].to_vec())
+                .collect(),
+        );
+        if width == 0 || height == 0 {
+            return cropped;
+        }
+
+        let horizontal = |c| c == b'-' || c == b'+';
+        let vertical = |c| c == b'|' || c == b'+';
+        let outside = |pt: Option<CanvasPoint>| pt.and_then(|pt| self.get(pt));
+
+        for yy in 0..height {
+            let (l, r) = (
+                self.at(CanvasPoint::new(x, y + yy)),
+                self.at(CanvasPoint::new(x + width - 1, y + yy)),
+            );
+            if horizontal(l)
+                && outside(x.checked_sub(1).map(|x| CanvasPoint::new(x, y + yy)))
+                    .is_some_and(horizontal)
+            {
+                cropped.set(CanvasPoint::new(0, yy), CUT_MARKER);
+            }
+            if horizontal(r)
+                && outside(Some(CanvasPoint::new(x + width, y + yy))).is_some_and(horizontal)
+            {
+                cropped.set(CanvasPoint::new(width - 1, yy), CUT_MARKER);
+            }
+        }
+        for xx in 0..width {
+            let (t, b) = (
+                self.at(CanvasPoint::new(x + xx, y)),
+                self.at(CanvasPoint::new(x + xx, y + height - 1)),
+            );
+            if vertical(t)
+                && outside(y.checked_sub(1).map(|y| CanvasPoint::new(x + xx, y)))
+                    .is_some_and(vertical)
+            {
+                cropped.set(CanvasPoint::new(xx, 0), CUT_MARKER);
+            }
+            if vertical(b)
+                && outside(Some(CanvasPoint::new(x + xx, y + height))).is_some_and(vertical)
+            {
+                cropped.set(CanvasPoint::new(xx, height - 1), CUT_MARKER);
+            }
+        }
+
+        cropped
+    }
+
+    /// The rows of the canvas from top to bottom.
+    pub fn rows(&self) -> impl Iterator<Item = &[u8]> {
+        self.canvas.iter().map(Vec::as_slice)
+    }
+
+    /// Consume the canvas returning its rows from top to bottom.
+    pub fn into_rows(self) -> Vec<Vec<u8>> {
+        self.canvas
+    }
 }
 
 fn minmax<T: Ord>(a: T, b: T) -> (T, T) {
@@ -68,3 +417,143 @@ fn minmax<T: Ord>(a: T, b: T) -> (T, T) {
         (b, a)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::assert_diagram_eq;
+
+    #[test]
+    fn test_line_ends_are_corners_only_when_turning() {
+        let mut canvas = Canvas::new(7, 7);
+
+        // an L shaped polyline crossed by a horizontal line right on its corner, the dangling
+        // ends are not corners
+        canvas.draw_vertical_line(1, (0, 3));
+        canvas.draw_horizontal_line(3, (1, 4));
+        canvas.draw_horizontal_line(3, (0, 6));
+
+        // a vertical line that ends in the middle of another one merely continues
+        canvas.draw_vertical_line(5, (4, 6));
+        canvas.draw_vertical_line(5, (5, 6));
+
+        // a dangling vertical line
+        canvas.draw_vertical_line(3, (5, 6));
+
+        assert_diagram_eq!(
+            canvas.into_rows(),
+            br#" |     
+ |     
+ |     
+-+-----
+     | 
+   | | 
+   | | "#
+        );
+    }
+
+    #[test]
+    fn test_diagonal_lines() {
+        let mut canvas = Canvas::new(12, 8);
+
+        // down right, up left, up right and down left
+        canvas.draw_diagonal_line(CanvasPoint::new(0, 0), CanvasPoint::new(2, 2));
+        canvas.draw_diagonal_line(CanvasPoint::new(5, 2), CanvasPoint::new(3, 0));
+        canvas.draw_diagonal_line(CanvasPoint::new(6, 2), CanvasPoint::new(8, 0));
+        canvas.draw_diagonal_line(CanvasPoint::new(11, 0), CanvasPoint::new(9, 2));
+
+        // two crossing lines
+        canvas.draw_diagonal_line(CanvasPoint::new(0, 3), CanvasPoint::new(4, 7));
+        canvas.draw_diagonal_line(CanvasPoint::new(0, 7), CanvasPoint::new(4, 3));
+
+        // a line ending on a vertical line
+        canvas.draw_vertical_line(8, (3, 7));
+        canvas.draw_diagonal_line(CanvasPoint::new(6, 5), CanvasPoint::new(8, 3));
+
+        assert_diagram_eq!(
+            canvas.into_rows(),
+            br#"\  \    /  /
+ \  \  /  / 
+  \  \/  /  
+\   /   +   
+ \ /   /|   
+  X   / |   
+ / \    |   
+/   \   |   "#
+        );
+    }
+
+    #[test]
+    fn test_fill_and_clear_rect() {
+        let mut canvas = Canvas::new(6, 4);
+
+        canvas.fill_rect(0, 0, 5, 3, b'#');
+        canvas.clear_rect(1, 1, 3, 1);
+        canvas.fill_rect(5, 3, 1, 1, b'@');
+        canvas.fill_rect(0, 3, 0, 1, b'@');
+
+        assert_diagram_eq!(
+            canvas.into_rows(),
+            br#"##### 
+#   # 
+##### 
+     @"#
+        );
+    }
+
+    #[test]
+    fn test_cell_access() {
+        let mut canvas = Canvas::new(3, 2);
+
+        canvas.set(CanvasPoint::new(1, 0), b'a');
+        canvas.set(CanvasPoint::new(2, 1), b'b');
+
+        assert_eq!(canvas.get(CanvasPoint::new(1, 0)), Some(b'a'));
+        assert_eq!(canvas.get(CanvasPoint::new(3, 0)), None);
+        assert_eq!(canvas.get(CanvasPoint::new(0, 2)), None);
+        assert_eq!(
+            canvas.iter_region(1, 0, 2, 2).collect::<Vec<_>>(),
+            vec![
+                (CanvasPoint::new(1, 0), b'a'),
+                (CanvasPoint::new(2, 0), b' '),
+                (CanvasPoint::new(1, 1), b' '),
+                (CanvasPoint::new(2, 1), b'b')
+            ]
+        );
+        assert_eq!(canvas.rows().collect::<Vec<_>>(), vec![&b" a "[..], b"  b"]);
+    }
+
+    #[test]
+    fn test_crop() {
+        let mut canvas = Canvas::new(12, 7);
+        canvas.draw_rect_outline(1, 1, 5, 3);
+        canvas.draw_vertical_line(3, (4, 6));
+        canvas.draw_horizontal_line(2, (6, 11));
+        canvas.draw_text(8, 5, b"hi");
+
+        assert_diagram_eq!(
+            canvas.crop(2, 0, 7, 6).into_rows(),
+            br#"       
+~--+   
+   |--~
+~--+   
+ |     
+ ~    h"#
+        );
+
+        // nothing is cut
+        assert_eq!(canvas.crop(0, 0, 12, 7), canvas);
+
+        // clamped to the canvas
+        assert_diagram_eq!(canvas.crop(10, 5, 10, 10).into_rows(), b"  \n  ");
+        assert_eq!(canvas.crop(20, 0, 1, 1).width, 0);
+    }
+
+    #[test]
+    fn test_from_rows() {
+        let canvas = Canvas::from_rows(vec![b"ab".to_vec(), b"c".to_vec(), vec![]]);
+        assert_eq!((canvas.width, canvas.height), (2, 3));
+        assert_diagram_eq!(canvas.into_rows(), b"ab\nc \n  ");
+    }
+}
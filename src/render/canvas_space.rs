@@ -1,7 +1,10 @@
+use std::collections::HashMap;
 use std::convert::TryFrom;
 
-use crate::render::RenderOptions;
-use crate::{Block, LogicalCoord, LogicalPoint};
+use crate::render::canvas::{CanvasPoint, CanvasRect};
+use crate::render::symmetry::{equalize, Axis, Mirror};
+use crate::render::{text, RenderOptions};
+use crate::{Block, LogicalCoord, LogicalPos};
 
 /// `CanvasSpace` is the definition of the cannvas dimensions (columns width and rows height)
 /// required to render a set of `Block`s.
@@ -21,8 +24,31 @@ pub struct CanvasSpace {
     rows_ys: Vec<usize>,
     rows_height: Vec<usize>,
 
+    // height of the footers at the bottom of each row, separator included.
+    rows_footer_height: Vec<usize>,
+
+    // number of synthetic empty columns and rows inserted in each gap between columns and rows
+    // respectively. The gap `i` is the one right before the column or row `i`, the last gap is
+    // the one after the last column or row.
+    columns_gutters: Vec<usize>,
+    rows_gutters: Vec<usize>,
+
+    // whether a block in each column and row casts a shadow, in that case the margin after the
+    // column or row is one character larger to make room for it.
+    columns_shadow: Vec<bool>,
+    rows_shadow: Vec<bool>,
+
+    // where each column and row must start at least, so that the blocks kept at a rectangle stay
+    // there, see `Block::with_rect`.
+    columns_min_x: Vec<usize>,
+    rows_min_y: Vec<usize>,
+
     blocks_map: Vec<Vec<bool>>,
 
+    // size of each block drawn over another one at the same position, see `Block::with_z`. It's
+    // `None` for the blocks that fill their cell.
+    overlays: Vec<Option<(usize, usize)>>,
+
     canvas_width: usize,
     canvas_height: usize,
 
@@ -31,15 +57,15 @@ pub struct CanvasSpace {
 
 impl CanvasSpace {
     pub fn new(boxes: &[Block], cfg: &RenderOptions) -> Self {
-        let mut min_column = LogicalCoord::max_value();
-        let mut min_row = LogicalCoord::max_value();
-        let mut max_column = LogicalCoord::min_value();
-        let mut max_row = LogicalCoord::min_value();
+        let mut min_column = LogicalCoord::MAX;
+        let mut min_row = LogicalCoord::MAX;
+        let mut max_column = LogicalCoord::MIN;
+        let mut max_row = LogicalCoord::MIN;
         for b in boxes {
-            min_column = min_column.min(b.column);
-            min_row = min_row.min(b.row);
-            max_column = max_column.max(b.column);
-            max_row = max_row.max(b.row);
+            min_column = min_column.min(b.pos.col);
+            min_row = min_row.min(b.pos.row);
+            max_column = max_column.max(b.pos.col);
+            max_row = max_row.max(b.pos.row);
         }
 
         // +1 is to go from inclusive coordinates to exclusive
@@ -55,45 +81,155 @@ impl CanvasSpace {
 
             rows_ys: vec![0; height],
             rows_height: vec![0; height],
+            rows_footer_height: vec![0; height],
+
+            columns_gutters: vec![0; width + 1],
+            rows_gutters: vec![0; height + 1],
+
+            columns_shadow: vec![false; width],
+            rows_shadow: vec![false; height],
+
+            columns_min_x: vec![0; width],
+            rows_min_y: vec![0; height],
 
             blocks_map: vec![vec![false; width]; height],
 
+            overlays: vec![None; boxes.len()],
+
             canvas_width: 0,
             canvas_height: 0,
 
             render_cfg: cfg.clone(),
         };
 
+        // only the blocks with the lowest z at each position fill their cell
+        let mut bottom = HashMap::with_capacity(boxes.len());
         for b in boxes {
-            let c = usize::try_from(b.column - min_column).unwrap();
-            let r = usize::try_from(b.row - min_row).unwrap();
+            let z = bottom.entry(b.pos).or_insert(b.z);
+            *z = b.z.min(*z);
+        }
+
+        for (i, b) in boxes.iter().enumerate() {
+            let c = usize::try_from(b.pos.col - min_column).unwrap();
+            let r = usize::try_from(b.pos.row - min_row).unwrap();
+            let (w, h) = block_size(b, cfg);
+
+            if b.shadow {
+                cs.columns_shadow[c] = true;
+                cs.rows_shadow[r] = true;
+            }
+            if let Some(rect) = b.rect {
+                cs.columns_min_x[c] = cs.columns_min_x[c].max(rect.x);
+                cs.rows_min_y[r] = cs.rows_min_y[r].max(rect.y);
+            }
 
-            // +2 to account for block borders
-            let w = 2 + b.text_width + cfg.padding * 2;
-            let h = 2 + b.text_height + cfg.padding * 2;
+            if bottom[&b.pos] != b.z {
+                let footer_h = if b.footer.is_empty() {
+                    0
+                } else {
+                    b.footer.len() + 1
+                };
+                cs.overlays[i] = Some((w, h + footer_h));
+                continue;
+            }
 
             cs.columns_width[c] = cs.columns_width[c].max(w);
             cs.rows_height[r] = cs.rows_height[r].max(h);
 
+            // +1 to account for the separator between the text and the footer
+            if !b.footer.is_empty() {
+                cs.rows_footer_height[r] = cs.rows_footer_height[r].max(b.footer.len() + 1);
+            }
+
             cs.blocks_map[r][c] = true;
         }
 
-        // note: margins are intentionally added before and after the first and last element in
-        // order to have more room for placing lines.
-        cs.columns_xs[0] = cfg.hmargin;
-        cs.rows_ys[0] = cfg.vmargin;
+        // mirrored columns or rows must be drawn the same for their edges to be mirrored too
+        if cfg.symmetric {
+            if let Some(mirror) = Mirror::find(boxes) {
+                match mirror.axis {
+                    Axis::Vertical => equalize(&mut cs.columns_width),
+                    Axis::Horizontal => {
+                        equalize(&mut cs.rows_height);
+                        equalize(&mut cs.rows_footer_height);
+                    }
+                }
+            }
+        }
 
-        for x in 1..width {
-            cs.columns_xs[x] = cs.columns_xs[x - 1] + cs.columns_width[x - 1] + cfg.hmargin;
+        // footers are aligned at the bottom of each row below the rest of the text
+        for (h, fh) in cs.rows_height.iter_mut().zip(&cs.rows_footer_height) {
+            *h += fh;
         }
-        for y in 1..height {
-            cs.rows_ys[y] = cs.rows_ys[y - 1] + cs.rows_height[y - 1] + cfg.vmargin;
+
+        cs.layout();
+        cs
+    }
+
+    /// Compute the position of columns and rows and the canvas dimensions.
+    fn layout(&mut self) {
+        let cfg = &self.render_cfg;
+
+        // note: margins are intentionally added before and after the first and last element in
+        // order to have more room for placing lines. Each gutter is an empty column or row, that
+        // is just an additional margin.
+        //
+        // Without margins adjacent columns and rows share their borders instead, unless there's a
+        // shadow in between.
+        let mut x = 0;
+        for (i, &w) in self.columns_width.iter().enumerate() {
+            let shared =
+                cfg.hmargin == 0 && shares_border(&self.columns_width, &self.columns_shadow, i);
+            x = (x - usize::from(shared) + cfg.hmargin * (1 + self.columns_gutters[i]))
+                .max(self.columns_min_x[i]);
+            self.columns_xs[i] = x;
+            x += w + usize::from(self.columns_shadow[i]);
+        }
+        self.canvas_width = x + cfg.hmargin * (1 + self.columns_gutters[self.columns_width.len()]);
+
+        let mut y = 0;
+        for (i, &h) in self.rows_height.iter().enumerate() {
+            let shared = cfg.vmargin == 0 && shares_border(&self.rows_height, &self.rows_shadow, i);
+            y = (y - usize::from(shared) + cfg.vmargin * (1 + self.rows_gutters[i]))
+                .max(self.rows_min_y[i]);
+            self.rows_ys[i] = y;
+            y += h + usize::from(self.rows_shadow[i]);
         }
+        self.canvas_height = y + cfg.vmargin * (1 + self.rows_gutters[self.rows_height.len()]);
+    }
 
-        cs.canvas_width = cs.columns_xs[width - 1] + cs.columns_width[width - 1] + cfg.hmargin;
-        cs.canvas_height = cs.rows_ys[height - 1] + cs.rows_height[height - 1] + cfg.vmargin;
+    /// Insert an empty column in the given gap between columns, see `column_gap_at`.
+    pub fn add_column_gutter(&mut self, gap: usize) {
+        self.columns_gutters[gap] += 1;
+        self.layout();
+    }
 
-        cs
+    /// Insert an empty row in the given gap between rows, see `row_gap_at`.
+    pub fn add_row_gutter(&mut self, gap: usize) {
+        self.rows_gutters[gap] += 1;
+        self.layout();
+    }
+
+    /// Number of empty columns inserted in each gap between columns, see `add_column_gutter`.
+    pub fn columns_gutters(&self) -> &[usize] {
+        &self.columns_gutters
+    }
+
+    /// Number of empty rows inserted in each gap between rows, see `add_row_gutter`.
+    pub fn rows_gutters(&self) -> &[usize] {
+        &self.rows_gutters
+    }
+
+    /// The gap between columns the given x falls in, if any. The gap `i` is the one right before
+    /// the `i`-th column from the left and the last gap is the one after the last column.
+    pub fn column_gap_at(&self, x: usize) -> Option<usize> {
+        gap_at(&self.columns_xs, &self.columns_width, x)
+    }
+
+    /// The gap between rows the given y falls in, if any. The gap `i` is the one right above the
+    /// `i`-th row from the top and the last gap is the one below the last row.
+    pub fn row_gap_at(&self, y: usize) -> Option<usize> {
+        gap_at(&self.rows_ys, &self.rows_height, y)
     }
 
     pub fn canvas_width(&self) -> usize {
@@ -117,12 +253,274 @@ impl CanvasSpace {
         self.rows_height[usize::try_from(row - self.min_row).unwrap()]
     }
 
+    /// Height of the footers at the bottom of the given row, separator included. It's 0 if
+    /// there are no footers in that row.
+    pub fn row_footer_height(&self, row: LogicalCoord) -> usize {
+        self.rows_footer_height[usize::try_from(row - self.min_row).unwrap()]
+    }
+
+    /// Height of the given row without the footers, that is the height of the space where the
+    /// text of the blocks is centered in.
+    pub fn row_body_height(&self, row: LogicalCoord) -> usize {
+        self.row_height(row) - self.row_footer_height(row)
+    }
+
     pub fn render_cfg(&self) -> &RenderOptions {
         &self.render_cfg
     }
 
-    pub fn has_block_at(&self, (row, column): LogicalPoint) -> bool {
+    pub fn has_block_at(&self, LogicalPos { row, col }: LogicalPos) -> bool {
         self.blocks_map[usize::try_from(row - self.min_row).unwrap()]
-            [usize::try_from(column - self.min_column).unwrap()]
+            [usize::try_from(col - self.min_column).unwrap()]
+    }
+
+    /// The rectangle of the cell at the given position, that is the rectangle a block there is
+    /// drawn in, borders included.
+    pub fn to_canvas(&self, pos: LogicalPos) -> CanvasRect {
+        CanvasRect {
+            x: self.column_x(pos.col),
+            y: self.row_y(pos.row),
+            width: self.column_width(pos.col),
+            height: self.row_height(pos.row),
+        }
+    }
+
+    /// The rectangle the `i`-th block is drawn in, borders included. It's the rectangle of its
+    /// cell unless the block is drawn over another one, see `Block::with_z`.
+    pub fn block_rect(&self, boxes: &[Block], i: usize) -> CanvasRect {
+        let cell = self.to_canvas(boxes[i].pos);
+        match self.overlays[i] {
+            Some((w, h)) => {
+                let (w, h) = (w.min(cell.width), h.min(cell.height));
+                CanvasRect {
+                    x: cell.x + cell.width - w,
+                    y: cell.y,
+                    width: w,
+                    height: h,
+                }
+            }
+            None => cell,
+        }
+    }
+
+    /// The points of the shadow of the `i`-th block, that is the column right after it and the row
+    /// right below it shifted by one, see `Block::with_shadow`. It's empty if the block has no
+    /// shadow.
+    pub fn shadow(&self, boxes: &[Block], i: usize) -> Vec<CanvasPoint> {
+        if !boxes[i].shadow {
+            return vec![];
+        }
+
+        let r = self.block_rect(boxes, i);
+        let right = (r.y + 1..=r.y + r.height).map(|y| CanvasPoint::new(r.x + r.width, y));
+        let below = (r.x + 1..r.x + r.width).map(|x| CanvasPoint::new(x, r.y + r.height));
+        right.chain(below).collect()
+    }
+
+    /// Whether the `i`-th block is drawn over another one at the same position, see
+    /// `Block::with_z`.
+    pub fn is_overlay(&self, i: usize) -> bool {
+        self.overlays[i].is_some()
+    }
+
+    /// The position of the block drawn over the given point, borders included. It's `None` if
+    /// the point is in the margins or in a cell without a block.
+    pub fn block_at_canvas_point(&self, pt: CanvasPoint) -> Option<LogicalPos> {
+        let c = cell_at(&self.columns_xs, &self.columns_width, pt.x)?;
+        let r = cell_at(&self.rows_ys, &self.rows_height, pt.y)?;
+        if !self.blocks_map[r][c] {
+            return None;
+        }
+
+        Some(LogicalPos::new(
+            self.min_row + LogicalCoord::try_from(r).unwrap(),
+            self.min_column + LogicalCoord::try_from(c).unwrap(),
+        ))
+    }
+}
+
+/// The (width, height) of the given block, borders included but footer excluded.
+fn block_size(b: &Block, cfg: &RenderOptions) -> (usize, usize) {
+    if let Some(r) = b.rect {
+        let footer_h = if b.footer.is_empty() {
+            0
+        } else {
+            b.footer.len() + 1
+        };
+        return (r.width.max(3), r.height.saturating_sub(footer_h).max(3));
+    }
+
+    // +2 to account for block borders, but blocks must always have at least a cell inside so that
+    // edges are not attached to corners
+    let mut w = b.width.unwrap_or(2 + b.text_width + cfg.padding * 2).max(3);
+
+    // make sure the corner text does not reach the center of the top border where edges are
+    // usually attached to
+    if !b.corner_text.is_empty() {
+        w = w.max(2 * (b.corner_text.len() + 1) + 1);
+    }
+    let mut h = b
+        .height
+        .unwrap_or(2 + text_height(b, w, cfg.padding) + cfg.padding * 2)
+        .max(3);
+
+    // only the narrow side is enlarged, unless its size is explicit
+    if let Some((aw, ah)) = cfg.block_aspect.filter(|&(aw, ah)| aw > 0 && ah > 0) {
+        if w * ah < h * aw {
+            if b.width.is_none() {
+                w = (h * aw).div_ceil(ah);
+            }
+        } else if b.height.is_none() {
+            h = (w * ah).div_ceil(aw);
+        }
+    }
+
+    (w, h)
+}
+
+/// The number of lines the text of the block takes in a block `w` characters wide, borders
+/// included. Horizontal text is wrapped like `draw_blocks` does when the width of the block is
+/// explicit, otherwise the block is as wide as its text.
+fn text_height(b: &Block, w: usize, padding: usize) -> usize {
+    if b.width.is_none() || b.vertical {
+        return b.text_height;
+    }
+
+    let inner_w = w - 2;
+    let body_w = b.text.iter().map(Vec::len).max().unwrap_or(0);
+    let pad_w = padding.min(inner_w.saturating_sub(body_w) / 2);
+    text::fit(&b.text, inner_w - 2 * pad_w, usize::MAX, b.overflow).len()
+}
+
+/// Whether the column or row `i` can share its first border with the last border of the previous
+/// one, that is both of them have blocks and there's no shadow in between.
+fn shares_border(sizes: &[usize], shadows: &[bool], i: usize) -> bool {
+    i > 0 && sizes[i - 1] > 0 && sizes[i] > 0 && !shadows[i - 1]
+}
+
+/// The index of the column or row the given coordinate falls in, if any.
+fn cell_at(starts: &[usize], sizes: &[usize], p: usize) -> Option<usize> {
+    let i = starts.iter().rposition(|&s| s <= p)?;
+    if p < starts[i] + sizes[i] {
+        Some(i)
+    } else {
+        None
+    }
+}
+
+fn gap_at(starts: &[usize], sizes: &[usize], p: usize) -> Option<usize> {
+    match starts.iter().position(|&s| p < s) {
+        Some(i) if i == 0 || p >= starts[i - 1] + sizes[i - 1] => Some(i),
+        Some(_) => None,
+        None if p >= starts[starts.len() - 1] + sizes[sizes.len() - 1] => Some(starts.len()),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gutters() {
+        let blocks = [Block::from_str((0, 0), "a"), Block::from_str((1, 1), "b")];
+        let mut cs = CanvasSpace::new(
+            &blocks,
+            &RenderOptions {
+                hmargin: 2,
+                vmargin: 1,
+                padding: 0,
+                seed: Some(0),
+                max_tweaks: 0,
+                ..RenderOptions::default()
+            },
+        );
+
+        // columns are at [2, 5) and [7, 10), rows at [1, 4) and [5, 8)
+        assert_eq!(cs.column_gap_at(0), Some(0));
+        assert_eq!(cs.column_gap_at(3), None);
+        assert_eq!(cs.column_gap_at(5), Some(1));
+        assert_eq!(cs.column_gap_at(11), Some(2));
+        assert_eq!(cs.row_gap_at(4), Some(1));
+        assert_eq!(cs.row_gap_at(8), Some(2));
+
+        cs.add_column_gutter(1);
+        cs.add_row_gutter(0);
+        assert_eq!(cs.column_x(1), 9);
+        assert_eq!(cs.row_y(0), 2);
+        assert_eq!(cs.row_y(1), 6);
+        assert_eq!((cs.canvas_width(), cs.canvas_height()), (14, 10));
+    }
+
+    #[test]
+    fn test_canvas_conversions() {
+        let blocks = [Block::from_str((0, 0), "a"), Block::from_str((1, 1), "b")];
+        let cs = CanvasSpace::new(
+            &blocks,
+            &RenderOptions {
+                hmargin: 2,
+                vmargin: 1,
+                padding: 0,
+                seed: Some(0),
+                max_tweaks: 0,
+                ..RenderOptions::default()
+            },
+        );
+
+        let rect = cs.to_canvas(LogicalPos::new(1, 1));
+        assert_eq!(
+            rect,
+            CanvasRect {
+                x: 7,
+                y: 5,
+                width: 3,
+                height: 3
+            }
+        );
+        assert!(rect.contains(rect.top_left()));
+        assert!(!rect.contains(CanvasPoint::new(10, 5)));
+
+        // borders are part of the block, margins and empty cells are not
+        assert_eq!(
+            cs.block_at_canvas_point(CanvasPoint::new(7, 7)),
+            Some(LogicalPos::new(1, 1))
+        );
+        assert_eq!(
+            cs.block_at_canvas_point(CanvasPoint::new(3, 2)),
+            Some(LogicalPos::new(0, 0))
+        );
+        assert_eq!(cs.block_at_canvas_point(CanvasPoint::new(6, 6)), None);
+        assert_eq!(cs.block_at_canvas_point(CanvasPoint::new(8, 2)), None);
+        assert_eq!(cs.block_at_canvas_point(CanvasPoint::new(100, 2)), None);
+    }
+
+    #[test]
+    fn test_block_aspect() {
+        let blocks = [
+            Block::from_str((0, 0), "a"),
+            Block::from_str((0, 1), "a rather long text"),
+            Block::from_str((1, 0), "one\ntwo\nthree\nfour\nfive"),
+            Block::from_str((1, 1), "b").with_width(4),
+        ];
+        let cs = CanvasSpace::new(
+            &blocks,
+            &RenderOptions {
+                hmargin: 2,
+                vmargin: 1,
+                padding: 0,
+                seed: Some(0),
+                max_tweaks: 0,
+                block_aspect: Some((3, 1)),
+                ..RenderOptions::default()
+            },
+        );
+
+        let size = |b: &Block| block_size(b, cs.render_cfg());
+        assert_eq!(size(&blocks[0]), (9, 3));
+        assert_eq!(size(&blocks[1]), (20, 7));
+        assert_eq!(size(&blocks[2]), (21, 7));
+
+        // explicit sizes are kept
+        assert_eq!(size(&blocks[3]), (4, 3));
     }
 }
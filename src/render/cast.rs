@@ -0,0 +1,168 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use crate::render::canvas::CanvasPoint;
+use crate::render::geometry::{Element, Layout};
+
+/// The non blank cells of a rendered diagram grouped in the strokes that draw it progressively,
+/// in order: the blocks first, row by row, then the edges one cell at a time from the block they
+/// start at to the one they end at followed by their labels and finally whatever else is on the
+/// diagram, like headers or a legend, row by row.
+///
+/// Each cell is drawn with the element it belongs to according to `Layout::hit_test`, so `layout`
+/// must be the one the diagram was rendered with.
+pub fn draw_order(rows: &[Vec<u8>], layout: &Layout) -> Vec<Vec<CanvasPoint>> {
+    let mut cells = HashMap::<Option<Element>, Vec<CanvasPoint>>::new();
+    for (y, row) in rows.iter().enumerate() {
+        for (x, &c) in row.iter().enumerate() {
+            if c != b' ' {
+                cells
+                    .entry(layout.hit_test(x, y))
+                    .or_default()
+                    .push(CanvasPoint::new(x, y));
+            }
+        }
+    }
+
+    let by_row = |cells: Vec<CanvasPoint>| {
+        let mut rows = BTreeMap::<usize, Vec<CanvasPoint>>::new();
+        for pt in cells {
+            rows.entry(pt.y).or_default().push(pt);
+        }
+        rows.into_values()
+    };
+
+    let mut strokes = vec![];
+    for i in 0..layout.blocks.len() {
+        if let Some(cells) = cells.remove(&Some(Element::Block(i))) {
+            strokes.extend(by_row(cells));
+        }
+    }
+    for (i, e) in layout.edges.iter().enumerate() {
+        let mut cells = cells.remove(&Some(Element::Edge(i))).unwrap_or_default();
+
+        let route = e.points.windows(2).flat_map(|w| {
+            let (a, b) = (w[0], w[1]);
+            let n = a.x.max(b.x) - a.x.min(b.x) + a.y.max(b.y) - a.y.min(b.y);
+            (0..n).map(move |k| {
+                let towards = |from: usize, to: usize| if to >= from { from + k } else { from - k };
+                if a.x == b.x {
+                    CanvasPoint::new(a.x, towards(a.y, b.y))
+                } else {
+                    CanvasPoint::new(towards(a.x, b.x), a.y)
+                }
+            })
+        });
+        let route = route.chain(e.points.last().copied());
+        for pt in route {
+            if let Some(j) = cells.iter().position(|&c| c == pt) {
+                strokes.push(vec![cells.remove(j)]);
+            }
+        }
+        strokes.extend(by_row(cells));
+    }
+    if let Some(cells) = cells.remove(&None) {
+        strokes.extend(by_row(cells));
+    }
+
+    strokes
+}
+
+/// Turn a rendered diagram into an [asciinema](https://asciinema.org) v2 cast that draws it
+/// progressively, one stroke of `draw_order` every `delay` seconds, like a typewriter. Useful for
+/// demos and to explain a diagram piece by piece.
+pub fn asciicast(rows: &[Vec<u8>], layout: &Layout, delay: f64) -> String {
+    let width = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let height = rows.len() + 1;
+
+    let mut cast = format!(
+        r#"{{"version": 2, "width": {}, "height": {}}}"#,
+        width, height
+    );
+    cast.push('\n');
+
+    let strokes = draw_order(rows, layout);
+    let n = strokes.len();
+    for (i, stroke) in strokes.into_iter().enumerate() {
+        let mut out = String::new();
+        let mut last = None;
+        for pt in stroke {
+            // consecutive cells of the same row are written without moving the cursor
+            if last != Some(CanvasPoint::new(pt.x.wrapping_sub(1), pt.y)) {
+                let _ = write!(out, "\x1b[{};{}H", pt.y + 1, pt.x + 1);
+            }
+            out.push(char::from(rows[pt.y][pt.x]));
+            last = Some(pt);
+        }
+        push_event(&mut cast, i as f64 * delay, &out);
+    }
+
+    // leave the cursor below the diagram
+    push_event(&mut cast, n as f64 * delay, &format!("\x1b[{};1H", height));
+    cast
+}
+
+/// Append an output event to a cast, escaping `data` as a JSON string.
+fn push_event(cast: &mut String, time: f64, data: &str) {
+    let _ = write!(cast, "[{:.3}, \"o\", \"", time);
+    for c in data.chars() {
+        match c {
+            '"' => cast.push_str("\\\""),
+            '\\' => cast.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(cast, "\\u{:04x}", u32::from(c));
+            }
+            c => cast.push(c),
+        }
+    }
+    cast.push_str("\"]\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::render::{render_layout, render_with_layout, RenderOptions};
+    use crate::{Block, Edge};
+
+    #[test]
+    fn test_asciicast() {
+        let blocks = [Block::from_str((0, 0), "a"), Block::from_str((0, 1), "b")];
+        let edges = [Edge::new(0, 1).with_directed(true)];
+        let config = RenderOptions {
+            hmargin: 4,
+            vmargin: 0,
+            padding: 0,
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        };
+
+        let layout = render_layout(&blocks, edges.clone(), config.clone());
+        let rows = render_with_layout(&blocks, edges, &layout, config).unwrap();
+        let strokes = draw_order(&rows, &layout);
+
+        // each block row by row and then the edge from a to b, arrowhead included
+        let stroke = |i: usize| {
+            strokes[i]
+                .iter()
+                .map(|&pt| char::from(rows[pt.y][pt.x]))
+                .collect::<String>()
+        };
+        assert_eq!(strokes.len(), 6 + 4);
+        assert_eq!(stroke(0), "+-+");
+        assert_eq!(stroke(1), "|a+");
+        assert_eq!(
+            (6..10).map(stroke).collect::<Vec<_>>(),
+            vec!["-", "-", "-", ">"]
+        );
+
+        let cast = asciicast(&rows, &layout, 0.1);
+        let lines = cast.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], r#"{"version": 2, "width": 18, "height": 4}"#);
+        assert_eq!(lines[1], r#"[0.000, "o", "\u001b[1;5H+-+"]"#);
+        assert_eq!(lines[7], r#"[0.600, "o", "\u001b[2;8H-"]"#);
+        assert_eq!(lines[11], r#"[1.000, "o", "\u001b[4;1H"]"#);
+        assert_eq!(lines.len(), 12);
+    }
+}
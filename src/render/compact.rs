@@ -0,0 +1,159 @@
+use crate::render::canvas::{Canvas, CanvasPoint};
+use crate::render::geometry::{EdgeRoute, Layout};
+
+/// Shrink the margins of a rendered diagram by removing the columns and rows that are the same as
+/// the one right before them and that only have empty space or lines running across them. Columns
+/// and rows that go through a block are always kept, so blocks keep their size and lines get
+/// shorter, but they still connect the same blocks and cross the same lines.
+///
+/// `layout` must be the one the diagram was rendered with, it's returned in the coordinates of the
+/// compacted diagram.
+pub fn compact_margins(rows: Vec<Vec<u8>>, layout: &Layout) -> (Vec<Vec<u8>>, Layout) {
+    let canvas = Canvas::from_rows(rows);
+
+    let column = |x: usize| canvas.rows().map(move |r| r[x]);
+    let keep_columns = (0..canvas.width())
+        .map(|x| {
+            x == 0
+                || layout
+                    .blocks
+                    .iter()
+                    .any(|r| (r.x..r.x + r.width).contains(&x))
+                || !column(x).all(|c| c == b' ' || c == b'-')
+                || !column(x).eq(column(x - 1))
+        })
+        .collect::<Vec<_>>();
+
+    let row = |y: usize| canvas.rows().nth(y).unwrap();
+    let keep_rows = (0..canvas.height())
+        .map(|y| {
+            y == 0
+                || layout
+                    .blocks
+                    .iter()
+                    .any(|r| (r.y..r.y + r.height).contains(&y))
+                || !row(y).iter().all(|&c| c == b' ' || c == b'|')
+                || row(y) != row(y - 1)
+        })
+        .collect::<Vec<_>>();
+
+    let rows = canvas
+        .rows()
+        .zip(&keep_rows)
+        .filter(|(_, &keep)| keep)
+        .map(|(r, _)| {
+            r.iter()
+                .zip(&keep_columns)
+                .filter(|(_, &keep)| keep)
+                .map(|(c, _)| *c)
+                .collect()
+        })
+        .collect();
+
+    // removed columns and rows are the same as the ones before them, so whatever was on them is
+    // now on the previous ones that were kept
+    let (xs, ys) = (new_coords(&keep_columns), new_coords(&keep_rows));
+    let map = |CanvasPoint { x, y }: CanvasPoint| CanvasPoint::new(xs[x], ys[y]);
+
+    let layout = Layout {
+        width: xs.last().map_or(0, |x| x + 1),
+        height: ys.last().map_or(0, |y| y + 1),
+        blocks: layout
+            .blocks
+            .iter()
+            .map(|r| {
+                let pt = map(CanvasPoint::new(r.x, r.y));
+                let mut r = *r;
+                r.x = pt.x;
+                r.y = pt.y;
+                r
+            })
+            .collect(),
+        edges: layout
+            .edges
+            .iter()
+            .map(|e| EdgeRoute {
+                src: map(e.src),
+                dst: map(e.dst),
+                points: e.points.iter().copied().map(map).collect(),
+                labels: e
+                    .labels
+                    .iter()
+                    .map(|(pt, l)| (map(*pt), l.clone()))
+                    .collect(),
+                ..e.clone()
+            })
+            .collect(),
+        column_gutters: layout.column_gutters.clone(),
+        row_gutters: layout.row_gutters.clone(),
+    };
+
+    (rows, layout)
+}
+
+/// The coordinate of each column or row after removing the ones not kept, removed ones take the
+/// coordinate of the last one kept before them.
+fn new_coords(keep: &[bool]) -> Vec<usize> {
+    let mut kept: usize = 0;
+    keep.iter()
+        .map(|&k| {
+            if k {
+                kept += 1;
+            }
+            kept.saturating_sub(1)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::assert_diagram_eq;
+    use crate::render::{render_layout, render_with_layout, RenderOptions};
+    use crate::{Block, Edge, Element};
+
+    #[test]
+    fn test_compact() {
+        let blocks = [
+            Block::from_str((0, 0), "a"),
+            Block::from_str((0, 1), "b"),
+            Block::from_str((1, 1), "c"),
+        ];
+        let edges = [Edge::new(0, 1), Edge::new(0, 2).with_to_label("1")];
+        let config = RenderOptions {
+            hmargin: 6,
+            vmargin: 4,
+            padding: 0,
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        };
+
+        let layout = render_layout(&blocks, edges.clone(), config.clone());
+        let rows = render_with_layout(&blocks, edges.clone(), &layout, config).unwrap();
+        let (rows, compacted) = compact_margins(rows, &layout);
+
+        assert_diagram_eq!(
+            rows,
+            br#"          
+ +-+  +-+ 
+ |a+--+b| 
+ +++  +-+ 
+  |       
+  |  1+-+ 
+  +---+c| 
+      +-+ 
+          "#
+        );
+
+        assert_eq!((compacted.width, compacted.height), (10, 9));
+        assert_eq!(compacted.hit_test(7, 2), Some(Element::Block(1)));
+        assert_eq!(compacted.hit_test(4, 2), Some(Element::Edge(0)));
+        assert_eq!(compacted.hit_test(2, 5), Some(Element::Edge(1)));
+        for (b, r) in compacted.blocks.iter().zip(&layout.blocks) {
+            assert_eq!((b.width, b.height), (r.width, r.height));
+            assert_eq!(rows[b.y][b.x], b'+');
+        }
+    }
+}
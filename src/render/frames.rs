@@ -0,0 +1,238 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::render::canvas::{Canvas, CanvasPoint};
+use crate::render::geometry::{BlockRect, EdgeRoute, Element, Layout};
+use crate::{Block, Shape};
+
+/// Erase the elements for which `hidden` returns true from a rendered diagram, as if they were
+/// never there but keeping everything else exactly where it is. Useful to show a diagram piece
+/// by piece, like in the frames of a walkthrough, where each frame must line up with the next
+/// one.
+///
+/// Lines shared with the edges that are kept are kept too and the borders of the blocks where
+/// erased edges were attached are drawn again. `layout` must be the one the diagram was rendered
+/// with.
+pub fn hide_elements(
+    rows: Vec<Vec<u8>>,
+    layout: &Layout,
+    blocks: &[Block],
+    hidden: impl Fn(Element) -> bool,
+) -> Vec<Vec<u8>> {
+    let mut canvas = Canvas::from_rows(rows);
+    let hidden_blocks = (0..layout.blocks.len())
+        .filter(|&i| hidden(Element::Block(i)))
+        .collect::<HashSet<_>>();
+    let hidden_edges = (0..layout.edges.len())
+        .filter(|&i| hidden(Element::Edge(i)))
+        .collect::<HashSet<_>>();
+
+    // what the edges that are kept draw on each point of their route, corners and ends included
+    let mut kept_lines = HashMap::new();
+    let mut kept = HashSet::new();
+    for (i, e) in layout.edges.iter().enumerate() {
+        if hidden_edges.contains(&i) {
+            continue;
+        }
+        for (pt, c) in route_chars(e) {
+            let prev = kept_lines.entry(pt).or_insert(c);
+            if *prev != c {
+                *prev = b'+';
+            }
+        }
+        kept.extend(arrowhead(layout, e));
+        kept.extend(label_points(e));
+    }
+    for (i, r) in layout.blocks.iter().enumerate() {
+        if !hidden_blocks.contains(&i) {
+            kept.extend(rect_points(r, blocks[i].has_shadow()));
+        }
+    }
+
+    for (i, e) in layout.edges.iter().enumerate() {
+        if !hidden_edges.contains(&i) {
+            continue;
+        }
+
+        let points = route_chars(e)
+            .into_iter()
+            .map(|(pt, _)| pt)
+            .chain(arrowhead(layout, e))
+            .chain(label_points(e));
+        for pt in points {
+            if kept_lines.contains_key(&pt) || kept.contains(&pt) {
+                continue;
+            }
+            canvas.set(pt, b' ');
+        }
+    }
+    for &i in &hidden_blocks {
+        for pt in rect_points(&layout.blocks[i], blocks[i].has_shadow()) {
+            if !kept_lines.contains_key(&pt) && !kept.contains(&pt) {
+                canvas.set(pt, b' ');
+            }
+        }
+    }
+
+    // junctions with the erased edges are drawn again as the lines and borders they're on
+    let junctions = layout
+        .edges
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| hidden_edges.contains(i))
+        .flat_map(|(_, e)| route_chars(e))
+        .map(|(pt, _)| pt)
+        .collect::<HashSet<_>>();
+    for pt in junctions {
+        if !matches!(canvas.at(pt), b'+' | b'*') {
+            continue;
+        }
+        if let Some(&c) = kept_lines.get(&pt) {
+            canvas.set(pt, c);
+            continue;
+        }
+
+        let border = layout
+            .blocks
+            .iter()
+            .enumerate()
+            .find(|(i, r)| !hidden_blocks.contains(i) && r.on_border(pt));
+        if let Some((i, r)) = border {
+            canvas.set(pt, border_char(r, pt, &blocks[i]));
+        }
+    }
+
+    canvas.into_rows()
+}
+
+/// The points of the route of an edge alongside the character drawn on each of them when the
+/// edge is alone, that is `+` on the corners and the ends.
+fn route_chars(e: &EdgeRoute) -> Vec<(CanvasPoint, u8)> {
+    let mut chars = vec![];
+    for w in e.points.windows(2) {
+        let (a, b) = (w[0], w[1]);
+        if a.x == b.x {
+            let (y0, y1) = (a.y.min(b.y), a.y.max(b.y));
+            chars.extend((y0 + 1..y1).map(|y| (CanvasPoint::new(a.x, y), b'|')));
+        } else {
+            let (x0, x1) = (a.x.min(b.x), a.x.max(b.x));
+            chars.extend((x0 + 1..x1).map(|x| (CanvasPoint::new(x, a.y), b'-')));
+        }
+    }
+    chars.extend(e.points.iter().map(|&pt| (pt, b'+')));
+    chars
+}
+
+/// Where the arrowhead of an edge would be drawn, right outside the block it points to. Edges
+/// left out and junctions between blocks sharing a border have none.
+fn arrowhead(layout: &Layout, e: &EdgeRoute) -> Option<CanvasPoint> {
+    if e.points.is_empty() || e.src == e.dst {
+        return None;
+    }
+    let r = layout.blocks.get(e.to)?;
+    let CanvasPoint { x, y } = e.dst;
+
+    let pt = if y == r.y {
+        CanvasPoint::new(x, y.checked_sub(1)?)
+    } else if y == r.y + r.height - 1 {
+        CanvasPoint::new(x, y + 1)
+    } else if x == r.x {
+        CanvasPoint::new(x.checked_sub(1)?, y)
+    } else {
+        CanvasPoint::new(x + 1, y)
+    };
+    Some(pt).filter(|pt| pt.x < layout.width && pt.y < layout.height)
+}
+
+fn label_points(e: &EdgeRoute) -> impl Iterator<Item = CanvasPoint> + '_ {
+    e.labels
+        .iter()
+        .flat_map(|(pt, l)| (pt.x..pt.x + l.len()).map(move |x| CanvasPoint::new(x, pt.y)))
+}
+
+/// The points covered by a block, its shadow included.
+fn rect_points(r: &BlockRect, shadow: bool) -> Vec<CanvasPoint> {
+    let mut points = (r.y..r.y + r.height)
+        .flat_map(|y| (r.x..r.x + r.width).map(move |x| CanvasPoint::new(x, y)))
+        .collect::<Vec<_>>();
+    if shadow {
+        points.extend((r.y + 1..=r.y + r.height).map(|y| CanvasPoint::new(r.x + r.width, y)));
+        points.extend((r.x + 1..r.x + r.width).map(|x| CanvasPoint::new(x, r.y + r.height)));
+    }
+    points
+}
+
+/// The character of the border of a block at the given point, see `Canvas::dash_rect_outline`
+/// and `Canvas::draw_external_outline`.
+fn border_char(r: &BlockRect, pt: CanvasPoint, b: &Block) -> u8 {
+    if pt.x == r.x || pt.x == r.x + r.width - 1 {
+        match (b.shape(), b.dashed) {
+            (Shape::External, _) if pt.x == r.x => b'[',
+            (Shape::External, _) => b']',
+            (_, true) => b':',
+            (_, false) => b'|',
+        }
+    } else if b.dashed && (pt.x - r.x).is_multiple_of(2) {
+        b' '
+    } else {
+        b'-'
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::assert_diagram_eq;
+    use crate::render::{render_layout, render_with_layout, RenderOptions};
+    use crate::Edge;
+
+    #[test]
+    fn test_hide_elements() {
+        let blocks = [
+            Block::from_str((0, 0), "a"),
+            Block::from_str((0, 1), "b"),
+            Block::from_str((1, 1), "c"),
+        ];
+        let edges = [Edge::new(0, 1), Edge::new(0, 2)];
+        let config = RenderOptions {
+            hmargin: 4,
+            vmargin: 1,
+            padding: 0,
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        };
+
+        let layout = render_layout(&blocks, edges.clone(), config.clone());
+        let rows = render_with_layout(&blocks, edges.clone(), &layout, config).unwrap();
+        let hidden = hide_elements(rows.clone(), &layout, &blocks, |e| {
+            e == Element::Block(2) || e == Element::Edge(1)
+        });
+        assert_diagram_eq!(
+            hidden,
+            br#"                  
+    +-+    +-+    
+    |a+----+b|    
+    +-+    +-+    
+                  
+                  
+                  
+                  
+                  "#
+        );
+
+        let hidden = hide_elements(rows, &layout, &blocks, |e| e == Element::Edge(0));
+        assert_diagram_eq!(
+            hidden,
+            br#"                  
+    +-+    +-+    
+    |a|    |b|    
+    +++    +-+    
+     |            
+     |     +-+    
+     +-----+c|    
+           +-+    
+                  "#
+        );
+    }
+}
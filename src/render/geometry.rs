@@ -0,0 +1,686 @@
+use std::fmt;
+
+use crate::render::canvas::{CanvasPoint, CanvasRect};
+use crate::render::canvas_space::CanvasSpace;
+use crate::render::lines::{Line, Route};
+use crate::render::suggest::{metrics, Metrics};
+use crate::render::{draw_blocks, draw_routes, layout, RouteError};
+use crate::{Block, Edge, RenderOptions};
+
+/// The geometry of a rendered diagram in canvas coordinates, that is where each character would
+/// be drawn by `render`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    pub width: usize,
+    pub height: usize,
+
+    /// rectangles of the blocks, in the same order as the blocks.
+    pub blocks: Vec<BlockRect>,
+
+    /// routes of the edges, in the same order as the edges.
+    pub edges: Vec<EdgeRoute>,
+
+    /// number of empty columns and rows inserted in each gap between columns and rows
+    /// respectively, see `RenderOptions::gutter_threshold`. The gap `i` is the one right before
+    /// the `i`-th column or row, the last gap is the one after the last column or row.
+    pub column_gutters: Vec<usize>,
+    pub row_gutters: Vec<usize>,
+}
+
+/// The rectangle occupied by a `Block`, borders included.
+pub type BlockRect = CanvasRect;
+
+/// How an `Edge` is routed between its blocks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EdgeRoute {
+    pub from: usize,
+    pub to: usize,
+
+    /// points where the edge is attached to the `from` and `to` blocks respectively.
+    pub src: CanvasPoint,
+    pub dst: CanvasPoint,
+
+    /// the corners of the edge from `src` to `dst`, both included. It's empty if the edge was
+    /// left out with `RouteFailure::Skip`.
+    pub points: Vec<CanvasPoint>,
+
+    /// labels of the edge alongside the point where they start.
+    pub labels: Vec<(CanvasPoint, Vec<u8>)>,
+}
+
+/// An element of a diagram, referenced by its index like in `render`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Element {
+    Block(usize),
+    Edge(usize),
+}
+
+impl Layout {
+    /// Find the element drawn at the given point of the canvas, if any. Blocks include their
+    /// borders while edges include their arrowheads and labels.
+    ///
+    /// Blocks take precedence over the edges attached to them and where edges cross the first one
+    /// is returned.
+    pub fn hit_test(&self, x: usize, y: usize) -> Option<Element> {
+        let pt = CanvasPoint::new(x, y);
+
+        if let Some(i) = self.blocks.iter().position(|r| r.contains(pt)) {
+            return Some(Element::Block(i));
+        }
+
+        self.edges
+            .iter()
+            .position(|e| e.contains(pt))
+            .map(Element::Edge)
+    }
+}
+
+impl EdgeRoute {
+    /// Whether the given point is on the route or on one of the labels.
+    fn contains(&self, pt: CanvasPoint) -> bool {
+        let on_segment = self.points.windows(2).any(|w| {
+            let (x0, x1) = (w[0].x.min(w[1].x), w[0].x.max(w[1].x));
+            let (y0, y1) = (w[0].y.min(w[1].y), w[0].y.max(w[1].y));
+            (x0..=x1).contains(&pt.x) && (y0..=y1).contains(&pt.y)
+        });
+        let on_label = self
+            .labels
+            .iter()
+            .any(|(l, text)| pt.y == l.y && (l.x..l.x + text.len()).contains(&pt.x));
+
+        on_segment || on_label
+    }
+}
+
+/// Why a `Layout` cannot be used to render a diagram with `render_with_layout`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutMismatch {
+    /// The layout has a different number of blocks, edges, or gaps between columns and rows.
+    Count,
+
+    /// The blocks would be drawn on a canvas of different dimensions.
+    Size,
+
+    /// The block at the given index would be drawn elsewhere.
+    Block(usize),
+
+    /// The edge at the given index connects different blocks or its route is not valid.
+    Edge(usize),
+}
+
+impl fmt::Display for LayoutMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LayoutMismatch::Count => write!(
+                f,
+                "the number of blocks, edges, columns or rows is different"
+            ),
+            LayoutMismatch::Size => write!(f, "the size of the canvas is different"),
+            LayoutMismatch::Block(i) => write!(f, "block {} is not where expected", i),
+            LayoutMismatch::Edge(i) => write!(f, "edge {} has an invalid route", i),
+        }
+    }
+}
+
+impl std::error::Error for LayoutMismatch {}
+
+/// Lay out the given diagram exactly like `render` does, but return its geometry instead of
+/// drawing it. Useful to draw the diagram with something else than ASCII characters or to
+/// overlay things on top of it.
+///
+/// Panic if an edge can't be routed with `RouteFailure::Error`, see `try_render_layout`.
+pub fn render_layout(
+    boxes: &[Block],
+    edges: impl IntoIterator<Item = impl Into<Edge>>,
+    config: RenderOptions,
+) -> Layout {
+    try_render_layout(boxes, edges, config).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Like `render_layout`, but fail with the first edge that can't be routed with
+/// `RouteFailure::Error` instead of panicking.
+pub fn try_render_layout(
+    boxes: &[Block],
+    edges: impl IntoIterator<Item = impl Into<Edge>>,
+    config: RenderOptions,
+) -> Result<Layout, RouteError> {
+    let edges = edges.into_iter().map(Into::into).collect::<Vec<_>>();
+    if boxes.is_empty() {
+        return Ok(Layout {
+            width: 0,
+            height: 0,
+            blocks: vec![],
+            edges: vec![],
+            column_gutters: vec![],
+            row_gutters: vec![],
+        });
+    }
+
+    let (cs, canvas, routes) = layout(boxes, &edges, &config)?;
+
+    let blocks = block_rects(&cs, boxes);
+
+    let edges = edges
+        .iter()
+        .zip(routes)
+        .map(|(e, r)| EdgeRoute {
+            from: e.from,
+            to: e.to,
+            src: r.src,
+            dst: r.dst,
+            points: points(&r),
+            labels: r.labels,
+        })
+        .collect();
+
+    Ok(Layout {
+        width: canvas.width(),
+        height: canvas.height(),
+        blocks,
+        edges,
+        column_gutters: cs.columns_gutters().to_vec(),
+        row_gutters: cs.rows_gutters().to_vec(),
+    })
+}
+
+/// Render the given diagram using the routes of the edges in `layout`, usually obtained by
+/// `render_layout`, instead of finding them again. This is much faster than `render` for large
+/// diagrams and it reproduces a previous rendering exactly.
+///
+/// Fail if the blocks would not be drawn where `layout` says or if its routes do not connect the
+/// blocks of the edges, that is if `layout` doesn't match the diagram anymore.
+pub fn render_with_layout(
+    boxes: &[Block],
+    edges: impl IntoIterator<Item = impl Into<Edge>>,
+    layout: &Layout,
+    config: RenderOptions,
+) -> Result<Vec<Vec<u8>>, LayoutMismatch> {
+    let edges = edges.into_iter().map(Into::into).collect::<Vec<_>>();
+    if boxes.len() != layout.blocks.len() || edges.len() != layout.edges.len() {
+        return Err(LayoutMismatch::Count);
+    }
+    if boxes.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let (cs, routes) = routes(boxes, &edges, layout, &config)?;
+
+    let mut canvas = draw_blocks(&cs, boxes, &config);
+    draw_routes(&cs, &mut canvas, boxes, &edges, &routes);
+
+    Ok(canvas.into_rows())
+}
+
+/// Measure the diagram drawn with the routes of the edges in `layout`, like `score` does for
+/// the routes found by `render`. Fail if `layout` doesn't match the diagram, see
+/// `render_with_layout`.
+pub fn score_layout(
+    boxes: &[Block],
+    edges: impl IntoIterator<Item = impl Into<Edge>>,
+    layout: &Layout,
+    config: RenderOptions,
+) -> Result<Metrics, LayoutMismatch> {
+    let edges = edges.into_iter().map(Into::into).collect::<Vec<_>>();
+    if boxes.len() != layout.blocks.len() || edges.len() != layout.edges.len() {
+        return Err(LayoutMismatch::Count);
+    }
+    if boxes.is_empty() {
+        return Ok(Metrics {
+            crossings: 0,
+            hugging: 0,
+            length: 0,
+        });
+    }
+
+    let (cs, routes) = routes(boxes, &edges, layout, &config)?;
+    Ok(metrics(&cs, &routes))
+}
+
+/// Check that `layout` matches the given diagram and turn its routes back into `Route`s. The
+/// number of blocks and edges must have been checked already.
+fn routes(
+    boxes: &[Block],
+    edges: &[Edge],
+    layout: &Layout,
+    config: &RenderOptions,
+) -> Result<(CanvasSpace, Vec<Route>), LayoutMismatch> {
+    let mut cs = CanvasSpace::new(boxes, config);
+    if cs.columns_gutters().len() != layout.column_gutters.len()
+        || cs.rows_gutters().len() != layout.row_gutters.len()
+    {
+        return Err(LayoutMismatch::Count);
+    }
+    for (gap, &n) in layout.column_gutters.iter().enumerate() {
+        for _ in 0..n {
+            cs.add_column_gutter(gap);
+        }
+    }
+    for (gap, &n) in layout.row_gutters.iter().enumerate() {
+        for _ in 0..n {
+            cs.add_row_gutter(gap);
+        }
+    }
+
+    if (cs.canvas_width(), cs.canvas_height()) != (layout.width, layout.height) {
+        return Err(LayoutMismatch::Size);
+    }
+
+    let rects = block_rects(&cs, boxes);
+    if let Some(i) = (0..rects.len()).find(|&i| rects[i] != layout.blocks[i]) {
+        return Err(LayoutMismatch::Block(i));
+    }
+
+    let mut routes = Vec::with_capacity(edges.len());
+    for (i, (e, r)) in edges.iter().zip(&layout.edges).enumerate() {
+        // edges left out with `RouteFailure::Skip`
+        if r.points.is_empty() && (e.from, e.to) == (r.from, r.to) {
+            routes.push(Route {
+                polyline: vec![],
+                src: r.src,
+                dst: r.dst,
+                labels: vec![],
+            });
+            continue;
+        }
+
+        let route = (e.from, e.to) == (r.from, r.to)
+            && rects[r.from].on_border(r.src)
+            && rects[r.to].on_border(r.dst)
+            && r.labels.iter().all(|(pt, l)| {
+                pt.y < layout.height && pt.x.checked_add(l.len()).is_some_and(|x| x <= layout.width)
+            });
+        let route = match polyline(&r.points, (layout.width, layout.height)) {
+            Some(polyline) if route && r.points.first() == Some(&r.src) => Route {
+                polyline,
+                src: r.src,
+                dst: r.dst,
+                labels: r.labels.clone(),
+            },
+            _ => return Err(LayoutMismatch::Edge(i)),
+        };
+        if r.points.last() != Some(&r.dst) {
+            return Err(LayoutMismatch::Edge(i));
+        }
+
+        routes.push(route);
+    }
+
+    Ok((cs, routes))
+}
+
+impl CanvasRect {
+    /// Whether the given point is on the border of the rectangle, corners excluded.
+    pub(crate) fn on_border(&self, CanvasPoint { x, y }: CanvasPoint) -> bool {
+        let (right, bottom) = (self.x + self.width - 1, self.y + self.height - 1);
+        let inside_x = x > self.x && x < right;
+        let inside_y = y > self.y && y < bottom;
+
+        ((x == self.x || x == right) && inside_y) || ((y == self.y || y == bottom) && inside_x)
+    }
+}
+
+fn block_rects(cs: &CanvasSpace, boxes: &[Block]) -> Vec<BlockRect> {
+    (0..boxes.len()).map(|i| cs.block_rect(boxes, i)).collect()
+}
+
+/// Turn the corners of a route back into lines, if all of them are inside a `width` x `height`
+/// canvas and consecutive ones are on the same row or column.
+pub(crate) fn polyline(
+    points: &[CanvasPoint],
+    (width, height): (usize, usize),
+) -> Option<Vec<Line>> {
+    if points.iter().any(|pt| pt.x >= width || pt.y >= height) {
+        return None;
+    }
+
+    // edges drawn as a junction between blocks sharing a border start and end at the same point
+    if let [a, b] = points {
+        if a == b {
+            return Some(vec![Line::Vertical(a.x, (a.y, a.y))]);
+        }
+    }
+
+    points
+        .windows(2)
+        .filter(|w| w[0] != w[1])
+        .map(|w| {
+            let ((x0, y0), (x1, y1)) = (w[0].into(), w[1].into());
+            if x0 == x1 {
+                Some(Line::Vertical(x0, (y0.min(y1), y0.max(y1))))
+            } else if y0 == y1 {
+                Some(Line::Horizontal(y0, (x0.min(x1), x0.max(x1))))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// The corners of the polyline of `route` going from its source to its destination.
+fn points(route: &Route) -> Vec<CanvasPoint> {
+    if route.polyline.is_empty() {
+        return vec![];
+    }
+
+    let mut lines = route.polyline.iter().map(|l| l.ends()).collect::<Vec<_>>();
+
+    // the polyline might have been found going from the destination to the source
+    if lines
+        .first()
+        .is_some_and(|&(a, b)| a != route.src && b != route.src)
+    {
+        lines.reverse();
+    }
+
+    let mut points = vec![route.src];
+    for (a, b) in lines {
+        let last = *points.last().unwrap();
+        points.push(if last == a { b } else { a });
+    }
+    points
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::assert_diagram_eq;
+    use crate::render::{render_rows, Attachment, RouteFailure};
+
+    fn config() -> RenderOptions {
+        RenderOptions {
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_render_layout() {
+        let blocks = [
+            Block::from_str((0, 0), "a"),
+            Block::from_str((0, 1), "b"),
+            Block::from_str((1, 1), "c"),
+        ];
+        let edges = [(0, 1), (2, 0)];
+
+        let layout = render_layout(&blocks, edges.iter().copied(), config());
+        let canvas = crate::render::render_rows(&blocks, edges.iter().copied(), config());
+
+        assert_eq!(
+            (layout.width, layout.height),
+            (canvas[0].len(), canvas.len())
+        );
+
+        for r in &layout.blocks {
+            for (x, y) in [
+                (r.x, r.y),
+                (r.x + r.width - 1, r.y),
+                (r.x, r.y + r.height - 1),
+                (r.x + r.width - 1, r.y + r.height - 1),
+            ] {
+                assert_eq!(canvas[y][x], b'+');
+            }
+        }
+
+        for (e, (from, to)) in layout.edges.iter().zip(&edges) {
+            assert_eq!((e.from, e.to), (*from, *to));
+            assert_eq!(e.points.first(), Some(&e.src));
+            assert_eq!(e.points.last(), Some(&e.dst));
+
+            // consecutive points are always on the same row or column
+            for w in e.points.windows(2) {
+                assert!(w[0].x == w[1].x || w[0].y == w[1].y);
+            }
+        }
+
+        assert_eq!(
+            layout.edges[0].points,
+            vec![
+                CanvasPoint::new(layout.blocks[0].x + 4, 5),
+                CanvasPoint::new(layout.blocks[1].x, 5)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fixed_routes_and_rects() {
+        let blocks = [Block::from_str((0, 0), "a"), Block::from_str((0, 1), "b")];
+        let free = render_layout(&blocks, [(0, 1)], config());
+
+        // over the blocks instead of straight between them
+        let route = [(7, 3), (7, 1), (17, 1), (17, 3)].map(|(x, y)| CanvasPoint::new(x, y));
+        let layout = render_layout(&blocks, [Edge::new(0, 1).with_route(route)], config());
+        assert_eq!(layout.edges[0].points, route);
+
+        // a route ending inside a block is ignored
+        let route = [(9, 5), (17, 5)].map(|(x, y)| CanvasPoint::new(x, y));
+        let layout = render_layout(&blocks, [Edge::new(0, 1).with_route(route)], config());
+        assert_eq!(layout.edges, free.edges);
+
+        // a route going through the blocks is ignored but its ends are kept
+        let route = [(7, 3), (17, 3)].map(|(x, y)| CanvasPoint::new(x, y));
+        let layout = render_layout(&blocks, [Edge::new(0, 1).with_route(route)], config());
+        assert_eq!(
+            (layout.edges[0].src, layout.edges[0].dst),
+            (route[0], route[1])
+        );
+        assert_eq!(layout.edges[0].points.len(), 4);
+
+        let rect = CanvasRect {
+            x: 18,
+            y: 4,
+            width: 7,
+            height: 5,
+        };
+        let blocks = [blocks[0].clone(), blocks[1].clone().with_rect(rect)];
+        let layout = render_layout(&blocks, [(0, 1)], config());
+        assert_eq!(layout.blocks[1], rect);
+        assert_eq!(layout.width, free.width + 5);
+    }
+
+    #[test]
+    fn test_route_failure() {
+        // the block in the middle is walled in by the others
+        let blocks = (0..9)
+            .map(|i| Block::from_str((i / 3, i % 3), "x"))
+            .collect::<Vec<_>>();
+        let walled = RenderOptions {
+            hmargin: 0,
+            vmargin: 0,
+            padding: 0,
+            ..config()
+        };
+
+        let err = try_render_layout(&blocks, [(4, 0)], walled.clone()).unwrap_err();
+        assert_eq!(
+            err,
+            RouteError {
+                edge: 0,
+                from: 4,
+                to: 0
+            }
+        );
+
+        let skip = RenderOptions {
+            route_failure: RouteFailure::Skip,
+            ..walled.clone()
+        };
+        let layout = render_layout(&blocks, [(4, 0)], skip.clone());
+        assert!(layout.edges[0].points.is_empty());
+        let rows = render_with_layout(&blocks, [(4, 0)], &layout, skip.clone()).unwrap();
+        assert_eq!(rows, render_rows(&blocks, Vec::<Edge>::new(), skip));
+
+        let force = RenderOptions {
+            route_failure: RouteFailure::Force,
+            ..walled
+        };
+        let rows = render_rows(&blocks, [(4, 0)], force.clone());
+        assert_diagram_eq!(
+            rows,
+            br#"+-+-+-+
+|x|x|x|
++-!-+-+
+|x|x|x|
++-+-+-+
+|x|x|x|
++-+-+-+"#
+        );
+
+        // the edge runs along the borders instead of cutting through the text of the block on
+        // its way
+        let blocks = (0..9)
+            .map(|i| Block::from_str((i / 3, i % 3), "ab\ncd"))
+            .collect::<Vec<_>>();
+        let rows = render_rows(
+            &blocks,
+            [(4, 0)],
+            RenderOptions {
+                padding: 1,
+                attachment: Attachment::HorizontalFirst,
+                ..force
+            },
+        );
+        assert_diagram_eq!(
+            rows,
+            br#"+----+----+----+
+|    |    |    |
+| ab | ab | ab |
+| cd | cd | cd |
+|    |    |    |
++---!!----+----+
+|    !    |    |
+| ab ! ab | ab |
+| cd | cd | cd |
+|    |    |    |
++----+----+----+
+|    |    |    |
+| ab | ab | ab |
+| cd | cd | cd |
+|    |    |    |
++----+----+----+"#
+        );
+    }
+
+    #[test]
+    fn test_hit_test() {
+        let blocks = [Block::from_str((0, 0), "a"), Block::from_str((0, 1), "b")];
+        let edges = [Edge::new(0, 1).with_directed(true).with_to_label("1")];
+        let layout = render_layout(&blocks, edges, config());
+
+        // the blocks are at [5, 10) and [15, 20) and the edge goes from (9, 5) to (15, 5)
+        assert_eq!(layout.hit_test(5, 3), Some(Element::Block(0)));
+        assert_eq!(layout.hit_test(9, 5), Some(Element::Block(0)));
+        assert_eq!(layout.hit_test(12, 5), Some(Element::Edge(0)));
+        assert_eq!(layout.hit_test(14, 5), Some(Element::Edge(0)));
+        assert_eq!(layout.hit_test(17, 7), Some(Element::Block(1)));
+        assert_eq!(layout.hit_test(12, 0), None);
+        assert_eq!(layout.hit_test(100, 100), None);
+
+        let (label, _) = layout.edges[0].labels[0];
+        assert_eq!(layout.hit_test(label.x, label.y), Some(Element::Edge(0)));
+    }
+
+    #[test]
+    fn test_render_with_layout() {
+        let blocks = [
+            Block::from_str((0, 0), "a"),
+            Block::from_str((0, 1), "b"),
+            Block::from_str((1, 1), "c"),
+        ];
+        let edges = [(0, 1), (2, 0)];
+        let mut cfg = config();
+        cfg.gutter_threshold = Some(0);
+
+        let layout = render_layout(&blocks, edges.iter().copied(), cfg.clone());
+        assert_eq!(
+            render_with_layout(&blocks, edges.iter().copied(), &layout, cfg.clone()),
+            Ok(crate::render::render_rows(
+                &blocks,
+                edges.iter().copied(),
+                cfg.clone()
+            ))
+        );
+
+        let render = |blocks: &[Block], edges: &[(usize, usize)], layout: &Layout| {
+            render_with_layout(blocks, edges.iter().copied(), layout, cfg.clone())
+        };
+
+        assert_eq!(
+            render(&blocks, &edges[..1], &layout),
+            Err(LayoutMismatch::Count)
+        );
+
+        let mut moved = blocks.clone();
+        moved[2] = Block::from_str((1, 0), "c");
+        assert_eq!(
+            render(&moved, &edges, &layout),
+            Err(LayoutMismatch::Block(2))
+        );
+
+        let mut bigger = blocks.clone();
+        bigger[0] = Block::from_str((0, 0), "aaa");
+        assert_eq!(render(&bigger, &edges, &layout), Err(LayoutMismatch::Size));
+
+        assert_eq!(
+            render(&blocks, &[(0, 1), (2, 1)], &layout),
+            Err(LayoutMismatch::Edge(1))
+        );
+
+        let mut diagonal = layout.clone();
+        diagonal.edges[0].points.insert(1, CanvasPoint::new(0, 0));
+        assert_eq!(
+            render(&blocks, &edges, &diagonal),
+            Err(LayoutMismatch::Edge(0))
+        );
+    }
+
+    #[test]
+    fn test_score_layout() {
+        let blocks = [
+            Block::from_str((0, 0), "a"),
+            Block::from_str((0, 1), "b"),
+            Block::from_str((0, 2), "c"),
+            Block::from_str((1, 1), "d"),
+        ];
+        let edges = [(0, 2), (1, 3)];
+        let mut cfg = config();
+        cfg.vmargin = 1;
+
+        let layout = render_layout(&blocks, edges.iter().copied(), cfg.clone());
+        let m = score_layout(&blocks, edges.iter().copied(), &layout, cfg.clone()).unwrap();
+        assert_eq!(m, crate::score(&blocks, edges.iter().copied(), cfg.clone()));
+        assert!(m.length > 0);
+
+        assert_eq!(
+            score_layout(&blocks, edges[..1].iter().copied(), &layout, cfg),
+            Err(LayoutMismatch::Count)
+        );
+    }
+
+    #[test]
+    fn test_points() {
+        // found from the destination to the source
+        let route = Route {
+            polyline: vec![Line::Vertical(5, (0, 3)), Line::Horizontal(3, (1, 5))],
+            src: CanvasPoint::new(1, 3),
+            dst: CanvasPoint::new(5, 0),
+            labels: vec![],
+        };
+        assert_eq!(
+            points(&route),
+            [(1, 3), (5, 3), (5, 0)].map(CanvasPoint::from)
+        );
+
+        let route = Route {
+            polyline: vec![Line::Horizontal(3, (1, 5)), Line::Vertical(5, (0, 3))],
+            src: CanvasPoint::new(1, 3),
+            dst: CanvasPoint::new(5, 0),
+            labels: vec![],
+        };
+        assert_eq!(
+            points(&route),
+            [(1, 3), (5, 3), (5, 0)].map(CanvasPoint::from)
+        );
+    }
+}
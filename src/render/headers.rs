@@ -0,0 +1,432 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use crate::render::canvas::CanvasPoint;
+use crate::render::geometry::{BlockRect, EdgeRoute, Layout};
+use crate::{Block, LogicalCoord};
+
+/// Add labels above the columns and to the left of the rows of a rendered diagram, like the
+/// tiers of an architecture or the lanes of a process. The `i`-th label of `column_labels` and
+/// `row_labels` is for the `i`-th column and row counting from the leftmost and topmost ones with
+/// blocks, whatever their coordinates, and labels of columns and rows without blocks are ignored.
+/// Headers are separated from the diagram by rules, like this:
+///
+/// ```text
+///          |  frontend | backend
+/// ---------+-----------+----------
+///          |
+///          |    +---+    +---+
+///  clients |    |web+----+api|
+///          |    +---+    +-+-+
+/// ---------+               |
+///          |             +-+-+
+///  storage |             |db |
+///          |             +---+
+/// ```
+///
+/// `layout` must be the one the diagram was rendered with, it's returned in the coordinates of the
+/// diagram with the headers.
+pub fn add_headers(
+    rows: Vec<Vec<u8>>,
+    layout: &Layout,
+    blocks: &[Block],
+    column_labels: &[impl AsRef<[u8]>],
+    row_labels: &[impl AsRef<[u8]>],
+) -> (Vec<Vec<u8>>, Layout) {
+    let columns = spans(blocks, layout, |b| b.pos.col, |r| (r.x, r.x + r.width));
+    let rows_spans = spans(blocks, layout, |b| b.pos.row, |r| (r.y, r.y + r.height));
+    let column_labels = labels_of(&columns, column_labels);
+    let row_labels = labels_of(&rows_spans, row_labels);
+
+    // the gutter is made of the row labels padded by a space on both sides and the rule
+    let label_w = row_labels.values().map(|l| l.len()).max();
+    let gutter_w = label_w.map_or(0, |w| w + 3);
+    let header_h = if column_labels.is_empty() { 0 } else { 2 };
+
+    let mut out = Vec::with_capacity(header_h + rows.len());
+    if header_h > 0 {
+        let mut labels = vec![b' '; gutter_w + layout.width];
+        let mut rule = vec![b'-'; gutter_w + layout.width];
+        if gutter_w > 0 {
+            labels[gutter_w - 1] = b'|';
+            rule[gutter_w - 1] = b'+';
+        }
+
+        let separators = separators(&columns);
+        for (c, label) in &column_labels {
+            let (lo, hi) = bounds(&separators, columns[c], layout.width);
+            let x = gutter_w + centered(columns[c], label.len(), (lo, hi));
+            let len = label.len().min(hi - lo);
+            labels[x..x + len].copy_from_slice(&label[..len]);
+        }
+        for x in separators {
+            labels[gutter_w + x] = b'|';
+            rule[gutter_w + x] = b'+';
+        }
+
+        out.push(labels);
+        out.push(rule);
+    }
+
+    let mut gutter = vec![vec![b' '; gutter_w]; rows.len()];
+    if gutter_w > 0 {
+        for y in separators(&rows_spans) {
+            gutter[y] = vec![b'-'; gutter_w];
+        }
+        for g in &mut gutter {
+            g[gutter_w - 1] = if g[0] == b'-' { b'+' } else { b'|' };
+        }
+        for (r, label) in &row_labels {
+            let (y0, y1) = rows_spans[r];
+            gutter[(y0 + y1 - 1) / 2][1..=label.len()].copy_from_slice(label);
+        }
+    }
+    for (mut g, row) in gutter.into_iter().zip(rows) {
+        g.extend(row);
+        out.push(g);
+    }
+
+    (out, shift(layout, gutter_w, header_h))
+}
+
+/// Add labels to the left of the given rows of a rendered diagram, like the offsets at the
+/// borders of the ranges of a memory map. Labels are right aligned and separated from the diagram
+/// by a space, like this:
+///
+/// ```text
+/// 0x0000 +--------+
+///        |  boot  |
+/// 0x1000 +--------+
+///        | kernel |
+/// 0x8000 +--------+
+/// ```
+///
+/// `layout` must be the one the diagram was rendered with, it's returned in the coordinates of the
+/// diagram with the labels. Labels of rows outside of the diagram are ignored.
+pub fn add_offsets(
+    rows: Vec<Vec<u8>>,
+    layout: &Layout,
+    offsets: &[(usize, impl AsRef<[u8]>)],
+) -> (Vec<Vec<u8>>, Layout) {
+    let offsets = offsets
+        .iter()
+        .filter(|(y, _)| *y < rows.len())
+        .map(|(y, l)| (*y, l.as_ref()))
+        .collect::<Vec<_>>();
+    let gutter_w = offsets.iter().map(|(_, l)| l.len() + 1).max().unwrap_or(0);
+
+    let mut gutter = vec![vec![b' '; gutter_w]; rows.len()];
+    for (y, label) in offsets {
+        let x = gutter_w - 1 - label.len();
+        gutter[y][x..x + label.len()].copy_from_slice(label);
+    }
+    let rows = gutter
+        .into_iter()
+        .zip(rows)
+        .map(|(mut g, row)| {
+            g.extend(row);
+            g
+        })
+        .collect();
+
+    (rows, shift(layout, gutter_w, 0))
+}
+
+/// Move a layout `dx` columns to the right and `dy` rows down, making the canvas larger.
+pub(crate) fn shift(layout: &Layout, dx: usize, dy: usize) -> Layout {
+    let map = |CanvasPoint { x, y }: CanvasPoint| CanvasPoint::new(x + dx, y + dy);
+    Layout {
+        width: layout.width + dx,
+        height: layout.height + dy,
+        blocks: layout
+            .blocks
+            .iter()
+            .map(|r| BlockRect {
+                x: r.x + dx,
+                y: r.y + dy,
+                ..*r
+            })
+            .collect(),
+        edges: layout
+            .edges
+            .iter()
+            .map(|e| EdgeRoute {
+                src: map(e.src),
+                dst: map(e.dst),
+                points: e.points.iter().copied().map(map).collect(),
+                labels: e
+                    .labels
+                    .iter()
+                    .map(|(pt, l)| (map(*pt), l.clone()))
+                    .collect(),
+                ..e.clone()
+            })
+            .collect(),
+        column_gutters: layout.column_gutters.clone(),
+        row_gutters: layout.row_gutters.clone(),
+    }
+}
+
+/// Mark the corners of the logical cells of a rendered diagram with a `.`, that is the points in
+/// the middle of the margins around the columns and rows with blocks. Marks are drawn only over
+/// empty space, after the edges are routed, so they never change the diagram otherwise.
+///
+/// `layout` must be the one the diagram was rendered with.
+pub fn add_guides(mut rows: Vec<Vec<u8>>, layout: &Layout, blocks: &[Block]) -> Vec<Vec<u8>> {
+    let columns = spans(blocks, layout, |b| b.pos.col, |r| (r.x, r.x + r.width));
+    let rows_spans = spans(blocks, layout, |b| b.pos.row, |r| (r.y, r.y + r.height));
+    let xs = boundaries(&columns, layout.width);
+    let ys = boundaries(&rows_spans, layout.height);
+
+    for &y in &ys {
+        for &x in &xs {
+            if rows[y][x] == b' ' {
+                rows[y][x] = b'.';
+            }
+        }
+    }
+    rows
+}
+
+/// The boundaries of the given columns or rows, that is the separators between them and the
+/// middle of the margins before the first one and after the last one, if any.
+fn boundaries(spans: &BTreeMap<LogicalCoord, (usize, usize)>, size: usize) -> Vec<usize> {
+    let first = spans
+        .values()
+        .next()
+        .filter(|s| s.0 > 0)
+        .map(|s| (s.0 - 1) / 2);
+    let last = spans
+        .values()
+        .last()
+        .filter(|s| s.1 < size)
+        .map(|s| (s.1 + size) / 2);
+
+    first
+        .into_iter()
+        .chain(separators(spans))
+        .chain(last)
+        .collect()
+}
+
+/// The range of canvas coordinates each column or row with blocks spans, end excluded.
+pub(crate) fn spans(
+    blocks: &[Block],
+    layout: &Layout,
+    coord: impl Fn(&Block) -> LogicalCoord,
+    range: impl Fn(&BlockRect) -> (usize, usize),
+) -> BTreeMap<LogicalCoord, (usize, usize)> {
+    let mut spans = BTreeMap::new();
+    for (b, r) in blocks.iter().zip(&layout.blocks) {
+        let (start, end) = range(r);
+        let span = spans.entry(coord(b)).or_insert((start, end));
+        *span = (span.0.min(start), span.1.max(end));
+    }
+    spans
+}
+
+/// The labels of the columns or rows that have blocks, by their coordinate. Labels are indexed
+/// from the first column or row with blocks and only the first line of each label is kept.
+fn labels_of(
+    spans: &BTreeMap<LogicalCoord, (usize, usize)>,
+    labels: &[impl AsRef<[u8]>],
+) -> BTreeMap<LogicalCoord, Vec<u8>> {
+    let first = match spans.keys().next() {
+        Some(&first) => first,
+        None => return BTreeMap::new(),
+    };
+
+    labels
+        .iter()
+        .enumerate()
+        .filter_map(|(i, l)| {
+            let c = first.checked_add(LogicalCoord::try_from(i).ok()?)?;
+            let label = l.as_ref().split(|&c| c == b'\n').next()?;
+            Some((c, label.to_vec())).filter(|_| spans.contains_key(&c))
+        })
+        .collect()
+}
+
+/// Where to draw the rules between consecutive columns or rows, that is in the middle of the
+/// margin between them. Columns or rows without margin between them have no rule.
+fn separators(spans: &BTreeMap<LogicalCoord, (usize, usize)>) -> Vec<usize> {
+    let spans = spans.values().collect::<Vec<_>>();
+    spans
+        .windows(2)
+        .filter(|w| w[0].1 < w[1].0)
+        .map(|w| (w[0].1 + w[1].0) / 2)
+        .collect()
+}
+
+/// The space available to the label of the column spanning `span`, that is between the rules
+/// around it leaving a space next to them.
+fn bounds(separators: &[usize], span: (usize, usize), width: usize) -> (usize, usize) {
+    let lo = separators
+        .iter()
+        .rev()
+        .find(|&&x| x < span.0)
+        .map_or(0, |x| x + 2);
+    let hi = separators
+        .iter()
+        .find(|&&x| x >= span.1)
+        .map_or(width, |x| x - 1);
+    (lo, hi.max(lo))
+}
+
+/// Where a text of length `len` centered over `span` starts, moved inside `(lo, hi)` if needed.
+fn centered((start, end): (usize, usize), len: usize, (lo, hi): (usize, usize)) -> usize {
+    let x = (start + end).saturating_sub(len) / 2;
+    x.min(hi.saturating_sub(len)).max(lo)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::assert_diagram_eq;
+    use crate::render::{render_layout, render_with_layout, RenderOptions};
+    use crate::{Edge, Element};
+
+    #[test]
+    fn test_headers() {
+        let blocks = [
+            Block::from_str((0, 0), "web"),
+            Block::from_str((0, 1), "api"),
+            Block::from_str((1, 1), "db"),
+        ];
+        let edges = [Edge::new(0, 1), Edge::new(1, 2)];
+        let config = RenderOptions {
+            hmargin: 4,
+            vmargin: 1,
+            padding: 0,
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        };
+
+        let layout = render_layout(&blocks, edges.clone(), config.clone());
+        let rows = render_with_layout(&blocks, edges.clone(), &layout, config).unwrap();
+        let (rows, headers) = add_headers(
+            rows,
+            &layout,
+            &blocks,
+            &["frontend", "backend", "unused"],
+            &["clients", "storage"],
+        );
+
+        assert_diagram_eq!(
+            rows,
+            br#"         |  frontend | backend  
+---------+-----------+----------
+         |                      
+         |    +---+    +---+    
+ clients |    |web+----+api|    
+         |    +---+    +-+-+    
+---------+               |      
+         |             +-+-+    
+ storage |             |db |    
+         |             +---+    
+         |                      "#
+        );
+
+        assert_eq!(headers.width, rows[0].len());
+        assert_eq!(headers.height, rows.len());
+        let b = &headers.blocks[2];
+        assert_eq!(headers.hit_test(b.x, b.y), Some(Element::Block(2)));
+    }
+
+    #[test]
+    fn test_headers_negative_coordinates() {
+        let blocks = [
+            Block::from_str((-1, -1), "a"),
+            Block::from_str((-1, 0), "b"),
+            Block::from_str((0, 1), "c"),
+        ];
+        let config = RenderOptions {
+            hmargin: 2,
+            vmargin: 1,
+            padding: 0,
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        };
+
+        let layout = render_layout(&blocks, Vec::<Edge>::new(), config.clone());
+        let rows = render_with_layout(&blocks, Vec::<Edge>::new(), &layout, config).unwrap();
+        let (rows, _) = add_headers(rows, &layout, &blocks, &["x", "y", "z"], &["top", "low"]);
+
+        assert_diagram_eq!(
+            rows,
+            br#"     |   x  | y  | z   
+-----+------+----+-----
+     |                 
+     |  +-+  +-+       
+ top |  |a|  |b|       
+     |  +-+  +-+       
+-----+                 
+     |            +-+  
+ low |            |c|  
+     |            +-+  
+     |                 "#
+        );
+    }
+
+    #[test]
+    fn test_guides() {
+        let blocks = [Block::from_str((0, 0), "a"), Block::from_str((1, 1), "b")];
+        let edges = [Edge::new(0, 1)];
+        let config = RenderOptions {
+            hmargin: 4,
+            vmargin: 2,
+            padding: 0,
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        };
+
+        let layout = render_layout(&blocks, edges.clone(), config.clone());
+        let rows = render_with_layout(&blocks, edges.clone(), &layout, config).unwrap();
+        assert_diagram_eq!(
+            add_guides(rows, &layout, &blocks),
+            br#" .       .      . 
+                  
+    +-+           
+    |a|           
+    +++           
+     |            
+ .   |   .      . 
+     |     +-+    
+     +-----+b|    
+           +-+    
+                  
+ .       .      . "#
+        );
+    }
+
+    #[test]
+    fn test_offsets() {
+        let blocks = [Block::from_str((0, 0), "a"), Block::from_str((0, 1), "b")];
+        let edges = [Edge::new(0, 1)];
+        let config = RenderOptions {
+            hmargin: 2,
+            vmargin: 0,
+            padding: 0,
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        };
+
+        let layout = render_layout(&blocks, edges.clone(), config.clone());
+        let rows = render_with_layout(&blocks, edges.clone(), &layout, config).unwrap();
+        let (rows, offsets) = add_offsets(rows, &layout, &[(0, "0"), (2, "16"), (3, "ignored")]);
+        assert_diagram_eq!(
+            rows,
+            br#" 0   +-+  +-+  
+     |a+--+b|  
+16   +-+  +-+  "#
+        );
+
+        assert_eq!(offsets.width, rows[0].len());
+        assert_eq!(offsets.edges[0].src.x, layout.edges[0].src.x + 3);
+    }
+}
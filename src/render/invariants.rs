@@ -0,0 +1,464 @@
+use std::collections::{BTreeMap, HashSet};
+
+use crate::render::canvas::CanvasPoint;
+use crate::render::geometry::{EdgeRoute, Layout};
+
+/// An edge running over a block, see `edge_intrusions`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Intrusion {
+    /// index of the edge.
+    pub edge: usize,
+
+    /// index of the block.
+    pub block: usize,
+
+    /// the point of the block the edge runs over, either on its route or on one of its labels.
+    pub point: CanvasPoint,
+}
+
+/// Find where the edges of a diagram run over its blocks, borders included, other than at the
+/// points where edges are attached to them. This never happens in the diagrams rendered by this
+/// crate, it's meant to check that a `Layout` edited or built by hand is still sound.
+pub fn edge_intrusions(layout: &Layout) -> Vec<Intrusion> {
+    let attachments = attachment_points(layout);
+
+    let mut intrusions = vec![];
+    for (i, e) in layout.edges.iter().enumerate() {
+        let route = e.points.windows(2).flat_map(|w| {
+            let (x0, x1) = (w[0].x.min(w[1].x), w[0].x.max(w[1].x));
+            let (y0, y1) = (w[0].y.min(w[1].y), w[0].y.max(w[1].y));
+            (y0..=y1).flat_map(move |y| (x0..=x1).map(move |x| CanvasPoint::new(x, y)))
+        });
+        let labels = e.labels.iter().flat_map(|(pt, l)| {
+            (pt.x..pt.x + l.len()).map(move |x| (CanvasPoint::new(x, pt.y), true))
+        });
+
+        let mut seen = HashSet::new();
+        for (pt, is_label) in route.map(|pt| (pt, false)).chain(labels) {
+            if !seen.insert((pt, is_label)) {
+                continue;
+            }
+            for (b, r) in layout.blocks.iter().enumerate() {
+                let attached = !is_label && attachments.contains(&(b, pt));
+                if r.contains(pt) && !attached {
+                    intrusions.push(Intrusion {
+                        edge: i,
+                        block: b,
+                        point: pt,
+                    });
+                }
+            }
+        }
+    }
+    intrusions
+}
+
+/// Two edges drawn over each other where the diagram can't be read anymore, see `collisions`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Collision {
+    /// index of the edge drawn over the other one.
+    pub over: usize,
+
+    /// index of the edge drawn over, it might be `over` itself.
+    pub under: usize,
+
+    pub kind: CollisionKind,
+
+    /// the first point, from top to bottom and from left to right, where the edges collide.
+    pub point: CanvasPoint,
+}
+
+/// What was drawn over what in a `Collision`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CollisionKind {
+    /// A label of `over` over a label of `under`.
+    LabelOverLabel,
+
+    /// A label of `over` over the line of `under`.
+    LabelOverLine,
+
+    /// The line of `over` over the end of the line of `under` right next to one of its blocks,
+    /// where it would look attached to it. Edges attached to the same points, like the ones in
+    /// the same group, are fine.
+    LineOverEnd,
+}
+
+/// Find where the edges of a diagram are drawn over each other other than by crossing or
+/// sharing their lines, like a label over a line, using the elements each cell belongs to in
+/// `layout`. There's one collision for each pair of edges and kind of collision. Edges running
+/// over blocks are found by `edge_intrusions` instead.
+pub fn collisions(layout: &Layout) -> Vec<Collision> {
+    let route = |e: &EdgeRoute| -> HashSet<CanvasPoint> {
+        e.points
+            .windows(2)
+            .flat_map(|w| {
+                let (x0, x1) = (w[0].x.min(w[1].x), w[0].x.max(w[1].x));
+                let (y0, y1) = (w[0].y.min(w[1].y), w[0].y.max(w[1].y));
+                (y0..=y1).flat_map(move |y| (x0..=x1).map(move |x| CanvasPoint::new(x, y)))
+            })
+            .collect()
+    };
+    let routes = layout.edges.iter().map(route).collect::<Vec<_>>();
+
+    // each cell of a label alongside the index of the label among all of them
+    let labels = layout
+        .edges
+        .iter()
+        .flat_map(|e| &e.labels)
+        .enumerate()
+        .flat_map(|(l, (pt, text))| (pt.x..pt.x + text.len()).map(move |x| (l, pt.y, x)));
+    let label_edges = layout
+        .edges
+        .iter()
+        .enumerate()
+        .flat_map(|(i, e)| e.labels.iter().map(move |_| i))
+        .collect::<Vec<_>>();
+
+    let mut found = BTreeMap::new();
+    let mut add = |over: usize, under: usize, kind: CollisionKind, pt: CanvasPoint| {
+        let first = found.entry((over, under, kind)).or_insert(pt);
+        if (pt.y, pt.x) < (first.y, first.x) {
+            *first = pt;
+        }
+    };
+
+    let mut label_cells = BTreeMap::<CanvasPoint, usize>::new();
+    for (l, y, x) in labels {
+        let pt = CanvasPoint::new(x, y);
+        let over = label_edges[l];
+        if let Some(&prev) = label_cells.get(&pt) {
+            add(over, label_edges[prev], CollisionKind::LabelOverLabel, pt);
+        }
+        label_cells.insert(pt, l);
+        for (under, r) in routes.iter().enumerate() {
+            if r.contains(&pt) {
+                add(over, under, CollisionKind::LabelOverLine, pt);
+            }
+        }
+    }
+
+    for (under, e) in layout.edges.iter().enumerate() {
+        let ends = [
+            (e.points.first(), e.points.get(1)),
+            (e.points.last(), e.points.iter().rev().nth(1)),
+        ];
+        for (end, next) in ends {
+            let (end, next) = match (end, next) {
+                (Some(&end), Some(&next)) if end != next => (end, next),
+                _ => continue,
+            };
+            let step = |a: usize, b: usize| {
+                if b > a {
+                    a + 1
+                } else if b < a {
+                    a - 1
+                } else {
+                    a
+                }
+            };
+            let pt = CanvasPoint::new(step(end.x, next.x), step(end.y, next.y));
+
+            for (over, o) in layout.edges.iter().enumerate() {
+                let shared = [o.src, o.dst].iter().any(|p| [e.src, e.dst].contains(p));
+                if over != under && !shared && routes[over].contains(&pt) {
+                    add(over, under, CollisionKind::LineOverEnd, pt);
+                }
+            }
+        }
+    }
+
+    found
+        .into_iter()
+        .map(|((over, under, kind), point)| Collision {
+            over,
+            under,
+            kind,
+            point,
+        })
+        .collect()
+}
+
+/// An edge routed the long way around its blocks, see `detours`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Detour {
+    /// index of the edge.
+    pub edge: usize,
+
+    /// length of the route of the edge.
+    pub length: usize,
+
+    /// Manhattan distance between the borders of the blocks of the edge.
+    pub distance: usize,
+}
+
+/// Find the edges whose route is more than `factor` times longer than the Manhattan distance
+/// between their blocks, at least 1, that is the edges the router had to take a long way around.
+/// Moving their blocks closer or increasing the margins usually gives them a shorter route.
+/// Edges left out with `RouteFailure::Skip` and edges from a block to itself are never detours.
+pub fn detours(layout: &Layout, factor: usize) -> Vec<Detour> {
+    let gap = |a0: usize, a1: usize, b0: usize, b1: usize| {
+        b0.saturating_sub(a1).max(a0.saturating_sub(b1))
+    };
+
+    let mut detours = vec![];
+    for (i, e) in layout.edges.iter().enumerate() {
+        if e.from == e.to || e.points.is_empty() {
+            continue;
+        }
+
+        let (a, b) = (layout.blocks[e.from], layout.blocks[e.to]);
+        let distance = gap(a.x, a.x + a.width - 1, b.x, b.x + b.width - 1)
+            + gap(a.y, a.y + a.height - 1, b.y, b.y + b.height - 1);
+        let length = e
+            .points
+            .windows(2)
+            .map(|w| w[0].x.abs_diff(w[1].x) + w[0].y.abs_diff(w[1].y))
+            .sum::<usize>();
+        if length > factor * distance.max(1) {
+            detours.push(Detour {
+                edge: i,
+                length,
+                distance,
+            });
+        }
+    }
+    detours
+}
+
+/// Find the cells of the blocks of a rendered diagram that are different from the same blocks
+/// rendered without any edge, like text or borders drawn over by lines, other than the points
+/// where edges are attached to them. `blocks_only` must be rendered with the same blocks and
+/// options as `rows`, whose `layout` is given.
+pub fn overwritten_cells(
+    rows: &[Vec<u8>],
+    blocks_only: &[Vec<u8>],
+    layout: &Layout,
+) -> Vec<CanvasPoint> {
+    let attachments = attachment_points(layout);
+    let at = |rows: &[Vec<u8>], pt: CanvasPoint| rows.get(pt.y).and_then(|r| r.get(pt.x)).copied();
+
+    let mut cells = vec![];
+    for (b, r) in layout.blocks.iter().enumerate() {
+        for y in r.y..r.y + r.height {
+            for x in r.x..r.x + r.width {
+                let pt = CanvasPoint::new(x, y);
+                if !attachments.contains(&(b, pt)) && at(rows, pt) != at(blocks_only, pt) {
+                    cells.push(pt);
+                }
+            }
+        }
+    }
+    cells.sort_by_key(|pt| (pt.y, pt.x));
+    cells.dedup();
+    cells
+}
+
+/// The points where edges are attached to blocks, alongside the index of the block.
+fn attachment_points(layout: &Layout) -> HashSet<(usize, CanvasPoint)> {
+    layout
+        .edges
+        .iter()
+        .flat_map(|e| [(e.from, e.src), (e.to, e.dst)])
+        .filter(|&(b, pt)| layout.blocks.get(b).is_some_and(|r| r.on_border(pt)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::render::rng::Rng;
+    use crate::render::{render_layout, render_with_layout, RenderOptions};
+    use crate::{Block, Edge};
+
+    /// A random grid of blocks with random edges between them.
+    fn random_diagram(rng: &mut Rng) -> (Vec<Block>, Vec<Edge>, RenderOptions) {
+        let (rows, columns) = (rng.gen_range(1..5) as i32, rng.gen_range(1..5) as i32);
+
+        let mut blocks = vec![];
+        for r in 0..rows {
+            for c in 0..columns {
+                if rng.gen_range(0..4) == 0 {
+                    continue;
+                }
+                let text = (0..rng.gen_range(1..3))
+                    .map(|_| "x".repeat(rng.gen_range(1..7)))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                blocks.push(Block::from_str((r, c), &text));
+            }
+        }
+        if blocks.len() < 2 {
+            blocks.push(Block::from_str((rows, 0), "a"));
+            blocks.push(Block::from_str((rows, 1), "b"));
+        }
+
+        let mut edges = vec![];
+        for _ in 0..rng.gen_range(0..8) {
+            let from = rng.gen_range(0..blocks.len());
+            let to = rng.gen_range(0..blocks.len());
+            if from == to || edges.iter().any(|e: &Edge| (e.from, e.to) == (from, to)) {
+                continue;
+            }
+
+            let mut e = Edge::new(from, to).with_directed(rng.gen_bool());
+            if rng.gen_range(0..3) == 0 {
+                e = e.with_to_label("l");
+            }
+            edges.push(e);
+        }
+
+        let config = RenderOptions {
+            hmargin: rng.gen_range(2..7),
+            vmargin: rng.gen_range(1..4),
+            padding: rng.gen_range(0..2),
+            seed: Some(rng.next_u64()),
+            max_tweaks: rng.gen_range(0..4),
+            ..RenderOptions::default()
+        };
+
+        (blocks, edges, config)
+    }
+
+    #[test]
+    fn test_edges_never_cross_blocks() {
+        for seed in 0..100 {
+            let mut rng = Rng::seed_from_u64(seed);
+            let (blocks, edges, config) = random_diagram(&mut rng);
+
+            let layout = render_layout(&blocks, edges.clone(), config.clone());
+            let rows = render_with_layout(&blocks, edges, &layout, config.clone()).unwrap();
+            let blocks_only = render_layout(&blocks, Vec::<Edge>::new(), config.clone());
+            let blocks_only =
+                render_with_layout(&blocks, Vec::<Edge>::new(), &blocks_only, config).unwrap();
+
+            assert_eq!(edge_intrusions(&layout), vec![], "seed {}", seed);
+            assert_eq!(
+                overwritten_cells(&rows, &blocks_only, &layout),
+                vec![],
+                "seed {}",
+                seed
+            );
+        }
+    }
+
+    #[test]
+    fn test_intrusions() {
+        let blocks = [
+            Block::from_str((0, 0), "a"),
+            Block::from_str((0, 1), "b"),
+            Block::from_str((0, 2), "c"),
+        ];
+        let config = RenderOptions {
+            hmargin: 2,
+            vmargin: 1,
+            padding: 0,
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        };
+        let mut layout = render_layout(&blocks, [(0, 2)], config);
+        assert_eq!(edge_intrusions(&layout), vec![]);
+
+        // an edge straight through b
+        let (a, c) = (layout.blocks[0], layout.blocks[2]);
+        let y = a.y + a.height / 2;
+        let e = &mut layout.edges[0];
+        e.src = CanvasPoint::new(a.x + a.width - 1, y);
+        e.dst = CanvasPoint::new(c.x, y);
+        e.points = vec![e.src, e.dst];
+
+        let b = layout.blocks[1];
+        let intrusions = edge_intrusions(&layout);
+        assert_eq!(intrusions.len(), b.width);
+        assert!(intrusions.iter().all(|i| i.edge == 0 && i.block == 1));
+        assert_eq!(intrusions[0].point, CanvasPoint::new(b.x, y));
+    }
+
+    #[test]
+    fn test_detours() {
+        let blocks = [Block::from_str((0, 0), "a"), Block::from_str((0, 1), "b")];
+        let config = RenderOptions {
+            hmargin: 2,
+            vmargin: 1,
+            padding: 0,
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        };
+        let mut layout = render_layout(&blocks, [(0, 1)], config);
+        assert_eq!(detours(&layout, 3), vec![]);
+
+        // a route going 10 rows down and back up between blocks 3 columns apart
+        let e = &mut layout.edges[0];
+        let (src, dst) = (e.src, e.dst);
+        e.points = vec![
+            src,
+            CanvasPoint::new(src.x, src.y + 10),
+            CanvasPoint::new(dst.x, src.y + 10),
+            dst,
+        ];
+        let length = 20 + dst.x - src.x;
+        assert_eq!(
+            detours(&layout, 3),
+            vec![Detour {
+                edge: 0,
+                length,
+                distance: 3,
+            }]
+        );
+        assert_eq!(detours(&layout, length / 2), vec![]);
+    }
+
+    #[test]
+    fn test_collisions() {
+        let blocks = [
+            Block::from_str((0, 0), "a"),
+            Block::from_str((0, 1), "b"),
+            Block::from_str((1, 0), "c"),
+            Block::from_str((1, 1), "d"),
+        ];
+        let edges = [Edge::new(0, 1).with_to_label("label"), Edge::new(2, 3)];
+        let config = RenderOptions {
+            hmargin: 8,
+            padding: 0,
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        };
+        let mut layout = render_layout(&blocks, edges, config);
+        assert_eq!(collisions(&layout), vec![]);
+
+        // the label of the first edge over the line of the second one
+        let pt = layout.edges[1].points[0];
+        let start = CanvasPoint::new(pt.x + 1, pt.y);
+        layout.edges[0].labels[0].0 = start;
+        assert_eq!(
+            collisions(&layout),
+            vec![Collision {
+                over: 0,
+                under: 1,
+                kind: CollisionKind::LabelOverLine,
+                point: start,
+            }]
+        );
+
+        // the second edge running across the first one right next to a
+        let e = &layout.edges[0];
+        let end = CanvasPoint::new(e.src.x + 1, e.src.y);
+        layout.edges[0].labels.clear();
+        layout.edges[1].points = vec![
+            CanvasPoint::new(end.x, end.y - 2),
+            CanvasPoint::new(end.x, end.y + 2),
+        ];
+        assert_eq!(
+            collisions(&layout),
+            vec![Collision {
+                over: 1,
+                under: 0,
+                kind: CollisionKind::LineOverEnd,
+                point: end,
+            }]
+        );
+    }
+}
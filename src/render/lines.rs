@@ -1,18 +1,33 @@
 use std::cmp::{Ord, Ordering, PartialOrd};
-use std::collections::{BinaryHeap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashSet};
+use std::convert::TryFrom;
 use std::ops::Add;
+use std::time::Instant;
 
-use rand::prelude::*;
-use rand::seq::SliceRandom;
-use rand_xoshiro::Xoshiro256PlusPlus;
-
-use crate::render::canvas::{Canvas, CanvasPoint};
+use crate::render::canvas::{Canvas, CanvasPoint, CanvasRect};
 use crate::render::canvas_space::CanvasSpace;
-use crate::{Block, LogicalPoint, RenderOptions};
+use crate::render::geometry::polyline;
+use crate::render::rng::Rng;
+use crate::render::symmetry::Symmetry;
+use crate::render::{Attachment, RouteFailure};
+use crate::{Block, Edge, LogicalPos, RenderOptions};
 
 /// A collection of `Line`s.
 pub type Polyline = Vec<Line>;
 
+/// The result of connecting an `Edge`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub polyline: Polyline,
+
+    /// points where the edge is attached to the source and destination blocks respectively.
+    pub src: CanvasPoint,
+    pub dst: CanvasPoint,
+
+    /// labels of the edge alongside the point where they start.
+    pub labels: Vec<(CanvasPoint, Vec<u8>)>,
+}
+
 /// A `Line` is a segment of a `Polyline` and it's the basic block to draw lines.
 ///
 /// Note that only vertical or horizontal lines are supported.
@@ -24,9 +39,20 @@ pub enum Line {
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 struct Score {
+    // edges that couldn't be routed, see `RouteFailure`.
+    failures: usize,
+
     path_len: usize,
     intersections: usize,
     turns: usize,
+
+    // tie-breakers between paths that are otherwise equally good, so that symmetric diagrams get
+    // symmetric lines. `off_center` is how far the cells are from the center of the channel they
+    // run in, `hugging` the number of cells right next to a block and `turns_distance` how far
+    // from the start the turns are, the earlier the better.
+    off_center: usize,
+    hugging: usize,
+    turns_distance: usize,
 }
 
 /// Try to find the shortest paths that minimize intersections between edges, but that still
@@ -35,17 +61,41 @@ pub fn find_edges(
     canvas: &Canvas,
     cs: &CanvasSpace,
     blocks: &[Block],
-    edges: impl IntoIterator<Item = (usize, usize)>,
+    edges: &[Edge],
     cfg: &RenderOptions,
-) -> Vec<Polyline> {
+) -> Vec<Route> {
+    // the text of the blocks, that edges forced over blocks never run over, see `forced_path`
+    let text = (0..blocks.len())
+        .filter_map(|i| text_rect(canvas, cs.block_rect(blocks, i)))
+        .collect::<Vec<_>>();
+
     // convert whatever is on the canvas to walls, lines are not considered walls as other lines
     // can pass on other lines but can never pass inside a block
     let mut canvas = canvas.clone();
-    for row in &mut canvas.canvas {
-        for c in row {
-            if *c != b' ' {
-                *c = b'#';
-            }
+    let non_empty = canvas
+        .iter_region(0, 0, canvas.width(), canvas.height())
+        .filter(|(_, c)| *c != b' ')
+        .map(|(pt, _)| pt)
+        .collect::<Vec<_>>();
+    for pt in non_empty {
+        canvas.set(pt, b'#');
+    }
+
+    // the empty space inside blocks must be a wall too otherwise lines attached to a block could
+    // go through it
+    for b in blocks {
+        let x = cs.column_x(b.pos.col);
+        let y = cs.row_y(b.pos.row);
+        let w = cs.column_width(b.pos.col);
+        let h = cs.row_height(b.pos.row);
+        canvas.fill_rect(x, y, w, h, b'#');
+    }
+
+    // shadows are in the margins, lines avoid them but they can still go through them to reach
+    // their blocks
+    for i in 0..blocks.len() {
+        for pt in cs.shadow(blocks, i) {
+            canvas.set(pt, b'@');
         }
     }
 
@@ -53,70 +103,151 @@ pub fn find_edges(
     // around the borders of the blocks to avoid passing through them if possible
     if cs.render_cfg().hmargin > 2 {
         for b in blocks {
-            let x = cs.column_x(b.column);
-            let y = cs.row_y(b.row);
-            let w = cs.column_width(b.column);
-            let h = cs.row_height(b.row);
-            for yy in 0..h {
-                canvas.canvas[y + yy][x - 1] = b'@';
-                canvas.canvas[y + yy][x + w] = b'@';
-            }
+            let x = cs.column_x(b.pos.col);
+            let y = cs.row_y(b.pos.row);
+            let w = cs.column_width(b.pos.col);
+            let h = cs.row_height(b.pos.row);
+            canvas.fill_rect(x - 1, y, 1, h, b'@');
+            canvas.fill_rect(x + w, y, 1, h, b'@');
         }
     }
     if cs.render_cfg().vmargin > 2 {
         for b in blocks {
-            let x = cs.column_x(b.column);
-            let y = cs.row_y(b.row);
-            let w = cs.column_width(b.column);
-            let h = cs.row_height(b.row);
-            for xx in 0..w {
-                canvas.canvas[y - 1][x + xx] = b'@';
-                canvas.canvas[y + h][x + xx] = b'@';
+            let x = cs.column_x(b.pos.col);
+            let y = cs.row_y(b.pos.row);
+            let w = cs.column_width(b.pos.col);
+            let h = cs.row_height(b.pos.row);
+            canvas.fill_rect(x, y - 1, w, 1, b'@');
+            canvas.fill_rect(x, y + h, w, 1, b'@');
+        }
+    }
+
+    // edges with a route of their own are drawn first, the others are routed around them
+    let mut fixed_routes = vec![];
+    for (e, edge) in edges.iter().enumerate() {
+        if let Some(polyline) = fixed_polyline(cs, &canvas, blocks, edge) {
+            for l in &polyline {
+                l.draw(&mut canvas);
             }
+            let (src, dst) = (edge.route[0], edge.route[edge.route.len() - 1]);
+            let labels = place_labels(cs, &mut canvas, blocks, edge, (src, dst));
+            fixed_routes.push((
+                e,
+                Route {
+                    polyline,
+                    src,
+                    dst,
+                    labels,
+                },
+            ));
         }
     }
 
-    let mut rng = match cfg.seed {
-        Some(seed) => Xoshiro256PlusPlus::seed_from_u64(seed),
-        None => Xoshiro256PlusPlus::from_entropy(),
+    // edges between blocks sharing a border are drawn as a junction on it, see `junction_point`
+    for (e, edge) in edges.iter().enumerate() {
+        if fixed_routes.iter().any(|(f, _)| *f == e) {
+            continue;
+        }
+        if let Some(pt) = junction_point(cs, blocks, edge) {
+            canvas.set(pt, b'+');
+            let labels = place_labels(cs, &mut canvas, blocks, edge, (pt, pt));
+            fixed_routes.push((
+                e,
+                Route {
+                    polyline: vec![Line::Vertical(pt.x, (pt.y, pt.y))],
+                    src: pt,
+                    dst: pt,
+                    labels,
+                },
+            ));
+        }
+    }
+    let routed = |e: &usize| !fixed_routes.iter().any(|(f, _)| f == e);
+
+    let mut rng = Rng::new(cfg.seed);
+    let symmetry = if cfg.symmetric {
+        Symmetry::new(cs, blocks, edges)
+    } else {
+        None
     };
 
     // sort edges by length in order to place the shortest edges first as we have less chance to
     // get them wrong (especially if they're between adjacent blocks)
-    let edge_len = |(b0, b1): &(usize, usize)| {
-        let b0 = &blocks[*b0];
-        let b1 = &blocks[*b1];
-        (b0.column - b1.column).abs() + (b0.row - b1.row).abs()
+    let edge_len = |e: &usize| {
+        let b0 = &blocks[edges[*e].from];
+        let b1 = &blocks[edges[*e].to];
+        b0.pos.manhattan_distance(b1.pos)
     };
-    let (short_edges, mut edges) = edges
-        .into_iter()
+
+    // note: routing must only depend on the input and the seed, both partitioning and sorting are
+    // stable so edges of the same length are always placed in the order they're given.
+    let (short_edges, mut long_edges) = (0..edges.len())
+        .filter(routed)
         .partition::<Vec<_>, _>(|e| edge_len(e) == 1);
 
-    edges.sort_by_key(edge_len);
+    long_edges.sort_by_key(edge_len);
 
     // tweaks do not apply to edges with length 1 because in those cases the simple solution is
     // always the preferred one.
-    let (_score, mut short_poly) = connect_edges(cs, &mut canvas, blocks, &short_edges, &mut rng);
+    let (_score, short_routes) = connect_edges(
+        cs,
+        &mut canvas,
+        blocks,
+        edges,
+        &short_edges,
+        &text,
+        symmetry.as_ref(),
+        &mut rng,
+    );
 
-    let (mut best_score, mut poly) =
-        connect_edges(cs, &mut canvas.clone(), blocks, &edges, &mut rng);
+    let (mut best_score, mut long_routes) = connect_edges(
+        cs,
+        &mut canvas.clone(),
+        blocks,
+        edges,
+        &long_edges,
+        &text,
+        symmetry.as_ref(),
+        &mut rng,
+    );
+    let deadline = cfg
+        .max_tweak_time
+        .and_then(|t| Instant::now().checked_add(t));
     for _ in 0..cfg.max_tweaks {
-        if best_score.intersections == 0 {
+        if best_score.intersections == 0 && best_score.failures == 0 {
+            break;
+        }
+        if deadline.is_some_and(|d| Instant::now() >= d) {
             break;
         }
 
         // tweak the current solution by shuffling the order of the edges hoping to find a better
         // solution
-        edges.shuffle(&mut rng);
-        let (s, p) = connect_edges(cs, &mut canvas.clone(), blocks, &edges, &mut rng);
+        rng.shuffle(&mut long_edges);
+        let (s, r) = connect_edges(
+            cs,
+            &mut canvas.clone(),
+            blocks,
+            edges,
+            &long_edges,
+            &text,
+            symmetry.as_ref(),
+            &mut rng,
+        );
         if s < best_score {
             best_score = s;
-            poly = p;
+            long_routes = r;
         }
     }
 
-    short_poly.extend_from_slice(&poly);
-    short_poly
+    // return the routes in the same order as the edges
+    let mut routes = fixed_routes
+        .into_iter()
+        .chain(short_routes)
+        .chain(long_routes)
+        .collect::<Vec<_>>();
+    routes.sort_by_key(|(e, _)| *e);
+    routes.into_iter().map(|(_, r)| r).collect()
 }
 
 /// Try to find the best paths that connect `blocks` according to `edges`.
@@ -124,32 +255,57 @@ pub fn find_edges(
 /// A path is considered "better" than another one if it creates fewer intersections with other
 /// lines, has less turns and it's shorter.
 ///
-/// The order of `edges` matters as it's totally possible that edges sorted in a given way generate
-/// intersections while sorted in another way they do not. This function blindly follows the order
-/// of `edges` and does not try to avoid intersections this way.
+/// Only the edges whose indices are in `order` are connected, following that order. The order
+/// matters as it's totally possible that edges sorted in a given way generate intersections while
+/// sorted in another way they do not. This function blindly follows `order` and does not try to
+/// avoid intersections this way.
+///
+/// If there's a `symmetry` then the edges whose image was already connected without
+/// intersections are connected by reflecting the path of the image, when that's possible.
+///
+/// `text` is where the text of the blocks is, see `forced_path`.
+///
+/// Returns the routes alongside the index of their edge.
+#[allow(clippy::too_many_arguments)]
 fn connect_edges(
     cs: &CanvasSpace,
     canvas: &mut Canvas,
     blocks: &[Block],
-    edges: &[(usize, usize)],
-    rng: &mut Xoshiro256PlusPlus,
-) -> (Score, Vec<Polyline>) {
-    let mut polylines = Vec::with_capacity(edges.len());
+    edges: &[Edge],
+    order: &[usize],
+    text: &[CanvasRect],
+    symmetry: Option<&Symmetry>,
+    rng: &mut Rng,
+) -> (Score, Vec<(usize, Route)>) {
+    let mut routes = Vec::with_capacity(order.len());
     let mut score = Score::new();
+    let mut paths: Vec<Option<(Score, Polyline, _)>> = vec![None; edges.len()];
 
-    for (b0, b1) in edges {
-        let b0 = &blocks[*b0];
-        let b1 = &blocks[*b1];
+    // points of the lines of the edges in each group, see `Edge::with_group`
+    let mut groups = BTreeMap::<&[u8], BTreeSet<CanvasPoint>>::new();
+    let no_group = BTreeSet::new();
+
+    for &e in order {
+        let edge = &edges[e];
+        let b0 = &blocks[edge.from];
+        let b1 = &blocks[edge.to];
+
+        let shared = if edge.group.is_empty() {
+            &no_group
+        } else {
+            groups.get(&edge.group[..]).unwrap_or(&no_group)
+        };
 
         // try to get the closest points on the given blocks, but if those are already occupied
-        // then connect random points on the boundaries
+        // then connect random points on the boundaries. Points occupied by edges in the same
+        // group are fine, they can be shared too.
         let mut get_points_on_block = |s, d| {
             let (mut a, mut b) = closest_points_on_blocks(cs, s, d);
-            if canvas.canvas[a.1][a.0] == b'+' {
+            if canvas.at(a) == b'+' && !shared.contains(&a) {
                 a = get_random_point_on_block(cs, s, rng);
             }
 
-            if canvas.canvas[b.1][b.0] == b'+' {
+            if canvas.at(b) == b'+' && !shared.contains(&b) {
                 b = get_random_point_on_block(cs, d, rng);
             }
 
@@ -157,80 +313,386 @@ fn connect_edges(
         };
 
         // try to connect the edge from src to dst and viceversa because the connection points
-        // might be different in case the edge is not straight.
-        let (p0, p1) = get_points_on_block((b0.row, b0.column), (b1.row, b1.column));
-        let (q0, q1) = get_points_on_block((b1.row, b1.column), (b0.row, b0.column));
+        // might be different in case the edge is not straight. The ends of the route of the edge
+        // are preferred if it has one that couldn't be used as is, see `Edge::with_route`.
+        let ends = route_ends(cs, canvas, blocks, edge, shared);
+        let (p0, p1) = ends.unwrap_or_else(|| get_points_on_block(b0.pos, b1.pos));
+        let (q0, q1) = match ends {
+            Some((a, b)) => (b, a),
+            None => get_points_on_block(b1.pos, b0.pos),
+        };
         let has_alternative = p0 != q1 || p1 != q0;
 
         // always prefer paths that do not create intersections because the final diagram is
         // easier to follow given that we need to just follow the lines.
-        let path = [false, true]
-            .iter()
-            .filter_map(|&allow_intersections| {
-                let path = shortest_path(cs, canvas, p0, p1, allow_intersections);
-                if !has_alternative {
-                    return path;
-                }
+        let search = || {
+            [false, true]
+                .iter()
+                .filter_map(|&allow_intersections| {
+                    let path = shortest_path(canvas, p0, p1, shared, allow_intersections);
+                    if !has_alternative {
+                        return path;
+                    }
 
-                let inv = shortest_path(cs, canvas, q0, q1, allow_intersections);
+                    let inv = shortest_path(canvas, q0, q1, shared, allow_intersections);
+
+                    // how centered the paths are is not comparable when they go through different
+                    // channels
+                    match (path, inv) {
+                        (Some(p), Some(q)) => {
+                            Some(if p.0.without_off_center() <= q.0.without_off_center() {
+                                p
+                            } else {
+                                inv_endpoints(q)
+                            })
+                        }
+                        (Some(p), _) => Some(p),
+                        (_, Some(q)) => Some(inv_endpoints(q)),
+                        (None, None) => None,
+                    }
+                })
+                .next()
+        };
 
-                match (path, inv) {
-                    (Some(p), Some(q)) => Some(if p.0 <= q.0 { p } else { q }),
-                    (Some(p), _) => Some(p),
-                    (_, Some(q)) => Some(q),
-                    (None, None) => None,
-                }
-            })
-            .next();
+        let reflected = symmetry.and_then(|sym| {
+            let (image, reversed) = sym.edge_image(e)?;
+            let (s, polyline, (src, dst)) = paths[image].as_ref()?;
+            reflect_path(sym, canvas, s, polyline, (*src, *dst), reversed)
+        });
 
-        match path {
-            Some((s, path)) => {
-                for l in &path {
+        // the edge can't be routed without running over a block, see `RouteFailure`
+        let (s, polyline, (src, dst)) = match reflected.or_else(search) {
+            Some(p) => p,
+            None => {
+                let mut s = Score::new();
+                s.failures = 1;
+                score = score + s;
+
+                let size = (canvas.width(), canvas.height());
+                let polyline = match cs.render_cfg().route_failure {
+                    RouteFailure::Force => forced_path(canvas, text, p0, p1)
+                        .or_else(|| polyline(&[p0, CanvasPoint::new(p1.x, p0.y), p1], size))
+                        .unwrap_or_default(),
+                    RouteFailure::Error | RouteFailure::Skip => vec![],
+                };
+                for l in &polyline {
                     l.draw(canvas);
                 }
-                polylines.push(path);
-                score = score + s;
-            }
-            None => {
-                unreachable!("no free path even with intersections enabled?");
+                let labels = if polyline.is_empty() {
+                    vec![]
+                } else {
+                    place_labels(cs, canvas, blocks, edge, (p0, p1))
+                };
+
+                routes.push((
+                    e,
+                    Route {
+                        polyline,
+                        src: p0,
+                        dst: p1,
+                        labels,
+                    },
+                ));
+                continue;
             }
+        };
+
+        for l in &polyline {
+            l.draw(canvas);
+        }
+        score = score + s.clone();
+        if !edge.group.is_empty() {
+            groups
+                .entry(&edge.group)
+                .or_default()
+                .extend(polyline.iter().flat_map(Line::points));
         }
+        paths[e] = Some((s, polyline.clone(), (src, dst)));
+
+        // labels are placed right after the edge has been drawn so that the following edges can
+        // avoid them
+        let labels = place_labels(cs, canvas, blocks, edge, (src, dst));
+
+        routes.push((
+            e,
+            Route {
+                polyline,
+                src,
+                dst,
+                labels,
+            },
+        ));
     }
 
-    (score, polylines)
+    (score, routes)
+}
+
+/// Find where to draw the labels of `edge` attached to its blocks at `src` and `dst`, marking
+/// them on the canvas so that other lines avoid them.
+fn place_labels(
+    cs: &CanvasSpace,
+    canvas: &mut Canvas,
+    blocks: &[Block],
+    edge: &Edge,
+    (src, dst): (CanvasPoint, CanvasPoint),
+) -> Vec<(CanvasPoint, Vec<u8>)> {
+    let (b0, b1) = (&blocks[edge.from], &blocks[edge.to]);
+
+    let mut labels = vec![];
+    for (b, pt, label) in [(b0, src, &edge.from_label), (b1, dst, &edge.to_label)] {
+        if label.is_empty() {
+            continue;
+        }
+
+        if let Some(lpt) = find_label_point(cs, canvas, b, pt, label.len()) {
+            canvas.fill_rect(lpt.x, lpt.y, label.len(), 1, b'#');
+            labels.push((lpt, label.clone()));
+        }
+    }
+    labels
+}
+
+/// The polyline of the route of `edge` given with `Edge::with_route`, if it still starts and
+/// ends on the borders of its blocks without going through any wall of `canvas`.
+fn fixed_polyline(
+    cs: &CanvasSpace,
+    canvas: &Canvas,
+    blocks: &[Block],
+    edge: &Edge,
+) -> Option<Polyline> {
+    let (&src, &dst) = (edge.route.first()?, edge.route.last()?);
+    let polyline = polyline(&edge.route, (canvas.width(), canvas.height()))?;
+    if polyline.is_empty()
+        || !cs.block_rect(blocks, edge.from).on_border(src)
+        || !cs.block_rect(blocks, edge.to).on_border(dst)
+    {
+        return None;
+    }
+
+    let free = polyline
+        .iter()
+        .flat_map(Line::points)
+        .all(|pt| pt == src || pt == dst || canvas.at(pt) != b'#');
+    Some(polyline).filter(|_| free)
+}
+
+/// The middle of the border shared by the blocks of `edge`, borders excluded, if they share one
+/// because there's no margin between them. The edge is drawn as a `+` there, without any line.
+fn junction_point(cs: &CanvasSpace, blocks: &[Block], edge: &Edge) -> Option<CanvasPoint> {
+    // the middle of the part of the shared border where both blocks have a side, corners
+    // excluded
+    let middle = |a0: usize, a1: usize, b0: usize, b1: usize| {
+        let (lo, hi) = (a0.max(b0) + 1, (a0 + a1).min(b0 + b1) - 1);
+        (lo < hi).then(|| lo + (hi - lo) / 2)
+    };
+    let shared = |a: CanvasRect, b: CanvasRect| {
+        if a.x + a.width - 1 == b.x {
+            middle(a.y, a.height, b.y, b.height).map(|y| CanvasPoint::new(b.x, y))
+        } else if a.y + a.height - 1 == b.y {
+            middle(a.x, a.width, b.x, b.width).map(|x| CanvasPoint::new(x, b.y))
+        } else {
+            None
+        }
+    };
+
+    let (a, b) = (
+        cs.block_rect(blocks, edge.from),
+        cs.block_rect(blocks, edge.to),
+    );
+    shared(a, b).or_else(|| shared(b, a))
+}
+
+/// The ends of the route of `edge` given with `Edge::with_route`, if they're still on the borders
+/// of its blocks and no other edge is attached there, unless it's in the same group.
+fn route_ends(
+    cs: &CanvasSpace,
+    canvas: &Canvas,
+    blocks: &[Block],
+    edge: &Edge,
+    shared: &BTreeSet<CanvasPoint>,
+) -> Option<(CanvasPoint, CanvasPoint)> {
+    let (&src, &dst) = (edge.route.first()?, edge.route.last()?);
+    let free = |pt: CanvasPoint| canvas.at(pt) != b'+' || shared.contains(&pt);
+
+    let usable = src != dst
+        && cs.block_rect(blocks, edge.from).on_border(src)
+        && cs.block_rect(blocks, edge.to).on_border(dst)
+        && free(src)
+        && free(dst);
+    Some((src, dst)).filter(|_| usable)
+}
+
+/// Reflect the path of the image of an edge, see `connect_edges`. It's `None` if the image has
+/// intersections or if the reflected path isn't free, as `shortest_path` would have it without
+/// intersections.
+fn reflect_path(
+    symmetry: &Symmetry,
+    canvas: &Canvas,
+    score: &Score,
+    polyline: &[Line],
+    (src, dst): (CanvasPoint, CanvasPoint),
+    reversed: bool,
+) -> Option<(Score, Polyline, (CanvasPoint, CanvasPoint))> {
+    if score.intersections > 0 {
+        return None;
+    }
+
+    let (mut src, mut dst) = (symmetry.reflect_point(src), symmetry.reflect_point(dst));
+    if reversed {
+        std::mem::swap(&mut src, &mut dst);
+    }
+
+    // attachment points already taken by other edges are not reused
+    if canvas.at(src) == b'+' || canvas.at(dst) == b'+' {
+        return None;
+    }
+
+    let polyline = symmetry.reflect_polyline(polyline, reversed);
+    let near_ends = |pt: CanvasPoint| {
+        pt.x.abs_diff(src.x) + pt.y.abs_diff(src.y) <= 1
+            || pt.x.abs_diff(dst.x) + pt.y.abs_diff(dst.y) <= 1
+    };
+    let free = polyline.iter().all(|l| {
+        let (a, b) = l.ends();
+        l.interior()
+            .into_iter()
+            .chain([a, b])
+            .filter(|&pt| pt != src && pt != dst)
+            .all(|pt| match canvas.at(pt) {
+                b' ' => true,
+                b'@' => near_ends(pt),
+                _ => false,
+            })
+    });
+
+    if free {
+        Some((score.clone(), polyline, (src, dst)))
+    } else {
+        None
+    }
+}
+
+/// Swap the endpoints of a path found by `shortest_path` to go from the destination to the source.
+fn inv_endpoints<T>(
+    (s, p, (a, b)): (Score, T, (CanvasPoint, CanvasPoint)),
+) -> (Score, T, (CanvasPoint, CanvasPoint)) {
+    (s, p, (b, a))
+}
+
+/// Find where to place a label of length `len` close to the point `pt` where an edge is attached
+/// to the given block. The label is placed on either side of the edge right outside the block,
+/// only in empty space.
+fn find_label_point(
+    cs: &CanvasSpace,
+    canvas: &Canvas,
+    b: &Block,
+    CanvasPoint { x: px, y: py }: CanvasPoint,
+    len: usize,
+) -> Option<CanvasPoint> {
+    let x = cs.column_x(b.pos.col);
+    let y = cs.row_y(b.pos.row);
+    let h = cs.row_height(b.pos.row);
+
+    // candidates are expressed as (x, y) offsets from the attachment point, they can be negative
+    // hence the isize.
+    let len = len as isize;
+    let candidates = if py == y {
+        [(1, -1), (-len, -1), (1, -2), (-len, -2)]
+    } else if py == y + h - 1 {
+        [(1, 1), (-len, 1), (1, 2), (-len, 2)]
+    } else if px == x {
+        [(-len, -1), (-len, 1), (-len - 1, -1), (-len - 1, 1)]
+    } else {
+        [(1, -1), (1, 1), (2, -1), (2, 1)]
+    };
+
+    candidates.iter().find_map(|&(dx, dy)| {
+        let lx = usize::try_from(px as isize + dx).ok()?;
+        let ly = usize::try_from(py as isize + dy).ok()?;
+        if ly >= canvas.height() || lx + len as usize > canvas.width() {
+            return None;
+        }
+
+        // '@' is the padding placed around blocks, labels are fine there
+        let free = (lx..lx + len as usize)
+            .all(|xx| matches!(canvas.at(CanvasPoint::new(xx, ly)), b' ' | b'@'));
+        if free {
+            Some(CanvasPoint::new(lx, ly))
+        } else {
+            None
+        }
+    })
 }
 
 /// Get a random point on the boundary of a given block.
 fn get_random_point_on_block(
     cs: &CanvasSpace,
-    (r, c): LogicalPoint,
-    rng: &mut Xoshiro256PlusPlus,
+    LogicalPos { row: r, col: c }: LogicalPos,
+    rng: &mut Rng,
 ) -> CanvasPoint {
-    if rng.gen() {
-        (
+    if rng.gen_bool() {
+        CanvasPoint::new(
             cs.column_x(c) + 1 + rng.gen_range(0..cs.column_width(c) - 2),
-            cs.row_y(r) + 1 + rng.gen_range(0..2) * (cs.row_height(r) - 2),
+            cs.row_y(r) + rng.gen_range(0..2) * (cs.row_height(r) - 1),
         )
     } else {
-        (
-            cs.column_x(c) + 1 + rng.gen_range(0..2) * (cs.column_width(c) - 2),
+        CanvasPoint::new(
+            cs.column_x(c) + rng.gen_range(0..2) * (cs.column_width(c) - 1),
             cs.row_y(r) + 1 + rng.gen_range(0..cs.row_height(r) - 2),
         )
     }
 }
 
-/// Return the best candidate points to connect the given two `LogicalPoint`s.
+/// Return the best candidate points to connect the given two `LogicalPos`s.
 ///
 /// Note that this function is not commutative (that is the order of the input points matters)
 /// because changing the order can result in different points that are equally good though. This
 /// can be used to easily explore different solutions.
 fn closest_points_on_blocks(
     cs: &CanvasSpace,
-    (r0, c0): LogicalPoint,
-    (r1, c1): LogicalPoint,
+    p0: LogicalPos,
+    p1: LogicalPos,
+) -> (CanvasPoint, CanvasPoint) {
+    let (src, dst) = ideal_points_on_blocks(cs, p0, p1);
+    (keep_off_corners(cs, p0, src), keep_off_corners(cs, p1, dst))
+}
+
+/// Move the given point on the border of the block at `(r, c)` away from its corners because
+/// lines that end on a corner or right next to it look broken.
+///
+/// Blocks that are too small do not have a point that is not next to a corner, in that case the
+/// point is just moved off the corner itself.
+fn keep_off_corners(
+    cs: &CanvasSpace,
+    LogicalPos { row: r, col: c }: LogicalPos,
+    CanvasPoint { x, y }: CanvasPoint,
+) -> CanvasPoint {
+    let x0 = cs.column_x(c);
+    let y0 = cs.row_y(r);
+    let w = cs.column_width(c);
+    let h = cs.row_height(r);
+
+    let clamp = |v: usize, start: usize, len: usize| {
+        let m = if len >= 5 { 2 } else { 1 };
+        v.max(start + m).min(start + len - 1 - m)
+    };
+
+    if y == y0 || y == y0 + h - 1 {
+        CanvasPoint::new(clamp(x, x0, w), y)
+    } else {
+        CanvasPoint::new(x, clamp(y, y0, h))
+    }
+}
+
+/// Return the ideal points to connect the given two `LogicalPos`s, without caring about
+/// corners.
+fn ideal_points_on_blocks(
+    cs: &CanvasSpace,
+    LogicalPos { row: r0, col: c0 }: LogicalPos,
+    LogicalPos { row: r1, col: c1 }: LogicalPos,
 ) -> (CanvasPoint, CanvasPoint) {
     if r0 == r1 {
-        if between(c0, c1).any(|cc| cs.has_block_at((r0, cc))) {
+        if between(c0, c1).any(|cc| cs.has_block_at(LogicalPos::new(r0, cc))) {
             //     +-------------+
             //     |             |
             //   +-++   +--+   +-++   +--+   +--+   +--+
@@ -239,7 +701,7 @@ fn closest_points_on_blocks(
             //                          |             |
             //                          +-------------+
 
-            let src = (
+            let src = CanvasPoint::new(
                 cs.column_x(c0) + cs.column_width(c0) / 2,
                 if c0 < c1 {
                     cs.row_y(r0)
@@ -247,7 +709,7 @@ fn closest_points_on_blocks(
                     cs.row_y(r0) + cs.row_height(r0) - 1
                 },
             );
-            let dst = (cs.column_x(c1) + cs.column_width(c1) / 2, src.1);
+            let dst = CanvasPoint::new(cs.column_x(c1) + cs.column_width(c1) / 2, src.y);
 
             return (src, dst);
         }
@@ -256,22 +718,22 @@ fn closest_points_on_blocks(
         // |s0+---+d0|
         // +--+   +--+
 
-        let mut src = (0, cs.row_y(r0) + cs.row_height(r0) / 2);
-        let mut dst = (0, src.1);
+        let mut src = CanvasPoint::new(0, cs.row_y(r0) + cs.row_body_height(r0) / 2);
+        let mut dst = CanvasPoint::new(0, src.y);
 
         if c0 < c1 {
-            src.0 = cs.column_x(c0) + cs.column_width(c0) - 1;
-            dst.0 = cs.column_x(c1);
+            src.x = cs.column_x(c0) + cs.column_width(c0) - 1;
+            dst.x = cs.column_x(c1);
         } else {
-            src.0 = cs.column_x(c0);
-            dst.0 = cs.column_x(c1) + cs.column_width(c1) - 1;
+            src.x = cs.column_x(c0);
+            dst.x = cs.column_x(c1) + cs.column_width(c1) - 1;
         }
 
         return (src, dst);
     }
 
     if c0 == c1 {
-        if between(r0, r1).any(|rr| cs.has_block_at((rr, c0))) {
+        if between(r0, r1).any(|rr| cs.has_block_at(LogicalPos::new(rr, c0))) {
             //   +--+         +--+
             //   |s0+-+     +-+d1|
             //   +--+ |     | +--+
@@ -284,15 +746,15 @@ fn closest_points_on_blocks(
             //   |d0+-+     +-+s1|
             //   +--+         +--+
 
-            let src = (
+            let src = CanvasPoint::new(
                 if r0 < r1 {
                     cs.column_x(c0) + cs.column_width(c0) - 1
                 } else {
                     cs.column_x(c0)
                 },
-                cs.row_y(r0) + cs.row_height(r0) / 2,
+                cs.row_y(r0) + cs.row_body_height(r0) / 2,
             );
-            let dst = (src.0, cs.row_y(r1) + cs.row_height(r1) / 2);
+            let dst = CanvasPoint::new(src.x, cs.row_y(r1) + cs.row_body_height(r1) / 2);
 
             return (src, dst);
         }
@@ -305,15 +767,15 @@ fn closest_points_on_blocks(
         // |s0|
         // +--+
 
-        let mut src = (cs.column_x(c0) + cs.column_width(c0) / 2, 0);
-        let mut dst = (src.0, 0);
+        let mut src = CanvasPoint::new(cs.column_x(c0) + cs.column_width(c0) / 2, 0);
+        let mut dst = CanvasPoint::new(src.x, 0);
 
         if r0 < r1 {
-            src.1 = cs.row_y(r0) + cs.row_height(r0) - 1;
-            dst.1 = cs.row_y(r1);
+            src.y = cs.row_y(r0) + cs.row_height(r0) - 1;
+            dst.y = cs.row_y(r1);
         } else {
-            src.1 = cs.row_y(r0);
-            dst.1 = cs.row_y(r1) + cs.row_height(r1) - 1;
+            src.y = cs.row_y(r0);
+            dst.y = cs.row_y(r1) + cs.row_height(r1) - 1;
         }
 
         return (src, dst);
@@ -328,74 +790,155 @@ fn closest_points_on_blocks(
     //        +--+      +--+             +--+                    +--+
     //
 
-    let src = (
-        cs.column_x(c0) + cs.column_width(c0) / 2,
-        if r0 < r1 {
-            cs.row_y(r0) + cs.row_height(r0) - 1
-        } else {
-            cs.row_y(r0)
-        },
+    let vertical_first = (
+        CanvasPoint::new(
+            cs.column_x(c0) + cs.column_width(c0) / 2,
+            if r0 < r1 {
+                cs.row_y(r0) + cs.row_height(r0) - 1
+            } else {
+                cs.row_y(r0)
+            },
+        ),
+        CanvasPoint::new(
+            if c0 < c1 {
+                cs.column_x(c1)
+            } else {
+                cs.column_x(c1) + cs.column_width(c1) - 1
+            },
+            cs.row_y(r1) + cs.row_body_height(r1) / 2,
+        ),
     );
 
-    let dst = (
-        if c0 < c1 {
-            cs.column_x(c1)
-        } else {
-            cs.column_x(c1) + cs.column_width(c1) - 1
-        },
-        cs.row_y(r1) + cs.row_height(r1) / 2,
+    // +--+                    +--+
+    // |s0+----+          +----+s1|
+    // +--+    |          |    +--+
+    //         |          |
+    //       +-++        ++-+
+    //       |d0|        |d1|
+    //       +--+        +--+
+
+    let horizontal_first = (
+        CanvasPoint::new(
+            if c0 < c1 {
+                cs.column_x(c0) + cs.column_width(c0) - 1
+            } else {
+                cs.column_x(c0)
+            },
+            cs.row_y(r0) + cs.row_body_height(r0) / 2,
+        ),
+        CanvasPoint::new(
+            cs.column_x(c1) + cs.column_width(c1) / 2,
+            if r0 < r1 {
+                cs.row_y(r1)
+            } else {
+                cs.row_y(r1) + cs.row_height(r1) - 1
+            },
+        ),
     );
 
-    (src, dst)
+    match cs.render_cfg().attachment {
+        Attachment::VerticalFirst => vertical_first,
+        Attachment::HorizontalFirst => horizontal_first,
+        Attachment::Shortest => {
+            let len = |(src, dst): (CanvasPoint, CanvasPoint)| {
+                src.x.abs_diff(dst.x) + src.y.abs_diff(dst.y)
+            };
+            if len(horizontal_first) < len(vertical_first) {
+                horizontal_first
+            } else {
+                vertical_first
+            }
+        }
+    }
 }
 
 /// Find the shortest path that goes from `src` to `dst`.
 ///
 /// By shortest we mean the path that generates the fewer intersections (if allowed) and turns.
+/// Among equally short paths the ones that run far from blocks in the middle of channels and
+/// that turn early are preferred.
+///
+/// The `shared` points are the ones of the lines of the edges in the same group, they're free to
+/// go through and they do not make the path longer so that edges in the same group are drawn as a
+/// single trunk as much as possible.
 ///
-/// Returns both the path and its score.
+/// Returns the score, the path and its endpoints.
 fn shortest_path(
-    cs: &CanvasSpace,
     canvas: &Canvas,
-    src: (usize, usize),
-    dst: (usize, usize),
+    src: CanvasPoint,
+    dst: CanvasPoint,
+    shared: &BTreeSet<CanvasPoint>,
     allow_intersections: bool,
-) -> Option<(Score, Polyline)> {
+) -> Option<(Score, Polyline, (CanvasPoint, CanvasPoint))> {
     use std::cmp::Reverse;
 
+    // `seen` is only used to check membership, never iterated, so its order doesn't matter.
+    // Candidates in the queue are totally ordered by score, path and point instead. Points are
+    // seen once per heading because the turns that follow depend on it.
     let mut seen = HashSet::new();
     let mut queue = BinaryHeap::new();
     queue.push((Reverse(Score::new()), vec![], src));
 
-    while let Some((Reverse(score), path, (x, y))) = queue.pop() {
-        if (x, y) == dst {
-            return Some((score, path));
+    let (horizontal_off_center, vertical_off_center) = off_center(canvas);
+    let is_wall = |x: usize, y: usize| {
+        x < canvas.width() && y < canvas.height() && canvas.at(CanvasPoint::new(x, y)) == b'#'
+    };
+
+    while let Some((Reverse(score), path, pt)) = queue.pop() {
+        if pt == dst {
+            return Some((score, path, (src, dst)));
         }
 
-        if !seen.insert((x, y)) {
+        let heading = match path.last() {
+            Some(Line::Vertical(..)) => 1,
+            Some(Line::Horizontal(..)) => 2,
+            None => 0,
+        };
+        if !seen.insert((pt, heading)) {
             continue;
         }
 
+        let CanvasPoint { x, y } = pt;
         let mut push_node = |xx: usize, yy: usize| {
+            let next = CanvasPoint::new(xx, yy);
+
             // always allow to overwrite the point outside src and dst even if there's a line and
             // intersections are not allowed
-            let srcd = xx.max(src.0) - xx.min(src.0) + yy.max(src.1) - yy.min(src.1);
-            let dstd = xx.max(dst.0) - xx.min(dst.0) + yy.max(dst.1) - yy.min(dst.1);
+            let srcd = xx.max(src.x) - xx.min(src.x) + yy.max(src.y) - yy.min(src.y);
+            let dstd = xx.max(dst.x) - xx.min(dst.x) + yy.max(dst.y) - yy.min(dst.y);
 
-            let c = canvas.at((xx, yy));
-            if (xx, yy) == src
-                || (xx, yy) == dst
+            let c = canvas.at(next);
+            let is_shared = c != b'#' && shared.contains(&next);
+            if next == src
+                || next == dst
                 || c == b' '
+                || is_shared
                 || (c != b'#' && (srcd <= 1 || dstd <= 1))
                 || (allow_intersections && c != b'#')
             {
                 let mut new_score = score.clone();
                 let mut new_path = path.clone();
 
-                if c != b' ' && c != b'@' && (xx, yy) != src && (xx, yy) != dst {
+                if c != b' ' && c != b'@' && !is_shared && next != src && next != dst {
                     new_score.intersections += 1;
                 }
 
+                // lines right outside the endpoints always run next to their blocks
+                if srcd > 1 && dstd > 1 {
+                    let hugs = is_wall(xx.wrapping_sub(1), yy)
+                        || is_wall(xx + 1, yy)
+                        || is_wall(xx, yy.wrapping_sub(1))
+                        || is_wall(xx, yy + 1);
+                    if hugs {
+                        new_score.hugging += 1;
+                    }
+                }
+                new_score.off_center += if yy == y {
+                    vertical_off_center[yy * canvas.width() + xx]
+                } else {
+                    horizontal_off_center[yy * canvas.width() + xx]
+                };
+
                 // if the new point is on the last line then do not insert a new segment, but
                 // extend the last one
                 match new_path.last_mut() {
@@ -415,22 +958,25 @@ fn shortest_path(
                         });
 
                         new_score.turns += 1;
+                        new_score.turns_distance += score.path_len;
                     }
                 }
 
-                new_score.path_len += 1;
+                if !is_shared {
+                    new_score.path_len += 1;
+                }
 
-                queue.push((Reverse(new_score), new_path, (xx, yy)));
+                queue.push((Reverse(new_score), new_path, next));
             }
         };
 
         if x > 0 {
             push_node(x - 1, y);
         }
-        if x + 1 < cs.canvas_width() {
+        if x + 1 < canvas.width() {
             push_node(x + 1, y);
         }
-        if y + 1 < cs.canvas_height() {
+        if y + 1 < canvas.height() {
             push_node(x, y + 1);
         }
         if y > 0 {
@@ -441,7 +987,332 @@ fn shortest_path(
     None
 }
 
+/// How much running over a cell of a block costs compared to any other cell, see `forced_path`.
+const BLOCK_CELL_COST: usize = 100;
+
+/// Find a path from `src` to `dst` for an edge that can't be routed without running over blocks,
+/// see `RouteFailure::Force`.
+///
+/// Every cell of a block costs as much as `BLOCK_CELL_COST` cells around them, so the path runs
+/// over as few of them as possible, while the cells in `text` can't be run over at all: the path
+/// goes along the borders and the padding of the blocks rather than between their lines of text.
+/// Among equally costly paths the ones with fewer turns are preferred. Return `None` if the text
+/// leaves no way through.
+fn forced_path(
+    canvas: &Canvas,
+    text: &[CanvasRect],
+    src: CanvasPoint,
+    dst: CanvasPoint,
+) -> Option<Polyline> {
+    use std::cmp::Reverse;
+    use std::collections::HashMap;
+
+    // points are reached once per heading, 0 at the start and then 1 horizontally and 2
+    // vertically, because the cost of the following turns depends on it
+    let mut costs = HashMap::new();
+    let mut prev = HashMap::new();
+    let mut queue = BinaryHeap::new();
+    costs.insert((src, 0), 0);
+    queue.push(Reverse((0, src, 0)));
+
+    let mut end = None;
+    while let Some(Reverse((cost, pt, heading))) = queue.pop() {
+        if pt == dst {
+            end = Some((pt, heading));
+            break;
+        }
+        if costs.get(&(pt, heading)).is_some_and(|&c| c < cost) {
+            continue;
+        }
+
+        let CanvasPoint { x, y } = pt;
+        let neighbors = [
+            (x.checked_sub(1), Some(y), 1),
+            (Some(x + 1), Some(y), 1),
+            (Some(x), y.checked_sub(1), 2),
+            (Some(x), Some(y + 1), 2),
+        ];
+        for (xx, yy, h) in neighbors {
+            let next = match (xx, yy) {
+                (Some(xx), Some(yy)) if xx < canvas.width() && yy < canvas.height() => {
+                    CanvasPoint::new(xx, yy)
+                }
+                _ => continue,
+            };
+            if next != dst && text.iter().any(|r| r.contains(next)) {
+                continue;
+            }
+
+            let step = if canvas.at(next) == b'#' {
+                BLOCK_CELL_COST
+            } else {
+                1
+            };
+            let turn = usize::from(heading != 0 && heading != h);
+            let next_cost = cost + step + turn;
+            if costs.get(&(next, h)).is_none_or(|&c| next_cost < c) {
+                costs.insert((next, h), next_cost);
+                prev.insert((next, h), (pt, heading));
+                queue.push(Reverse((next_cost, next, h)));
+            }
+        }
+    }
+
+    let mut node = end?;
+    let mut points = vec![node.0];
+    while let Some(&p) = prev.get(&node) {
+        points.push(p.0);
+        node = p;
+    }
+    points.reverse();
+
+    // only the corners are needed to draw the polyline
+    let corners = (0..points.len())
+        .filter(|&i| {
+            i == 0
+                || i + 1 == points.len()
+                || (points[i - 1].x != points[i + 1].x && points[i - 1].y != points[i + 1].y)
+        })
+        .map(|i| points[i])
+        .collect::<Vec<_>>();
+    polyline(&corners, (canvas.width(), canvas.height()))
+}
+
+/// The smallest rectangle with all the text inside the block drawn in `rect` on `canvas`, if it
+/// has any.
+fn text_rect(canvas: &Canvas, rect: CanvasRect) -> Option<CanvasRect> {
+    if rect.width < 3 || rect.height < 3 {
+        return None;
+    }
+
+    let points = canvas
+        .iter_region(rect.x + 1, rect.y + 1, rect.width - 2, rect.height - 2)
+        .filter(|&(_, c)| c != b' ')
+        .map(|(pt, _)| pt)
+        .collect::<Vec<_>>();
+    let x0 = points.iter().map(|p| p.x).min()?;
+    let x1 = points.iter().map(|p| p.x).max()?;
+    let y0 = points.iter().map(|p| p.y).min()?;
+    let y1 = points.iter().map(|p| p.y).max()?;
+    Some(CanvasRect {
+        x: x0,
+        y: y0,
+        width: x1 - x0 + 1,
+        height: y1 - y0 + 1,
+    })
+}
+
+/// How far each cell of the canvas is from the center of the channel it's in, both horizontally
+/// and vertically, indexed by `y * width + x`. A channel is a run of cells between two walls in
+/// the same row or column, runs that reach the borders of the canvas are open space and all their
+/// cells are considered centered.
+fn off_center(canvas: &Canvas) -> (Vec<usize>, Vec<usize>) {
+    let (w, h) = (canvas.width(), canvas.height());
+    let is_wall = |x: usize, y: usize| canvas.at(CanvasPoint::new(x, y)) == b'#';
+
+    let distances = |cells: &mut dyn Iterator<Item = (usize, bool)>, out: &mut Vec<usize>| {
+        let mut run = vec![];
+        let mut walled = false;
+        for (i, wall) in cells {
+            if !wall {
+                run.push(i);
+                continue;
+            }
+
+            // both cells in the middle of channels with an even width are centered
+            if walled {
+                for (j, &ix) in run.iter().enumerate() {
+                    out[ix] = (run.len() - 1).abs_diff(2 * j) / 2;
+                }
+            }
+            run.clear();
+            walled = true;
+        }
+    };
+
+    let mut horizontal = vec![0; w * h];
+    let mut vertical = vec![0; w * h];
+    for y in 0..h {
+        distances(
+            &mut (0..w).map(|x| (y * w + x, is_wall(x, y))),
+            &mut horizontal,
+        );
+    }
+    for x in 0..w {
+        distances(
+            &mut (0..h).map(|y| (y * w + x, is_wall(x, y))),
+            &mut vertical,
+        );
+    }
+
+    (horizontal, vertical)
+}
+
+/// Route a single edge on a hand drawn fixture like the router does while rendering a diagram and
+/// draw it on the fixture, to write focused regression tests for the router without building a
+/// whole diagram around them, for example with `assert_diagram_eq`.
+///
+/// The fixture is an ASCII grid where `#` are walls, like the inside of blocks, `@` are cells
+/// lines go through only when there's no other way, like the padding around blocks, and `S` and
+/// `D` are where the edge starts and ends. Anything else that isn't a space is a line the edge can
+/// cross if there's no way around it. Rows shorter than the longest one are padded with spaces.
+///
+/// ```text
+///   S             +S
+///   ####          |####
+///   ####   -->    |####
+///     D           +--D
+/// ```
+///
+/// Return the fixture with the edge drawn on it, `S` and `D` included, or why it can't be routed.
+pub fn route_fixture(fixture: &str) -> Result<Vec<Vec<u8>>, String> {
+    let lines = fixture.lines().collect::<Vec<_>>();
+    let width = lines.iter().map(|l| l.len()).max().unwrap_or(0);
+    let mut canvas = Canvas::new(width, lines.len());
+    for (y, l) in lines.iter().enumerate() {
+        canvas.draw_text(0, y, l.as_bytes());
+    }
+
+    let marker = |m: u8| {
+        let found = canvas
+            .iter_region(0, 0, canvas.width(), canvas.height())
+            .filter(|&(_, c)| c == m)
+            .map(|(pt, _)| pt)
+            .collect::<Vec<_>>();
+        match found[..] {
+            [pt] => Ok(pt),
+            _ => Err(format!(
+                "the fixture must have exactly one {}, found {}",
+                char::from(m),
+                found.len()
+            )),
+        }
+    };
+    let (src, dst) = (marker(b'S')?, marker(b'D')?);
+    canvas.set(src, b' ');
+    canvas.set(dst, b' ');
+
+    let path = [false, true].iter().find_map(|&allow_intersections| {
+        shortest_path(&canvas, src, dst, &BTreeSet::new(), allow_intersections)
+    });
+    let (_, polyline, _) = path.ok_or_else(|| "there's no path from S to D".to_string())?;
+
+    for l in &polyline {
+        l.draw(&mut canvas);
+    }
+    canvas.set(src, b'S');
+    canvas.set(dst, b'D');
+
+    Ok(canvas.into_rows())
+}
+
+/// Find the points where a vertical line of an edge crosses an horizontal line of another edge.
+/// Corners are not considered crossings since they're where edges join.
+///
+/// The points of each pair of edges are sorted, so the result is always the same for the same
+/// routes.
+pub fn crossing_points(routes: &[Route]) -> Vec<CanvasPoint> {
+    let cells = routes
+        .iter()
+        .map(|r| {
+            let mut vertical = BTreeSet::new();
+            let mut horizontal = BTreeSet::new();
+            for l in &r.polyline {
+                match l {
+                    Line::Vertical(..) => vertical.extend(l.interior()),
+                    Line::Horizontal(..) => horizontal.extend(l.interior()),
+                }
+            }
+            (vertical, horizontal)
+        })
+        .collect::<Vec<_>>();
+
+    let mut points = vec![];
+    for (i, (v0, h0)) in cells.iter().enumerate() {
+        for (v1, h1) in &cells[i + 1..] {
+            points.extend(v0.intersection(h1));
+            points.extend(h0.intersection(v1));
+        }
+    }
+    points
+}
+
+/// Find the points where the edges of a group branch off, that is where at least three lines of
+/// the edges in the same group meet. See `Edge::with_group`.
+pub fn branch_points(edges: &[Edge], routes: &[Route]) -> Vec<CanvasPoint> {
+    // the directions each point is connected to, as a bit set of left, right, up and down
+    let mut groups = BTreeMap::<&[u8], BTreeMap<CanvasPoint, u8>>::new();
+    for (e, r) in edges.iter().zip(routes) {
+        if e.group.is_empty() {
+            continue;
+        }
+
+        let directions = groups.entry(&e.group).or_default();
+        for l in &r.polyline {
+            let (a, b) = l.ends();
+            let (before, after) = match l {
+                Line::Horizontal(..) => (1, 2),
+                Line::Vertical(..) => (4, 8),
+            };
+            for pt in l.points() {
+                let d = directions.entry(pt).or_default();
+                if pt != a {
+                    *d |= before;
+                }
+                if pt != b {
+                    *d |= after;
+                }
+            }
+        }
+    }
+
+    groups
+        .into_values()
+        .flat_map(|directions| {
+            directions
+                .into_iter()
+                .filter(|(_, d)| d.count_ones() >= 3)
+                .map(|(pt, _)| pt)
+        })
+        .collect()
+}
+
 impl Line {
+    /// The points of the line without its ends.
+    pub fn interior(&self) -> Vec<CanvasPoint> {
+        match *self {
+            Line::Vertical(x, (y0, y1)) => (y0.min(y1) + 1..y0.max(y1))
+                .map(|y| CanvasPoint::new(x, y))
+                .collect(),
+            Line::Horizontal(y, (x0, x1)) => (x0.min(x1) + 1..x0.max(x1))
+                .map(|x| CanvasPoint::new(x, y))
+                .collect(),
+        }
+    }
+
+    /// All the points of the line, ends included.
+    pub fn points(&self) -> Vec<CanvasPoint> {
+        let (a, b) = self.ends();
+        let mut points = self.interior();
+        points.insert(0, a);
+        points.push(b);
+        points
+    }
+
+    /// The two ends of the line, top or left one first.
+    pub fn ends(&self) -> (CanvasPoint, CanvasPoint) {
+        match *self {
+            Line::Vertical(x, (y0, y1)) => (
+                CanvasPoint::new(x, y0.min(y1)),
+                CanvasPoint::new(x, y0.max(y1)),
+            ),
+            Line::Horizontal(y, (x0, x1)) => (
+                CanvasPoint::new(x0.min(x1), y),
+                CanvasPoint::new(x0.max(x1), y),
+            ),
+        }
+    }
+
     pub fn draw(&self, canvas: &mut Canvas) {
         match *self {
             Line::Horizontal(y, xs) => canvas.draw_horizontal_line(y, xs),
@@ -453,9 +1324,20 @@ impl Line {
 impl Score {
     fn new() -> Self {
         Score {
+            failures: 0,
             path_len: 0,
             turns: 0,
             intersections: 0,
+            hugging: 0,
+            off_center: 0,
+            turns_distance: 0,
+        }
+    }
+
+    fn without_off_center(&self) -> Self {
+        Score {
+            off_center: 0,
+            ..self.clone()
         }
     }
 }
@@ -465,25 +1347,36 @@ impl Add for Score {
 
     fn add(self, rhs: Score) -> Self::Output {
         Score {
+            failures: self.failures + rhs.failures,
             intersections: self.intersections + rhs.intersections,
             path_len: self.path_len + rhs.path_len,
             turns: self.turns + rhs.turns,
+            hugging: self.hugging + rhs.hugging,
+            off_center: self.off_center + rhs.off_center,
+            turns_distance: self.turns_distance + rhs.turns_distance,
         }
     }
 }
 
 impl Ord for Score {
     fn cmp(&self, o: &Score) -> Ordering {
-        self.partial_cmp(o).unwrap()
+        let key = |s: &Score| {
+            (
+                s.failures,
+                s.intersections,
+                s.turns,
+                s.path_len,
+                s.off_center,
+                s.hugging,
+                s.turns_distance,
+            )
+        };
+        key(self).cmp(&key(o))
     }
 }
 impl PartialOrd for Score {
     fn partial_cmp(&self, o: &Score) -> Option<Ordering> {
-        (self.intersections, self.turns, self.path_len).partial_cmp(&(
-            o.intersections,
-            o.turns,
-            o.path_len,
-        ))
+        Some(self.cmp(o))
     }
 }
 
@@ -497,6 +1390,7 @@ fn between(a: i32, b: i32) -> impl Iterator<Item = i32> {
 mod tests {
     use super::*;
 
+    use crate::assert_diagram_eq;
     use crate::render::canvas_space::CanvasSpace;
 
     #[test]
@@ -520,12 +1414,12 @@ mod tests {
         //
 
         let blocks = [
-            Block::new((0, 0), b"000"),
-            Block::new((0, 1), b"111"),
-            Block::new((1, 0), b"222"),
-            Block::new((2, 1), b"333"),
-            Block::new((0, 3), b"777"),
-            Block::new((3, 1), b"999"),
+            Block::from_str((0, 0), "000"),
+            Block::from_str((0, 1), "111"),
+            Block::from_str((1, 0), "222"),
+            Block::from_str((2, 1), "333"),
+            Block::from_str((0, 3), "777"),
+            Block::from_str((3, 1), "999"),
         ];
         let cs = CanvasSpace::new(
             &blocks,
@@ -535,71 +1429,278 @@ mod tests {
                 padding: 0,
                 seed: Some(0),
                 max_tweaks: 0,
+                ..RenderOptions::default()
             },
         );
 
         // 000 <-> 111
         assert_eq!(
-            closest_points_on_blocks(&cs, (0, 0), (0, 1)),
-            ((6, 2), (9, 2))
+            closest_points_on_blocks(&cs, LogicalPos::new(0, 0), LogicalPos::new(0, 1)),
+            (CanvasPoint::new(6, 2), CanvasPoint::new(9, 2))
         );
         assert_eq!(
-            closest_points_on_blocks(&cs, (0, 1), (0, 0)),
-            ((9, 2), (6, 2))
+            closest_points_on_blocks(&cs, LogicalPos::new(0, 1), LogicalPos::new(0, 0)),
+            (CanvasPoint::new(9, 2), CanvasPoint::new(6, 2))
         );
 
         // 111 <-> 333
         assert_eq!(
-            closest_points_on_blocks(&cs, (2, 1), (0, 1)),
-            ((11, 9), (11, 3))
+            closest_points_on_blocks(&cs, LogicalPos::new(2, 1), LogicalPos::new(0, 1)),
+            (CanvasPoint::new(11, 9), CanvasPoint::new(11, 3))
         );
         assert_eq!(
-            closest_points_on_blocks(&cs, (0, 1), (2, 1)),
-            ((11, 3), (11, 9))
+            closest_points_on_blocks(&cs, LogicalPos::new(0, 1), LogicalPos::new(2, 1)),
+            (CanvasPoint::new(11, 3), CanvasPoint::new(11, 9))
         );
 
         // 222 -> 111
         assert_eq!(
-            closest_points_on_blocks(&cs, (1, 0), (0, 1)),
-            ((4, 5), (9, 2))
+            closest_points_on_blocks(&cs, LogicalPos::new(1, 0), LogicalPos::new(0, 1)),
+            (CanvasPoint::new(4, 5), CanvasPoint::new(9, 2))
         );
 
         // 111 -> 222
         assert_eq!(
-            closest_points_on_blocks(&cs, (0, 1), (1, 0)),
-            ((11, 3), (6, 6))
+            closest_points_on_blocks(&cs, LogicalPos::new(0, 1), LogicalPos::new(1, 0)),
+            (CanvasPoint::new(11, 3), CanvasPoint::new(6, 6))
         );
 
         // 222 -> 333
         assert_eq!(
-            closest_points_on_blocks(&cs, (1, 0), (2, 1)),
-            ((4, 7), (9, 10))
+            closest_points_on_blocks(&cs, LogicalPos::new(1, 0), LogicalPos::new(2, 1)),
+            (CanvasPoint::new(4, 7), CanvasPoint::new(9, 10))
         );
 
         // 333 -> 222
         assert_eq!(
-            closest_points_on_blocks(&cs, (2, 1), (1, 0)),
-            ((11, 9), (6, 6))
+            closest_points_on_blocks(&cs, LogicalPos::new(2, 1), LogicalPos::new(1, 0)),
+            (CanvasPoint::new(11, 9), CanvasPoint::new(6, 6))
         );
 
         // 000 -> 777
         assert_eq!(
-            closest_points_on_blocks(&cs, (0, 0), (0, 3)),
-            ((4, 1), (20, 1))
+            closest_points_on_blocks(&cs, LogicalPos::new(0, 0), LogicalPos::new(0, 3)),
+            (CanvasPoint::new(4, 1), CanvasPoint::new(20, 1))
         );
         assert_eq!(
-            closest_points_on_blocks(&cs, (0, 3), (0, 0)),
-            ((20, 3), (4, 3))
+            closest_points_on_blocks(&cs, LogicalPos::new(0, 3), LogicalPos::new(0, 0)),
+            (CanvasPoint::new(20, 3), CanvasPoint::new(4, 3))
         );
 
         // 111 -> 999
         assert_eq!(
-            closest_points_on_blocks(&cs, (0, 1), (3, 1)),
-            ((13, 2), (13, 14))
+            closest_points_on_blocks(&cs, LogicalPos::new(0, 1), LogicalPos::new(3, 1)),
+            (CanvasPoint::new(13, 2), CanvasPoint::new(13, 14))
         );
         assert_eq!(
-            closest_points_on_blocks(&cs, (3, 1), (0, 1)),
-            ((9, 14), (9, 2))
+            closest_points_on_blocks(&cs, LogicalPos::new(3, 1), LogicalPos::new(0, 1)),
+            (CanvasPoint::new(9, 14), CanvasPoint::new(9, 2))
+        );
+    }
+
+    #[test]
+    fn test_attachment() {
+        //
+        //  +---+
+        //  |000|
+        //  +---+
+        //
+        //         +------------+
+        //         |111111111111|
+        //         +------------+
+        //
+
+        let blocks = [
+            Block::from_str((0, 0), "000"),
+            Block::from_str((1, 1), "111111111111"),
+        ];
+        let points = |attachment, p0, p1| {
+            let cs = CanvasSpace::new(
+                &blocks,
+                &RenderOptions {
+                    hmargin: 2,
+                    vmargin: 1,
+                    padding: 0,
+                    seed: Some(0),
+                    max_tweaks: 0,
+                    attachment,
+                    ..RenderOptions::default()
+                },
+            );
+            closest_points_on_blocks(&cs, p0, p1)
+        };
+        let (p0, p1) = (LogicalPos::new(0, 0), LogicalPos::new(1, 1));
+
+        // 000 -> 111
+        assert_eq!(
+            points(Attachment::VerticalFirst, p0, p1),
+            (CanvasPoint::new(4, 3), CanvasPoint::new(9, 6))
+        );
+        assert_eq!(
+            points(Attachment::HorizontalFirst, p0, p1),
+            (CanvasPoint::new(6, 2), CanvasPoint::new(16, 5))
+        );
+        assert_eq!(
+            points(Attachment::Shortest, p0, p1),
+            (CanvasPoint::new(4, 3), CanvasPoint::new(9, 6))
+        );
+
+        // 111 -> 000, leaving 111 from its top center is the longer way round
+        assert_eq!(
+            points(Attachment::VerticalFirst, p1, p0),
+            (CanvasPoint::new(16, 5), CanvasPoint::new(6, 2))
+        );
+        assert_eq!(
+            points(Attachment::Shortest, p1, p0),
+            (CanvasPoint::new(9, 6), CanvasPoint::new(4, 3))
+        );
+    }
+
+    #[test]
+    fn test_keep_off_corners() {
+        let blocks = [
+            Block::from_str((0, 0), "x"),
+            Block::from_str((0, 1), "ab"),
+            Block::from_str((1, 2), "abcdef\n\n\n"),
+        ];
+        let cs = CanvasSpace::new(
+            &blocks,
+            &RenderOptions {
+                hmargin: 1,
+                vmargin: 1,
+                padding: 0,
+                seed: Some(0),
+                max_tweaks: 0,
+                ..RenderOptions::default()
+            },
+        );
+
+        // 1 char wide block, the only valid point on each side is the center
+        assert_eq!(
+            keep_off_corners(&cs, LogicalPos::new(0, 0), CanvasPoint::new(1, 1)),
+            CanvasPoint::new(2, 1)
+        );
+        assert_eq!(
+            keep_off_corners(&cs, LogicalPos::new(0, 0), CanvasPoint::new(3, 1)),
+            CanvasPoint::new(2, 1)
+        );
+        assert_eq!(
+            keep_off_corners(&cs, LogicalPos::new(0, 0), CanvasPoint::new(1, 2)),
+            CanvasPoint::new(1, 2)
+        );
+
+        // 2 chars wide block, it's only possible to move off the corners
+        assert_eq!(
+            keep_off_corners(&cs, LogicalPos::new(0, 1), CanvasPoint::new(5, 3)),
+            CanvasPoint::new(6, 3)
+        );
+        assert_eq!(
+            keep_off_corners(&cs, LogicalPos::new(0, 1), CanvasPoint::new(8, 3)),
+            CanvasPoint::new(7, 3)
+        );
+
+        // big enough blocks, points are moved away from the cells next to the corners too
+        assert_eq!(
+            keep_off_corners(&cs, LogicalPos::new(1, 2), CanvasPoint::new(10, 5)),
+            CanvasPoint::new(12, 5)
+        );
+        assert_eq!(
+            keep_off_corners(&cs, LogicalPos::new(1, 2), CanvasPoint::new(16, 10)),
+            CanvasPoint::new(15, 10)
+        );
+        assert_eq!(
+            keep_off_corners(&cs, LogicalPos::new(1, 2), CanvasPoint::new(10, 6)),
+            CanvasPoint::new(10, 7)
+        );
+        assert_eq!(
+            keep_off_corners(&cs, LogicalPos::new(1, 2), CanvasPoint::new(17, 9)),
+            CanvasPoint::new(17, 8)
+        );
+        assert_eq!(
+            keep_off_corners(&cs, LogicalPos::new(1, 2), CanvasPoint::new(17, 8)),
+            CanvasPoint::new(17, 8)
+        );
+    }
+
+    #[test]
+    fn test_route_fixture() {
+        let fixture = "
+  S
+  ####
+  ####
+    D
+";
+        assert_diagram_eq!(
+            route_fixture(fixture).unwrap(),
+            br#"      
+ +S   
+ |####
+ |####
+ +--D "#
+        );
+
+        // lines are crossed only when there's no way around them and padding is avoided
+        let fixture = "
+ S  |
+@@@@|
+    |   D
+";
+        assert_diagram_eq!(
+            route_fixture(fixture).unwrap(),
+            br#" +------+
+ S  |   |
+@@@@|   |
+    |   D"#
+        );
+
+        assert!(route_fixture("S ").is_err());
+        assert!(route_fixture("S#D").is_err());
+    }
+
+    #[test]
+    fn test_shortest_path_tie_breakers() {
+        let blocks = [
+            Block::from_str((0, 0), "a"),
+            Block::from_str((1, 0), "b"),
+            Block::from_str((1, 1), "c"),
+            Block::from_str((2, 1), "d"),
+        ];
+        let cs = CanvasSpace::new(
+            &blocks,
+            &RenderOptions {
+                vmargin: 2,
+                padding: 0,
+                seed: Some(0),
+                max_tweaks: 0,
+                ..RenderOptions::default()
+            },
+        );
+
+        // only the blocks in the middle row are walls, leaving a channel between x=8 and x=12
+        let mut canvas = Canvas::new(cs.canvas_width(), cs.canvas_height());
+        for pos in [LogicalPos::new(1, 0), LogicalPos::new(1, 1)] {
+            let r = cs.to_canvas(pos);
+            canvas.fill_rect(r.x, r.y, r.width, r.height, b'#');
+        }
+
+        // all the paths going down through the channel are equally short and have the same
+        // turns, the one in its center is the farthest from the blocks
+        let (score, path, _) = shortest_path(
+            &canvas,
+            CanvasPoint::new(6, 0),
+            CanvasPoint::new(14, 14),
+            &BTreeSet::new(),
+            false,
+        )
+        .unwrap();
+        assert_eq!((score.turns, score.path_len), (3, 22));
+        assert_eq!(
+            path,
+            vec![
+                Line::Horizontal(0, (6, 10)),
+                Line::Vertical(10, (0, 14)),
+                Line::Horizontal(14, (10, 14)),
+            ]
         );
     }
 }
@@ -1,20 +1,63 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
 #[macro_use]
 mod canvas;
 mod canvas_space;
+mod cast;
+mod compact;
+mod frames;
+mod geometry;
+mod headers;
+mod invariants;
 mod lines;
+mod optimize;
+mod packet;
+mod polylines;
+mod rng;
+mod stubs;
+mod suggest;
+mod symmetry;
+mod text;
 
-use canvas::Canvas;
+pub use canvas::{Canvas, CanvasPoint, CanvasRect, CUT_MARKER};
 use canvas_space::CanvasSpace;
-use lines::find_edges;
+pub use cast::{asciicast, draw_order};
+pub use compact::compact_margins;
+pub use frames::hide_elements;
+pub use geometry::{
+    render_layout, render_with_layout, score_layout, try_render_layout, BlockRect, EdgeRoute,
+    Element, Layout, LayoutMismatch,
+};
+pub use headers::{add_guides, add_headers, add_offsets};
+pub use invariants::{
+    collisions, detours, edge_intrusions, overwritten_cells, Collision, CollisionKind, Detour,
+    Intrusion,
+};
+pub use lines::route_fixture;
+use lines::{branch_points, crossing_points, find_edges, Line, Route};
+pub use optimize::{auto_margins, fit_to_width, optimize_layout};
+pub use packet::packet;
+pub use polylines::{add_polylines, Polyline, PolylineError, PolylinePoint};
+pub use stubs::add_stubs;
+pub use suggest::{score, suggest, Metrics, Suggestion};
+pub use text::sparkline;
 
-use crate::Block;
+use crate::{Block, Edge, LogicalCoord, Shape, TextDirection};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RenderOptions {
     /// horizontal margin placed between columns, also after the last one and before the first one.
+    ///
+    /// Without margin adjacent blocks share their borders and the edges between them are drawn
+    /// as a `+` on the shared border, without arrowheads. Edges between blocks that aren't
+    /// adjacent can be routed only through empty cells then, see `route_failure`.
     pub hmargin: usize,
 
     /// vertical margin placed between rows, also after the last one and before the first one.
+    /// Without margin adjacent rows share their borders too, see `hmargin`.
     pub vmargin: usize,
 
     /// padding around the text inside the `Block`s.
@@ -25,44 +68,639 @@ pub struct RenderOptions {
 
     /// maximum number of tweaks to find the best arrangements of lines.
     pub max_tweaks: usize,
+
+    /// maximum time spent on the tweaks, the best arrangement of lines found by then is kept.
+    /// The lines are still routed at least once however long it takes, also after inserting
+    /// gutters. How many tweaks fit in
+    /// the time depends on the machine, so renderings with a limit might not be reproducible.
+    pub max_tweak_time: Option<Duration>,
+
+    /// maximum number of crossings allowed in the space between two columns or rows. When there
+    /// are more crossings than that an empty column or row is inserted there to make room for the
+    /// lines. `None` never inserts empty columns or rows.
+    pub gutter_threshold: Option<usize>,
+
+    /// route the edges of mirrored block arrangements symmetrically, that is the edges on one
+    /// side are drawn as the reflection of the ones on the other side when possible. Mirrored
+    /// columns or rows are made as wide as each other.
+    pub symmetric: bool,
+
+    /// make the blocks proportional to the given (width, height) in characters by enlarging
+    /// their narrow side, so that blocks with short or long texts look alike in a grid. Explicit
+    /// block sizes are kept and it's ignored if either dimension is 0.
+    pub block_aspect: Option<(usize, usize)>,
+
+    /// what to do with the edges that can't be routed without running over a block, like the
+    /// edges of a block surrounded by other blocks without margins.
+    pub route_failure: RouteFailure,
+
+    /// which sides of their blocks the edges between blocks in different rows and columns leave
+    /// and enter from, unless they have ports.
+    pub attachment: Attachment,
+}
+
+impl Default for RenderOptions {
+    /// The same options diagram specs are rendered with unless they say otherwise.
+    fn default() -> Self {
+        RenderOptions {
+            hmargin: 5,
+            vmargin: 3,
+            padding: 1,
+            seed: None,
+            max_tweaks: 100,
+            max_tweak_time: None,
+            gutter_threshold: None,
+            symmetric: false,
+            block_aspect: None,
+            route_failure: RouteFailure::default(),
+            attachment: Attachment::default(),
+        }
+    }
+}
+
+/// What to do with an edge that can't be routed, see `RenderOptions::route_failure`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RouteFailure {
+    /// Fail, that is `try_render_layout` returns a `RouteError` while the functions that can't
+    /// fail panic with it.
+    #[default]
+    Error,
+
+    /// Leave the edge out of the diagram. Its route in the `Layout` has no points.
+    Skip,
+
+    /// Draw the edge over whatever is in its way, marking the cells where it runs over blocks
+    /// with `!`. It runs over as few cells of blocks as possible, along their borders and padding
+    /// rather than through their text, or with a single turn if the text leaves no way through.
+    Force,
 }
 
+/// Which sides of their blocks edges leave and enter from when their blocks are neither in the
+/// same row nor in the same column, see `RenderOptions::attachment`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Attachment {
+    /// Leave from the top or bottom side and enter from the left or right side.
+    #[default]
+    VerticalFirst,
+
+    /// Leave from the left or right side and enter from the top or bottom side.
+    HorizontalFirst,
+
+    /// Whichever of the two has its ends closer to each other, vertical first on ties.
+    Shortest,
+}
+
+/// An edge that can't be routed with `RouteFailure::Error`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RouteError {
+    /// index of the edge.
+    pub edge: usize,
+
+    /// indices of the blocks the edge connects.
+    pub from: usize,
+    pub to: usize,
+}
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "edge {} from block {} to block {} cannot be routed without running over a block",
+            self.edge, self.from, self.to
+        )
+    }
+}
+
+impl std::error::Error for RouteError {}
+
+/// Maximum number of times the edges are routed again after inserting gutters.
+const MAX_GUTTER_ROUNDS: usize = 5;
+
+/// Number of rows written at once by `write_rows`.
+const BAND_HEIGHT: usize = 64;
+
+/// Render the given blocks and edges as rows of bytes, see `render_to_string`.
+#[deprecated(note = "use `render_to_string` instead")]
 pub fn render(
     boxes: &[Block],
-    edges: impl IntoIterator<Item = (usize, usize)>,
+    edges: impl IntoIterator<Item = impl Into<Edge>>,
+    config: RenderOptions,
+) -> Vec<Vec<u8>> {
+    render_rows(boxes, edges, config)
+}
+
+/// Render the given blocks and the edges between them, referenced by their index in `boxes`.
+/// Return the lines of the diagram, without newlines.
+pub fn render_to_string(
+    boxes: &[Block],
+    edges: impl IntoIterator<Item = impl Into<Edge>>,
+    config: RenderOptions,
+) -> Vec<String> {
+    render_rows(boxes, edges, config)
+        .into_iter()
+        .map(into_string)
+        .collect()
+}
+
+/// Turn a row of the canvas into a string, this never fails because only ASCII characters are
+/// ever drawn.
+pub(crate) fn into_string(row: Vec<u8>) -> String {
+    String::from_utf8(row).expect("the canvas contains only ASCII characters")
+}
+
+pub(crate) fn render_rows(
+    boxes: &[Block],
+    edges: impl IntoIterator<Item = impl Into<Edge>>,
     config: RenderOptions,
 ) -> Vec<Vec<u8>> {
     if boxes.is_empty() {
         return vec![];
     }
 
+    let edges = edges.into_iter().map(Into::into).collect::<Vec<_>>();
+    let (cs, mut canvas, routes) =
+        layout(boxes, &edges, &config).unwrap_or_else(|e| panic!("{}", e));
+    draw_routes(&cs, &mut canvas, boxes, &edges, &routes);
+
+    canvas.into_rows()
+}
+
+/// Render the given diagram like `render` does and write it to `out`, see `write_rows`. The whole
+/// canvas is drawn before the first row is written, routing the edges needs all of it.
+pub fn render_to(
+    boxes: &[Block],
+    edges: impl IntoIterator<Item = impl Into<Edge>>,
+    config: RenderOptions,
+    out: impl Write,
+) -> io::Result<()> {
+    write_rows(render_rows(boxes, edges, config), out)
+}
+
+/// Write the given rows to `out`, each one terminated by a newline, flushing it after each band
+/// of rows so that readers at the other end of a pipe get the first rows early.
+pub fn write_rows(rows: impl IntoIterator<Item = Vec<u8>>, mut out: impl Write) -> io::Result<()> {
+    for (i, row) in rows.into_iter().enumerate() {
+        out.write_all(&row)?;
+        out.write_all(b"\n")?;
+
+        if (i + 1) % BAND_HEIGHT == 0 {
+            out.flush()?;
+        }
+    }
+    out.flush()
+}
+
+/// Compute the dimensions of the canvas `render` would draw the given blocks on, as
+/// (width, height), without routing any edge. This is cheap even for large diagrams.
+///
+/// The dimensions are exact unless `config` has a `gutter_threshold`, in which case they're a
+/// lower bound because gutters are inserted only after routing the edges.
+pub fn estimate_size(boxes: &[Block], config: RenderOptions) -> (usize, usize) {
+    if boxes.is_empty() {
+        return (0, 0);
+    }
+
+    let cs = CanvasSpace::new(boxes, &config);
+    (cs.canvas_width(), cs.canvas_height())
+}
+
+/// The logical grid the blocks of a diagram are laid out on, see `logical_grid`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogicalGrid {
+    /// logical coordinates of the first column and of the first row.
+    pub min_column: LogicalCoord,
+    pub min_row: LogicalCoord,
+
+    /// width of each column and height of each row in characters, that is of the largest block
+    /// in it, borders included. Empty columns and rows are 0 wide and tall.
+    pub columns_width: Vec<usize>,
+    pub rows_height: Vec<usize>,
+
+    /// index of the block at each row and column, the first one of the blocks at the same
+    /// position.
+    pub cells: Vec<Vec<Option<usize>>>,
+}
+
+/// Find which cells of the logical grid are occupied by the given blocks and how large each
+/// column and row is, to position the blocks of large diagrams by hand. It's as cheap as
+/// `estimate_size`.
+pub fn logical_grid(boxes: &[Block], config: RenderOptions) -> LogicalGrid {
+    if boxes.is_empty() {
+        return LogicalGrid {
+            min_column: 0,
+            min_row: 0,
+            columns_width: vec![],
+            rows_height: vec![],
+            cells: vec![],
+        };
+    }
+
+    let cs = CanvasSpace::new(boxes, &config);
+    let columns = boxes.iter().map(|b| b.pos.col);
+    let rows = boxes.iter().map(|b| b.pos.row);
+    let (min_column, max_column) = (columns.clone().min().unwrap(), columns.max().unwrap());
+    let (min_row, max_row) = (rows.clone().min().unwrap(), rows.max().unwrap());
+
+    let mut cells =
+        vec![vec![None; (max_column - min_column + 1) as usize]; (max_row - min_row + 1) as usize];
+    for (i, b) in boxes.iter().enumerate() {
+        let cell = &mut cells[(b.pos.row - min_row) as usize][(b.pos.col - min_column) as usize];
+        cell.get_or_insert(i);
+    }
+
+    LogicalGrid {
+        min_column,
+        min_row,
+        columns_width: (min_column..=max_column)
+            .map(|c| cs.column_width(c))
+            .collect(),
+        rows_height: (min_row..=max_row).map(|r| cs.row_height(r)).collect(),
+        cells,
+    }
+}
+
+/// Two blocks drawn over each other, see `overlapping_blocks`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overlap {
+    /// indices of the blocks, the first one is drawn below the second one.
+    pub blocks: (usize, usize),
+
+    /// rectangles of the blocks, borders included.
+    pub rects: (CanvasRect, CanvasRect),
+}
+
+/// Find the blocks that would be drawn over each other by `render` by mistake, like blocks with
+/// the same z at the same position. It's as cheap as `estimate_size` and it should be checked
+/// before rendering since `render` silently draws the blocks that come later over the ones before
+/// them. Blocks with different z overlap on purpose, see `Block::with_z`, while blocks sharing a
+/// border because there are no margins don't overlap.
+pub fn overlapping_blocks(boxes: &[Block], config: RenderOptions) -> Vec<Overlap> {
+    if boxes.is_empty() {
+        return vec![];
+    }
+
     let cs = CanvasSpace::new(boxes, &config);
+    let rects = (0..boxes.len())
+        .map(|i| cs.block_rect(boxes, i))
+        .collect::<Vec<_>>();
+
+    // only the blocks starting before the end of a block can overlap it
+    let mut by_x = (0..boxes.len()).collect::<Vec<_>>();
+    by_x.sort_by_key(|&i| (rects[i].x, i));
+
+    let mut overlaps = vec![];
+    for (k, &i) in by_x.iter().enumerate() {
+        for &j in &by_x[k + 1..] {
+            if rects[j].x >= rects[i].x + rects[i].width {
+                break;
+            }
+            if boxes[i].z == boxes[j].z && rects[i].overlaps(&rects[j]) {
+                let (a, b) = (i.min(j), i.max(j));
+                overlaps.push(Overlap {
+                    blocks: (a, b),
+                    rects: (rects[a], rects[b]),
+                });
+            }
+        }
+    }
+    overlaps.sort_by_key(|o| o.blocks);
+    overlaps
+}
+
+/// Draw the routes of the edges on a canvas with the blocks already drawn on it, alongside their
+/// arrowheads and labels, and then the corner texts of the blocks.
+fn draw_routes(
+    cs: &CanvasSpace,
+    canvas: &mut Canvas,
+    boxes: &[Block],
+    edges: &[Edge],
+    routes: &[Route],
+) {
+    for r in routes {
+        for l in &r.polyline {
+            l.draw(canvas);
+        }
+    }
+
+    // dashed edges are drawn like the others and then dashed where no other edge passes
+    let solid = edges
+        .iter()
+        .zip(routes)
+        .filter(|(e, _)| !e.dashed)
+        .flat_map(|(_, r)| r.polyline.iter().flat_map(Line::points))
+        .collect::<HashSet<_>>();
+    for (_, r) in edges.iter().zip(routes).filter(|(e, _)| e.dashed) {
+        for pt in r.polyline.iter().flat_map(Line::points) {
+            if pt == r.src || pt == r.dst || solid.contains(&pt) {
+                continue;
+            }
+            match canvas.at(pt) {
+                b'|' => canvas.set(pt, b':'),
+                b'-' if pt.x % 2 == 0 => canvas.set(pt, b' '),
+                _ => {}
+            }
+        }
+    }
+    for pt in branch_points(edges, routes) {
+        canvas.set(pt, b'*');
+    }
+
+    // edges between blocks sharing a border are just a junction on it, without arrowheads
+    for r in routes
+        .iter()
+        .filter(|r| r.src == r.dst && !r.polyline.is_empty())
+    {
+        canvas.set(r.src, b'+');
+    }
+
+    // edges forced over blocks with `RouteFailure::Force` are marked where they run over them
+    let rects = (0..boxes.len())
+        .map(|i| cs.block_rect(boxes, i))
+        .collect::<Vec<_>>();
+    for r in routes {
+        for pt in r.polyline.iter().flat_map(Line::points) {
+            let over_block =
+                cs.block_at_canvas_point(pt).is_some() && rects.iter().any(|b| b.contains(pt));
+            if over_block && pt != r.src && pt != r.dst {
+                canvas.set(pt, b'!');
+            }
+        }
+    }
+
+    // arrowheads are drawn right outside the `to` block, pointing to it, and outside the `from`
+    // block too for bidirectional edges
+    for (e, r) in edges.iter().zip(routes) {
+        if r.polyline.is_empty() || r.src == r.dst {
+            continue;
+        }
+        if e.directed || e.bidirectional {
+            if let Some((pt, c)) = arrowhead(cs, &boxes[e.to], r.dst) {
+                canvas.set(pt, c);
+            }
+        }
+        if e.bidirectional {
+            if let Some((pt, c)) = arrowhead(cs, &boxes[e.from], r.src) {
+                canvas.set(pt, c);
+            }
+        }
+    }
+
+    // labels are drawn after all the lines because they're placed in the free space left by
+    // lines anyway
+    for r in routes {
+        for (pt, label) in &r.labels {
+            canvas.draw_text(pt.x, pt.y, label);
+        }
+    }
+
+    // corner texts are drawn last so that they're never hidden by lines
+    for (i, b) in boxes.iter().enumerate() {
+        if !b.corner_text.is_empty() {
+            let r = cs.block_rect(boxes, i);
+            canvas.draw_text(r.x + 1, r.y, &b.corner_text);
+        }
+    }
+}
+
+/// The blocks of a diagram placed on the canvas and drawn on it, without any edge. It depends
+/// only on the blocks and on the `RenderOptions` and so it can be reused to render the same
+/// blocks again with different edges.
+#[derive(Debug, Clone)]
+pub(crate) struct PlacedBlocks {
+    cs: CanvasSpace,
+    canvas: Canvas,
+}
+
+impl PlacedBlocks {
+    /// Place the given blocks, they must not be empty.
+    pub(crate) fn new(boxes: &[Block], config: &RenderOptions) -> Self {
+        let cs = CanvasSpace::new(boxes, config);
+        let canvas = draw_blocks(&cs, boxes, config);
+        PlacedBlocks { cs, canvas }
+    }
+
+    /// Render the blocks these were placed from with the given edges like `render` does.
+    pub(crate) fn render(
+        &self,
+        boxes: &[Block],
+        edges: &[Edge],
+        config: &RenderOptions,
+    ) -> Vec<Vec<u8>> {
+        let (cs, mut canvas, routes) =
+            layout_placed(self.clone(), boxes, edges, config).unwrap_or_else(|e| panic!("{}", e));
+        draw_routes(&cs, &mut canvas, boxes, edges, &routes);
+
+        canvas.into_rows()
+    }
+}
+
+/// Place the blocks on the canvas and route the edges between them, inserting gutters where
+/// there's too much congestion if requested. Return the canvas with only the blocks drawn on it
+/// alongside the routes of the edges.
+fn layout(
+    boxes: &[Block],
+    edges: &[Edge],
+    config: &RenderOptions,
+) -> Result<(CanvasSpace, Canvas, Vec<Route>), RouteError> {
+    layout_placed(PlacedBlocks::new(boxes, config), boxes, edges, config)
+}
+
+/// Like `layout`, but starting from blocks that were already placed.
+fn layout_placed(
+    placed: PlacedBlocks,
+    boxes: &[Block],
+    edges: &[Edge],
+    config: &RenderOptions,
+) -> Result<(CanvasSpace, Canvas, Vec<Route>), RouteError> {
+    let PlacedBlocks { mut cs, canvas } = placed;
+    let mut placed_canvas = Some(canvas);
+    let mut round = 0;
+
+    // the time for the tweaks is shared by all the rounds
+    let deadline = config
+        .max_tweak_time
+        .and_then(|t| Instant::now().checked_add(t));
+    let mut round_config = config.clone();
+
+    loop {
+        // the blocks must be drawn again only after gutters are inserted
+        let canvas = placed_canvas
+            .take()
+            .unwrap_or_else(|| draw_blocks(&cs, boxes, config));
+        round_config.max_tweak_time = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+        let routes = find_edges(&canvas, &cs, boxes, edges, &round_config);
+        if config.route_failure == RouteFailure::Error {
+            let failed = edges
+                .iter()
+                .zip(&routes)
+                .position(|(_, r)| r.polyline.is_empty());
+            if let Some(i) = failed {
+                return Err(RouteError {
+                    edge: i,
+                    from: edges[i].from,
+                    to: edges[i].to,
+                });
+            }
+        }
+
+        let threshold = match config.gutter_threshold {
+            Some(t) if round < MAX_GUTTER_ROUNDS => t,
+            _ => return Ok((cs, canvas, routes)),
+        };
+
+        let mut columns_crossings = BTreeMap::new();
+        let mut rows_crossings = BTreeMap::new();
+        for CanvasPoint { x, y } in crossing_points(&routes) {
+            if let Some(gap) = cs.column_gap_at(x) {
+                *columns_crossings.entry(gap).or_insert(0) += 1;
+            }
+            if let Some(gap) = cs.row_gap_at(y) {
+                *rows_crossings.entry(gap).or_insert(0) += 1;
+            }
+        }
+
+        let mut congested = false;
+        for (&gap, &n) in &columns_crossings {
+            if n > threshold {
+                cs.add_column_gutter(gap);
+                congested = true;
+            }
+        }
+        for (&gap, &n) in &rows_crossings {
+            if n > threshold {
+                cs.add_row_gutter(gap);
+                congested = true;
+            }
+        }
+
+        if !congested {
+            return Ok((cs, canvas, routes));
+        }
+        round += 1;
+    }
+}
+
+/// Find where to draw the arrowhead of an edge attached to the block `b` at `pt` and the
+/// character to use so that it points to the block, if there's room for it on the canvas. There
+/// may be none without margins, when the edge is forced to the border of the canvas.
+fn arrowhead(
+    cs: &CanvasSpace,
+    b: &Block,
+    CanvasPoint { x, y }: CanvasPoint,
+) -> Option<(CanvasPoint, u8)> {
+    let rect = cs.to_canvas(b.pos);
+
+    let (pt, c) = if y == rect.y {
+        (CanvasPoint::new(x, y.checked_sub(1)?), b'v')
+    } else if y == rect.y + rect.height - 1 {
+        (CanvasPoint::new(x, y + 1), b'^')
+    } else if x == rect.x {
+        (CanvasPoint::new(x.checked_sub(1)?, y), b'>')
+    } else {
+        (CanvasPoint::new(x + 1, y), b'<')
+    };
+    Some((pt, c)).filter(|(pt, _)| pt.x < cs.canvas_width() && pt.y < cs.canvas_height())
+}
+
+/// Draw the outline and the text of all the blocks on a new canvas.
+fn draw_blocks(cs: &CanvasSpace, boxes: &[Block], config: &RenderOptions) -> Canvas {
     let mut canvas = Canvas::new(cs.canvas_width(), cs.canvas_height());
 
-    for b in boxes {
-        let x = cs.column_x(b.column);
-        let y = cs.row_y(b.row);
-        let w = cs.column_width(b.column);
-        let h = cs.row_height(b.row);
+    // blocks at the same position are drawn over each other in increasing z order
+    let mut order = (0..boxes.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| boxes[i].z);
+
+    for i in order {
+        let b = &boxes[i];
+        let CanvasRect {
+            x,
+            y,
+            width: w,
+            height: h,
+        } = cs.block_rect(boxes, i);
+        let overlay = cs.is_overlay(i);
+
+        // whatever is below a block drawn over another one is hidden
+        if overlay {
+            canvas.fill_rect(x, y, w, h, b' ');
+        }
+
+        // the fill is drawn first so that the outline and the text are drawn over it
+        let text_h = match (overlay, b.footer.is_empty()) {
+            (false, _) => cs.row_body_height(b.pos.row),
+            (true, true) => h,
+            (true, false) => h.saturating_sub(b.footer.len() + 1).max(3),
+        };
+        if let Some(c) = b.fill {
+            canvas.fill_rect(x + 1, y + 1, w - 2, text_h - 2, c);
+        }
+
+        match b.shape {
+            Shape::Rectangle => canvas.draw_rect_outline(x, y, w, h),
+            Shape::Note => canvas.draw_note_outline(x, y, w, h),
+            Shape::External => canvas.draw_external_outline(x, y, w, h),
+        }
+        if b.dashed {
+            canvas.dash_rect_outline(x, y, w, h);
+        }
+
+        let rtl = b.text_direction == TextDirection::RightToLeft;
+        let body = if b.vertical {
+            text::vertical(&b.text, b.text_direction)
+        } else {
+            b.text.clone()
+        };
+
+        // the text might not fit in blocks with an explicit size, the padding is sacrificed
+        // before the text in that case
+        let inner_w = w - 2;
+        let pad_w = config
+            .padding
+            .min(inner_w.saturating_sub(text_width(&body)) / 2);
 
-        canvas.draw_rect_outline(x, y, w, h);
+        // the footer sits at the bottom of the block right below a separator, while the text is
+        // centered in the remaining space
+        if !b.footer.is_empty() {
+            canvas.draw_horizontal_line(y + text_h - 1, (x, x + w - 1));
+
+            let mut footer = text::fit(&b.footer, inner_w - 2 * pad_w, b.footer.len(), b.overflow);
+            if rtl {
+                footer = text::right_to_left(&footer);
+            }
+            let xoff = (w - text_width(&footer)) / 2;
+            for (ty, t) in footer.iter().enumerate() {
+                canvas.draw_text(x + xoff, y + text_h + ty, t);
+            }
+        }
+
+        let text = text::fit(&body, inner_w - 2 * pad_w, usize::MAX, b.overflow);
+        let inner_h = text_h - 2;
+        let pad_h = config.padding.min(inner_h.saturating_sub(text.len()) / 2);
+        let mut text = text::fit(&text, inner_w - 2 * pad_w, inner_h - 2 * pad_h, b.overflow);
+
+        // vertical text already follows the text direction, see `text::vertical`
+        if rtl && !b.vertical {
+            text = text::right_to_left(&text);
+        }
 
         // center text horizontally and vertically
-        let xoff = (w - b.text_width) / 2;
-        let yoff = (h - b.text_height) / 2;
+        let xoff = (w - text_width(&text)) / 2;
+        let yoff = (text_h - text.len()) / 2;
 
-        for (ty, t) in b.text.iter().enumerate() {
+        for (ty, t) in text.iter().enumerate() {
             canvas.draw_text(x + xoff, y + yoff + ty, t);
         }
-    }
 
-    for poly in find_edges(&canvas, &cs, boxes, edges, &config) {
-        for l in poly {
-            l.draw(&mut canvas);
+        for pt in cs.shadow(boxes, i) {
+            canvas.set(pt, b'#');
         }
     }
 
-    canvas.canvas
+    canvas
+}
+
+fn text_width(text: &[Vec<u8>]) -> usize {
+    text.iter().map(|l| l.len()).max().unwrap_or(0)
 }
 
 #[macro_export]
@@ -95,13 +733,13 @@ mod tests {
     #[test]
     fn test_basic_diagram() {
         let boxes = [
-            Block::new((-1, -1), b"ciao mondo"),
-            Block::new((0, 0), b"center"),
-            Block::new((1, -1), b"yolo"),
-            Block::new((-1, 0), b"l'ultimo dell'anno"),
-            Block::new((1, 1), b"cacca"),
-            Block::new((-1, 1), b"yolo\nfoo\nbar"),
-            Block::new((-1, 2), b"aperitivo della vittoria"),
+            Block::from_str((-1, -1), "ciao mondo"),
+            Block::from_str((0, 0), "center"),
+            Block::from_str((1, -1), "yolo"),
+            Block::from_str((-1, 0), "l'ultimo dell'anno"),
+            Block::from_str((1, 1), "cacca"),
+            Block::from_str((-1, 1), "yolo\nfoo\nbar"),
+            Block::from_str((-1, 2), "aperitivo della vittoria"),
         ];
         let edges = [
             (0, 2),
@@ -114,15 +752,14 @@ mod tests {
             (4, 1),
         ];
 
-        let canvas = render(
+        let canvas = render_rows(
             &boxes,
             edges.iter().copied(),
             RenderOptions {
-                hmargin: 5,
                 vmargin: 2,
-                padding: 1,
                 seed: Some(0),
                 max_tweaks: 0,
+                ..RenderOptions::default()
             },
         );
 
@@ -158,26 +795,26 @@ mod tests {
     #[test]
     fn test_aoc2019_day25_diagram() {
         let boxes = [
-            Block::new((0, 0), b"hull breach"),
-            Block::new((-1, 0), b"hot chocolate fountain"),
-            Block::new((-1, -1), b"arcade"),
-            Block::new((-2, -1), b"warp drive maintenance room"),
-            Block::new((-1, 1), b"sick bay"),
-            Block::new((-1, 2), b"gift wrapping center"),
-            Block::new((-1, 3), b"navigation"),
-            Block::new((0, 1), b"observatory"),
-            Block::new((0, 2), b"storage room"),
-            Block::new((1, 0), b"hallway"),
-            Block::new((1, -1), b"holodeck"),
-            Block::new((1, -2), b"stables"),
-            Block::new((2, 0), b"passages"),
-            Block::new((1, 1), b"science lab"),
-            Block::new((2, 1), b"kitchen"),
-            Block::new((1, 2), b"corridor"),
-            Block::new((2, 2), b"engineering room"),
-            Block::new((2, 4), b"crew quarters"),
-            Block::new((1, 4), b"security checkpoint"),
-            Block::new((1, 3), b"activation pad"),
+            Block::from_str((0, 0), "hull breach"),
+            Block::from_str((-1, 0), "hot chocolate fountain"),
+            Block::from_str((-1, -1), "arcade"),
+            Block::from_str((-2, -1), "warp drive maintenance room"),
+            Block::from_str((-1, 1), "sick bay"),
+            Block::from_str((-1, 2), "gift wrapping center"),
+            Block::from_str((-1, 3), "navigation"),
+            Block::from_str((0, 1), "observatory"),
+            Block::from_str((0, 2), "storage room"),
+            Block::from_str((1, 0), "hallway"),
+            Block::from_str((1, -1), "holodeck"),
+            Block::from_str((1, -2), "stables"),
+            Block::from_str((2, 0), "passages"),
+            Block::from_str((1, 1), "science lab"),
+            Block::from_str((2, 1), "kitchen"),
+            Block::from_str((1, 2), "corridor"),
+            Block::from_str((2, 2), "engineering room"),
+            Block::from_str((2, 4), "crew quarters"),
+            Block::from_str((1, 4), "security checkpoint"),
+            Block::from_str((1, 3), "activation pad"),
         ];
         let edges = [
             (0, 1),
@@ -201,15 +838,14 @@ mod tests {
             (18, 19),
         ];
 
-        let canvas = render(
+        let canvas = render_rows(
             &boxes,
             edges.iter().copied(),
             RenderOptions {
-                hmargin: 5,
                 vmargin: 2,
-                padding: 1,
                 seed: Some(0),
                 max_tweaks: 0,
+                ..RenderOptions::default()
             },
         );
 
@@ -259,25 +895,23 @@ mod tests {
     #[test]
     fn test_lines_do_not_touch_walls_if_enough_margin() {
         let blocks = [
-            Block::new((0, 0), b"zero"),
-            Block::new((0, 1), b"one"),
-            Block::new((0, 2), b"two"),
-            Block::new((1, 2), b"four"),
-            Block::new((1, 0), b"0000"),
-            Block::new((2, 0), b"oooo"),
+            Block::from_str((0, 0), "zero"),
+            Block::from_str((0, 1), "one"),
+            Block::from_str((0, 2), "two"),
+            Block::from_str((1, 2), "four"),
+            Block::from_str((1, 0), "0000"),
+            Block::from_str((2, 0), "oooo"),
         ];
 
         let edges = [(1, 3), (2, 0), (1, 4), (0, 5)];
 
-        let canvas = render(
+        let canvas = render_rows(
             &blocks,
             edges.iter().copied(),
             RenderOptions {
-                hmargin: 5,
-                vmargin: 3,
-                padding: 1,
                 seed: Some(0),
                 max_tweaks: 0,
+                ..RenderOptions::default()
             },
         );
 
@@ -292,11 +926,11 @@ mod tests {
    | |      |   | |     |     |      |     
    | +------+   | +--+--+     +------+     
    |            |    |                     
-   |     +------+    |                     
-   |     |           |                     
-   | +---+--+        |        +------+     
-   | |      |        |        |      |     
-   | | 0000 |        +--------+ four |     
+   |            |    |                     
+   |            |    |                     
+   | +------+   |    |        +------+     
+   | |      |   |    |        |      |     
+   | | 0000 +---+    +--------+ four |     
    | |      |                 |      |     
    | +------+                 +------+     
    |                                       
@@ -316,23 +950,21 @@ mod tests {
     #[test]
     fn test_diagram_avoid_intersections_with_straight_line() {
         let blocks = [
-            Block::new((0, 0), b"left"),
-            Block::new((0, 1), b"center"),
-            Block::new((0, 2), b"right"),
-            Block::new((1, 1), b"bottom"),
+            Block::from_str((0, 0), "left"),
+            Block::from_str((0, 1), "center"),
+            Block::from_str((0, 2), "right"),
+            Block::from_str((1, 1), "bottom"),
         ];
 
         let edges = [(0, 2), (1, 3)];
 
-        let canvas = render(
+        let canvas = render_rows(
             &blocks,
             edges.iter().copied(),
             RenderOptions {
-                hmargin: 5,
-                vmargin: 3,
-                padding: 1,
                 seed: Some(0),
                 max_tweaks: 0,
+                ..RenderOptions::default()
             },
         );
 
@@ -359,4 +991,986 @@ mod tests {
                                                "#
         );
     }
+
+    #[test]
+    fn test_block_fixed_size() {
+        let blocks = [
+            Block::from_str((0, 0), "a rather long description of the block").with_width(12),
+            Block::from_str((0, 1), "short")
+                .with_width(12)
+                .with_height(4),
+            Block::from_str((1, 0), "one\ntwo\nthree\nfour").with_height(5),
+        ];
+
+        let canvas = render_rows(
+            &blocks,
+            [(0, 1), (0, 2)].iter().copied(),
+            RenderOptions {
+                hmargin: 3,
+                vmargin: 1,
+                seed: Some(0),
+                max_tweaks: 0,
+                ..RenderOptions::default()
+            },
+        );
+
+        assert_diagram_eq!(
+            canvas,
+            br#"                                 
+   +----------+   +----------+   
+   |          |   |          |   
+   |a rather  |   |          |   
+   |long      |   |          |   
+   |descrip...+---+  short   |   
+   |of the    |   |          |   
+   |block     |   |          |   
+   |          |   |          |   
+   +-----+----+   +----------+   
+         |                       
+   +-----+----+                  
+   | one      |                  
+   | two      |                  
+   | three... |                  
+   +----------+                  
+                                 "#
+        );
+    }
+
+    #[test]
+    fn test_text_direction() {
+        let blocks = [
+            Block::from_str((0, 0), "hello\nab")
+                .with_footer(b"v1")
+                .with_text_direction(TextDirection::RightToLeft),
+            Block::from_str((0, 1), "axis\ny").with_vertical_text(true),
+            Block::from_str((0, 2), "axis\ny")
+                .with_vertical_text(true)
+                .with_text_direction(TextDirection::RightToLeft),
+        ];
+
+        let canvas = render_rows(
+            &blocks,
+            [(0, 1), (1, 2)].iter().copied(),
+            RenderOptions {
+                hmargin: 3,
+                vmargin: 1,
+                seed: Some(0),
+                max_tweaks: 0,
+                ..RenderOptions::default()
+            },
+        );
+
+        assert_diagram_eq!(
+            canvas,
+            br#"                                   
+   +-------+   +-----+   +-----+   
+   |       |   |     |   |     |   
+   |       |   | a y |   | y a |   
+   | olleh |   | x   |   |   x |   
+   |    ba +---+ i   +---+   i |   
+   |       |   | s   |   |   s |   
+   |       |   |     |   |     |   
+   +-------+   |     |   |     |   
+   |  1v   |   |     |   |     |   
+   +-------+   +-----+   +-----+   
+                                   "#
+        );
+    }
+
+    #[test]
+    fn test_gutters() {
+        let blocks = [
+            Block::from_str((1, 0), "0"),
+            Block::from_str((0, 0), "1"),
+            Block::from_str((0, 1), "2"),
+            Block::from_str((2, 2), "3"),
+            Block::from_str((2, 0), "4"),
+            Block::from_str((1, 1), "5"),
+        ];
+        let edges = [(1, 4), (5, 1), (2, 0)];
+        let config = |gutter_threshold| RenderOptions {
+            hmargin: 2,
+            vmargin: 1,
+            padding: 0,
+            seed: Some(0),
+            max_tweaks: 0,
+            gutter_threshold,
+            ..RenderOptions::default()
+        };
+
+        let canvas = render_rows(&blocks, edges.iter().copied(), config(None));
+        assert_diagram_eq!(
+            canvas,
+            br#" +----+          
+ |+-+ |+-+       
+ ++1++||2|       
+  +-+||+++       
+     |+-+-+      
+  +-+| +++|      
+  |0+-+|5||      
+  +-+||+-+|      
+     |+---+      
+  +-+|      +-+  
+  |4++      |3|  
+  +-+       +-+  
+                 "#
+        );
+
+        // an empty column is inserted between the first two columns to remove the crossing
+        let canvas = render_rows(&blocks, edges.iter().copied(), config(Some(0)));
+        assert_diagram_eq!(
+            canvas,
+            br#" +----+ +---+      
+ |+-+ | |+-+|      
+ ++1++| ++2||      
+  +-+||  +-+|      
+ +-+ |+---+ |      
+ |+++|   +++|      
+ ||0||   |5||      
+ |+-+|   +-+|      
+ |   |      |      
+ |+-+|      | +-+  
+ ||4++      | |3|  
+ |+-+       | +-+  
+ +----------+      "#
+        );
+    }
+
+    #[test]
+    fn test_directed_edges() {
+        let blocks = [
+            Block::from_str((0, 0), "center"),
+            Block::from_str((-1, 0), "up"),
+            Block::from_str((1, 0), "down"),
+            Block::from_str((0, -1), "left"),
+            Block::from_str((0, 1), "right"),
+        ];
+        let edges = (1..blocks.len()).map(|i| Edge::new(0, i).with_directed(true));
+
+        let canvas = render_rows(
+            &blocks,
+            edges,
+            RenderOptions {
+                hmargin: 3,
+                vmargin: 2,
+                padding: 0,
+                seed: Some(0),
+                max_tweaks: 0,
+                ..RenderOptions::default()
+            },
+        );
+
+        assert_diagram_eq!(
+            canvas,
+            br#"                                 
+                                 
+            +------+             
+            |  up  |             
+            +---+--+             
+                ^                
+                |                
+   +----+   +---+--+   +-----+   
+   |left+<--+center+-->+right|   
+   +----+   +---+--+   +-----+   
+                |                
+                v                
+            +---+--+             
+            | down |             
+            +------+             
+                                 
+                                 "#
+        );
+    }
+
+    #[test]
+    fn test_bidirectional_edges() {
+        let blocks = [
+            Block::from_str((0, 0), "client"),
+            Block::from_str((0, 1), "server"),
+            Block::from_str((1, 0), "cache"),
+        ];
+        let edges = [
+            Edge::new(0, 1).with_bidirectional(true),
+            Edge::new(0, 2).with_directed(true).with_bidirectional(true),
+        ];
+
+        let canvas = render_rows(
+            &blocks,
+            edges.iter().cloned(),
+            RenderOptions {
+                hmargin: 3,
+                vmargin: 3,
+                padding: 0,
+                seed: Some(0),
+                max_tweaks: 0,
+                ..RenderOptions::default()
+            },
+        );
+
+        assert_diagram_eq!(
+            canvas,
+            br#"                         
+                         
+                         
+   +------+   +------+   
+   |client+<->+server|   
+   +---+--+   +------+   
+       ^                 
+       |                 
+       v                 
+   +---+--+              
+   |cache |              
+   +------+              
+                         
+                         
+                         "#
+        );
+    }
+
+    #[test]
+    fn test_block_footer() {
+        let blocks = [
+            Block::from_str((0, 0), "service").with_footer(b"v1.2.3"),
+            Block::from_str((0, 1), "database").with_footer(b"owner: ops\nhourly backups"),
+        ];
+
+        let canvas = render_rows(
+            &blocks,
+            [(0, 1)].iter().copied(),
+            RenderOptions {
+                hmargin: 3,
+                vmargin: 1,
+                seed: Some(0),
+                max_tweaks: 0,
+                ..RenderOptions::default()
+            },
+        );
+
+        assert_diagram_eq!(
+            canvas,
+            br#"                                      
+   +---------+   +----------------+   
+   |         |   |                |   
+   | service +---+    database    |   
+   |         |   |                |   
+   +---------+   +----------------+   
+   | v1.2.3  |   | owner: ops     |   
+   |         |   | hourly backups |   
+   +---------+   +----------------+   
+                                      "#
+        );
+    }
+
+    #[test]
+    fn test_note_block() {
+        let blocks = [
+            Block::from_str((0, 0), "server"),
+            Block::from_str((0, 1), "restarts\nnightly").with_shape(Shape::Note),
+        ];
+
+        let canvas = render_rows(
+            &blocks,
+            [(0, 1)].iter().copied(),
+            RenderOptions {
+                hmargin: 3,
+                vmargin: 1,
+                seed: Some(0),
+                max_tweaks: 0,
+                ..RenderOptions::default()
+            },
+        );
+
+        assert_diagram_eq!(
+            canvas,
+            br#"                               
+   +--------+   +--------+\    
+   |        |   |        |_\   
+   | server |   | restarts |   
+   |        +---+ nightly  |   
+   |        |   |          |   
+   +--------+   +----------+   
+                               "#
+        );
+    }
+
+    #[test]
+    fn test_block_corner_text() {
+        let blocks = [
+            Block::from_str((0, 0), "a").with_corner_text(b"[1]"),
+            Block::from_str((0, 1), "longer text").with_corner_text(b"[2]"),
+            Block::from_str((1, 0), "c").with_corner_text(b"[3]"),
+        ];
+
+        let canvas = render_rows(
+            &blocks,
+            [(0, 1), (0, 2)].iter().copied(),
+            RenderOptions {
+                hmargin: 3,
+                vmargin: 1,
+                seed: Some(0),
+                max_tweaks: 0,
+                ..RenderOptions::default()
+            },
+        );
+
+        assert_diagram_eq!(
+            canvas,
+            br#"                                 
+   +[1]----+   +[2]----------+   
+   |       |   |             |   
+   |   a   +---+ longer text |   
+   |       |   |             |   
+   +---+---+   +-------------+   
+       |                         
+   +[3]+---+                     
+   |       |                     
+   |   c   |                     
+   |       |                     
+   +-------+                     
+                                 "#
+        );
+    }
+
+    #[test]
+    fn test_edge_endpoint_labels() {
+        let blocks = [
+            Block::from_str((0, 0), "customer"),
+            Block::from_str((0, 1), "order"),
+            Block::from_str((1, 1), "item"),
+        ];
+        let edges = [
+            Edge::new(0, 1).with_from_label(b"1").with_to_label(b"*"),
+            Edge::new(1, 2)
+                .with_from_label(b"owner")
+                .with_to_label(b"1..n"),
+        ];
+
+        let canvas = render_rows(
+            &blocks,
+            edges.iter().cloned(),
+            RenderOptions {
+                seed: Some(0),
+                max_tweaks: 0,
+                ..RenderOptions::default()
+            },
+        );
+
+        assert_diagram_eq!(
+            canvas,
+            br#"                                    
+                                    
+                                    
+     +----------+     +-------+     
+     |          |1   *|       |     
+     | customer +-----+ order |     
+     |          |     |       |     
+     +----------+     +---+---+     
+                          |owner    
+                          |         
+                          |1..n     
+                      +---+---+     
+                      |       |     
+                      | item  |     
+                      |       |     
+                      +-------+     
+                                    
+                                    
+                                    "#
+        );
+    }
+
+    #[test]
+    fn test_edges_on_tiny_blocks() {
+        let blocks = [
+            Block::from_str((0, 0), "x"),
+            Block::from_str((0, 1), ""),
+            Block::from_str((1, 0), "ab"),
+            Block::from_str((1, 1), "y"),
+            Block::from_str((2, 2), "z"),
+        ];
+
+        let canvas = render_rows(
+            &blocks,
+            [(0, 1), (0, 2), (1, 3), (2, 4), (0, 4)].iter().copied(),
+            RenderOptions {
+                hmargin: 3,
+                vmargin: 2,
+                padding: 0,
+                seed: Some(0),
+                max_tweaks: 0,
+                ..RenderOptions::default()
+            },
+        );
+
+        assert_diagram_eq!(
+            canvas,
+            br#"                      
+ +---------------+    
+ | +--+   +-+    |    
+ +-+x +---+ |    |    
+   +-++   +++    |    
+     |     |     |    
+     |     |     |    
+   +-++   +++    |    
+   |ab|   |y|    |    
+   +-++   +-+    |    
+     |           |    
+     |           |    
+     |          +++   
+     +----------+z|   
+                +-+   
+                      
+                      "#
+        );
+    }
+
+    #[test]
+    fn test_estimate_size() {
+        let boxes = [
+            Block::from_str((0, 0), "hello"),
+            Block::from_str((0, 1), "world\nfoo").with_footer(b"bar"),
+            Block::from_str((2, 0), "x").with_width(12),
+        ];
+        let edges = [(0, 1), (1, 2), (2, 0)];
+        let config = RenderOptions {
+            vmargin: 2,
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        };
+
+        let canvas = render_rows(&boxes, edges.iter().copied(), config.clone());
+        assert_eq!(
+            estimate_size(&boxes, config.clone()),
+            (canvas[0].len(), canvas.len())
+        );
+        assert_eq!(estimate_size(&[], config), (0, 0));
+    }
+
+    #[test]
+    fn test_render_to() {
+        /// Records the writes between flushes.
+        #[derive(Default)]
+        struct Bands {
+            bands: Vec<Vec<u8>>,
+            pending: Vec<u8>,
+        }
+
+        impl Write for &mut Bands {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.pending.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                if !self.pending.is_empty() {
+                    self.bands.push(std::mem::take(&mut self.pending));
+                }
+                Ok(())
+            }
+        }
+
+        let boxes = (0..30)
+            .map(|r| Block::from_str((r, 0), "block"))
+            .collect::<Vec<_>>();
+        let edges = (1..30).map(|r| (r - 1, r)).collect::<Vec<_>>();
+        let config = RenderOptions {
+            vmargin: 2,
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        };
+
+        let mut bands = Bands::default();
+        render_to(&boxes, edges.iter().copied(), config.clone(), &mut bands).unwrap();
+
+        let canvas = render_rows(&boxes, edges.iter().copied(), config);
+        assert_eq!(bands.bands.len(), canvas.len().div_ceil(BAND_HEIGHT));
+        assert!(bands.bands[..bands.bands.len() - 1].iter().all(|b| b
+            .iter()
+            .filter(|c| **c == b'\n')
+            .count()
+            == BAND_HEIGHT));
+
+        let mut expected = canvas.join(&b"\n"[..]);
+        expected.push(b'\n');
+        assert_eq!(bands.bands.concat(), expected);
+    }
+
+    #[test]
+    fn test_render_to_string() {
+        let config = RenderOptions {
+            hmargin: 1,
+            vmargin: 0,
+            padding: 0,
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        };
+
+        // characters other than printable ASCII are dropped
+        let boxes = [
+            Block::from_str((0, 0), "caffè"),
+            Block::from_str((0, 1), "b"),
+        ];
+        assert_eq!(
+            render_to_string(&boxes, [(0, 1)], config.clone()),
+            vec![" +----+ +-+ ", " |caff+-+b| ", " +----+ +-+ "]
+        );
+
+        #[allow(deprecated)]
+        let rows = render(&boxes, [(0, 1)], config);
+        assert_eq!(rows.concat(), b" +----+ +-+  |caff+-+b|  +----+ +-+ ");
+    }
+
+    #[test]
+    fn test_routing_is_deterministic() {
+        // a ring of blocks with a block inside connected to one outside, the edge must cross the
+        // ring and routing is tweaked to find better solutions
+        let mut boxes = [
+            (0, 0),
+            (0, 1),
+            (0, 2),
+            (1, 2),
+            (2, 2),
+            (2, 1),
+            (2, 0),
+            (1, 0),
+        ]
+        .iter()
+        .map(|&p| Block::from_str(p, "r"))
+        .collect::<Vec<_>>();
+        boxes.push(Block::from_str((1, 1), "in"));
+        boxes.push(Block::from_str((1, 3), "out"));
+
+        let mut edges = (0..8)
+            .map(|i| Edge::new(i, (i + 1) % 8))
+            .collect::<Vec<_>>();
+        edges.push(Edge::new(8, 9));
+
+        let config = RenderOptions {
+            hmargin: 2,
+            vmargin: 1,
+            padding: 0,
+            seed: Some(42),
+            max_tweaks: 3,
+            gutter_threshold: Some(2),
+            ..RenderOptions::default()
+        };
+
+        let crossings = crossing_points(&layout(&boxes, &edges, &config).unwrap().2);
+        assert!(!crossings.is_empty());
+
+        let expected = render_rows(&boxes, edges.clone(), config.clone());
+        for _ in 0..100 {
+            assert_eq!(
+                crossing_points(&layout(&boxes, &edges, &config).unwrap().2),
+                crossings
+            );
+            assert_eq!(render_rows(&boxes, edges.clone(), config.clone()), expected);
+        }
+    }
+
+    #[test]
+    fn test_symmetric_edges() {
+        let blocks = [
+            Block::from_str((0, 0), "client"),
+            Block::from_str((0, 2), "cli"),
+            Block::from_str((1, 1), "server"),
+        ];
+        let edges = [Edge::new(0, 2), Edge::new(1, 2)];
+        let config = RenderOptions {
+            hmargin: 3,
+            vmargin: 2,
+            padding: 0,
+            seed: Some(0),
+            max_tweaks: 0,
+            symmetric: true,
+            ..RenderOptions::default()
+        };
+
+        // the columns of the mirrored blocks are as wide and the edge of `cli` is the reflection
+        // of the one of `client`, rather than being attached right in the middle of `cli`
+        let canvas = render_rows(&blocks, edges.clone(), config);
+        assert_diagram_eq!(
+            canvas,
+            br#"                                    
+                                    
+   +------+              +------+   
+   |client|              | cli  |   
+   +---+--+              +--+---+   
+       |                    |       
+       |                    |       
+       |      +------+      |       
+       +------+server+------+       
+              +------+              
+                                    
+                                    "#
+        );
+
+        // without text every row reads the same backwards
+        for row in canvas {
+            let row = row
+                .iter()
+                .map(|c| if c.is_ascii_alphabetic() { b' ' } else { *c })
+                .collect::<Vec<_>>();
+            assert_eq!(row.iter().rev().copied().collect::<Vec<_>>(), row);
+        }
+    }
+
+    #[test]
+    fn test_edge_groups() {
+        let blocks = [
+            Block::from_str((0, 0), "api"),
+            Block::from_str((0, 1), "worker"),
+            Block::from_str((0, 2), "cron"),
+            Block::from_str((0, 3), "web"),
+            Block::from_str((2, 1), "log sink"),
+        ];
+        let edges =
+            |group| (0..4).map(move |i| Edge::new(i, 4).with_group(group).with_directed(true));
+        let config = RenderOptions {
+            hmargin: 3,
+            vmargin: 2,
+            padding: 0,
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        };
+
+        let canvas = render_rows(&blocks, edges(""), config.clone());
+        assert_diagram_eq!(
+            canvas,
+            br#"                                         
+                                         
+   +---+   +--------+   +----+   +---+   
+   |api|   | worker |   |cron|   |web|   
+   +-+-+   +----+---+   +--+-+   +-+-+   
+     |          |          |       |     
+     |          |          |       |     
+     |          |          |       |     
+     |          v          |       |     
+     |     +----+---+      |       |     
+     +---->+log sink+<-----+       |     
+          |+--------+              |     
+          +------------------------+     
+                                         "#
+        );
+
+        // the edge from `web` joins the one from `cron` instead of going around the sink
+        let canvas = render_rows(&blocks, edges("events"), config);
+        assert_diagram_eq!(
+            canvas,
+            br#"                                         
+                                         
+   +---+   +--------+   +----+   +---+   
+   |api|   | worker |   |cron|   |web|   
+   +-+-+   +----+---+   +--+-+   +-+-+   
+     |          |          |       |     
+     |          |          |       |     
+     |          |          |       |     
+     |          v          |       |     
+     |     +----+---+      |       |     
+     +---->+log sink+<-----*-------+     
+           +--------+                    
+                                         
+                                         "#
+        );
+    }
+
+    #[test]
+    fn test_logical_grid() {
+        let boxes = [
+            Block::from_str((0, 0), "hello"),
+            Block::from_str((2, 1), "x").with_width(12),
+            Block::from_str((2, 1), "y").with_z(1),
+        ];
+        let config = RenderOptions {
+            vmargin: 2,
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        };
+
+        // the first of the blocks at the same position is the one in the cell
+        let grid = logical_grid(&boxes, config.clone());
+        assert_eq!((grid.min_column, grid.min_row), (0, 0));
+        assert_eq!(grid.columns_width, vec![9, 12]);
+        assert_eq!(grid.rows_height, vec![5, 0, 5]);
+        assert_eq!(
+            grid.cells,
+            vec![vec![Some(0), None], vec![None, None], vec![None, Some(1)]]
+        );
+        assert_eq!(logical_grid(&[], config).cells, Vec::<Vec<_>>::new());
+    }
+
+    #[test]
+    fn test_overlapping_blocks() {
+        let config = RenderOptions {
+            hmargin: 1,
+            vmargin: 1,
+            padding: 0,
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        };
+
+        let blocks = [
+            Block::from_str((0, 0), "a"),
+            Block::from_str((0, 1), "b"),
+            Block::from_str((1, 0), "c"),
+        ];
+        assert_eq!(overlapping_blocks(&blocks, config.clone()), vec![]);
+
+        let blocks = [
+            Block::from_str((0, 1), "b"),
+            Block::from_str((0, 0), "a"),
+            Block::from_str((0, 1), "bb"),
+        ];
+        let rect = CanvasRect {
+            x: 5,
+            y: 1,
+            width: 4,
+            height: 3,
+        };
+        assert_eq!(
+            overlapping_blocks(&blocks, config),
+            vec![Overlap {
+                blocks: (0, 2),
+                rects: (rect, rect),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_shared_borders() {
+        let blocks = [
+            Block::from_str((0, 0), "api"),
+            Block::from_str((0, 1), "db"),
+            Block::from_str((1, 0), "web"),
+            Block::from_str((1, 1), "cache"),
+        ];
+        let edges = vec![
+            Edge::new(0, 1).with_directed(true),
+            Edge::new(2, 0),
+            Edge::new(1, 3),
+        ];
+        let config = RenderOptions {
+            hmargin: 0,
+            vmargin: 0,
+            padding: 0,
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        };
+
+        // adjacent blocks share their borders and the edges between them are junctions on them
+        assert_eq!(overlapping_blocks(&blocks, config.clone()), vec![]);
+        let canvas = render_rows(&blocks, edges.clone(), config.clone());
+        assert_diagram_eq!(
+            canvas,
+            br#"+---+-----+
+|api+ db  |
++-+-+--+--+
+|web|cache|
++---+-----+"#
+        );
+
+        let layout = render_layout(&blocks, edges.clone(), config.clone());
+        assert_eq!(
+            render_with_layout(&blocks, edges.clone(), &layout, config.clone()).unwrap(),
+            canvas
+        );
+
+        // a single character of margin is a single lane for the lines
+        let config = RenderOptions {
+            hmargin: 1,
+            vmargin: 1,
+            ..config
+        };
+        let canvas = render_rows(&blocks, edges, config);
+        assert_diagram_eq!(
+            canvas,
+            br#"               
+ +---+ +-----+ 
+ |api+>+ db  | 
+ +-+-+ +--+--+ 
+   |      |    
+ +-+-+ +--+--+ 
+ |web| |cache| 
+ +---+ +-----+ 
+               "#
+        );
+    }
+
+    #[test]
+    fn test_block_z() {
+        let blocks = [
+            Block::from_str((0, 0), "client"),
+            Block::from_str((0, 1), "storage").with_height(7),
+            Block::from_str((0, 1), "v2").with_z(1),
+        ];
+        let edges = vec![Edge::new(0, 1)];
+        let config = RenderOptions {
+            hmargin: 3,
+            vmargin: 1,
+            padding: 0,
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        };
+
+        // the badge is drawn over the top right corner of the block below it
+        assert_eq!(overlapping_blocks(&blocks, config.clone()), vec![]);
+        let canvas = render_rows(&blocks, edges, config);
+        assert_diagram_eq!(
+            canvas,
+            br#"                          
+   +------+   +----+--+   
+   |      |   |    |v2|   
+   |      |   |    +--+   
+   |client+---+storage|   
+   |      |   |       |   
+   |      |   |       |   
+   +------+   +-------+   
+                          "#
+        );
+    }
+
+    #[test]
+    fn test_shadow() {
+        let blocks = [
+            Block::from_str((0, 0), "client").with_shadow(true),
+            Block::from_str((0, 1), "server"),
+            Block::from_str((1, 0), "cache"),
+        ];
+        let edges = vec![Edge::new(0, 1), Edge::new(0, 2), Edge::new(2, 1)];
+        let config = RenderOptions {
+            hmargin: 3,
+            vmargin: 1,
+            padding: 0,
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        };
+
+        // lines go through the shadow only right next to the block casting it
+        let canvas = render_rows(&blocks, edges, config);
+        assert_diagram_eq!(
+            canvas,
+            br#"                          
+   +------+    +------+   
+   |client+----+server|   
+   +---+--+#   +---+--+   
+    ###|####       |      
+       |           |      
+   +---+--+        |      
+   |cache +--------+      
+   +------+               
+                          "#
+        );
+    }
+
+    #[test]
+    fn test_dashed() {
+        let blocks = [
+            Block::from_str((0, 0), "client"),
+            Block::from_str((0, 1), "legacy").with_dashed(true),
+            Block::from_str((1, 0), "server"),
+        ];
+        let edges = vec![
+            Edge::new(0, 1).with_dashed(true),
+            Edge::new(0, 2).with_dashed(true),
+        ];
+        let config = RenderOptions {
+            hmargin: 6,
+            vmargin: 2,
+            padding: 0,
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        };
+
+        assert_diagram_eq!(
+            render_rows(&blocks, edges, config),
+            br#"                                  
+                                  
+      +------+      +- - - +      
+      |client+ - - -+legacy:      
+      +---+--+      +- - - +      
+          :                       
+          :                       
+      +---+--+                    
+      |server|                    
+      +------+                    
+                                  
+                                  "#
+        );
+    }
+
+    #[test]
+    fn test_arrowheads_at_canvas_border() {
+        let blocks = [
+            Block::from_str((0, 0), "a"),
+            Block::from_str((0, 1), "b"),
+            Block::from_str((0, 2), "c"),
+        ];
+        let edges = [(0, 1), (1, 2), (2, 0), (0, 2)]
+            .iter()
+            .map(|&(from, to)| Edge::new(from, to).with_directed(true))
+            .collect::<Vec<_>>();
+        let config = RenderOptions {
+            hmargin: 4,
+            vmargin: 0,
+            seed: Some(0),
+            max_tweaks: 1,
+            route_failure: RouteFailure::Force,
+            ..RenderOptions::default()
+        };
+
+        // the edges forced over the top border of the blocks have no room for an arrowhead
+        assert_diagram_eq!(
+            render_rows(&blocks, edges, config),
+            br#"    +--!!---++---+   +!!--+    
+    |   |   ||   |   ||   |    
+    | a +--->+ b +--->+ c |    
+    |   |   ||   |   ||   |    
+    +--!!---+!!!!!---+!!--+    "#
+        );
+    }
+
+    #[test]
+    fn test_max_tweak_time() {
+        // a ring of blocks with a block inside connected to a block outside, the edge always
+        // crosses the ring so the tweaks would never stop on their own
+        let mut blocks = [
+            (0, 0),
+            (0, 1),
+            (0, 2),
+            (1, 2),
+            (2, 2),
+            (2, 1),
+            (2, 0),
+            (1, 0),
+        ]
+        .iter()
+        .map(|&p| Block::from_str(p, "r"))
+        .collect::<Vec<_>>();
+        blocks.push(Block::from_str((1, 1), "in"));
+        blocks.push(Block::from_str((1, 3), "out"));
+        let mut edges = (0..8).map(|i| (i, (i + 1) % 8)).collect::<Vec<_>>();
+        edges.push((8, 9));
+
+        let config = RenderOptions {
+            seed: Some(0),
+            max_tweaks: usize::MAX,
+            max_tweak_time: Some(Duration::from_millis(200)),
+            gutter_threshold: Some(0),
+            ..RenderOptions::default()
+        };
+
+        let start = Instant::now();
+        render_rows(&blocks, edges, config);
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
 }
@@ -0,0 +1,481 @@
+use std::collections::HashMap;
+
+use crate::render::estimate_size;
+use crate::render::suggest::{measure, search_options, swap_positions};
+use crate::render::text;
+use crate::{Block, Edge, LogicalCoord, LogicalPos, RenderOptions};
+
+/// Maximum number of moves applied to a layout.
+const MAX_ROUNDS: usize = 10;
+
+/// Maximum number of candidate layouts rendered by `optimize_layout` over all the rounds.
+const MAX_LAYOUT_RENDERS: usize = 100;
+
+/// Maximum number of times the margins are increased by `auto_margins`.
+const MAX_MARGINS_PASSES: usize = 8;
+
+/// Blocks are never made narrower than this by `fit_to_width`, borders included.
+const MIN_FIT_BLOCK_WIDTH: usize = 8;
+
+/// Move the blocks around to minimize the crossings and then the total length of the edges,
+/// keeping the blocks in the same order as `boxes`.
+///
+/// Blocks are only moved along their row or column, either by swapping them with another block
+/// or by moving them to an empty cell, or by swapping whole rows or columns. At each round the
+/// move that improves the layout the most is applied, until there are no more improvements.
+///
+/// Pinned blocks are never moved and blocks with an order are kept in their row and in sequence,
+/// see `Block::with_pinned` and `Block::with_order`.
+///
+/// This requires rendering the diagram many times, so the candidates are rendered without
+/// tweaks, see `search_options`, and the search stops after `MAX_LAYOUT_RENDERS` of them.
+pub fn optimize_layout(
+    boxes: &[Block],
+    edges: impl IntoIterator<Item = impl Into<Edge>>,
+    config: RenderOptions,
+) -> Vec<Block> {
+    let edges = edges.into_iter().map(Into::into).collect::<Vec<_>>();
+    let config = search_options(&config);
+
+    let mut best = boxes.to_vec();
+    if best.is_empty() || edges.is_empty() {
+        return best;
+    }
+
+    // layouts where some edges can't be routed are the worst of all
+    let score = |boxes: &[Block]| {
+        measure(boxes, &edges, &config)
+            .map_or((usize::MAX, usize::MAX), |m| (m.crossings, m.length))
+    };
+    let mut best_score = score(&best);
+    let mut renders = 1;
+
+    for _ in 0..MAX_ROUNDS {
+        let mut improved = None;
+        for layout in moves(&best) {
+            if !respects_constraints(boxes, &layout) {
+                continue;
+            }
+            if renders == MAX_LAYOUT_RENDERS {
+                break;
+            }
+            renders += 1;
+
+            let s = score(&layout);
+            if s < improved.as_ref().map_or(best_score, |(s, _)| *s) {
+                improved = Some((s, layout));
+            }
+        }
+
+        match improved {
+            Some((s, layout)) => {
+                best = layout;
+                best_score = s;
+            }
+            None => break,
+        }
+    }
+
+    best
+}
+
+/// Increase the margins in `config` until there are no crossings and no lines running right next
+/// to blocks, or until a fixed number of passes. At each pass either the horizontal margin, the
+/// vertical one or both are increased by one, whatever improves the diagram the most, and the
+/// best margins overall are kept.
+///
+/// Return `config` with the tuned margins, the candidates are rendered without tweaks and with a
+/// fixed seed, see `search_options`, but the returned config keeps the tweaks and the seed of
+/// `config`.
+pub fn auto_margins(
+    boxes: &[Block],
+    edges: impl IntoIterator<Item = impl Into<Edge>>,
+    config: RenderOptions,
+) -> RenderOptions {
+    let edges = edges.into_iter().map(Into::into).collect::<Vec<_>>();
+    if boxes.is_empty() || edges.is_empty() {
+        return config;
+    }
+
+    let mut current = search_options(&config);
+
+    // margins with which some edges can't be routed are the worst of all
+    let score = |cfg: &RenderOptions| {
+        measure(boxes, &edges, cfg).map_or((usize::MAX, usize::MAX), |m| {
+            (m.crossings + m.hugging, m.length)
+        })
+    };
+    let mut best_score = score(&current);
+    let mut best = current.clone();
+
+    // larger margins might pay off only after a few passes, for example lines are kept away from
+    // blocks only with margins larger than 2, hence keep going even if there's no improvement
+    for _ in 0..MAX_MARGINS_PASSES {
+        if best_score.0 == 0 {
+            break;
+        }
+
+        let (cfg, s) = [(1, 0), (0, 1), (1, 1)]
+            .iter()
+            .map(|(dh, dv)| {
+                let mut cfg = current.clone();
+                cfg.hmargin += dh;
+                cfg.vmargin += dv;
+                let s = score(&cfg);
+                (cfg, s)
+            })
+            .min_by_key(|(_, s)| *s)
+            .unwrap();
+
+        if s < best_score {
+            best = cfg.clone();
+            best_score = s;
+        }
+        current = cfg;
+    }
+
+    RenderOptions {
+        hmargin: best.hmargin,
+        vmargin: best.vmargin,
+        ..config
+    }
+}
+
+/// Shrink the diagram so that it's at most `width` characters wide, if possible. First the
+/// horizontal margin is reduced down to 1 and then the widest blocks are made narrower, wrapping
+/// their text, down to `MIN_FIT_BLOCK_WIDTH`. Blocks with vertical text are left alone.
+///
+/// Return the blocks and the config to render the diagram with, they might still be too wide if
+/// there's no way to fit the diagram. This doesn't need to route any edge, see `estimate_size`.
+pub fn fit_to_width(
+    boxes: &[Block],
+    config: RenderOptions,
+    width: usize,
+) -> (Vec<Block>, RenderOptions) {
+    let fits = |boxes: &[Block], cfg: &RenderOptions| estimate_size(boxes, cfg.clone()).0 <= width;
+
+    let mut cfg = config;
+    while !fits(boxes, &cfg) && cfg.hmargin > 1 {
+        cfg.hmargin -= 1;
+    }
+
+    let block_width = |b: &Block| b.width.unwrap_or(2 + b.text_width + 2 * cfg.padding);
+    let max_width = boxes.iter().map(block_width).max().unwrap_or(0);
+
+    let mut fitted = boxes.to_vec();
+    for cap in (MIN_FIT_BLOCK_WIDTH..max_width).rev() {
+        if fits(&fitted, &cfg) {
+            break;
+        }
+
+        fitted = boxes
+            .iter()
+            .map(|b| {
+                if b.vertical || block_width(b) <= cap {
+                    return b.clone();
+                }
+
+                // the padding is sacrificed before the text, see `draw_blocks`
+                let lines = text::fit(&b.text, cap - 2, usize::MAX, b.overflow).len();
+                let height = b.height.unwrap_or(2 + lines + 2 * cfg.padding);
+                b.clone().with_width(cap).with_height(height)
+            })
+            .collect();
+    }
+
+    (fitted, cfg)
+}
+
+/// Whether `layout` doesn't move pinned blocks, it doesn't move ordered blocks to another row and
+/// it keeps in sequence the ordered blocks that are in sequence in the `original` layout.
+fn respects_constraints(original: &[Block], layout: &[Block]) -> bool {
+    let pinned_moved = original
+        .iter()
+        .zip(layout)
+        .any(|(o, b)| o.pinned && o.pos != b.pos);
+    let ordered_moved = original
+        .iter()
+        .zip(layout)
+        .any(|(o, b)| o.order.is_some() && o.pos.row != b.pos.row);
+
+    !pinned_moved
+        && !ordered_moved
+        && in_sequence(original)
+            .iter()
+            .all(|&(i, j)| layout[i].pos.col < layout[j].pos.col)
+        && stacks(layout) == stacks(original)
+}
+
+/// The index of the first block at the position of each block, so that blocks drawn over each
+/// other are kept together, see `Block::with_z`.
+fn stacks(boxes: &[Block]) -> Vec<usize> {
+    let mut first = HashMap::with_capacity(boxes.len());
+    boxes
+        .iter()
+        .enumerate()
+        .map(|(i, b)| *first.entry(b.pos).or_insert(i))
+        .collect()
+}
+
+/// The pairs of indices of ordered blocks in the same row that are in sequence from left to right,
+/// the block on the left comes first.
+fn in_sequence(boxes: &[Block]) -> Vec<(usize, usize)> {
+    let ordered = boxes
+        .iter()
+        .enumerate()
+        .filter_map(|(i, b)| Some((i, b.pos, b.order?)))
+        .collect::<Vec<_>>();
+
+    let mut pairs = vec![];
+    for (i, p0, o0) in &ordered {
+        for (j, p1, o1) in &ordered {
+            if p0.row == p1.row && p0.col < p1.col && o0 < o1 {
+                pairs.push((*i, *j));
+            }
+        }
+    }
+    pairs
+}
+
+/// All the layouts that can be reached from `boxes` with a single move, see `optimize_layout`.
+fn moves(boxes: &[Block]) -> Vec<Vec<Block>> {
+    let min_row = boxes.iter().map(|b| b.pos.row).min().unwrap();
+    let max_row = boxes.iter().map(|b| b.pos.row).max().unwrap();
+    let min_column = boxes.iter().map(|b| b.pos.col).min().unwrap();
+    let max_column = boxes.iter().map(|b| b.pos.col).max().unwrap();
+
+    let mut layouts = vec![];
+
+    for (i, b) in boxes.iter().enumerate() {
+        let along_row = (min_column..=max_column)
+            .filter(|&c| c != b.pos.col)
+            .map(|c| LogicalPos::new(b.pos.row, c));
+        let along_column = (min_row..=max_row)
+            .filter(|&r| r != b.pos.row)
+            .map(|r| LogicalPos::new(r, b.pos.col));
+
+        for pos in along_row.chain(along_column) {
+            let mut layout = boxes.to_vec();
+            match boxes.iter().position(|o| o.pos == pos) {
+                // the same swap has already been considered from the other block
+                Some(j) if j < i => continue,
+                Some(j) => swap_positions(&mut layout, i, j),
+                None => layout[i].pos = pos,
+            }
+            layouts.push(layout);
+        }
+    }
+
+    let swap_lines = |layouts: &mut Vec<Vec<Block>>,
+                      min: LogicalCoord,
+                      max: LogicalCoord,
+                      coord: fn(&mut Block) -> &mut LogicalCoord| {
+        for l0 in min..=max {
+            for l1 in l0 + 1..=max {
+                let mut layout = boxes.to_vec();
+                for b in &mut layout {
+                    let c = coord(b);
+                    if *c == l0 {
+                        *c = l1;
+                    } else if *c == l1 {
+                        *c = l0;
+                    }
+                }
+                layouts.push(layout);
+            }
+        }
+    };
+    swap_lines(&mut layouts, min_row, max_row, |b| &mut b.pos.row);
+    swap_lines(&mut layouts, min_column, max_column, |b| &mut b.pos.col);
+
+    layouts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> RenderOptions {
+        RenderOptions {
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_optimize_layout_brings_connected_blocks_closer() {
+        let blocks = [
+            Block::from_str((0, 0), "a"),
+            Block::from_str((0, 1), "b"),
+            Block::from_str((0, 2), "c"),
+            Block::from_str((1, 1), "d"),
+        ];
+
+        let optimized = optimize_layout(&blocks, [(0, 2), (1, 3)].iter().copied(), config());
+        let distance = |i: usize, j: usize| optimized[i].pos.manhattan_distance(optimized[j].pos);
+
+        assert_eq!(distance(0, 2), 1);
+        assert_eq!(distance(1, 3), 1);
+    }
+
+    #[test]
+    fn test_optimize_layout_keeps_optimal_layouts() {
+        let blocks = [
+            Block::from_str((0, 0), "a"),
+            Block::from_str((0, 1), "b"),
+            Block::from_str((1, 1), "c"),
+        ];
+
+        assert_eq!(
+            optimize_layout(&blocks, [(0, 1), (1, 2)].iter().copied(), config()),
+            blocks
+        );
+    }
+
+    #[test]
+    fn test_optimize_layout_respects_constraints() {
+        // same as above, but the blocks cannot be moved freely anymore
+        let blocks = [
+            Block::from_str((0, 0), "a").with_order(0),
+            Block::from_str((0, 1), "b").with_order(1),
+            Block::from_str((0, 2), "c").with_order(2),
+            Block::from_str((1, 1), "d").with_pinned(true),
+        ];
+
+        let optimized = optimize_layout(&blocks, [(0, 2), (1, 3)].iter().copied(), config());
+
+        assert_eq!(optimized[3].pos, LogicalPos::new(1, 1));
+        assert_eq!(in_sequence(&optimized), [(0, 1), (0, 2), (1, 2)]);
+    }
+
+    #[test]
+    fn test_in_sequence() {
+        let blocks = [
+            Block::from_str((0, 0), "a").with_order(0),
+            Block::from_str((0, 1), "b").with_order(2),
+            Block::from_str((0, 2), "c").with_order(1),
+            Block::from_str((0, 3), "d"),
+            Block::from_str((1, 4), "e").with_order(3),
+        ];
+
+        assert_eq!(in_sequence(&blocks), [(0, 1), (0, 2)]);
+    }
+
+    #[test]
+    fn test_respects_constraints() {
+        let blocks = [
+            Block::from_str((0, 0), "a").with_order(1),
+            Block::from_str((0, 1), "b").with_order(0),
+            Block::from_str((0, 2), "c").with_order(2),
+            Block::from_str((1, 0), "d"),
+        ];
+        let moved = |moves: &[(usize, (LogicalCoord, LogicalCoord))]| {
+            let mut layout = blocks.to_vec();
+            for &(i, (row, col)) in moves {
+                layout[i].pos = LogicalPos::new(row, col);
+            }
+            respects_constraints(&blocks, &layout)
+        };
+
+        // a and b are out of sequence already, but c must stay after both of them
+        assert!(moved(&[(0, (0, 1)), (1, (0, 0))]));
+        assert!(moved(&[(2, (0, 3))]));
+        assert!(!moved(&[(0, (0, 3))]));
+        assert!(!moved(&[(1, (0, 3))]));
+        assert!(!moved(&[(2, (0, 0)), (0, (0, 2))]));
+
+        // ordered blocks are kept in their row, the others can move freely
+        assert!(!moved(&[(0, (1, 1))]));
+        assert!(moved(&[(3, (1, 1))]));
+    }
+
+    #[test]
+    fn test_auto_margins() {
+        let blocks = [
+            Block::from_str((0, 0), "a"),
+            Block::from_str((0, 1), "b"),
+            Block::from_str((0, 2), "c"),
+        ];
+        let mut cfg = config();
+        cfg.hmargin = 1;
+        cfg.vmargin = 1;
+        cfg.seed = None;
+        cfg.max_tweaks = 10;
+
+        // the line connecting a and c hugs b unless the vertical margin is large enough
+        let tuned = auto_margins(&blocks, [(0, 2)].iter().copied(), cfg.clone());
+        assert_eq!(
+            (tuned.hmargin, tuned.vmargin, tuned.seed, tuned.max_tweaks),
+            (1, 3, None, 10)
+        );
+
+        // nothing to improve
+        let tuned = auto_margins(&blocks, [(0, 1)].iter().copied(), cfg.clone());
+        assert_eq!(tuned, cfg);
+    }
+
+    #[test]
+    fn test_unroutable_candidates() {
+        // without margins some of the moves leave no room for the edges
+        let blocks = [
+            Block::from_str((0, 0), "a"),
+            Block::from_str((0, 2), "b"),
+            Block::from_str((1, 1), "c"),
+        ];
+        let edges = [(0, 1), (1, 2), (2, 0)];
+        let cfg = RenderOptions {
+            hmargin: 0,
+            vmargin: 0,
+            ..config()
+        };
+        assert!(measure(&blocks, &edges.map(Edge::from), &cfg).is_some());
+
+        let optimized = optimize_layout(&blocks, edges.iter().copied(), cfg.clone());
+        assert!(measure(&optimized, &edges.map(Edge::from), &cfg).is_some());
+
+        let tuned = auto_margins(&blocks, edges.iter().copied(), cfg.clone());
+        assert!(measure(&blocks, &edges.map(Edge::from), &tuned).is_some());
+
+        crate::render::suggest(&blocks, edges.iter().copied(), cfg);
+    }
+
+    #[test]
+    fn test_fit_to_width() {
+        let blocks = [
+            Block::from_str((0, 0), "a"),
+            Block::from_str((0, 1), "a rather long text that needs to be wrapped"),
+            Block::from_str((0, 2), "c"),
+        ];
+        let fits = |blocks: &[Block], cfg: &RenderOptions, width| {
+            estimate_size(blocks, cfg.clone()).0 <= width
+        };
+
+        // already fits
+        let (fitted, cfg) = fit_to_width(&blocks, config(), 100);
+        assert_eq!((fitted.as_slice(), cfg), (&blocks[..], config()));
+
+        // reducing the margins is enough
+        let (fitted, cfg) = fit_to_width(&blocks, config(), 70);
+        assert_eq!(fitted, blocks);
+        assert!(cfg.hmargin < 5 && fits(&fitted, &cfg, 70));
+
+        // the long block must be wrapped too
+        let (fitted, cfg) = fit_to_width(&blocks, config(), 40);
+        assert_eq!(cfg.hmargin, 1);
+        assert_eq!(&fitted[0], &blocks[0]);
+        assert!(fits(&fitted, &cfg, 40));
+
+        // the whole text is still there
+        let canvas =
+            crate::render::render_rows(&fitted, Vec::<(usize, usize)>::new(), cfg).concat();
+        let canvas = String::from_utf8(canvas).unwrap();
+        assert!(!canvas.contains("..."));
+        assert!(canvas.contains("wrapped"));
+
+        // impossible
+        let (fitted, cfg) = fit_to_width(&blocks, config(), 10);
+        assert_eq!(fitted[1].width, Some(MIN_FIT_BLOCK_WIDTH));
+        assert!(!fits(&fitted, &cfg, 10));
+    }
+}
@@ -0,0 +1,150 @@
+use crate::render::canvas::Canvas;
+
+/// Draw the layout of the fields of a packet like in RFCs, `bits_per_row` bits per row under a
+/// ruler numbering them, each bit two characters wide. `fields` are the names of the fields
+/// alongside their width in bits, in order, like this:
+///
+/// ```text
+///  0                   1                   2                   3
+///  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+/// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+/// |          Source Port          |       Destination Port        |
+/// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+/// |                                                               |
+/// |                        Sequence Number                        |
+/// |                                                               |
+/// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+/// ```
+///
+/// Fields spanning whole rows are drawn as a single cell, the others are split at the end of each
+/// row and named in each of their parts. Names are truncated to fit their cell. Fields without
+/// bits are ignored and nothing is drawn if `bits_per_row` is 0.
+pub fn packet(fields: &[(impl AsRef<[u8]>, usize)], bits_per_row: usize) -> Vec<Vec<u8>> {
+    let total = fields.iter().map(|(_, bits)| bits).sum::<usize>();
+    if bits_per_row == 0 || total == 0 {
+        return vec![];
+    }
+
+    // the field each bit of each row belongs to
+    let n_rows = total.div_ceil(bits_per_row);
+    let mut owners = vec![vec![None; bits_per_row]; n_rows];
+    let mut bit = 0;
+    for (i, (_, bits)) in fields.iter().enumerate() {
+        for b in bit..bit + bits {
+            owners[b / bits_per_row][b % bits_per_row] = Some(i);
+        }
+        bit += bits;
+    }
+    let owner = |r: usize, b: usize| -> Option<usize> { owners.get(r)?.get(b).copied().flatten() };
+    let left = |r: usize, b: usize| b.checked_sub(1).and_then(|b| owner(r, b));
+
+    let mut canvas = Canvas::new(2 * bits_per_row + 1, 3 + 2 * n_rows);
+
+    // the ruler, tens above units
+    for b in 0..bits_per_row {
+        if b % 10 == 0 {
+            canvas.set((2 * b + 1, 0).into(), b'0' + (b / 10 % 10) as u8);
+        }
+        canvas.set((2 * b + 1, 1).into(), b'0' + (b % 10) as u8);
+    }
+
+    for r in 0..=n_rows {
+        // the rule above row `r`, open where a field continues from the row above
+        let y = 2 + 2 * r;
+        let above = |b| r.checked_sub(1).and_then(|r| owner(r, b));
+        let above_left = |b| r.checked_sub(1).and_then(|r| left(r, b));
+        for b in 0..=bits_per_row {
+            let (al, ar, bl, br) = (above_left(b), above(b), left(r, b), owner(r, b));
+            let corner = if al == ar && ar == bl && bl == br {
+                b' '
+            } else if al == bl && ar == br {
+                b'|'
+            } else {
+                b'+'
+            };
+            canvas.set((2 * b, y).into(), corner);
+
+            if b < bits_per_row && (ar.is_some() || br.is_some()) && ar != br {
+                canvas.set((2 * b + 1, y).into(), b'-');
+            }
+        }
+
+        // the borders between the fields of the row
+        if r < n_rows {
+            for b in 0..=bits_per_row {
+                if left(r, b) != owner(r, b) {
+                    canvas.set((2 * b, y + 1).into(), b'|');
+                }
+            }
+        }
+    }
+
+    // names are centered in their cell, that is in the middle of the rows a field spans whole
+    for (i, (name, _)) in fields.iter().enumerate() {
+        let mut r = 0;
+        while r < n_rows {
+            let bits = (0..bits_per_row)
+                .filter(|&b| owner(r, b) == Some(i))
+                .collect::<Vec<_>>();
+            let (first, last) = match (bits.first(), bits.last()) {
+                (Some(&f), Some(&l)) => (f, l),
+                _ => {
+                    r += 1;
+                    continue;
+                }
+            };
+
+            let whole = |r: usize| (0..bits_per_row).all(|b| owner(r, b) == Some(i));
+            let mut end = r + 1;
+            if whole(r) {
+                while end < n_rows && whole(end) {
+                    end += 1;
+                }
+            }
+
+            let (x0, x1) = (2 * first + 1, 2 * last + 2);
+            let name = name.as_ref();
+            let name = &name[..name.len().min(x1 - x0)];
+            let x = x0 + (x1 - x0 - name.len()) / 2;
+            canvas.draw_text(x, 2 + r + end, name);
+            r = end;
+        }
+    }
+
+    canvas.into_rows()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::assert_diagram_eq;
+
+    #[test]
+    fn test_packet() {
+        let fields = [
+            ("Source Port", 16),
+            ("Destination Port", 16),
+            ("Sequence Number", 64),
+            ("Offset", 4),
+            ("Flags", 20),
+            ("Window", 16),
+        ];
+        assert_diagram_eq!(
+            packet(&fields, 32),
+            br#" 0                   1                   2                   3   
+ 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 
++-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+|          Source Port          |       Destination Port        |
++-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+|                                                               |
+|                        Sequence Number                        |
+|                                                               |
++-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+|Offset |                 Flags                 |    Window     |
++-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+|    Window     |                                                
++-+-+-+-+-+-+-+-+                                                "#
+        );
+    }
+}
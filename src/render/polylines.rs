@@ -0,0 +1,329 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::render::canvas::{Canvas, CanvasPoint};
+use crate::render::geometry::Layout;
+use crate::render::headers::spans;
+use crate::{Block, LogicalCoord, LogicalPos};
+
+/// A line drawn on a rendered diagram without being attached to any block, like a separator, an
+/// axis or an arrow pointing at something, see `add_polylines`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Polyline {
+    /// the corners of the line, each segment between them must be horizontal, vertical or at 45
+    /// degrees.
+    pub points: Vec<PolylinePoint>,
+
+    /// whether to end the line with an arrowhead.
+    pub arrow: bool,
+}
+
+/// A corner of a `Polyline`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolylinePoint {
+    /// A point of the rendered diagram.
+    Canvas(CanvasPoint),
+
+    /// The top left corner of a logical cell, in the middle of the margins around it like the
+    /// marks of `add_guides`. The corners right after the last column and row are the ones at
+    /// the other side of them.
+    Logical(LogicalPos),
+}
+
+/// Why a `Polyline` cannot be drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolylineError {
+    /// The polyline has less than two points.
+    TooShort,
+
+    /// The point at the given index is outside of the diagram.
+    Outside(usize),
+
+    /// The segment starting at the point at the given index is neither horizontal, vertical nor
+    /// at 45 degrees.
+    Slanted(usize),
+
+    /// The segment starting at the point at the given index has no length, its ends are the same
+    /// point of the diagram.
+    Empty(usize),
+
+    /// The polyline goes through the inside of the block at the given index.
+    Block(usize),
+}
+
+impl fmt::Display for PolylineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PolylineError::TooShort => write!(f, "it has less than two points"),
+            PolylineError::Outside(i) => write!(f, "point {} is outside of the diagram", i),
+            PolylineError::Slanted(i) => write!(
+                f,
+                "the segment from point {} is neither horizontal, vertical nor at 45 degrees",
+                i
+            ),
+            PolylineError::Empty(i) => write!(f, "the segment from point {} has no length", i),
+            PolylineError::Block(i) => write!(f, "it goes through block {}", i),
+        }
+    }
+}
+
+/// Draw lines that aren't attached to blocks on a rendered diagram, each ending with an
+/// arrowhead if it has `Polyline::arrow`. Where they cross the edges or each other they're drawn
+/// as `+`, while the ones that can't be drawn, for example because they go through a
+/// block, are left out and returned alongside their index.
+///
+/// `layout` must be the one the diagram was rendered with.
+pub fn add_polylines(
+    rows: Vec<Vec<u8>>,
+    layout: &Layout,
+    blocks: &[Block],
+    polylines: &[Polyline],
+) -> (Vec<Vec<u8>>, Vec<(usize, PolylineError)>) {
+    let columns = spans(blocks, layout, |b| b.pos.col, |r| (r.x, r.x + r.width));
+    let rows_spans = spans(blocks, layout, |b| b.pos.row, |r| (r.y, r.y + r.height));
+    let to_canvas = |pt: PolylinePoint| match pt {
+        PolylinePoint::Canvas(pt) => {
+            Some(pt).filter(|pt| pt.x < layout.width && pt.y < layout.height)
+        }
+        PolylinePoint::Logical(LogicalPos { row, col }) => Some(CanvasPoint::new(
+            corner(&columns, layout.width, col)?,
+            corner(&rows_spans, layout.height, row)?,
+        )),
+    };
+
+    let mut canvas = Canvas::from_rows(rows);
+    let mut failed = vec![];
+    for (i, line) in polylines.iter().enumerate() {
+        match corners(line, to_canvas, layout) {
+            Ok(points) => draw(&mut canvas, &points, line.arrow),
+            Err(e) => failed.push((i, e)),
+        }
+    }
+    (canvas.into_rows(), failed)
+}
+
+/// The corners of the given polyline on the canvas, checked to be drawable.
+fn corners(
+    line: &Polyline,
+    to_canvas: impl Fn(PolylinePoint) -> Option<CanvasPoint>,
+    layout: &Layout,
+) -> Result<Vec<CanvasPoint>, PolylineError> {
+    if line.points.len() < 2 {
+        return Err(PolylineError::TooShort);
+    }
+
+    let points = line
+        .points
+        .iter()
+        .enumerate()
+        .map(|(i, &pt)| to_canvas(pt).ok_or(PolylineError::Outside(i)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (i, w) in points.windows(2).enumerate() {
+        let (dx, dy) = (w[0].x.abs_diff(w[1].x), w[0].y.abs_diff(w[1].y));
+        if dx == 0 && dy == 0 {
+            return Err(PolylineError::Empty(i));
+        }
+        if dx != 0 && dy != 0 && dx != dy {
+            return Err(PolylineError::Slanted(i));
+        }
+
+        for pt in segment(w[0], w[1]) {
+            let inside = layout.blocks.iter().position(|r| {
+                pt.x > r.x && pt.x + 1 < r.x + r.width && pt.y > r.y && pt.y + 1 < r.y + r.height
+            });
+            if let Some(b) = inside {
+                return Err(PolylineError::Block(b));
+            }
+        }
+    }
+
+    Ok(points)
+}
+
+/// The points of the segment from `p0` to `p1`, both included.
+fn segment(p0: CanvasPoint, p1: CanvasPoint) -> impl Iterator<Item = CanvasPoint> {
+    let len = p0.x.abs_diff(p1.x).max(p0.y.abs_diff(p1.y));
+    let step = |a: usize, b: usize, i: usize| {
+        if b >= a {
+            a + i.min(b - a)
+        } else {
+            a - i.min(a - b)
+        }
+    };
+    (0..=len).map(move |i| CanvasPoint::new(step(p0.x, p1.x, i), step(p0.y, p1.y, i)))
+}
+
+/// Draw a polyline with the given corners, crossing the lines already there with a `+`.
+fn draw(canvas: &mut Canvas, points: &[CanvasPoint], arrow: bool) {
+    for w in points.windows(2) {
+        let (p0, p1) = (w[0], w[1]);
+        let crossed = if p0.y == p1.y { b'|' } else { b'-' };
+        let crossings = segment(p0, p1)
+            .filter(|&pt| pt != p0 && pt != p1 && canvas.at(pt) == crossed)
+            .collect::<Vec<_>>();
+        if p0.y == p1.y {
+            canvas.draw_horizontal_line(p0.y, (p0.x, p1.x));
+        } else if p0.x == p1.x {
+            canvas.draw_vertical_line(p0.x, (p0.y, p1.y));
+        } else {
+            canvas.draw_diagonal_line(p0, p1);
+        }
+        for pt in crossings {
+            canvas.set(pt, b'+');
+        }
+    }
+
+    let (from, to) = (points[points.len() - 2], points[points.len() - 1]);
+    if arrow && from != to {
+        let head = if from.y < to.y {
+            b'v'
+        } else if from.y > to.y {
+            b'^'
+        } else if from.x < to.x {
+            b'>'
+        } else {
+            b'<'
+        };
+        canvas.set(to, head);
+    }
+}
+
+/// Where the corner before the given column or row is on the canvas, given the range each
+/// column or row with blocks spans. It's in the middle of the margin before the first one, of the
+/// margin after the last one for the one right after it and of the margins between them
+/// otherwise.
+fn corner(
+    spans: &BTreeMap<LogicalCoord, (usize, usize)>,
+    size: usize,
+    c: LogicalCoord,
+) -> Option<usize> {
+    let (&first, &(start, _)) = spans.iter().next()?;
+    let (&last, &(_, end)) = spans.iter().next_back()?;
+    if c == first {
+        Some(start.saturating_sub(1) / 2)
+    } else if c == last + 1 {
+        Some(((end + size) / 2).min(size - 1))
+    } else if c > first && c <= last {
+        let (_, &(_, prev)) = spans.range(..c).next_back()?;
+        let (_, &(next, _)) = spans.range(c..).next()?;
+        Some((prev + next) / 2)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::assert_diagram_eq;
+    use crate::render::{render_layout, render_with_layout, RenderOptions};
+    use crate::Edge;
+
+    #[test]
+    fn test_polylines() {
+        let blocks = [
+            Block::from_str((0, 0), "web"),
+            Block::from_str((0, 1), "api"),
+            Block::from_str((1, 1), "db"),
+        ];
+        let edges = [Edge::new(0, 1), Edge::new(1, 2)];
+        let config = RenderOptions {
+            hmargin: 3,
+            vmargin: 1,
+            padding: 0,
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        };
+
+        let layout = render_layout(&blocks, edges.clone(), config.clone());
+        let rows = render_with_layout(&blocks, edges.clone(), &layout, config).unwrap();
+        let logical = |row, col| PolylinePoint::Logical(LogicalPos { row, col });
+        let canvas = |x, y| PolylinePoint::Canvas(CanvasPoint::new(x, y));
+        let (rows, failed) = add_polylines(
+            rows,
+            &layout,
+            &blocks,
+            &[
+                Polyline {
+                    points: vec![logical(1, 0), logical(1, 2)],
+                    arrow: false,
+                },
+                Polyline {
+                    points: vec![canvas(1, 8), canvas(1, 6), canvas(3, 6)],
+                    arrow: true,
+                },
+                Polyline {
+                    points: vec![canvas(0, 2), canvas(8, 2)],
+                    arrow: false,
+                },
+                Polyline {
+                    points: vec![canvas(0, 0), canvas(3, 1)],
+                    arrow: false,
+                },
+                Polyline {
+                    points: vec![logical(0, 3), logical(0, 0)],
+                    arrow: false,
+                },
+                Polyline {
+                    points: vec![canvas(16, 8), canvas(16, 6), canvas(16, 6)],
+                    arrow: true,
+                },
+            ],
+        );
+
+        assert_diagram_eq!(
+            rows,
+            br#"                   
+   +---+   +---+   
+   |web+---+api|   
+   +---+   +-+-+   
+ ------------+---- 
+           +-+-+   
+ +->       |db |   
+ |         +---+   
+ |                 "#
+        );
+        assert_eq!(
+            failed,
+            vec![
+                (2, PolylineError::Block(0)),
+                (3, PolylineError::Slanted(0)),
+                (4, PolylineError::Outside(0)),
+                (5, PolylineError::Empty(1)),
+            ]
+        );
+    }
+    #[test]
+    fn test_polylines_same_corner() {
+        // without blocks in column 1 the corners before columns 1 and 2 are both in the middle
+        // of the margin between columns 0 and 2
+        let blocks = [Block::from_str((0, 0), "a"), Block::from_str((0, 2), "b")];
+        let config = RenderOptions {
+            hmargin: 3,
+            vmargin: 1,
+            padding: 0,
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        };
+
+        let layout = render_layout(&blocks, Vec::<Edge>::new(), config.clone());
+        let rows = render_with_layout(&blocks, Vec::<Edge>::new(), &layout, config).unwrap();
+        let logical = |row, col| PolylinePoint::Logical(LogicalPos { row, col });
+        let (drawn, failed) = add_polylines(
+            rows.clone(),
+            &layout,
+            &blocks,
+            &[Polyline {
+                points: vec![logical(0, 1), logical(0, 2)],
+                arrow: true,
+            }],
+        );
+
+        assert_eq!(drawn, rows);
+        assert_eq!(failed, vec![(0, PolylineError::Empty(0))]);
+    }
+}
@@ -0,0 +1,126 @@
+//! The random number generator used by the rendering heuristics.
+//!
+//! This is xoshiro256++ seeded through SplitMix64 and sampled exactly like `rand` 0.8 does with
+//! `rand_xoshiro::Xoshiro256PlusPlus`, so that a given seed always renders the same diagram
+//! whether the `rand` feature is enabled or not. The `rand` feature is only needed to pick a
+//! random seed when none is given.
+
+use std::ops::Range;
+
+#[derive(Debug, Clone)]
+pub struct Rng {
+    s: [u64; 4],
+}
+
+impl Rng {
+    pub fn seed_from_u64(seed: u64) -> Self {
+        let mut x = seed;
+        let mut s = [0; 4];
+        for s in &mut s {
+            x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = x;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            *s = z ^ (z >> 31);
+        }
+        Rng { s }
+    }
+
+    /// Create a generator from the given seed or from a random one if it's `None`. Without the
+    /// `rand` feature there's no source of randomness and 0 is used instead.
+    pub fn new(seed: Option<u64>) -> Self {
+        #[cfg(feature = "rand")]
+        let seed = seed.unwrap_or_else(rand::random);
+
+        #[cfg(not(feature = "rand"))]
+        let seed = seed.unwrap_or(0);
+
+        Rng::seed_from_u64(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let s = &mut self.s;
+        let res = s[0].wrapping_add(s[3]).rotate_left(23).wrapping_add(s[0]);
+        let t = s[1] << 17;
+
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+
+        res
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        // the lowest bits are weaker, use the highest ones
+        (self.next_u64() >> 32) as u32
+    }
+
+    pub fn gen_bool(&mut self) -> bool {
+        (self.next_u32() as i32) < 0
+    }
+
+    /// Pick a number in the given range uniformly, it must not be empty.
+    pub fn gen_range(&mut self, range: Range<usize>) -> usize {
+        assert!(range.start < range.end, "cannot sample an empty range");
+
+        let n = (range.end - range.start) as u64;
+        let zone = (n << n.leading_zeros()).wrapping_sub(1);
+        loop {
+            let m = u128::from(self.next_u64()) * u128::from(n);
+            if m as u64 <= zone {
+                return range.start + (m >> 64) as usize;
+            }
+        }
+    }
+
+    /// Shuffle the given items with the Fisher-Yates algorithm.
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = self.gen_index(i + 1);
+            items.swap(i, j);
+        }
+    }
+
+    // `rand` samples indices as u32 when they fit, that consumes different bits than
+    // `gen_range`.
+    fn gen_index(&mut self, n: usize) -> usize {
+        if n > u32::MAX as usize {
+            return self.gen_range(0..n);
+        }
+
+        let n = n as u32;
+        let zone = (n << n.leading_zeros()).wrapping_sub(1);
+        loop {
+            let m = u64::from(self.next_u32()) * u64::from(n);
+            if m as u32 <= zone {
+                return (m >> 32) as usize;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rng_matches_rand() {
+        // reference values from `rand` 0.8 with `Xoshiro256PlusPlus::seed_from_u64(42)`
+        let mut rng = Rng::seed_from_u64(42);
+
+        let bools = (0..8).map(|_| rng.gen_bool()).collect::<Vec<_>>();
+        assert_eq!(bools, [true, false, true, true, true, true, false, true]);
+
+        let ns = (0..8)
+            .map(|i| rng.gen_range(0..3 + i * 7))
+            .collect::<Vec<_>>();
+        assert_eq!(ns, [0, 9, 9, 20, 21, 15, 24, 11]);
+
+        let mut items = (0..8).collect::<Vec<_>>();
+        rng.shuffle(&mut items);
+        assert_eq!(items, [1, 7, 6, 2, 3, 5, 4, 0]);
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::HashSet;
+
+use crate::render::geometry::Layout;
+use crate::render::headers::shift;
+
+/// Number of line characters of a stub between the border of its block and its arrowhead.
+const STUB_LEN: isize = 2;
+
+/// Add stubs to a rendered diagram: short lines leaving a block toward the nearest border of
+/// the canvas, ending with an arrowhead and a label, for edges to something outside of the
+/// diagram like the internet. The `i`-th stub is `(block, label)`, like this:
+///
+/// ```text
+/// +---+
+/// |web+--> internet
+/// +---+
+/// ```
+///
+/// The sides of a block are tried from the nearest to the border of the canvas, a stub is drawn
+/// on the first one whose cells are empty and the canvas grows when it sticks out. Returns the
+/// new rows, `layout` moved to match them and the indices of the stubs that couldn't be drawn
+/// because every side of their block is taken.
+pub fn add_stubs<L: AsRef<[u8]>>(
+    rows: Vec<Vec<u8>>,
+    layout: &Layout,
+    stubs: &[(usize, L)],
+) -> (Vec<Vec<u8>>, Layout, Vec<usize>) {
+    let (w, h) = (layout.width as isize, layout.height as isize);
+    let at = |x: isize, y: isize| -> Option<u8> {
+        if x < 0 || y < 0 || x >= w || y >= h {
+            return None;
+        }
+        Some(rows[y as usize].get(x as usize).copied().unwrap_or(b' '))
+    };
+
+    let mut taken = HashSet::new();
+    let mut cells = vec![];
+    let mut failed = vec![];
+    for (i, (block, label)) in stubs.iter().enumerate() {
+        let r = &layout.blocks[*block];
+        let (x0, y0) = (r.x as isize, r.y as isize);
+        let (x1, y1) = (x0 + r.width as isize - 1, y0 + r.height as isize - 1);
+        let (cx, cy) = ((x0 + x1) / 2, (y0 + y1) / 2);
+
+        let mut sides = [(w - 1 - x1, 0), (x0, 1), (h - 1 - y1, 2), (y0, 3)];
+        sides.sort_by_key(|&(dist, side)| (dist, side));
+
+        let stub = sides.iter().find_map(|&(_, side)| {
+            let stub = stub_cells(side, (x0, y0, x1, y1), (cx, cy), label.as_ref());
+            let (jx, jy, _) = stub[0];
+            let free = matches!(at(jx, jy), Some(b'|') | Some(b'-'))
+                && stub[1..].iter().all(|&(x, y, _)| {
+                    at(x, y).is_none_or(|c| c == b' ') && !taken.contains(&(x, y))
+                });
+            if free {
+                Some(stub)
+            } else {
+                None
+            }
+        });
+
+        match stub {
+            Some(stub) => {
+                taken.extend(stub.iter().map(|&(x, y, _)| (x, y)));
+                cells.extend(stub);
+            }
+            None => failed.push(i),
+        }
+    }
+
+    let (dx, dy) = cells
+        .iter()
+        .fold((0, 0), |(dx, dy), &(x, y, _)| (dx.max(-x), dy.max(-y)));
+    let (width, height) = cells.iter().fold((w + dx, h + dy), |(w, h), &(x, y, _)| {
+        (w.max(x + dx + 1), h.max(y + dy + 1))
+    });
+
+    let mut out = vec![vec![b' '; width as usize]; height as usize];
+    for (y, row) in rows.iter().enumerate() {
+        let start = dx as usize;
+        out[y + dy as usize][start..start + row.len()].copy_from_slice(row);
+    }
+    for (x, y, c) in cells {
+        out[(y + dy) as usize][(x + dx) as usize] = c;
+    }
+
+    let mut layout = shift(layout, dx as usize, dy as usize);
+    layout.width = width as usize;
+    layout.height = height as usize;
+    (out, layout, failed)
+}
+
+/// The cells of a stub leaving the block with the given corners from its `side`, 0 to 3 being
+/// right, left, bottom and top. The first cell is the junction on the border of the block, the
+/// spaces between the arrowhead and the label are included to keep them clear.
+fn stub_cells(
+    side: u8,
+    (x0, y0, x1, y1): (isize, isize, isize, isize),
+    (cx, cy): (isize, isize),
+    label: &[u8],
+) -> Vec<(isize, isize, u8)> {
+    let len = label.len() as isize;
+    let mut cells = vec![];
+    match side {
+        0 | 1 => {
+            let (jx, dir) = if side == 0 { (x1, 1) } else { (x0, -1) };
+            cells.push((jx, cy, b'+'));
+            cells.extend((1..=STUB_LEN).map(|d| (jx + dir * d, cy, b'-')));
+            let tip = jx + dir * (STUB_LEN + 1);
+            cells.push((tip, cy, if side == 0 { b'>' } else { b'<' }));
+            if len > 0 {
+                cells.push((tip + dir, cy, b' '));
+                let start = if side == 0 { tip + 2 } else { tip - 1 - len };
+                cells.extend((0..len).map(|i| (start + i, cy, label[i as usize])));
+            }
+        }
+        _ => {
+            let (jy, dir) = if side == 2 { (y1, 1) } else { (y0, -1) };
+            cells.push((cx, jy, b'+'));
+            cells.extend((1..=STUB_LEN).map(|d| (cx, jy + dir * d, b'|')));
+            let tip = jy + dir * (STUB_LEN + 1);
+            cells.push((cx, tip, if side == 2 { b'v' } else { b'^' }));
+            let start = cx - (len - 1).max(0) / 2;
+            cells.extend((0..len).map(|i| (start + i, tip + dir, label[i as usize])));
+        }
+    }
+    cells
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::assert_diagram_eq;
+    use crate::render::{render_layout, render_with_layout, RenderOptions};
+    use crate::{Block, Edge};
+
+    #[test]
+    fn test_stubs() {
+        let blocks = [
+            Block::from_str((0, 0), "web"),
+            Block::from_str((1, 0), "api"),
+            Block::from_str((1, 1), "db"),
+        ];
+        let edges = [Edge::new(0, 1), Edge::new(1, 2)];
+        let config = RenderOptions {
+            hmargin: 3,
+            vmargin: 1,
+            padding: 0,
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        };
+
+        let layout = render_layout(&blocks, edges.clone(), config.clone());
+        let rows = render_with_layout(&blocks, edges.clone(), &layout, config).unwrap();
+        let (rows, stubs, failed) = add_stubs(
+            rows,
+            &layout,
+            &[(1, "internet"), (2, "s3"), (0, "cdn"), (0, "")],
+        );
+
+        assert_diagram_eq!(
+            rows,
+            br#"    cdn           
+     ^            
+     |            
+     |            
+   +-+-+          
+<--+web|          
+   +-+-+          
+     |            
+   +-+-+   +--+   
+   |api+---+db|   
+   +-+-+   ++-+   
+     |      |     
+     |      |     
+     v      v     
+  internet  s3    "#
+        );
+        assert_eq!(failed, Vec::<usize>::new());
+        assert_eq!(stubs.width, rows[0].len());
+        assert_eq!(stubs.height, rows.len());
+    }
+}
@@ -0,0 +1,386 @@
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+
+use crate::render::canvas::CanvasPoint;
+use crate::render::canvas_space::CanvasSpace;
+use crate::render::layout;
+use crate::render::lines::{crossing_points, Line, Route};
+use crate::{Block, Edge, RenderOptions};
+
+/// How many margins larger than the current one are tried before giving up.
+const MAX_MARGIN_INCREASE: usize = 3;
+
+/// Maximum number of swaps to suggest, there can be many equivalent ones.
+const MAX_SWAP_SUGGESTIONS: usize = 3;
+
+/// Maximum number of swaps that are tried by `suggest`, each one is a full rendering, see
+/// `swap_candidates`.
+const MAX_SWAP_CANDIDATES: usize = 45;
+
+/// A concrete change to a diagram that makes it easier to read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Suggestion {
+    /// Swap the positions of the blocks at the given indices to remove some crossings.
+    SwapBlocks {
+        a: usize,
+        b: usize,
+        crossings_removed: usize,
+    },
+
+    /// Use the given horizontal margin so that fewer lines run right next to blocks.
+    IncreaseHorizontalMargin {
+        margin: usize,
+        hugging_removed: usize,
+    },
+
+    /// Use the given vertical margin so that fewer lines run right next to blocks.
+    IncreaseVerticalMargin {
+        margin: usize,
+        hugging_removed: usize,
+    },
+}
+
+/// Metrics of a rendered diagram, the lower the better.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metrics {
+    /// number of points where two edges cross.
+    pub crossings: usize,
+
+    /// number of line cells that run parallel right next to the border of a block.
+    pub hugging: usize,
+
+    /// total length of all the edges.
+    pub length: usize,
+}
+
+/// Analyze how the given diagram is rendered and suggest changes that would improve it, the most
+/// effective swaps come first.
+///
+/// Each suggestion requires rendering the diagram again, with the same tweaks so that the
+/// crossings counted are the ones `render` draws, so this is much slower than `render`. Only the
+/// most promising swaps are tried, see `swap_candidates`. If `config` has no seed a fixed one is
+/// used so that the renderings are comparable.
+pub fn suggest(
+    boxes: &[Block],
+    edges: impl IntoIterator<Item = impl Into<Edge>>,
+    config: RenderOptions,
+) -> Vec<Suggestion> {
+    if boxes.is_empty() {
+        return vec![];
+    }
+
+    let edges = edges.into_iter().map(Into::into).collect::<Vec<_>>();
+    let config = RenderOptions {
+        seed: config.seed.or(Some(0)),
+        ..config
+    };
+
+    let (cs, _, routes) = match layout(boxes, &edges, &config) {
+        Ok(layout) => layout,
+        Err(_) => return vec![],
+    };
+    let base = metrics(&cs, &routes);
+    let mut suggestions = vec![];
+
+    if base.crossings > 0 {
+        let candidates = swap_candidates(boxes.len(), &edges, &routes);
+
+        let mut swaps = vec![];
+        let mut swapped = boxes.to_vec();
+        for (a, b) in candidates {
+            swap_positions(&mut swapped, a, b);
+            let m = measure(&swapped, &edges, &config);
+            swap_positions(&mut swapped, a, b);
+
+            // swaps after which some edges can't be routed are no suggestion
+            let m = match m {
+                Some(m) => m,
+                None => continue,
+            };
+            if m.crossings < base.crossings {
+                swaps.push((base.crossings - m.crossings, m.hugging, a, b));
+            }
+        }
+
+        // prefer the swaps that remove the most crossings without making lines hug blocks
+        swaps.sort_by_key(|&(removed, hugging, a, b)| (Reverse(removed), hugging, a, b));
+        suggestions.extend(swaps.into_iter().take(MAX_SWAP_SUGGESTIONS).map(
+            |(crossings_removed, _, a, b)| Suggestion::SwapBlocks {
+                a,
+                b,
+                crossings_removed,
+            },
+        ));
+    }
+
+    if base.hugging > 0 {
+        let increase_margin = |margin: &dyn Fn(&mut RenderOptions) -> &mut usize| {
+            let mut cfg = config.clone();
+            let start = *margin(&mut cfg);
+
+            let mut best = None;
+            for m in start + 1..=start + MAX_MARGIN_INCREASE {
+                *margin(&mut cfg) = m;
+                let hugging = match measure(boxes, &edges, &cfg) {
+                    Some(m) => m.hugging,
+                    None => continue,
+                };
+                if hugging < best.map_or(base.hugging, |(_, h)| h) {
+                    best = Some((m, hugging));
+                }
+                if hugging == 0 {
+                    break;
+                }
+            }
+
+            best.map(|(m, hugging)| (m, base.hugging - hugging))
+        };
+
+        if let Some((margin, hugging_removed)) = increase_margin(&|c| &mut c.hmargin) {
+            suggestions.push(Suggestion::IncreaseHorizontalMargin {
+                margin,
+                hugging_removed,
+            });
+        }
+        if let Some((margin, hugging_removed)) = increase_margin(&|c| &mut c.vmargin) {
+            suggestions.push(Suggestion::IncreaseVerticalMargin {
+                margin,
+                hugging_removed,
+            });
+        }
+    }
+
+    suggestions
+}
+
+pub(super) fn swap_positions(boxes: &mut [Block], a: usize, b: usize) {
+    let pa = boxes[a].pos;
+    boxes[a].pos = boxes[b].pos;
+    boxes[b].pos = pa;
+}
+
+/// The pairs of blocks whose swap is worth trying to remove the crossings of the diagram drawn
+/// with `routes`, at most `MAX_SWAP_CANDIDATES` of them. Each block is ranked by the crossings
+/// along its edges and the pairs with the highest sum come first, so that the blocks at the ends
+/// of crossing edges are tried with every other block before the rest. Swapping two blocks
+/// without edges can't remove any crossing of their own, such pairs are never tried.
+fn swap_candidates(blocks: usize, edges: &[Edge], routes: &[Route]) -> Vec<(usize, usize)> {
+    let crossings = crossing_points(routes).into_iter().collect::<BTreeSet<_>>();
+    let mut connected = vec![false; blocks];
+    let mut rank = vec![0; blocks];
+    for (e, r) in edges.iter().zip(routes) {
+        let crossed = r
+            .polyline
+            .iter()
+            .flat_map(Line::interior)
+            .filter(|pt| crossings.contains(pt))
+            .count();
+        for b in [e.from, e.to] {
+            connected[b] = true;
+            rank[b] += crossed;
+        }
+    }
+
+    let mut pairs = (0..blocks)
+        .flat_map(|a| (a + 1..blocks).map(move |b| (a, b)))
+        .filter(|&(a, b)| connected[a] || connected[b])
+        .collect::<Vec<_>>();
+    pairs.sort_by_key(|&(a, b)| (Reverse(rank[a] + rank[b]), a, b));
+    pairs.truncate(MAX_SWAP_CANDIDATES);
+    pairs
+}
+
+/// Render the given diagram and measure how easy it is to read, see `Metrics`. If `config` has
+/// no seed a fixed one is used so that the scores of different diagrams are comparable.
+///
+/// Panics if an edge can't be routed, like `render_layout`.
+pub fn score(
+    boxes: &[Block],
+    edges: impl IntoIterator<Item = impl Into<Edge>>,
+    mut config: RenderOptions,
+) -> Metrics {
+    let edges = edges.into_iter().map(Into::into).collect::<Vec<_>>();
+    config.seed = config.seed.or(Some(0));
+
+    let (cs, _, routes) = layout(boxes, &edges, &config).unwrap_or_else(|e| panic!("{}", e));
+    metrics(&cs, &routes)
+}
+
+/// The options to render candidate layouts and margins with: tweaking the lines is by far the
+/// slowest part of rendering, so it's skipped and the candidates are compared by their first
+/// routing. If `config` has no seed a fixed one is used so that the renderings are comparable.
+pub(super) fn search_options(config: &RenderOptions) -> RenderOptions {
+    RenderOptions {
+        seed: config.seed.or(Some(0)),
+        max_tweaks: 0,
+        ..config.clone()
+    }
+}
+
+/// Render the given diagram and measure it, if all of its edges can be routed. Candidate
+/// layouts and margins can make edges unroutable even if the diagram itself isn't, with no
+/// margins for example.
+pub(super) fn measure(boxes: &[Block], edges: &[Edge], config: &RenderOptions) -> Option<Metrics> {
+    let (cs, _, routes) = layout(boxes, edges, config).ok()?;
+    Some(metrics(&cs, &routes))
+}
+
+/// Measure the diagram drawn with the given routes.
+pub(super) fn metrics(cs: &CanvasSpace, routes: &[Route]) -> Metrics {
+    Metrics {
+        crossings: crossing_points(routes).len(),
+        hugging: hugging(cs, routes),
+        length: routes
+            .iter()
+            .flat_map(|r| &r.polyline)
+            .map(|l| l.interior().len() + 1)
+            .sum(),
+    }
+}
+
+/// Count the cells of lines that run parallel right next to the border of a block.
+fn hugging(cs: &CanvasSpace, routes: &[Route]) -> usize {
+    let in_block = |x: usize, y: usize| cs.block_at_canvas_point(CanvasPoint::new(x, y)).is_some();
+
+    let mut hugging = BTreeSet::new();
+    for l in routes.iter().flat_map(|r| &r.polyline) {
+        for pt in l.interior() {
+            let CanvasPoint { x, y } = pt;
+            let hugs = match l {
+                Line::Vertical(..) => in_block(x.wrapping_sub(1), y) || in_block(x + 1, y),
+                Line::Horizontal(..) => in_block(x, y.wrapping_sub(1)) || in_block(x, y + 1),
+            };
+            if hugs {
+                hugging.insert(pt);
+            }
+        }
+    }
+    hugging.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(hmargin: usize, vmargin: usize) -> RenderOptions {
+        RenderOptions {
+            hmargin,
+            vmargin,
+            seed: Some(0),
+            max_tweaks: 0,
+            ..RenderOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_suggest_swapping_blocks() {
+        // a ring of blocks with a block inside connected to a block outside, the edge must cross
+        // the ring
+        let mut blocks = [
+            (0, 0),
+            (0, 1),
+            (0, 2),
+            (1, 2),
+            (2, 2),
+            (2, 1),
+            (2, 0),
+            (1, 0),
+        ]
+        .iter()
+        .map(|&p| Block::from_str(p, "r"))
+        .collect::<Vec<_>>();
+        blocks.push(Block::from_str((1, 1), "in"));
+        blocks.push(Block::from_str((1, 3), "out"));
+
+        let mut edges = (0..8).map(|i| (i, (i + 1) % 8)).collect::<Vec<_>>();
+        edges.push((8, 9));
+
+        let suggestions = suggest(&blocks, edges, config(5, 3));
+        assert!(!suggestions.is_empty());
+        assert!(suggestions.len() <= MAX_SWAP_SUGGESTIONS);
+        assert!(suggestions.iter().all(|s| matches!(
+            s,
+            Suggestion::SwapBlocks {
+                crossings_removed: 1,
+                ..
+            }
+        )));
+    }
+
+    #[test]
+    fn test_swap_candidates() {
+        // the same ring after a chain of blocks without crossings, whose swaps alone are more
+        // than `MAX_SWAP_CANDIDATES`
+        let mut blocks = (0..10)
+            .map(|c| Block::from_str((4, c), "c"))
+            .collect::<Vec<_>>();
+        let first = blocks.len();
+        let mut edges = (1..first).map(|i| (i - 1, i)).collect::<Vec<_>>();
+        assert!(first * (first - 1) / 2 >= MAX_SWAP_CANDIDATES);
+
+        let ring = [
+            (0, 0),
+            (0, 1),
+            (0, 2),
+            (1, 2),
+            (2, 2),
+            (2, 1),
+            (2, 0),
+            (1, 0),
+        ];
+        blocks.extend(ring.iter().map(|&p| Block::from_str(p, "r")));
+        blocks.push(Block::from_str((1, 1), "in"));
+        blocks.push(Block::from_str((1, 3), "out"));
+        edges.extend((0..8).map(|i| (first + i, first + (i + 1) % 8)));
+        edges.push((first + 8, first + 9));
+
+        let edges = edges.into_iter().map(Edge::from).collect::<Vec<_>>();
+        let (_, _, routes) = layout(&blocks, &edges, &config(5, 3)).unwrap();
+        let candidates = swap_candidates(blocks.len(), &edges, &routes);
+        assert_eq!(candidates.len(), MAX_SWAP_CANDIDATES);
+
+        // the blocks of the crossing edges are tried first, with each other and then with the
+        // others
+        assert!(candidates[..6].contains(&(first + 8, first + 9)));
+        assert!(candidates.iter().all(|&(_, b)| b >= first));
+    }
+
+    #[test]
+    fn test_suggest_increasing_margins() {
+        let blocks = [
+            Block::from_str((0, 0), "a"),
+            Block::from_str((0, 1), "b"),
+            Block::from_str((0, 2), "c"),
+        ];
+
+        assert_eq!(
+            suggest(&blocks, [(0, 2)].iter().copied(), config(1, 1)),
+            vec![Suggestion::IncreaseVerticalMargin {
+                margin: 3,
+                hugging_removed: 9,
+            }]
+        );
+        assert_eq!(
+            suggest(&blocks, [(0, 2)].iter().copied(), config(5, 3)),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_score() {
+        let blocks = [
+            Block::from_str((0, 0), "a"),
+            Block::from_str((0, 1), "b"),
+            Block::from_str((0, 2), "c"),
+        ];
+
+        let m = score(&blocks, [(0, 2)].iter().copied(), config(1, 1));
+        assert_eq!(m.crossings, 0);
+        assert_eq!(m.hugging, 9);
+        assert_eq!(m, score(&blocks, [(0, 2)].iter().copied(), config(1, 1)));
+        assert_eq!(
+            score(&blocks, [(0, 2)].iter().copied(), config(5, 3)).hugging,
+            0
+        );
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+
+use crate::render::canvas::CanvasPoint;
+use crate::render::canvas_space::CanvasSpace;
+use crate::render::lines::{Line, Polyline};
+use crate::{Block, Edge, LogicalCoord, LogicalPos};
+
+/// The axis a block arrangement is mirrored across.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Axis {
+    /// the left and the right halves mirror each other.
+    Vertical,
+
+    /// the top and the bottom halves mirror each other.
+    Horizontal,
+}
+
+/// A mirrored block arrangement, see `RenderOptions::symmetric`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mirror {
+    pub axis: Axis,
+
+    /// the sum of the coordinates of a block and of its image along the axis.
+    sum: LogicalCoord,
+
+    /// the index of the image of each block, blocks on the axis are their own image.
+    pub images: Vec<usize>,
+}
+
+impl Mirror {
+    /// Find how the given blocks are mirrored, if they are. Arrangements mirrored left to right
+    /// are preferred over the ones mirrored top to bottom and the ones where every block is on
+    /// the axis are ignored.
+    pub fn find(boxes: &[Block]) -> Option<Self> {
+        let index = boxes
+            .iter()
+            .enumerate()
+            .map(|(i, b)| (b.pos, i))
+            .collect::<HashMap<_, _>>();
+
+        [Axis::Vertical, Axis::Horizontal].iter().find_map(|&axis| {
+            let coords = boxes.iter().map(|b| coord(axis, b.pos));
+            let sum = coords.clone().min()? + coords.max()?;
+            let mirror = Mirror {
+                axis,
+                sum,
+                images: vec![],
+            };
+
+            let images = boxes
+                .iter()
+                .map(|b| index.get(&mirror.reflect_pos(b.pos)).copied())
+                .collect::<Option<Vec<_>>>()?;
+
+            if images.iter().enumerate().all(|(i, &j)| i == j) {
+                return None;
+            }
+
+            Some(Mirror { images, ..mirror })
+        })
+    }
+
+    /// The image of each edge alongside whether it goes in the opposite direction. Edges without
+    /// an image or that are their own image are `None`.
+    pub fn edge_images(&self, edges: &[Edge]) -> Vec<Option<(usize, bool)>> {
+        let index = edges
+            .iter()
+            .enumerate()
+            .map(|(i, e)| ((e.from, e.to), i))
+            .collect::<HashMap<_, _>>();
+
+        edges
+            .iter()
+            .enumerate()
+            .map(|(i, e)| {
+                let (from, to) = (self.images[e.from], self.images[e.to]);
+                let same = index.get(&(from, to)).map(|&j| (j, false));
+                let reversed = index.get(&(to, from)).map(|&j| (j, true));
+
+                same.into_iter()
+                    .chain(reversed.filter(|_| !e.directed || e.bidirectional))
+                    .find(|&(j, _)| {
+                        j != i
+                            && edges[j].directed == e.directed
+                            && edges[j].bidirectional == e.bidirectional
+                    })
+            })
+            .collect()
+    }
+
+    /// Whether the blocks are drawn mirrored on the canvas too, that is mirrored blocks have the
+    /// same size and the margins around them are the same.
+    fn is_drawn_mirrored(&self, cs: &CanvasSpace, boxes: &[Block]) -> bool {
+        boxes.iter().zip(&self.images).all(|(b, &image)| {
+            let r = cs.to_canvas(b.pos);
+            let m = cs.to_canvas(boxes[image].pos);
+            match self.axis {
+                Axis::Vertical => {
+                    r.y == m.y && r.height == m.height && r.x + r.width + m.x == cs.canvas_width()
+                }
+                Axis::Horizontal => {
+                    r.x == m.x && r.width == m.width && r.y + r.height + m.y == cs.canvas_height()
+                }
+            }
+        })
+    }
+
+    fn reflect_pos(&self, LogicalPos { row, col }: LogicalPos) -> LogicalPos {
+        match self.axis {
+            Axis::Vertical => LogicalPos::new(row, self.sum - col),
+            Axis::Horizontal => LogicalPos::new(self.sum - row, col),
+        }
+    }
+}
+
+/// Make the sizes of mirrored columns or rows the same, that is the largest of the two. The
+/// image of the `i`-th column or row is the `i`-th from the other end.
+pub fn equalize(sizes: &mut [usize]) {
+    for i in 0..sizes.len() / 2 {
+        let j = sizes.len() - 1 - i;
+        let s = sizes[i].max(sizes[j]);
+        sizes[i] = s;
+        sizes[j] = s;
+    }
+}
+
+/// The edges of a mirrored block arrangement that is also drawn mirrored, so that the route of
+/// an edge can be reflected to get the route of its image.
+#[derive(Debug, Clone)]
+pub struct Symmetry {
+    axis: Axis,
+    canvas_width: usize,
+    canvas_height: usize,
+
+    // the image of each edge alongside whether it goes in the opposite direction.
+    edge_images: Vec<Option<(usize, bool)>>,
+}
+
+impl Symmetry {
+    /// The symmetry of the given diagram, if its blocks are mirrored both logically and on the
+    /// canvas and at least an edge has an image.
+    pub fn new(cs: &CanvasSpace, boxes: &[Block], edges: &[Edge]) -> Option<Self> {
+        let mirror = Mirror::find(boxes)?;
+        if !mirror.is_drawn_mirrored(cs, boxes) {
+            return None;
+        }
+
+        let edge_images = mirror.edge_images(edges);
+        if edge_images.iter().all(Option::is_none) {
+            return None;
+        }
+
+        Some(Symmetry {
+            axis: mirror.axis,
+            canvas_width: cs.canvas_width(),
+            canvas_height: cs.canvas_height(),
+            edge_images,
+        })
+    }
+
+    /// The image of the given edge and whether it goes in the opposite direction, if any.
+    pub fn edge_image(&self, e: usize) -> Option<(usize, bool)> {
+        self.edge_images[e]
+    }
+
+    pub fn reflect_point(&self, CanvasPoint { x, y }: CanvasPoint) -> CanvasPoint {
+        match self.axis {
+            Axis::Vertical => CanvasPoint::new(self.canvas_width - 1 - x, y),
+            Axis::Horizontal => CanvasPoint::new(x, self.canvas_height - 1 - y),
+        }
+    }
+
+    /// Reflect the given polyline, going through it backwards if `reversed` is true.
+    pub fn reflect_polyline(&self, polyline: &[Line], reversed: bool) -> Polyline {
+        let mut reflected = polyline
+            .iter()
+            .map(|l| {
+                let (a, b) = l.ends();
+                let (a, b) = (self.reflect_point(a), self.reflect_point(b));
+                match l {
+                    Line::Vertical(..) => Line::Vertical(a.x, (a.y.min(b.y), a.y.max(b.y))),
+                    Line::Horizontal(..) => Line::Horizontal(a.y, (a.x.min(b.x), a.x.max(b.x))),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        if reversed {
+            reflected.reverse();
+        }
+        reflected
+    }
+}
+
+fn coord(axis: Axis, pos: LogicalPos) -> LogicalCoord {
+    match axis {
+        Axis::Vertical => pos.col,
+        Axis::Horizontal => pos.row,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_mirror() {
+        let blocks = [
+            Block::from_str((0, 1), "top"),
+            Block::from_str((1, 0), "left"),
+            Block::from_str((1, 2), "right"),
+        ];
+        let mirror = Mirror::find(&blocks).unwrap();
+        assert_eq!(mirror.axis, Axis::Vertical);
+        assert_eq!(mirror.images, vec![0, 2, 1]);
+
+        let edges = [Edge::new(0, 1), Edge::new(2, 0), Edge::new(1, 2)];
+        assert_eq!(
+            mirror.edge_images(&edges),
+            vec![Some((1, true)), Some((0, true)), None]
+        );
+
+        // directed edges are mirrored only if they go in the same direction
+        let edges = [Edge::new(0, 1).with_directed(true), Edge::new(2, 0)];
+        assert_eq!(mirror.edge_images(&edges), vec![None, None]);
+
+        // a column of blocks is only mirrored top to bottom
+        let blocks = [
+            Block::from_str((0, 0), "a"),
+            Block::from_str((1, 0), "b"),
+            Block::from_str((2, 0), "c"),
+        ];
+        let mirror = Mirror::find(&blocks).unwrap();
+        assert_eq!(mirror.axis, Axis::Horizontal);
+        assert_eq!(mirror.images, vec![2, 1, 0]);
+
+        let blocks = [Block::from_str((0, 0), "a"), Block::from_str((1, 1), "b")];
+        assert_eq!(Mirror::find(&blocks), None);
+        assert_eq!(Mirror::find(&blocks[..1]), None);
+    }
+}
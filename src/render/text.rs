@@ -0,0 +1,208 @@
+use crate::{Overflow, TextDirection};
+
+/// Marker placed at the end of text that had to be cut to fit in a block.
+pub const ELLIPSIS: &[u8] = b"...";
+
+/// Characters of a sparkline, from the lowest value to the highest.
+const SPARKLINE_RAMP: &[u8] = b"_.-~^'";
+
+/// Fit the given lines in a `width` x `height` area according to `overflow`. Lines are either
+/// wrapped at word boundaries or truncated, words that are longer than `width` are always
+/// truncated. If there are still too many lines the exceeding ones are dropped and the last
+/// visible line is terminated by an `ELLIPSIS`.
+pub fn fit(text: &[Vec<u8>], width: usize, height: usize, overflow: Overflow) -> Vec<Vec<u8>> {
+    let mut lines = match overflow {
+        Overflow::Wrap => text.iter().flat_map(|l| wrap(l, width)).collect::<Vec<_>>(),
+        Overflow::Truncate => text
+            .iter()
+            .map(|l| {
+                let mut l = l.clone();
+                if l.len() > width {
+                    ellipsize(&mut l, width);
+                }
+                l
+            })
+            .collect(),
+    };
+
+    if lines.len() > height {
+        lines.truncate(height);
+        if let Some(last) = lines.last_mut() {
+            ellipsize(last, width);
+        }
+    }
+
+    lines
+}
+
+/// Draw the given lines from right to left, aligning them to the right.
+pub fn right_to_left(text: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let width = text.iter().map(Vec::len).max().unwrap_or(0);
+
+    text.iter()
+        .map(|l| {
+            let mut l = l.iter().rev().copied().collect::<Vec<_>>();
+            l.splice(0..0, std::iter::repeat_n(b' ', width - l.len()));
+            l
+        })
+        .collect()
+}
+
+/// Transpose the given lines so that each one becomes a column, one character per row. Columns
+/// are separated by a space and laid out following `direction`.
+pub fn vertical(text: &[Vec<u8>], direction: TextDirection) -> Vec<Vec<u8>> {
+    let height = text.iter().map(Vec::len).max().unwrap_or(0);
+
+    let mut columns = text.iter().collect::<Vec<_>>();
+    if direction == TextDirection::RightToLeft {
+        columns.reverse();
+    }
+
+    (0..height)
+        .map(|y| {
+            let mut row = columns
+                .iter()
+                .flat_map(|c| vec![c.get(y).copied().unwrap_or(b' '), b' '])
+                .collect::<Vec<_>>();
+            row.pop();
+            row
+        })
+        .collect()
+}
+
+/// Split `line` in lines at most `width` characters long at spaces.
+fn wrap(line: &[u8], width: usize) -> Vec<Vec<u8>> {
+    if line.len() <= width {
+        return vec![line.to_vec()];
+    }
+
+    let mut lines = vec![];
+    let mut cur: Vec<u8> = vec![];
+    for word in line.split(|c| *c == b' ').filter(|w| !w.is_empty()) {
+        if !cur.is_empty() && cur.len() + 1 + word.len() <= width {
+            cur.push(b' ');
+            cur.extend_from_slice(word);
+            continue;
+        }
+
+        if !cur.is_empty() {
+            lines.push(cur);
+        }
+
+        cur = word.to_vec();
+        if cur.len() > width {
+            ellipsize(&mut cur, width);
+        }
+    }
+    lines.push(cur);
+
+    lines
+}
+
+/// Draw the given values as a sparkline, that is a single line with a character per value whose
+/// height is proportional to the value, from the lowest to the highest. It's made of printable
+/// ASCII characters only so it can be used in the text of a `Block`, like `load: _.-^'^-.`.
+/// Values that aren't finite are drawn as spaces.
+pub fn sparkline(values: &[f64]) -> String {
+    let finite = values.iter().filter(|v| v.is_finite());
+    let min = finite.clone().copied().fold(f64::INFINITY, f64::min);
+    let max = finite.copied().fold(f64::NEG_INFINITY, f64::max);
+
+    values
+        .iter()
+        .map(|&v| {
+            if !v.is_finite() {
+                return ' ';
+            }
+
+            // flat lines are drawn in the middle
+            let t = if max > min {
+                (v - min) / (max - min)
+            } else {
+                0.5
+            };
+            let i = (t * (SPARKLINE_RAMP.len() - 1) as f64).round() as usize;
+            char::from(SPARKLINE_RAMP[i])
+        })
+        .collect()
+}
+
+/// Terminate `line` with an `ELLIPSIS` making sure the result is at most `width` characters long.
+fn ellipsize(line: &mut Vec<u8>, width: usize) {
+    let ellipsis = &ELLIPSIS[..ELLIPSIS.len().min(width)];
+
+    line.truncate(width - ellipsis.len());
+    while line.last() == Some(&b' ') {
+        line.pop();
+    }
+    line.extend_from_slice(ellipsis);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(t: &str) -> Vec<Vec<u8>> {
+        t.split('\n').map(|l| l.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_fit() {
+        let wrap = |t, w, h| fit(&lines(t), w, h, Overflow::Wrap);
+
+        assert_eq!(wrap("hello world", 20, 1), lines("hello world"));
+        assert_eq!(wrap("hello world", 8, 2), lines("hello\nworld"));
+        assert_eq!(wrap("a quick brown fox", 9, 3), lines("a quick\nbrown fox"));
+        assert_eq!(
+            wrap("the supercalifragilistic word", 8, 3),
+            lines("the\nsuper...\nword")
+        );
+
+        assert_eq!(wrap("hello world", 8, 1), lines("hello..."));
+        assert_eq!(wrap("a b c d e f", 3, 2), lines("a b\n..."));
+        assert_eq!(wrap("one\ntwo\nthree", 10, 2), lines("one\ntwo..."));
+        assert_eq!(wrap("hello world", 2, 1), lines(".."));
+        assert_eq!(wrap("hello", 5, 0), Vec::<Vec<u8>>::new());
+
+        let truncate = |t, w, h| fit(&lines(t), w, h, Overflow::Truncate);
+
+        assert_eq!(truncate("hello world", 20, 1), lines("hello world"));
+        assert_eq!(truncate("hello world", 8, 2), lines("hello..."));
+        assert_eq!(
+            truncate("hello world\nfoo\nbar", 8, 2),
+            lines("hello...\nfoo...")
+        );
+    }
+
+    #[test]
+    fn test_right_to_left() {
+        assert_eq!(right_to_left(&lines("abc")), lines("cba"));
+        assert_eq!(
+            right_to_left(&lines("hello\nab\n")),
+            lines("olleh\n   ba\n     ")
+        );
+    }
+
+    #[test]
+    fn test_vertical() {
+        assert_eq!(
+            vertical(&lines("abc"), TextDirection::LeftToRight),
+            lines("a\nb\nc")
+        );
+        assert_eq!(
+            vertical(&lines("ab\ncde"), TextDirection::LeftToRight),
+            lines("a c\nb d\n  e")
+        );
+        assert_eq!(
+            vertical(&lines("ab\ncde"), TextDirection::RightToLeft),
+            lines("c a\nd b\ne  ")
+        );
+    }
+    #[test]
+    fn test_sparkline() {
+        assert_eq!(sparkline(&[1.0, 4.0, 2.0, 8.0, 8.0, 0.0]), ".~.''_");
+        assert_eq!(sparkline(&[0.0, 5.0, f64::NAN, 10.0]), "_~ '");
+        assert_eq!(sparkline(&[3.0, 3.0]), "~~");
+        assert_eq!(sparkline(&[]), "");
+    }
+}
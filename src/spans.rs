@@ -0,0 +1,525 @@
+//! Locate the blocks and the edges of a diagram in its source text.
+//!
+//! Neither serde_json nor toml report where values come from, so the source is scanned again
+//! with a small tokenizer that only understands strings, comments and brackets. That is enough
+//! to find the elements of the top level `blocks` and `edges` arrays, whatever their content.
+
+use std::ops::Range;
+
+use crate::spec::Format;
+
+/// A region of the source text of a diagram, see `RenderedDiagram::block_spans`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Span {
+    /// Byte offset of the first character of the region.
+    pub start: usize,
+
+    /// Byte offset right after the last character of the region.
+    pub end: usize,
+
+    /// Line the region starts at, starting from 1.
+    pub line: usize,
+}
+
+/// Which of the top level arrays an element belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Blocks,
+    Edges,
+}
+
+impl Section {
+    fn from_key(key: &str) -> Option<Self> {
+        match key {
+            "blocks" => Some(Section::Blocks),
+            "edges" => Some(Section::Edges),
+            _ => None,
+        }
+    }
+}
+
+/// The spans of the blocks and of the edges of the diagram `input`, in the order they are
+/// written in. It's `None` if they can't be told apart, the caller is expected to double check
+/// that there are as many spans as parsed blocks and edges.
+pub(crate) fn find_spans(input: &str, format: Format) -> Option<(Vec<Span>, Vec<Span>)> {
+    let ranges = match format {
+        Format::Json => json_ranges(input)?,
+        Format::Toml => toml_ranges(input)?,
+        Format::Dot | Format::Yaml | Format::D2 | Format::PlantUml => return None,
+    };
+
+    let mut blocks = vec![];
+    let mut edges = vec![];
+    for (section, start, end) in ranges {
+        let span = Span {
+            start,
+            end,
+            line: 1 + input[..start].matches('\n').count(),
+        };
+        match section {
+            Section::Blocks => blocks.push(span),
+            Section::Edges => edges.push(span),
+        }
+    }
+    Some((blocks, edges))
+}
+
+/// Where the row and the column of the position of the block written in `span` of `input` are,
+/// as byte ranges of their values. It's `None` if either is missing or isn't a plain integer.
+pub(crate) fn find_position(input: &str, span: Span) -> Option<(Range<usize>, Range<usize>)> {
+    let bytes = input.as_bytes();
+    let mut tokens = vec![];
+
+    let mut i = span.start;
+    while i < span.end {
+        let c = bytes[i];
+        match c {
+            b'"' | b'\'' => {
+                let end = string_end(bytes, i, c, c == b'"')?;
+                tokens.push((Token::Key, i + 1..end - 1));
+                i = end;
+                continue;
+            }
+            b'#' => {
+                while i < span.end && bytes[i] != b'\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            b'=' | b':' => tokens.push((Token::Assign, i..i + 1)),
+            c if c.is_ascii_alphanumeric() || c == b'_' || c == b'-' || c == b'.' => {
+                let len = bytes[i..span.end]
+                    .iter()
+                    .take_while(|b| b.is_ascii_alphanumeric() || b"_-.".contains(b))
+                    .count();
+                tokens.push((Token::Key, i..i + len));
+                i += len;
+                continue;
+            }
+            c if c.is_ascii_whitespace() => {}
+            _ => tokens.push((Token::Other, i..i + 1)),
+        }
+        i += 1;
+    }
+
+    // keys can be dotted, as in `position.row = 0` or `[blocks.position]`
+    let mut in_position = false;
+    let (mut row, mut column) = (None, None);
+    for w in tokens.windows(3) {
+        if w[0].0 != Token::Key {
+            continue;
+        }
+        let key = &input[w[0].1.clone()];
+        let mut segments = key.rsplit('.');
+        let last = segments.next().unwrap_or(key);
+        if last == "position" || segments.next() == Some("position") {
+            in_position = true;
+        }
+
+        let value = &w[2].1;
+        let is_integer = w[2].0 == Token::Key && input[value.clone()].parse::<i64>().is_ok();
+        if in_position && w[1].0 == Token::Assign && is_integer {
+            match last {
+                "row" if row.is_none() => row = Some(value.clone()),
+                "column" if column.is_none() => column = Some(value.clone()),
+                _ => {}
+            }
+        }
+    }
+
+    Some((row?, column?))
+}
+
+/// The kinds of tokens `find_position` cares about, keys include strings and numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    Key,
+    Assign,
+    Other,
+}
+
+/// Collects the elements of the array being scanned, if it's one of the top level ones.
+#[derive(Default)]
+struct ArrayElements {
+    current: Option<(Section, usize)>,
+    start: Option<usize>,
+    ranges: Vec<(Section, usize, usize)>,
+}
+
+impl ArrayElements {
+    fn open(&mut self, section: Section, depth: usize) {
+        self.current = Some((section, depth));
+        self.start = None;
+    }
+
+    /// A token starting at `i` was found at the given depth.
+    fn token(&mut self, i: usize, depth: usize) {
+        if matches!(self.current, Some((_, d)) if d == depth) && self.start.is_none() {
+            self.start = Some(i);
+        }
+    }
+
+    /// An element separator or the end of the array was found at the given depth, `end` is the
+    /// end of the last token.
+    fn separator(&mut self, end: usize, depth: usize) {
+        if let Some((section, d)) = self.current {
+            if d == depth {
+                if let Some(start) = self.start.take() {
+                    self.ranges.push((section, start, end));
+                }
+            }
+        }
+    }
+
+    fn close(&mut self, end: usize, depth: usize) {
+        self.separator(end, depth);
+        if matches!(self.current, Some((_, d)) if d == depth) {
+            self.current = None;
+        }
+    }
+}
+
+fn json_ranges(input: &str) -> Option<Vec<(Section, usize, usize)>> {
+    let bytes = input.as_bytes();
+    let mut elements = ArrayElements::default();
+    let mut depth = 0;
+    let mut last_string = None;
+    let mut key = None;
+    let mut token_end = 0;
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        match c {
+            b'"' => {
+                elements.token(i, depth);
+                let end = string_end(bytes, i, b'"', true)?;
+                last_string = Some(&input[i + 1..end - 1]);
+                token_end = end;
+                i = end;
+                continue;
+            }
+            b':' if depth == 1 => key = last_string.and_then(Section::from_key),
+            b',' => {
+                elements.separator(token_end, depth);
+                if depth == 1 {
+                    key = None;
+                }
+            }
+            b'[' | b'{' => {
+                elements.token(i, depth);
+                depth += 1;
+                if c == b'[' && depth == 2 {
+                    if let Some(section) = key.take() {
+                        elements.open(section, depth);
+                    }
+                }
+                token_end = i + 1;
+            }
+            b']' | b'}' => {
+                elements.close(token_end, depth);
+                depth = depth.checked_sub(1)?;
+                token_end = i + 1;
+            }
+            c if c.is_ascii_whitespace() => {}
+            _ => {
+                elements.token(i, depth);
+                token_end = i + 1;
+            }
+        }
+        i += 1;
+    }
+
+    Some(elements.ranges)
+}
+
+fn toml_ranges(input: &str) -> Option<Vec<(Section, usize, usize)>> {
+    let bytes = input.as_bytes();
+    let mut elements = ArrayElements::default();
+    let mut ranges = vec![];
+    let mut depth = 0;
+    let mut line_start = true;
+    let mut in_root_table = true;
+    let mut key: Option<&str> = None;
+    let mut table: Option<(Section, usize)> = None;
+    let mut token_end = 0;
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        match c {
+            b'\n' => line_start = true,
+            c if c.is_ascii_whitespace() => {}
+            b'#' => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            b'[' if depth == 0 && line_start => {
+                let array_of_tables = bytes.get(i + 1) == Some(&b'[');
+                let close = if array_of_tables { "]]" } else { "]" };
+                let name_start = i + close.len();
+                let name_end = name_start + input[name_start..].find(close)?;
+                let name = input[name_start..name_end].trim();
+
+                // sub tables like `[blocks.position]` belong to the last block
+                let parent = name.split('.').next().map(str::trim);
+                let continues = match table {
+                    Some((section, _)) => {
+                        parent.and_then(Section::from_key) == Some(section) && name.contains('.')
+                    }
+                    None => false,
+                };
+                if !continues {
+                    if let Some((section, start)) = table.take() {
+                        ranges.push((section, start, token_end));
+                    }
+                    if array_of_tables {
+                        table = Section::from_key(name).map(|s| (s, i));
+                    }
+                }
+
+                in_root_table = false;
+                line_start = false;
+                token_end = name_end + close.len();
+                i = token_end;
+                continue;
+            }
+            b'"' | b'\'' => {
+                elements.token(i, depth);
+                let multiline = input[i..].starts_with(if c == b'"' { "\"\"\"" } else { "'''" });
+                let end = if multiline {
+                    let delimiter = &input[i..i + 3];
+                    let mut end = i + 3 + input[i + 3..].find(delimiter)? + 3;
+                    // up to two quotes can be right before the closing delimiter
+                    while bytes.get(end) == Some(&c) {
+                        end += 1;
+                    }
+                    end
+                } else {
+                    string_end(bytes, i, c, c == b'"')?
+                };
+                if depth == 0 && line_start {
+                    key = Some(&input[i + 1..end - 1]);
+                }
+                line_start = false;
+                token_end = end;
+                i = end;
+                continue;
+            }
+            b'=' if depth == 0 => {}
+            b',' => elements.separator(token_end, depth),
+            b'[' | b'{' => {
+                elements.token(i, depth);
+                if depth == 0 && c == b'[' && in_root_table {
+                    if let Some(section) = key.and_then(Section::from_key) {
+                        elements.open(section, depth + 1);
+                    }
+                }
+                depth += 1;
+                token_end = i + 1;
+            }
+            b']' | b'}' => {
+                elements.close(token_end, depth);
+                depth = depth.checked_sub(1)?;
+                token_end = i + 1;
+            }
+            _ => {
+                elements.token(i, depth);
+                if depth == 0 && line_start {
+                    let len = bytes[i..]
+                        .iter()
+                        .take_while(|b| b.is_ascii_alphanumeric() || **b == b'_' || **b == b'-')
+                        .count()
+                        .max(1);
+                    key = Some(&input[i..i + len]);
+                    token_end = i + len;
+                    i += len;
+                    line_start = false;
+                    continue;
+                }
+                token_end = i + 1;
+            }
+        }
+
+        if !c.is_ascii_whitespace() {
+            line_start = false;
+        }
+        i += 1;
+    }
+
+    if let Some((section, start)) = table {
+        ranges.push((section, start, token_end));
+    }
+
+    // inline arrays and arrays of tables can't be mixed, so the order is preserved by keeping the
+    // elements of each section in the order they were found
+    ranges.extend(elements.ranges);
+    ranges.sort_by_key(|&(_, start, _)| start);
+    Some(ranges)
+}
+
+/// How deep arrays and inline tables are nested in the TOML document `input`, at most. Table
+/// headers count as arrays, so documents without arrays and inline tables are 1 or 2 deep.
+pub(crate) fn toml_depth(input: &str) -> usize {
+    let bytes = input.as_bytes();
+    let (mut depth, mut max) = (0_usize, 0);
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'#' => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            c @ (b'"' | b'\'') => {
+                let delimiter = if c == b'"' { "\"\"\"" } else { "'''" };
+                let end = if input[i..].starts_with(delimiter) {
+                    input[i + 3..].find(delimiter).map(|e| i + 3 + e + 3)
+                } else {
+                    string_end(bytes, i, c, c == b'"')
+                };
+                match end {
+                    Some(end) => i = end,
+                    None => break,
+                }
+                continue;
+            }
+            b'[' | b'{' => {
+                depth += 1;
+                max = max.max(depth);
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        i += 1;
+    }
+    max
+}
+
+/// The index right after the closing quote of the string starting at `start`.
+fn string_end(bytes: &[u8], start: usize, quote: u8, escapes: bool) -> Option<usize> {
+    let mut i = start + 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' if escapes => i += 1,
+            b if b == quote => return Some(i + 1),
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The text and the line of each span.
+    fn texts(input: &str, spans: Vec<Span>) -> Vec<(&str, usize)> {
+        spans
+            .into_iter()
+            .map(|s| (&input[s.start..s.end], s.line))
+            .collect()
+    }
+
+    #[test]
+    fn test_json_spans() {
+        let input = r#"{
+  "edges": [{"from": "a", "to": "b]"}],
+  "blocks": [
+    {"text": "a \"[\"", "position": {"row": 0, "column": 0}},
+    {"text": "b]", "position": {"row": 0, "column": 1}}
+  ],
+  "note": ["blocks"]
+}"#;
+
+        let (blocks, edges) = find_spans(input, Format::Json).unwrap();
+        let (blocks, edges) = (texts(input, blocks), texts(input, edges));
+        assert_eq!(
+            blocks,
+            vec![
+                (
+                    r#"{"text": "a \"[\"", "position": {"row": 0, "column": 0}}"#,
+                    4
+                ),
+                (r#"{"text": "b]", "position": {"row": 0, "column": 1}}"#, 5),
+            ]
+        );
+        assert_eq!(edges, vec![(r#"{"from": "a", "to": "b]"}"#, 2)]);
+    }
+
+    #[test]
+    fn test_toml_spans() {
+        let input = r#"
+edges = [ {from = "a", to = "b"}, # a comment with [brackets]
+          {from = "b", to = "a"} ]
+
+[[blocks]]
+text = """
+[not a header]
+"""
+position = {row = 0, column = 0}
+
+# the sub table belongs to the block
+[[blocks]]
+text = 'b'
+[blocks.position]
+row = 0
+column = 1
+
+[options]
+blocks = [1]
+"#;
+
+        let (blocks, edges) = find_spans(input, Format::Toml).unwrap();
+        let (blocks, edges) = (texts(input, blocks), texts(input, edges));
+        assert_eq!(
+            blocks,
+            vec![
+                (
+                    "[[blocks]]\ntext = \"\"\"\n[not a header]\n\"\"\"\nposition = {row = 0, column = 0}",
+                    5
+                ),
+                (
+                    "[[blocks]]\ntext = 'b'\n[blocks.position]\nrow = 0\ncolumn = 1",
+                    12
+                ),
+            ]
+        );
+        assert_eq!(
+            edges,
+            vec![
+                (r#"{from = "a", to = "b"}"#, 2),
+                (r#"{from = "b", to = "a"}"#, 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_position() {
+        fn position(input: &str) -> Option<(&str, &str)> {
+            let span = Span {
+                start: 0,
+                end: input.len(),
+                line: 1,
+            };
+            find_position(input, span).map(|(r, c)| (&input[r], &input[c]))
+        }
+
+        assert_eq!(
+            position(r#"{"text": "row: 3", "position": {"column": -1, "row": 2}}"#),
+            Some(("2", "-1"))
+        );
+        assert_eq!(
+            position("[[blocks]]\ntext = 'a'\n[blocks.position]\nrow = 0 # top\ncolumn = 4"),
+            Some(("0", "4"))
+        );
+        assert_eq!(
+            position("text = 'a'\nposition.row = 1\nposition.column = 2"),
+            Some(("1", "2"))
+        );
+        assert_eq!(position("text = 'a'\nrow = 1\ncolumn = 2"), None);
+    }
+}
@@ -0,0 +1,60 @@
+use crate::spec::{Spec, SpecEdge, SpecNode};
+
+/// Turn the elements of a C4 diagram into nodes and its relationships into directed edges, to be
+/// laid out by `expand_layered`. Each element is drawn with its name, its type and technology in
+/// square brackets and its description, like `[Container: Rust]`. The lines of the legend of the
+/// diagram are returned.
+pub fn expand_c4(spec: &mut Spec) -> Vec<String> {
+    let elements = [
+        ("Person", std::mem::take(&mut spec.persons)),
+        ("Software System", std::mem::take(&mut spec.systems)),
+        ("Container", std::mem::take(&mut spec.containers)),
+    ];
+
+    let mut external = false;
+    for (kind, elements) in elements {
+        for e in elements {
+            let mut text = e.name.clone();
+            match &e.technology {
+                Some(t) => text.push_str(&format!("\n[{}: {}]", kind, t)),
+                None => text.push_str(&format!("\n[{}]", kind)),
+            }
+            if let Some(d) = &e.description {
+                text.push_str(&format!("\n\n{}", d));
+            }
+
+            let is_external = e.external || kind == "Person";
+            external |= is_external;
+            spec.nodes.push(SpecNode {
+                id: Some(e.id.unwrap_or(e.name)),
+                text,
+                parent: None,
+                external: is_external,
+            });
+        }
+    }
+
+    let relationships = std::mem::take(&mut spec.relationships);
+    let mut legend = vec![];
+    if external {
+        legend.push("[    ] person or external software system".to_string());
+    }
+    if !relationships.is_empty() {
+        legend.push("-->    relationship, labelled with its description [technology]".to_string());
+    }
+    for r in relationships {
+        let label = match (r.description, r.technology) {
+            (Some(d), Some(t)) => Some(format!("{} [{}]", d, t)),
+            (None, Some(t)) => Some(format!("[{}]", t)),
+            (d, None) => d,
+        };
+        spec.edges.push(SpecEdge {
+            from: r.from,
+            to: r.to,
+            from_label: label,
+            directed: Some(true),
+            ..SpecEdge::default()
+        });
+    }
+    legend
+}
@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use crate::spec::{Spec, SpecBlock, SpecPosition};
+use crate::LogicalCoord;
+
+/// Names of the days of the week, starting from Monday.
+const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+/// Add a block for each day of the month or week of the calendar to the spec, one week per row
+/// with the names of the days as column labels unless the spec has its own. All the days are as
+/// large as the one with the longest event. Return the warnings about the events of other days.
+pub fn expand_calendar(spec: &mut Spec) -> Result<Vec<String>, String> {
+    let (first, end) = match (&spec.month, &spec.week) {
+        (Some(month), None) => {
+            let first = parse_date(&format!("{}-01", month))
+                .ok_or_else(|| format!(r#"invalid month "{}", it must be YYYY-MM"#, month))?;
+            let (y, m, _) = civil_from_days(first);
+            let next = if m == 12 { (y + 1, 1) } else { (y, m + 1) };
+            (first, days_from_civil(next.0, next.1, 1))
+        }
+        (None, Some(week)) => {
+            let day = parse_date(week)
+                .ok_or_else(|| format!(r#"invalid week "{}", it must be YYYY-MM-DD"#, week))?;
+            let monday = day - weekday(day);
+            (monday, monday + 7)
+        }
+        (Some(_), Some(_)) => return Err("a calendar has either a month or a week".to_string()),
+        (None, None) => return Err("a calendar needs a month or a week".to_string()),
+    };
+
+    let mut events = BTreeMap::new();
+    let mut warnings = vec![];
+    for (date, text) in std::mem::take(&mut spec.events) {
+        let day = parse_date(&date)
+            .ok_or_else(|| format!(r#"invalid date "{}", it must be YYYY-MM-DD"#, date))?;
+        if (first..end).contains(&day) {
+            events.insert(day, text);
+        } else {
+            warnings.push(format!(
+                "the event on {} is ignored, the day is not in the calendar",
+                date
+            ));
+        }
+    }
+
+    let texts = (first..end)
+        .map(|day| {
+            let (_, _, d) = civil_from_days(day);
+            match events.remove(&day) {
+                Some(text) => format!("{}\n{}", d, text),
+                None => d.to_string(),
+            }
+        })
+        .collect::<Vec<_>>();
+    let width = texts
+        .iter()
+        .flat_map(|t| t.lines())
+        .map(|l| l.chars().count())
+        .chain(WEEKDAYS.iter().map(|d| d.len()))
+        .max()
+        .unwrap_or(0);
+    let height = texts.iter().map(|t| t.lines().count()).max().unwrap_or(0);
+
+    // the text of each day is padded to the same width so that it's aligned to the left like
+    // in paper calendars
+    let texts = texts.into_iter().map(|t| {
+        t.lines()
+            .map(|l| format!("{:w$}", l, w = width))
+            .collect::<Vec<_>>()
+            .join("\n")
+    });
+
+    let start = first - weekday(first);
+    for (day, text) in (first..end).zip(texts) {
+        let (y, m, d) = civil_from_days(day);
+        let offset = day - start;
+        spec.blocks.push(SpecBlock {
+            id: Some(format!("{:04}-{:02}-{:02}", y, m, d)),
+            text,
+            position: SpecPosition {
+                row: LogicalCoord::try_from(offset / 7).map_err(|e| e.to_string())?,
+                column: LogicalCoord::try_from(offset % 7).map_err(|e| e.to_string())?,
+            },
+            width: Some(width + 2 + 2 * spec.padding),
+            height: Some(height + 2 + 2 * spec.padding),
+            ..SpecBlock::default()
+        });
+    }
+
+    if spec.column_labels.is_empty() {
+        spec.column_labels = WEEKDAYS.iter().map(|d| d.to_string()).collect();
+    }
+    spec.compact = true;
+    Ok(warnings)
+}
+
+/// The days since 1970-01-01 of a `YYYY-MM-DD` date, if it's valid.
+fn parse_date(date: &str) -> Option<i64> {
+    let mut parts = date.splitn(3, '-');
+    let y = parts.next()?.parse::<i64>().ok()?;
+    let m = parts.next()?.parse::<u32>().ok()?;
+    let d = parts.next()?.parse::<u32>().ok()?;
+    if !(1..=12).contains(&m) || d == 0 {
+        return None;
+    }
+
+    // days past the end of the month end up in the next one
+    let days = days_from_civil(y, m, d);
+    Some(days).filter(|&days| civil_from_days(days) == (y, m, d))
+}
+
+/// The day of the week of the given days since 1970-01-01, 0 is Monday.
+fn weekday(days: i64) -> i64 {
+    // 1970-01-01 was a Thursday
+    (days + 3).rem_euclid(7)
+}
+
+/// The days since 1970-01-01 of the given day of the Gregorian calendar, see
+/// http://howardhinnant.github.io/date_algorithms.html.
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (i64::from(m) + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + i64::from(d) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// The inverse of `days_from_civil`, as (year, month, day).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = ((mp + 2) % 12 + 1) as u32;
+    (
+        if m <= 2 {
+            yoe + era * 400 + 1
+        } else {
+            yoe + era * 400
+        },
+        m,
+        d,
+    )
+}
@@ -0,0 +1,206 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::render::{BlockRect, CanvasPoint};
+use crate::spec::nodes::{node_ids, push_nodes, ComputedRoutes};
+use crate::spec::{Spec, SpecEdge, SpecOrientation};
+
+/// Add a block for each node of a layered diagram to the spec, in the layer after the ones of all
+/// the nodes with an edge to it, and an edge from the parent of each node to it. Nodes in the same
+/// layer are in the order they're declared, external ones last.
+///
+/// Cycles are broken by reversing the edges that close them, the feedback edges, only to find the
+/// layers. They're returned to be drawn looping around the diagram, see `loop_route`.
+pub fn expand_layered(spec: &mut Spec) -> Result<ComputedRoutes, String> {
+    let nodes = std::mem::take(&mut spec.nodes);
+    let (ids, index) = node_ids(&nodes)?;
+
+    for (n, id) in nodes.iter().zip(&ids) {
+        if let Some(p) = &n.parent {
+            if !index.contains_key(p) {
+                return Err(format!(
+                    r#"the parent "{}" of node "{}" doesn't exist"#,
+                    p, id
+                ));
+            }
+            spec.edges.push(SpecEdge {
+                from: p.clone(),
+                to: id.clone(),
+                ..SpecEdge::default()
+            });
+        }
+    }
+
+    // edges from or to blocks that aren't nodes don't affect the layers
+    let mut successors = vec![vec![]; nodes.len()];
+    for e in &spec.edges {
+        if let (Some(&from), Some(&to)) = (index.get(&e.from), index.get(&e.to)) {
+            if from != to {
+                successors[from].push(to);
+            }
+        }
+    }
+
+    let feedback = feedback_edges(&successors);
+    let mut predecessors = vec![vec![]; nodes.len()];
+    for (from, succ) in successors.iter().enumerate() {
+        for &to in succ {
+            if feedback.contains(&(from, to)) {
+                predecessors[from].push(to);
+            } else {
+                predecessors[to].push(from);
+            }
+        }
+    }
+
+    // with the feedback edges reversed there are no cycles left
+    let mut layers = vec![None; nodes.len()];
+    for i in 0..nodes.len() {
+        layer_of(i, &predecessors, &mut layers);
+    }
+
+    // external nodes are after the others in their layer, on the periphery of the diagram
+    let mut order = (0..nodes.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| nodes[i].external);
+    let mut slots = HashMap::<usize, usize>::new();
+    let mut placed = vec![(0, 0); nodes.len()];
+    for i in order {
+        let layer = layers[i].unwrap_or(0);
+        let slot = slots.entry(layer).or_default();
+        placed[i] = (layer, *slot);
+        *slot += 1;
+    }
+    push_nodes(spec, nodes, &ids, placed.into_iter())?;
+
+    let mut routes = ComputedRoutes {
+        orientation: spec.orientation,
+        ..ComputedRoutes::default()
+    };
+    let mut feedback = feedback.into_iter().collect::<Vec<_>>();
+    feedback.sort_unstable();
+    for (from, to) in feedback {
+        routes.loops.push((ids[from].clone(), ids[to].clone()));
+    }
+    Ok(routes)
+}
+
+/// Find a small set of edges that breaks all the cycles of the graph with the given successors,
+/// the ones that go back to a node still being visited in a depth first search from the nodes in
+/// order.
+fn feedback_edges(successors: &[Vec<usize>]) -> HashSet<(usize, usize)> {
+    // 0 is not visited yet, 1 is being visited and 2 is done
+    let mut state = vec![0_u8; successors.len()];
+    let mut feedback = HashSet::new();
+    for root in 0..successors.len() {
+        if state[root] != 0 {
+            continue;
+        }
+
+        state[root] = 1;
+        let mut stack = vec![(root, 0)];
+        while let Some((n, next)) = stack.last_mut() {
+            let n = *n;
+            match successors[n].get(*next) {
+                Some(&s) => {
+                    *next += 1;
+                    match state[s] {
+                        0 => {
+                            state[s] = 1;
+                            stack.push((s, 0));
+                        }
+                        1 => {
+                            feedback.insert((n, s));
+                        }
+                        _ => {}
+                    }
+                }
+                None => {
+                    state[n] = 2;
+                    stack.pop();
+                }
+            }
+        }
+    }
+    feedback
+}
+
+/// The layer of a node, that is one after the last layer of its predecessors or 0 if it has none.
+/// `predecessors` must not have cycles.
+fn layer_of(node: usize, predecessors: &[Vec<usize>], layers: &mut [Option<usize>]) -> usize {
+    if let Some(layer) = layers[node] {
+        return layer;
+    }
+    let layer = predecessors[node]
+        .iter()
+        .map(|&p| layer_of(p, predecessors, layers) + 1)
+        .max()
+        .unwrap_or(0);
+    layers[node] = Some(layer);
+    layer
+}
+
+/// The route of the `lane`-th feedback edge of a layered diagram `width` x `height` characters
+/// big. It leaves its block from the side facing the next layer, that is the bottom one or the
+/// right one with `SpecOrientation::Horizontal`, runs around all the blocks in its own lane and
+/// enters the block it points to from the side facing the previous layer. Both ends are next to a
+/// corner to keep them apart from the other edges. None if there's no room for the route.
+pub fn loop_route(
+    from: &BlockRect,
+    to: &BlockRect,
+    blocks: &[BlockRect],
+    lane: usize,
+    orientation: SpecOrientation,
+    (width, height): (usize, usize),
+) -> Option<Vec<CanvasPoint>> {
+    let route = match orientation {
+        SpecOrientation::Vertical => {
+            let (x0, x1) = (from.x + from.width - 2, to.x + to.width - 2);
+            let (y0, y1) = (from.y + from.height - 1, to.y);
+            let (out, into) = (y0 + 2, y1.checked_sub(2)?);
+            let lane = blocks.iter().map(|b| b.x + b.width).max()? + 1 + 2 * lane;
+            if lane >= width || out >= height {
+                return None;
+            }
+            vec![
+                (x0, y0),
+                (x0, out),
+                (lane, out),
+                (lane, into),
+                (x1, into),
+                (x1, y1),
+            ]
+        }
+        SpecOrientation::Horizontal => {
+            let (x0, x1) = (from.x + from.width - 1, to.x);
+            let (y0, y1) = (from.y + from.height - 2, to.y + to.height - 2);
+            let (out, into) = (x0 + 2, x1.checked_sub(2)?);
+            let lane = blocks.iter().map(|b| b.y + b.height).max()? + 1 + 2 * lane;
+            if lane >= height || out >= width {
+                return None;
+            }
+            vec![
+                (x0, y0),
+                (out, y0),
+                (out, lane),
+                (into, lane),
+                (into, y1),
+                (x1, y1),
+            ]
+        }
+    };
+    Some(route.into_iter().map(CanvasPoint::from).collect())
+}
+
+/// The warning about the feedback edges drawn around a layered diagram, if there are any.
+pub fn describe_loops(loops: &[(String, String)]) -> Option<String> {
+    if loops.is_empty() {
+        return None;
+    }
+    let loops = loops
+        .iter()
+        .map(|(from, to)| format!(r#""{}" -> "{}""#, from, to))
+        .collect::<Vec<_>>();
+    Some(format!(
+        "feedback edges drawn around the diagram to break cycles: {}",
+        loops.join(", ")
+    ))
+}
@@ -0,0 +1,89 @@
+use std::convert::TryFrom;
+
+use crate::spec::{Spec, SpecBlock, SpecMatrix, SpecPosition};
+use crate::LogicalCoord;
+
+/// Characters filling the cells of a heatmap, from the lowest value to the highest.
+const HEAT_RAMP: &[u8] = b" .:-=+*#%@";
+
+/// Add a block for each cell of the matrix to the spec, the labels of its columns and rows are
+/// used as headers unless the spec has its own.
+pub fn expand_matrix(spec: &mut Spec, matrix: SpecMatrix) -> Result<(), String> {
+    // the length of each row of cells, emphasis and heat must match the labels
+    let check_shape = |what: &str, lens: Vec<usize>| {
+        if lens.len() != matrix.rows.len() {
+            return Err(format!(
+                "the matrix has {} rows but {} has {}",
+                matrix.rows.len(),
+                what,
+                lens.len()
+            ));
+        }
+        match lens.iter().position(|&n| n != matrix.columns.len()) {
+            Some(r) => Err(format!(
+                "the matrix has {} columns but row {} of {} has {}",
+                matrix.columns.len(),
+                r,
+                what,
+                lens[r]
+            )),
+            None => Ok(()),
+        }
+    };
+    check_shape("cells", matrix.cells.iter().map(Vec::len).collect())?;
+    if !matrix.emphasis.is_empty() {
+        check_shape("emphasis", matrix.emphasis.iter().map(Vec::len).collect())?;
+    }
+    if !matrix.heat.is_empty() {
+        check_shape("heat", matrix.heat.iter().map(Vec::len).collect())?;
+    }
+
+    let SpecMatrix {
+        columns,
+        rows,
+        cells,
+        emphasis,
+        heat: values,
+    } = matrix;
+
+    let finite = values.iter().flatten().filter(|v| v.is_finite());
+    let min = finite.clone().copied().fold(f64::INFINITY, f64::min);
+    let max = finite.copied().fold(f64::NEG_INFINITY, f64::max);
+    let heat = |v: f64| {
+        let t = if max > min {
+            (v - min) / (max - min)
+        } else {
+            1.0
+        };
+        let c = HEAT_RAMP[(t * (HEAT_RAMP.len() - 1) as f64).round() as usize];
+        Some(char::from(c)).filter(|_| v.is_finite() && c != b' ')
+    };
+
+    for (r, row) in cells.into_iter().enumerate() {
+        for (c, text) in row.into_iter().enumerate() {
+            let fill = emphasis
+                .get(r)
+                .and_then(|e| e[c].chars().next())
+                .or_else(|| values.get(r).and_then(|v| heat(v[c])));
+
+            spec.blocks.push(SpecBlock {
+                id: Some(format!("{}/{}", r, c)),
+                text,
+                position: SpecPosition {
+                    row: LogicalCoord::try_from(r).map_err(|e| e.to_string())?,
+                    column: LogicalCoord::try_from(c).map_err(|e| e.to_string())?,
+                },
+                fill,
+                ..SpecBlock::default()
+            });
+        }
+    }
+
+    if spec.column_labels.is_empty() {
+        spec.column_labels = columns;
+    }
+    if spec.row_labels.is_empty() {
+        spec.row_labels = rows;
+    }
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+
+use crate::render::{add_offsets, Layout};
+use crate::spec::{Spec, SpecBlock, SpecPosition};
+
+/// The addresses of the blocks of a memory map, see `expand_memmap`.
+pub struct MemmapAddresses {
+    // ids of the blocks alongside the address they start at.
+    starts: Vec<(String, u64)>,
+
+    // the address the last block ends at.
+    end: Option<u64>,
+}
+
+/// Add a block for each range of the memory map to the spec, stacked in a column from the lowest
+/// address to the highest one, and a block filled with dots for each gap between them.
+pub fn expand_memmap(spec: &mut Spec) -> Result<MemmapAddresses, String> {
+    let mut ranges = std::mem::take(&mut spec.ranges);
+    ranges.sort_by_key(|r| (r.start, r.end));
+
+    if let Some(r) = ranges.iter().find(|r| r.end <= r.start) {
+        return Err(format!(r#"range "{}" ends before it starts"#, r.name));
+    }
+    if let Some(w) = ranges.windows(2).find(|w| w[1].start < w[0].end) {
+        return Err(format!(
+            r#"ranges "{}" and "{}" overlap"#,
+            w[0].name, w[1].name
+        ));
+    }
+
+    let mut starts = vec![];
+    let mut row = spec
+        .blocks
+        .iter()
+        .map(|b| b.position.row + 1)
+        .max()
+        .unwrap_or(0);
+    let mut push = |spec: &mut Spec, id: String, text: String, start, fill| {
+        spec.blocks.push(SpecBlock {
+            id: Some(id.clone()),
+            text,
+            position: SpecPosition { row, column: 0 },
+            fill,
+            ..SpecBlock::default()
+        });
+        starts.push((id, start));
+        row += 1;
+    };
+
+    let mut end = None;
+    for r in ranges {
+        if let Some(e) = end.filter(|&e| e < r.start) {
+            push(spec, format!("{:#x}", e), String::new(), e, Some('.'));
+        }
+        push(spec, r.name.clone(), r.name, r.start, None);
+        end = Some(r.end);
+    }
+
+    spec.compact = true;
+    Ok(MemmapAddresses { starts, end })
+}
+
+/// Draw the addresses of a memory map to the left of the rendered diagram, at the top border of
+/// each range and at the bottom border of the last one.
+pub fn add_addresses(
+    canvas: Vec<Vec<u8>>,
+    layout: &Layout,
+    addresses: &MemmapAddresses,
+    id_to_block_id: &HashMap<&String, usize>,
+) -> (Vec<Vec<u8>>, Layout) {
+    let MemmapAddresses { starts, end } = addresses;
+    let digits = end.map_or(1, |e| format!("{:x}", e).len()).max(4);
+    let address = |a: u64| format!("0x{:0w$x}", a, w = digits);
+
+    let mut offsets = starts
+        .iter()
+        .filter_map(|(id, start)| {
+            let r = layout.blocks[*id_to_block_id.get(id)?];
+            Some((r.y, address(*start)))
+        })
+        .collect::<Vec<_>>();
+    let last = starts.last().and_then(|(id, _)| id_to_block_id.get(id));
+    if let (Some(&i), Some(end)) = (last, end) {
+        let r = layout.blocks[i];
+        offsets.push((r.y + r.height - 1, address(*end)));
+    }
+
+    add_offsets(canvas, layout, &offsets)
+}
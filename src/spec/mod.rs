@@ -0,0 +1,4960 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+mod c4;
+mod calendar;
+mod layered;
+mod matrix;
+mod memmap;
+mod nodes;
+mod packet;
+mod tree;
+
+use crate::d2::d2_to_json;
+use crate::dot::dot_to_json;
+use crate::plantuml::plantuml_to_json;
+use crate::render::{
+    add_guides, add_headers, add_polylines, add_stubs, auto_margins, collisions, compact_margins,
+    detours, edge_intrusions, estimate_size, fit_to_width, hide_elements, logical_grid,
+    optimize_layout, overlapping_blocks, render_with_layout, score_layout, sparkline, suggest,
+    try_render_layout, Attachment, BlockRect, Canvas, CanvasPoint, CollisionKind, EdgeRoute,
+    Element, Layout, LogicalGrid, Metrics, Overlap, Polyline, PolylineError, PolylinePoint,
+    RenderOptions, RouteError, RouteFailure, Suggestion, CUT_MARKER,
+};
+use crate::spans::{find_spans, toml_depth, Span};
+use crate::{Block, Edge, LogicalCoord, LogicalPos, Overflow, Shape, TextDirection};
+use c4::expand_c4;
+use calendar::expand_calendar;
+use layered::{describe_loops, expand_layered};
+use matrix::expand_matrix;
+use memmap::{add_addresses, expand_memmap, MemmapAddresses};
+use nodes::{add_computed_routes, ComputedRoutes};
+use packet::render_packet;
+use tree::expand_tree;
+
+// serde doesn't support literals as default values yet, have to use functions instead...
+//
+// See https://github.com/serde-rs/serde/issues/368
+const fn default_hmargin() -> usize {
+    5
+}
+const fn default_vmargin() -> usize {
+    3
+}
+const fn default_padding() -> usize {
+    1
+}
+const fn default_tab_width() -> usize {
+    4
+}
+const fn default_bits_per_row() -> usize {
+    32
+}
+
+/// How many times longer than the distance between its blocks an edge must be to be reported as
+/// a detour, see `detours`.
+const DETOUR_FACTOR: usize = 4;
+
+/// The languages a diagram can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Toml,
+    Json,
+
+    /// Graphviz DOT, see `dot_to_json`.
+    Dot,
+
+    /// The same structure as TOML and JSON written in YAML.
+    Yaml,
+
+    /// The D2 diagram language, see `d2_to_json`.
+    D2,
+
+    /// PlantUML component and use case diagrams, see `plantuml_to_json`.
+    PlantUml,
+}
+
+impl Format {
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext {
+            "toml" => Some(Format::Toml),
+            "json" => Some(Format::Json),
+            "dot" | "gv" => Some(Format::Dot),
+            "yaml" | "yml" => Some(Format::Yaml),
+            "d2" => Some(Format::D2),
+            "puml" | "plantuml" | "pu" => Some(Format::PlantUml),
+            _ => None,
+        }
+    }
+
+    /// The usual file extension of diagrams in this format, see `from_extension`.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Toml => "toml",
+            Format::Json => "json",
+            Format::Dot => "dot",
+            Format::Yaml => "yaml",
+            Format::D2 => "d2",
+            Format::PlantUml => "puml",
+        }
+    }
+
+    /// Guess the format of the diagram `text`. JSON diagrams must be objects while TOML ones
+    /// cannot start with a curly brace, use that to tell them apart. DOT diagrams start with
+    /// `graph`, `digraph` or `strict` after any comment, not followed by an equal sign which
+    /// would make it a TOML key. D2 diagrams have a connection like `a -> b` on a line that isn't
+    /// indented, anywhere in the diagram since they can start with keys like `direction: right`.
+    /// The first line of YAML diagrams other than comments is `---`, an item or a key followed by
+    /// a colon rather than an equal sign. PlantUML diagrams start with `@startuml`.
+    pub fn guess(text: &str) -> Self {
+        let text = text.trim_start();
+        let code = skip_leading_comments(text);
+        let first = code.split_whitespace().next().unwrap_or("");
+        let dot = ["graph", "digraph", "strict"]
+            .iter()
+            .any(|kw| first.eq_ignore_ascii_case(kw) || first == format!("{}{{", kw));
+        let mut lines = text
+            .lines()
+            .filter(|l| !l.starts_with([' ', '\t']))
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'));
+        let line = lines.clone().next().unwrap_or("");
+        let key = match (line.find(':'), line.find('=')) {
+            (Some(colon), Some(equal)) => colon < equal,
+            (colon, _) => colon.is_some(),
+        };
+        let yaml = line == "---" || line.starts_with("- ") || (key && !line.starts_with('['));
+        let d2 = lines.any(|l| {
+            let head = l.split([':', '=', '{', '"']).next().unwrap_or("");
+            !head.starts_with('-') && ["->", "<-", "--"].iter().any(|a| head.contains(a))
+        });
+        if text.starts_with('{') {
+            Format::Json
+        } else if text.starts_with("@startuml") {
+            Format::PlantUml
+        } else if dot && !code[first.len()..].trim_start().starts_with('=') {
+            Format::Dot
+        } else if d2 {
+            Format::D2
+        } else if yaml {
+            Format::Yaml
+        } else {
+            Format::Toml
+        }
+    }
+}
+
+/// Skip the comments at the start of `text`, the `//`, `/* */` and `#` comments of DOT diagrams.
+fn skip_leading_comments(mut text: &str) -> &str {
+    loop {
+        text = text.trim_start();
+        if text.starts_with("//") || text.starts_with('#') {
+            text = text.find('\n').map_or("", |i| &text[i..]);
+        } else if let Some(rest) = text.strip_prefix("/*") {
+            text = rest.find("*/").map_or("", |i| &rest[i + 2..]);
+        } else {
+            return text;
+        }
+    }
+}
+
+/// Options that change how a diagram is rendered on top of what the diagram itself specifies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overrides {
+    /// Seed to use for the rendering algorithm.
+    pub seed: Option<u64>,
+
+    /// Maximum number of tweaks to find the best arrangement of lines.
+    pub max_tweaks: usize,
+
+    /// Margins to use instead of the ones in the diagram.
+    pub horizontal_margin: Option<usize>,
+    pub vertical_margin: Option<usize>,
+
+    /// Transliterate non-ASCII characters to their closest ASCII representation instead of
+    /// dropping them.
+    pub ascii_only: bool,
+
+    /// Report some changes to the diagram that would make it easier to read as warnings, see
+    /// `suggest`.
+    pub suggest: bool,
+
+    /// Move the blocks around to minimize crossings and edge length, see `optimize_layout`.
+    pub optimize_layout: bool,
+
+    /// Increase the margins until the diagram is easy to read, see `auto_margins`.
+    pub auto_margins: bool,
+
+    /// Render only the edges with at least one of these tags, all of them if it's empty.
+    pub edge_tags: Vec<String>,
+
+    /// Do not render the edges with any of these tags.
+    pub exclude_edge_tags: Vec<String>,
+
+    /// Draw the edges with any of these tags dashed, like the legacy ones.
+    pub dashed_edge_tags: Vec<String>,
+
+    /// Emit the fully resolved diagram as JSON instead of rendering it.
+    pub dump_resolved: bool,
+
+    /// Emit the geometry of the diagram as JSON instead of rendering it, see `render_layout`.
+    pub layout_json: bool,
+
+    /// Render the diagram using the routes in a layout JSON previously emitted with
+    /// `layout_json`.
+    pub from_layout: Option<PathBuf>,
+
+    /// Route the edges like in a layout JSON previously emitted with `layout_json` where
+    /// possible, so that small changes to the diagram produce small changes to its rendering.
+    /// Unlike with `from_layout` the layout doesn't need to match the diagram, see
+    /// `Edge::with_route`.
+    pub prev_layout: Option<PathBuf>,
+
+    /// Directory where to cache the routes of the edges.
+    pub cache_dir: Option<PathBuf>,
+
+    /// Shrink the margins of the rendered diagram where they're mostly empty, see
+    /// `compact_margins`.
+    pub compact_margins: bool,
+
+    /// Mark the corners of the logical cells of the diagram, see `add_guides`.
+    pub guides: bool,
+
+    /// Emit only this region of the rendered diagram.
+    pub viewport: Option<Viewport>,
+
+    /// Split diagrams wider than this in pages of at most this width.
+    pub split_width: Option<usize>,
+
+    /// Render the diagram step by step, as a sequence of frames separated by rows with only a
+    /// form feed. Blocks appear in the order they're declared or, if any of them has one, in the
+    /// order of their `step`, and edges appear with their blocks or at their own `step`. All the
+    /// frames have the same layout, see `hide_elements`.
+    pub frames: bool,
+
+    /// Shrink diagrams wider than `terminal_width`, see `fit_to_width`.
+    pub fit: bool,
+
+    /// Width of the terminal the diagram is written to, if any.
+    pub terminal_width: Option<usize>,
+
+    /// Reject diagrams too big to be rendered safely.
+    pub limits: Limits,
+}
+
+impl Default for Overrides {
+    fn default() -> Self {
+        Overrides {
+            seed: None,
+            max_tweaks: 100,
+            horizontal_margin: None,
+            vertical_margin: None,
+            ascii_only: false,
+            suggest: false,
+            optimize_layout: false,
+            auto_margins: false,
+            edge_tags: vec![],
+            exclude_edge_tags: vec![],
+            dashed_edge_tags: vec![],
+            dump_resolved: false,
+            layout_json: false,
+            from_layout: None,
+            prev_layout: None,
+            cache_dir: None,
+            compact_margins: false,
+            guides: false,
+            viewport: None,
+            split_width: None,
+            frames: false,
+            fit: false,
+            terminal_width: None,
+            limits: Limits::default(),
+        }
+    }
+}
+
+/// Upper bounds on the size of a diagram, to render diagrams from untrusted sources. A diagram
+/// over any of them is rejected with `Error::Limit` before it's laid out, and by default there
+/// are no limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum number of blocks, including the ones added for nodes, ranges, events and the like.
+    pub max_blocks: usize,
+
+    /// Maximum number of edges.
+    pub max_edges: usize,
+
+    /// Maximum length of any text in the diagram, after expanding the tabs.
+    pub max_text_length: usize,
+
+    /// Maximum number of characters of the rendered diagram.
+    pub max_canvas_cells: usize,
+
+    /// Maximum time spent tweaking the lines, see `RenderOptions::max_tweak_time`. Diagrams are
+    /// never rejected for it, the lines are tweaked less instead.
+    pub max_tweak_time: Option<Duration>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_blocks: usize::MAX,
+            max_edges: usize::MAX,
+            max_text_length: usize::MAX,
+            max_canvas_cells: usize::MAX,
+            max_tweak_time: None,
+        }
+    }
+}
+
+/// The limit of `Limits` a diagram exceeds, alongside its value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Blocks(usize),
+    Edges(usize),
+    TextLength(usize),
+    CanvasCells(usize),
+}
+
+/// The row separating the frames of a diagram rendered with `Overrides::frames`.
+pub const FRAME_SEPARATOR: &[u8] = b"\x0c";
+
+/// A rectangular region of the rendered diagram, see `Overrides::viewport`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Viewport {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl FromStr for Viewport {
+    type Err = String;
+
+    /// Parse a `Viewport` written as `x,y,width,height`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let coords = s
+            .split(',')
+            .map(|c| c.trim().parse::<usize>().map_err(|e| e.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        match coords[..] {
+            [x, y, width, height] => Ok(Viewport {
+                x,
+                y,
+                width,
+                height,
+            }),
+            _ => Err(format!(
+                r#"invalid viewport "{}", expected x,y,width,height"#,
+                s
+            )),
+        }
+    }
+}
+
+/// The reasons a diagram cannot be rendered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The diagram or a layout JSON is malformed.
+    Parse(String),
+
+    /// The diagram cannot be rendered as requested, for example because the text of a block
+    /// doesn't fit or a layout JSON doesn't match the diagram.
+    Invalid(String),
+
+    /// A file could not be read.
+    Io(String),
+
+    /// The diagram exceeds one of the `Overrides::limits`.
+    Limit(Limit),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Parse(e) | Error::Invalid(e) | Error::Io(e) => write!(f, "{}", e),
+            Error::Limit(Limit::Blocks(n)) => write!(f, "the diagram has more than {} blocks", n),
+            Error::Limit(Limit::Edges(n)) => write!(f, "the diagram has more than {} edges", n),
+            Error::Limit(Limit::TextLength(n)) => {
+                write!(f, "the diagram has a text longer than {} characters", n)
+            }
+            Error::Limit(Limit::CanvasCells(n)) => {
+                write!(
+                    f,
+                    "the diagram would be drawn on more than {} characters",
+                    n
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A diagram rendered by `render_spec_str` alongside its geometry and what happened while
+/// rendering it. It's displayed as the text of the diagram.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenderedDiagram {
+    /// The rows of the rendered diagram, or of the JSON emitted instead of it.
+    pub rows: Vec<Vec<u8>>,
+
+    /// What went wrong or was changed while rendering the diagram, like ignored edges between
+    /// unknown blocks, alongside the suggestions if requested.
+    pub warnings: Vec<String>,
+
+    /// The options the diagram was rendered with, including the margins chosen by
+    /// `Overrides::auto_margins` and `Overrides::fit`.
+    pub config: RenderOptions,
+
+    /// The ids of the blocks, in the same order as the blocks of the layout.
+    pub ids: Vec<String>,
+
+    /// Where each block is written in the source of the diagram, in the same order as `ids`. A
+    /// span is `None` when the source is too unusual to be scanned, like with dotted keys.
+    pub block_spans: Vec<Option<Span>>,
+
+    /// Where each edge is written in the source of the diagram, in the same order as the edges
+    /// of the layout. Edges inferred from the text of a block point to that block instead.
+    pub edge_spans: Vec<Option<Span>>,
+
+    /// The rectangles of the blocks and the polylines of the edges of the whole diagram, after
+    /// applying `Overrides::compact_margins` and adding the column and row labels but before
+    /// applying `Overrides::viewport` and `Overrides::split_width`. It's `None` with
+    /// `Overrides::dump_resolved` and for packet diagrams, which have no blocks nor edges.
+    pub layout: Option<Layout>,
+
+    /// The score of the final arrangement of blocks and edges, see `score`. It's `None` when
+    /// `layout` is.
+    pub score: Option<Metrics>,
+
+    /// The columns and rows of the blocks, in the same order as `ids`, see `logical_grid`. It's
+    /// `None` when `layout` is.
+    pub grid: Option<LogicalGrid>,
+}
+
+impl fmt::Display for RenderedDiagram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, row) in self.rows.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", String::from_utf8_lossy(row))?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse the diagram `input` written in `format` and render it, applying the `overrides`.
+///
+/// Problems that don't prevent rendering the diagram, like edges referencing unknown blocks,
+/// are not errors but are reported in `RenderedDiagram::warnings` instead.
+pub fn render_spec_str(
+    input: &str,
+    format: Format,
+    overrides: &Overrides,
+) -> Result<RenderedDiagram, Error> {
+    let spec = parse_spec(input, format)?;
+    render_diagram(spec, overrides)
+}
+
+/// A diagram rendered by `render_spec_all` alongside where it is among the others.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkedDiagram {
+    /// The name of the diagram among the `diagrams` of the top one, empty for the top one itself.
+    pub name: String,
+
+    /// The titles of the diagrams from the top one to this one, following the blocks detailed by
+    /// each of them. Diagrams without a `title` are referred to by their name.
+    pub breadcrumbs: Vec<String>,
+
+    pub rendered: RenderedDiagram,
+}
+
+/// The options of a diagram that the ones in its `diagrams` use too, unless they set them.
+const SHARED_STYLE: &[&str] = &[
+    "horizontal_margin",
+    "vertical_margin",
+    "cell_aspect",
+    "padding",
+    "compact",
+    "tab_width",
+    "gutter_threshold",
+    "symmetric",
+    "block_aspect",
+    "route_failure",
+    "attachment",
+    "directed",
+    "id_matching",
+    "sibling_order",
+    "orientation",
+    "connector",
+];
+
+/// Render the diagram `input` written in `format` and all the diagrams in its `diagrams`, which
+/// detail its blocks, like the containers of a system in a C4 model. A block links to the diagram
+/// with its details by its name with `details` and each diagram is followed by a legend with its
+/// links.
+///
+/// Diagrams are rendered starting from `input`, each followed by the ones its blocks link to in
+/// order, and their breadcrumbs follow the same links. Diagrams not linked from any block are
+/// rendered last. All of them are rendered with the same `overrides` and the style options of
+/// `input`, like the margins, unless they set them.
+pub fn render_spec_all(
+    input: &str,
+    format: Format,
+    overrides: &Overrides,
+) -> Result<Vec<LinkedDiagram>, Error> {
+    let mut top: serde_json::Value = match format {
+        Format::Json => serde_json::from_str(input).map_err(|e| Error::Parse(e.to_string()))?,
+        Format::Toml => from_toml(input)?,
+        Format::Dot => dot_to_json(input)?,
+        Format::Yaml => from_yaml(input)?,
+        Format::D2 => d2_to_json(input)?,
+        Format::PlantUml => plantuml_to_json(input)?,
+    };
+    let diagrams = match top.as_object_mut().and_then(|t| t.remove("diagrams")) {
+        Some(serde_json::Value::Object(diagrams)) => diagrams,
+        Some(_) => return Err(Error::Parse("diagrams must be a table".to_string())),
+        None => serde_json::Map::new(),
+    };
+
+    let style = SHARED_STYLE
+        .iter()
+        .filter_map(|&k| Some((k, top.get(k)?.clone())))
+        .collect::<Vec<_>>();
+    let mut specs = BTreeMap::new();
+    for (name, mut diagram) in diagrams {
+        if let Some(d) = diagram.as_object_mut() {
+            for (k, v) in &style {
+                d.entry(k.to_string()).or_insert_with(|| v.clone());
+            }
+        }
+        let spec = serde_json::from_value::<Spec>(diagram)
+            .map_err(|e| Error::Parse(format!(r#"diagram "{}": {}"#, name, e)))?;
+        specs.insert(name, spec);
+    }
+    let names = specs.keys().cloned().collect::<HashSet<_>>();
+
+    let top = serde_json::from_value::<Spec>(top).map_err(|e| Error::Parse(e.to_string()))?;
+    let mut pending = vec![(String::new(), top, vec![])];
+    let mut linked = vec![];
+    loop {
+        let (name, spec, mut breadcrumbs) = match pending.pop() {
+            Some(p) => p,
+            None => match specs.pop_first() {
+                Some((name, spec)) => (name, spec, vec![]),
+                None => break,
+            },
+        };
+
+        let title = spec.title.clone().unwrap_or_else(|| name.clone());
+        breadcrumbs.push(title);
+        let links = spec
+            .blocks
+            .iter()
+            .filter_map(|b| Some((b.id.as_ref().unwrap_or(&b.text).clone(), b.details.clone()?)))
+            .collect::<Vec<_>>();
+
+        let mut rendered = render_diagram(spec, overrides)?;
+        if !name.is_empty() && breadcrumbs.len() == 1 {
+            rendered
+                .warnings
+                .push(format!(r#"diagram "{}" doesn't detail any block"#, name));
+        }
+
+        // pushed in reverse so that they're rendered in the order of the blocks
+        for (id, details) in links.into_iter().rev() {
+            if !names.contains(&details) {
+                rendered.warnings.push(format!(
+                    r#"block "{}" is detailed in unknown diagram "{}""#,
+                    id, details
+                ));
+            } else if let Some(spec) = specs.remove(&details) {
+                pending.push((details, spec, breadcrumbs.clone()));
+            }
+        }
+        linked.push(LinkedDiagram {
+            name,
+            breadcrumbs,
+            rendered,
+        });
+    }
+    Ok(linked)
+}
+
+/// Render the union of two versions of a diagram as a visual changelog. Blocks and edges only
+/// in `old` are drawn dashed, see `Block::with_dashed`, while the others are drawn as they are in
+/// `new`, which gives the options of the diagram too. The diagram is followed by a legend listing
+/// the added and the removed blocks and edges.
+///
+/// Blocks are matched by their ids and edges by the ids of their blocks. A removed block at the
+/// same position as a block of `new` can't be drawn, it's listed in the legend anyway. Cells of
+/// a `matrix` are only taken from `new`.
+pub fn render_spec_diff(
+    old: &str,
+    old_format: Format,
+    new: &str,
+    new_format: Format,
+    overrides: &Overrides,
+) -> Result<RenderedDiagram, Error> {
+    let old = parse_spec(old, old_format)?;
+    let mut spec = parse_spec(new, new_format)?;
+
+    let block_id = |b: &SpecBlock| b.id.clone().unwrap_or_else(|| b.text.clone());
+    let edge_id = |e: &SpecEdge| (e.from.clone(), e.to.clone());
+    let old_ids = old.blocks.iter().map(block_id).collect::<HashSet<_>>();
+    let old_edges = old.edges.iter().map(edge_id).collect::<HashSet<_>>();
+    let mut ids = spec.blocks.iter().map(block_id).collect::<HashSet<_>>();
+    let new_edges = spec.edges.iter().map(edge_id).collect::<HashSet<_>>();
+    let positions = spec
+        .blocks
+        .iter()
+        .map(|b| (b.position.row, b.position.column))
+        .collect::<HashSet<_>>();
+
+    let mut added = spec
+        .blocks
+        .iter()
+        .map(block_id)
+        .filter(|id| !old_ids.contains(id))
+        .collect::<Vec<_>>();
+    added.extend(
+        spec.edges
+            .iter()
+            .filter(|e| !old_edges.contains(&edge_id(e)))
+            .map(|e| format!("{} -> {}", e.from, e.to)),
+    );
+
+    let mut removed = vec![];
+    for mut b in old.blocks {
+        let id = block_id(&b);
+        if ids.contains(&id) {
+            continue;
+        }
+        if positions.contains(&(b.position.row, b.position.column)) {
+            removed.push(format!("{} (not drawn, its cell is taken)", id));
+            continue;
+        }
+
+        removed.push(id.clone());
+        ids.insert(id);
+        b.dashed = true;
+        b.span = None;
+        spec.blocks.push(b);
+    }
+    for mut e in old.edges {
+        if new_edges.contains(&edge_id(&e)) {
+            continue;
+        }
+
+        removed.push(format!("{} -> {}", e.from, e.to));
+        if ids.contains(&e.from) && ids.contains(&e.to) {
+            e.dashed = true;
+            e.span = None;
+            spec.edges.push(e);
+        }
+    }
+
+    let mut rendered = render_diagram(spec, overrides)?;
+    // the legend would make the JSON emitted instead of the diagram invalid
+    if !overrides.dump_resolved && !overrides.layout_json {
+        for (what, items) in [("added", added), ("removed (dashed)", removed)] {
+            if !items.is_empty() {
+                let line = format!("{}: {}", what, items.join(", "));
+                rendered.rows.push(line.into_bytes());
+            }
+        }
+    }
+    Ok(rendered)
+}
+
+#[derive(Deserialize)]
+struct Spec {
+    #[serde(default)]
+    blocks: Vec<SpecBlock>,
+    #[serde(default)]
+    edges: Vec<SpecEdge>,
+
+    // lines drawn on the diagram without being attached to blocks, see `SpecLine`.
+    #[serde(default)]
+    lines: Vec<SpecLine>,
+
+    // a grid of cells added to the blocks, see `SpecMatrix`.
+    matrix: Option<SpecMatrix>,
+
+    // what the diagram is, see `SpecDiagramKind`.
+    #[serde(default)]
+    kind: SpecDiagramKind,
+
+    // the title of the diagram in the breadcrumbs of `render_spec_all`.
+    title: Option<String>,
+
+    // other diagrams detailing the blocks of this one by name, each a whole diagram, see
+    // `render_spec_all`.
+    #[serde(default)]
+    diagrams: BTreeMap<String, serde_json::Value>,
+
+    // ranges of a `SpecDiagramKind::Memmap` diagram, in any order.
+    #[serde(default)]
+    ranges: Vec<SpecRange>,
+
+    // nodes of a `SpecDiagramKind::Tree` or `SpecDiagramKind::Layered` diagram and how they're
+    // laid out.
+    #[serde(default)]
+    nodes: Vec<SpecNode>,
+    #[serde(default)]
+    sibling_order: SpecSiblingOrder,
+    #[serde(default)]
+    orientation: SpecOrientation,
+    #[serde(default)]
+    connector: SpecConnector,
+
+    // the elements of a `SpecDiagramKind::C4` diagram and the relationships between them.
+    #[serde(default)]
+    persons: Vec<SpecC4Element>,
+    #[serde(default)]
+    systems: Vec<SpecC4Element>,
+    #[serde(default)]
+    containers: Vec<SpecC4Element>,
+    #[serde(default)]
+    relationships: Vec<SpecC4Relationship>,
+
+    // the month of a `SpecDiagramKind::Calendar` diagram as `YYYY-MM`, or a day of the week to
+    // draw instead as `YYYY-MM-DD`, and the text of its days by their date as `YYYY-MM-DD`.
+    month: Option<String>,
+    week: Option<String>,
+    #[serde(default)]
+    events: BTreeMap<String, String>,
+
+    // fields of a `SpecDiagramKind::Packet` diagram, in order, and how many bits are drawn on
+    // each row.
+    #[serde(default)]
+    fields: Vec<SpecField>,
+    #[serde(default = "default_bits_per_row")]
+    bits_per_row: usize,
+
+    #[serde(default = "default_hmargin")]
+    horizontal_margin: usize,
+
+    // it's derived from `cell_aspect` if it's not given.
+    vertical_margin: Option<usize>,
+
+    // how many times a character cell is taller than it's wide. If it's given the vertical margin
+    // defaults to the horizontal one divided by it, so that the margins look about as large
+    // horizontally as vertically.
+    cell_aspect: Option<f64>,
+
+    #[serde(default = "default_padding")]
+    padding: usize,
+
+    // whether adjacent blocks share their borders like the cells of a table, that is both margins
+    // are 0 whatever they're set to.
+    #[serde(default)]
+    compact: bool,
+
+    #[serde(default)]
+    number_blocks: bool,
+
+    #[serde(default = "default_tab_width")]
+    tab_width: usize,
+
+    gutter_threshold: Option<usize>,
+
+    #[serde(default)]
+    symmetric: bool,
+
+    // the width and height blocks are made proportional to, see `RenderOptions::block_aspect`.
+    block_aspect: Option<(usize, usize)>,
+
+    // what to do with the edges that can't be routed, see `RouteFailure`.
+    #[serde(default)]
+    route_failure: SpecRouteFailure,
+
+    // which sides edges leave and enter their blocks from, see `Attachment`.
+    #[serde(default)]
+    attachment: SpecAttachment,
+
+    #[serde(default)]
+    directed: bool,
+
+    #[serde(default)]
+    id_matching: SpecIdMatching,
+
+    // whether references to other blocks like `[id]` inside the text of a block create edges.
+    #[serde(default)]
+    infer_edges: bool,
+
+    // labels drawn above each column and to the left of each row, by their index counting from
+    // the leftmost column and the topmost row with blocks.
+    #[serde(default)]
+    column_labels: Vec<String>,
+    #[serde(default)]
+    row_labels: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct SpecBlock {
+    id: Option<String>,
+    text: String,
+    footer: Option<String>,
+    position: SpecPosition,
+
+    // explicit size of the block in characters, the text is wrapped and truncated to fit.
+    width: Option<usize>,
+    height: Option<usize>,
+
+    #[serde(default)]
+    overflow: SpecOverflow,
+
+    #[serde(default)]
+    shape: SpecShape,
+
+    #[serde(default)]
+    text_direction: SpecTextDirection,
+
+    #[serde(default)]
+    vertical: bool,
+
+    #[serde(default)]
+    pinned: bool,
+    order: Option<i32>,
+
+    // name of the diagram among `Spec::diagrams` with the details of the block.
+    details: Option<String>,
+
+    // whether the block is a system or a user outside of the diagram, like in context diagrams.
+    // It's drawn with `SpecShape::External`, pinned and expected on the periphery of the diagram.
+    #[serde(default)]
+    external: bool,
+
+    // blocks at the same position are allowed only if they have different z.
+    z: Option<i32>,
+
+    #[serde(default)]
+    shadow: bool,
+
+    // character filling the empty space inside the block.
+    fill: Option<char>,
+
+    #[serde(default)]
+    kind: SpecKind,
+
+    // label and value of each bar of a `SpecKind::Bar` block.
+    #[serde(default)]
+    bars: Vec<SpecBar>,
+
+    // values drawn as a sparkline on a line of their own after the text.
+    #[serde(default)]
+    sparkline: Vec<f64>,
+
+    // rectangle of the canvas the block is kept at, taken from the layout JSON of a previous
+    // rendering.
+    rect: Option<SpecRect>,
+
+    #[serde(default)]
+    dashed: bool,
+
+    // frame the block appears in with `Overrides::frames`, blocks appear in the order they're
+    // declared if none of them has a step.
+    step: Option<i32>,
+
+    #[serde(skip)]
+    span: Option<Span>,
+}
+
+/// A grid of cells like a dependency matrix or a heatmap, where each cell is a block with the
+/// labels of its column and row as headers. Cells are given row by row.
+#[derive(Deserialize)]
+struct SpecMatrix {
+    columns: Vec<String>,
+    rows: Vec<String>,
+    cells: Vec<Vec<String>>,
+
+    // character filling each cell to make it stand out, empty strings are not filled.
+    #[serde(default)]
+    emphasis: Vec<Vec<String>>,
+
+    // value of each cell, cells are filled with denser characters the higher their value. It's
+    // ignored for the cells with an emphasis.
+    #[serde(default)]
+    heat: Vec<Vec<f64>>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum SpecDiagramKind {
+    // blocks and edges between them.
+    #[default]
+    Graph,
+
+    // the `ranges` of a memory map stacked from the lowest address to the highest one, with the
+    // addresses where they start and end on the side. Blocks share their borders like with
+    // `compact`.
+    Memmap,
+
+    // the `fields` of a packet drawn like in RFCs, see `packet`. There are no blocks nor edges.
+    Packet,
+
+    // the days of a `month` or a `week`, from Monday to Sunday, with their `events`. Blocks share
+    // their borders like with `compact`.
+    Calendar,
+
+    // the `nodes` of a tree like an org chart, each connected to its `parent`. Their positions are
+    // computed according to `sibling_order`, `orientation` and `connector`.
+    Tree,
+
+    // the `nodes` of a graph like a flowchart, placed in layers so that edges go from a layer to
+    // the next ones according to `orientation`. Edges closing cycles loop around the diagram.
+    Layered,
+
+    // an architecture diagram following the C4 model made of `persons`, `systems` and
+    // `containers` connected by `relationships`, laid out like a `Layered` one.
+    C4,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum SpecSiblingOrder {
+    // the children of a node are in the order they're declared.
+    #[default]
+    Declared,
+
+    // the children of a node are sorted by their text.
+    Text,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum SpecOrientation {
+    // the roots are at the top and their children in the rows below them, like org charts.
+    #[default]
+    Vertical,
+
+    // the roots are on the left and their children in the columns after them, like file trees.
+    Horizontal,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum SpecConnector {
+    // the edges from a node to its children share a trunk that branches off to each of them.
+    #[default]
+    Elbow,
+
+    // each child is connected to its parent by a line of its own.
+    Straight,
+}
+
+/// A person, a software system or a container of a C4 diagram, its id is its name unless it has
+/// one.
+#[derive(Deserialize)]
+struct SpecC4Element {
+    id: Option<String>,
+    name: String,
+    technology: Option<String>,
+    description: Option<String>,
+
+    // whether the element is outside of the systems being described, persons always are.
+    #[serde(default)]
+    external: bool,
+}
+
+/// A relationship between two elements of a C4 diagram, like "uses" or "sends emails to".
+#[derive(Deserialize)]
+struct SpecC4Relationship {
+    from: String,
+    to: String,
+    description: Option<String>,
+    technology: Option<String>,
+}
+
+/// A node of a tree, its id is its text unless it has one.
+#[derive(Deserialize)]
+struct SpecNode {
+    id: Option<String>,
+    text: String,
+
+    // id of the parent of the node, roots have none.
+    parent: Option<String>,
+
+    // see `SpecBlock::external`
+    #[serde(default)]
+    external: bool,
+}
+
+/// A field of a packet and its width.
+#[derive(Deserialize)]
+struct SpecField {
+    name: String,
+    bits: usize,
+}
+
+/// A range of addresses of a memory map, the end is excluded.
+#[derive(Deserialize)]
+struct SpecRange {
+    name: String,
+    start: u64,
+    end: u64,
+}
+
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum SpecOverflow {
+    #[default]
+    Wrap,
+    Truncate,
+    Error,
+}
+
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum SpecShape {
+    #[default]
+    Rectangle,
+    Note,
+    External,
+}
+
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum SpecKind {
+    #[default]
+    Text,
+
+    // the text is followed by a bar chart of `SpecBlock::bars`, like a panel of metrics.
+    Bar,
+}
+
+#[derive(Deserialize, Serialize)]
+struct SpecBar {
+    label: String,
+    value: f64,
+}
+
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum SpecTextDirection {
+    #[default]
+    Ltr,
+    Rtl,
+}
+
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum SpecRouteFailure {
+    #[default]
+    Error,
+    Skip,
+    Force,
+}
+
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum SpecAttachment {
+    #[default]
+    VerticalFirst,
+    HorizontalFirst,
+    Shortest,
+}
+
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum SpecIdMatching {
+    #[default]
+    Exact,
+
+    // ignore case and punctuation and match block texts too.
+    Loose,
+}
+
+#[derive(Deserialize, Default)]
+struct SpecEdge {
+    from: String,
+    #[serde(default)]
+    to: String,
+    from_label: Option<String>,
+    to_label: Option<String>,
+
+    #[serde(default)]
+    tags: Vec<String>,
+
+    directed: Option<bool>,
+
+    // whether arrowheads are drawn at both ends, see `Edge::with_bidirectional`.
+    #[serde(default)]
+    bidirectional: bool,
+
+    group: Option<String>,
+
+    // corners of the polyline the edge is drawn along, taken from the layout JSON of a previous
+    // rendering.
+    #[serde(default)]
+    route: Vec<SpecPoint>,
+
+    #[serde(default)]
+    dashed: bool,
+
+    // frame the edge appears in with `Overrides::frames`, never before both of its blocks.
+    step: Option<i32>,
+
+    // something outside of the diagram the edge goes to instead of `to`, like "internet", drawn
+    // as a stub leaving `from` toward the border of the canvas.
+    external: Option<String>,
+
+    #[serde(skip)]
+    span: Option<Span>,
+}
+
+/// A line that isn't attached to blocks, like a separator or an axis, see `add_polylines`.
+#[derive(Deserialize, Serialize)]
+struct SpecLine {
+    points: Vec<SpecLinePoint>,
+
+    #[serde(default)]
+    arrow: bool,
+}
+
+/// A corner of a `SpecLine`, either a point of the rendered diagram or the top left corner of a
+/// logical cell.
+#[derive(Deserialize, Serialize, Clone, Copy)]
+#[serde(untagged)]
+enum SpecLinePoint {
+    Canvas {
+        x: usize,
+        y: usize,
+    },
+    Logical {
+        row: LogicalCoord,
+        column: LogicalCoord,
+    },
+}
+
+impl From<SpecLinePoint> for PolylinePoint {
+    fn from(p: SpecLinePoint) -> Self {
+        match p {
+            SpecLinePoint::Canvas { x, y } => PolylinePoint::Canvas(CanvasPoint::new(x, y)),
+            SpecLinePoint::Logical { row, column } => {
+                PolylinePoint::Logical(LogicalPos::new(row, column))
+            }
+        }
+    }
+}
+
+/// A rectangle of the canvas, as in the layout JSON.
+#[derive(Deserialize, Serialize, Clone, Copy)]
+struct SpecRect {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+impl From<SpecRect> for BlockRect {
+    fn from(r: SpecRect) -> Self {
+        BlockRect {
+            x: r.x,
+            y: r.y,
+            width: r.width,
+            height: r.height,
+        }
+    }
+}
+
+/// A point of the canvas, as in the layout JSON.
+#[derive(Deserialize, Serialize, Clone, Copy)]
+struct SpecPoint {
+    x: usize,
+    y: usize,
+}
+
+impl From<SpecPoint> for CanvasPoint {
+    fn from(p: SpecPoint) -> Self {
+        CanvasPoint::new(p.x, p.y)
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct SpecPosition {
+    row: LogicalCoord,
+    column: LogicalCoord,
+}
+
+/// Split `canvas` in pages at most `width` characters wide, from left to right. Each page has a
+/// header and it's followed by the rows where lines continue on the previous or next page.
+fn split_pages(canvas: &Canvas, width: usize) -> Result<Vec<Vec<u8>>, String> {
+    if width == 0 {
+        return Err("the split width must be positive".to_string());
+    }
+    if canvas.width() <= width {
+        return Ok(canvas.rows().map(<[u8]>::to_vec).collect());
+    }
+
+    let pages = canvas.width().div_ceil(width);
+    let mut rows = vec![];
+    for page in 0..pages {
+        let x = page * width;
+        let tile = canvas.crop(x, 0, width, canvas.height());
+
+        // lines cut at a border are those marked in the tile but not in the canvas
+        let cut_rows = |tx: usize| {
+            (0..tile.height())
+                .filter(|&y| {
+                    tile.at(CanvasPoint::new(tx, y)) == CUT_MARKER
+                        && canvas.at(CanvasPoint::new(x + tx, y)) != CUT_MARKER
+                })
+                .map(|y| (y + 1).to_string())
+                .collect::<Vec<_>>()
+        };
+
+        if page > 0 {
+            rows.push(vec![]);
+        }
+        rows.push(
+            format!(
+                "[page {}/{}, columns {}-{}]",
+                page + 1,
+                pages,
+                x + 1,
+                x + tile.width()
+            )
+            .into_bytes(),
+        );
+        rows.extend(tile.rows().map(<[u8]>::to_vec));
+
+        let previous = cut_rows(0);
+        if page > 0 && !previous.is_empty() {
+            let note = format!(
+                "continued from page {} at rows {}",
+                page,
+                previous.join(", ")
+            );
+            rows.push(note.into_bytes());
+        }
+        let next = cut_rows(tile.width() - 1);
+        if page + 1 < pages && !next.is_empty() {
+            let note = format!("continues on page {} at rows {}", page + 2, next.join(", "));
+            rows.push(note.into_bytes());
+        }
+    }
+
+    Ok(rows)
+}
+
+/// How deep arrays and inline tables can be nested in TOML diagrams, like the recursion limit of
+/// serde_json. The toml crate has no limit of its own and overflows the stack on deeper inputs.
+const MAX_TOML_DEPTH: usize = 128;
+
+/// Parse the TOML document `input`, checking how deep it is first, see `MAX_TOML_DEPTH`.
+fn from_toml<T: DeserializeOwned>(input: &str) -> Result<T, Error> {
+    if toml_depth(input) > MAX_TOML_DEPTH {
+        return Err(Error::Parse(format!(
+            "arrays and tables are nested more than {} levels deep",
+            MAX_TOML_DEPTH
+        )));
+    }
+    toml::from_str(input).map_err(|e| Error::Parse(e.to_string()))
+}
+
+/// Parse the YAML document `input`, serde_yaml limits how deep it can be on its own.
+fn from_yaml<T: DeserializeOwned>(input: &str) -> Result<T, Error> {
+    serde_yaml::from_str(input).map_err(|e| Error::Parse(e.to_string()))
+}
+
+/// Parse a diagram `Spec` written in the given format, recording where its blocks and edges are
+/// written in `input` when they can be found.
+fn parse_spec(input: &str, format: Format) -> Result<Spec, Error> {
+    let mut spec: Spec =
+        match format {
+            Format::Json => serde_json::from_str(input).map_err(|e| Error::Parse(e.to_string()))?,
+            Format::Toml => from_toml(input)?,
+            Format::Dot => serde_json::from_value(dot_to_json(input)?)
+                .map_err(|e| Error::Parse(e.to_string()))?,
+            Format::Yaml => from_yaml(input)?,
+            Format::D2 => serde_json::from_value(d2_to_json(input)?)
+                .map_err(|e| Error::Parse(e.to_string()))?,
+            Format::PlantUml => serde_json::from_value(plantuml_to_json(input)?)
+                .map_err(|e| Error::Parse(e.to_string()))?,
+        };
+
+    if let Some((blocks, edges)) = find_spans(input, format) {
+        if blocks.len() == spec.blocks.len() && edges.len() == spec.edges.len() {
+            for (b, span) in spec.blocks.iter_mut().zip(blocks) {
+                b.span = Some(span);
+            }
+            for (e, span) in spec.edges.iter_mut().zip(edges) {
+                e.span = Some(span);
+            }
+        }
+    }
+
+    Ok(spec)
+}
+
+/// Length of the longest bar of a bar block without an explicit width.
+const DEFAULT_BAR_WIDTH: usize = 20;
+
+/// Draw the given bars one per line, each made of its label, a run of `#` as long as its value
+/// relative to the largest one and the value itself. The lines are at most `width` characters
+/// long if it's given and there's enough room for the labels and the values.
+fn bar_chart(bars: &[(String, f64)], width: Option<usize>) -> String {
+    let values = bars.iter().map(|(_, v)| v.to_string()).collect::<Vec<_>>();
+    let label_w = bars.iter().map(|(l, _)| l.len()).max().unwrap_or(0);
+    let value_w = values.iter().map(String::len).max().unwrap_or(0);
+    let bar_w = width.map_or(DEFAULT_BAR_WIDTH, |w| {
+        w.saturating_sub(label_w + value_w + 2).max(1)
+    });
+
+    // negative values and NaNs have no bar
+    let max = bars.iter().map(|(_, v)| *v).fold(0.0, f64::max);
+    bars.iter()
+        .zip(values)
+        .map(|((label, v), value)| {
+            let n = if max > 0.0 {
+                (v.max(0.0) / max * bar_w as f64).round() as usize
+            } else {
+                0
+            };
+            format!(
+                "{:<lw$} {:<bw$} {:>vw$}",
+                label,
+                "#".repeat(n),
+                value,
+                lw = label_w,
+                bw = bar_w,
+                vw = value_w
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Check that `spec` is within `limits` without doing anything expensive, so that it's safe to
+/// expand and lay it out afterwards. It's checked both before and after adding the blocks and
+/// edges of nodes, ranges and the like, since a few of them can add many blocks.
+fn check_limits(spec: &Spec, limits: &Limits) -> Result<(), Error> {
+    let too_big = |limit| Err(Error::Limit(limit));
+
+    let blocks = spec.blocks.len()
+        + spec.nodes.len()
+        + spec.ranges.len()
+        + spec.events.len()
+        + spec.persons.len()
+        + spec.systems.len()
+        + spec.containers.len()
+        + spec
+            .matrix
+            .as_ref()
+            .map_or(0, |m| m.cells.iter().map(Vec::len).sum());
+    if blocks > limits.max_blocks {
+        return too_big(Limit::Blocks(limits.max_blocks));
+    }
+    if spec.edges.len() + spec.relationships.len() > limits.max_edges {
+        return too_big(Limit::Edges(limits.max_edges));
+    }
+
+    // tabs are the only characters that get longer when normalizing the text
+    let text_len = |t: &str| {
+        let tabs = t.matches('\t').count();
+        t.len().saturating_add(tabs.saturating_mul(spec.tab_width))
+    };
+    let texts = spec
+        .blocks
+        .iter()
+        .flat_map(|b| {
+            let bars = b.bars.iter().map(|bar| &bar.label);
+            b.id.iter()
+                .chain(Some(&b.text))
+                .chain(&b.footer)
+                .chain(bars)
+        })
+        .chain(
+            spec.edges
+                .iter()
+                .flat_map(|e| e.from_label.iter().chain(&e.to_label)),
+        )
+        .chain(spec.nodes.iter().map(|n| &n.text))
+        .chain(
+            spec.persons
+                .iter()
+                .chain(&spec.systems)
+                .chain(&spec.containers)
+                .flat_map(|e| {
+                    Some(&e.name)
+                        .into_iter()
+                        .chain(&e.description)
+                        .chain(&e.technology)
+                }),
+        )
+        .chain(
+            spec.relationships
+                .iter()
+                .flat_map(|r| r.description.iter().chain(&r.technology)),
+        )
+        .chain(spec.ranges.iter().map(|r| &r.name))
+        .chain(spec.events.values())
+        .chain(spec.fields.iter().map(|f| &f.name))
+        .chain(&spec.column_labels)
+        .chain(&spec.row_labels)
+        .chain(&spec.title);
+    if texts
+        .map(|t| text_len(t))
+        .any(|n| n > limits.max_text_length)
+    {
+        return too_big(Limit::TextLength(limits.max_text_length));
+    }
+
+    // every logical cell, margin and character of a block takes at least a character
+    let cells = limits.max_canvas_cells;
+    let span = |coords: Vec<LogicalCoord>| {
+        let min = coords.iter().min().copied().unwrap_or(0);
+        let max = coords.iter().max().copied().unwrap_or(0);
+        usize::try_from(i64::from(max) - i64::from(min) + 1).unwrap_or(usize::MAX)
+    };
+    let rows = span(spec.blocks.iter().map(|b| b.position.row).collect());
+    let columns = span(spec.blocks.iter().map(|b| b.position.column).collect());
+    let margins = [
+        spec.horizontal_margin,
+        spec.vertical_margin.unwrap_or(0),
+        spec.padding,
+    ];
+    let block_sizes = spec
+        .blocks
+        .iter()
+        .map(|b| b.width.unwrap_or(1).saturating_mul(b.height.unwrap_or(1)));
+    if rows.saturating_mul(columns) > cells
+        || margins.iter().any(|&m| m > cells)
+        || block_sizes.into_iter().any(|n| n > cells)
+    {
+        return too_big(Limit::CanvasCells(cells));
+    }
+    if spec.kind == SpecDiagramKind::Packet && spec.bits_per_row > 0 {
+        let bits = spec
+            .fields
+            .iter()
+            .fold(0_usize, |bits, f| bits.saturating_add(f.bits));
+        let packet_rows = bits.div_ceil(spec.bits_per_row);
+        let width = spec.bits_per_row.saturating_mul(2).saturating_add(1);
+        let height = packet_rows.saturating_mul(2).saturating_add(3);
+        if width.saturating_mul(height) > cells {
+            return too_big(Limit::CanvasCells(cells));
+        }
+    }
+
+    Ok(())
+}
+
+/// Expand the tabs in `text` to the next multiple of `tab_width` columns, or remove them if it's
+/// 0, and turn `\r\n` into plain newlines. Return the normalized text alongside the other control
+/// characters found, which are ignored when rendering.
+/// Joins the lines of `text` with a space, for texts written on a single row like the legend.
+fn single_line(text: &str) -> String {
+    text.lines().collect::<Vec<_>>().join(" ")
+}
+
+fn normalize_text(text: &str, tab_width: usize) -> (String, BTreeSet<char>) {
+    let mut out = String::with_capacity(text.len());
+    let mut control_chars = BTreeSet::new();
+    let mut column = 0;
+
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => {
+                out.push(c);
+                column = 0;
+                continue;
+            }
+            '\r' if chars.peek() == Some(&'\n') => continue,
+            '\t' => {
+                let n = tab_width.saturating_sub(column % tab_width.max(1));
+                out.extend(std::iter::repeat_n(' ', n));
+                column += n;
+                continue;
+            }
+            c if c.is_control() => {
+                control_chars.insert(c);
+                continue;
+            }
+            c => out.push(c),
+        }
+        column += 1;
+    }
+
+    (out, control_chars)
+}
+
+/// Replace the non-ASCII characters in `text` with their closest ASCII representation, or with a
+/// '?' if there's none. Return the ASCII text alongside what each character was replaced with.
+fn transliterate(text: &str) -> (String, BTreeMap<char, String>) {
+    let mut table = BTreeMap::new();
+
+    let ascii = text
+        .chars()
+        .map(|c| {
+            if c.is_ascii() {
+                return c.to_string();
+            }
+
+            let ascii = deunicode::deunicode_char(c).unwrap_or("?").to_string();
+            table.insert(c, ascii.clone());
+            ascii
+        })
+        .collect();
+
+    (ascii, table)
+}
+
+/// The step each block and then each edge of the diagram appears at with `Overrides::frames`.
+/// Blocks appear in the given order if none of them has a step and at step 0 if others have one,
+/// while edges never appear before their blocks.
+fn frame_steps(
+    block_steps: &[Option<i32>],
+    edge_steps: &BTreeMap<(usize, usize), Option<i32>>,
+) -> Vec<i32> {
+    let mut steps = if block_steps.iter().all(Option::is_none) {
+        (0..block_steps.len())
+            .map(|i| i32::try_from(i).unwrap_or(i32::MAX))
+            .collect::<Vec<_>>()
+    } else {
+        block_steps.iter().map(|s| s.unwrap_or(0)).collect()
+    };
+
+    let edges = edge_steps
+        .iter()
+        .map(|(&(from, to), s)| steps[from].max(steps[to]).max(s.unwrap_or(i32::MIN)))
+        .collect::<Vec<_>>();
+    steps.extend(edges);
+    steps
+}
+
+/// Whether an edge with the given tags should be rendered according to the tag filters in `opts`.
+fn is_edge_selected(tags: &[String], opts: &Overrides) -> bool {
+    let included = opts.edge_tags.is_empty() || tags.iter().any(|t| opts.edge_tags.contains(t));
+    let excluded = tags.iter().any(|t| opts.exclude_edge_tags.contains(t));
+
+    included && !excluded
+}
+
+/// Normalize an id for loose matching, that is lowercase it and replace all the runs of
+/// punctuation and whitespace with a single space.
+fn slugify(id: &str) -> String {
+    id.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Find the references to other blocks inside `text`, that is the text between square brackets
+/// like in `see [db]`.
+fn references(text: &str) -> Vec<String> {
+    let mut refs = vec![];
+    let mut rest = text;
+    while let Some(start) = rest.find('[') {
+        rest = &rest[start + 1..];
+
+        let end = match rest.find(['[', ']', '\n']) {
+            Some(end) => end,
+            None => break,
+        };
+        if rest[end..].starts_with(']') && !rest[..end].trim().is_empty() {
+            refs.push(rest[..end].to_string());
+        }
+        rest = &rest[end..];
+    }
+    refs
+}
+
+/// Describe why `name` doesn't reference any block, suggesting the closest one if there's one.
+/// `known` maps both the ids and the texts of the blocks to their ids, so that blocks can be found
+/// by their text even when they have an explicit id.
+fn describe_unknown_id(name: &str, known: &[(&str, &str)]) -> String {
+    // allow roughly a typo every three characters
+    let max_distance = (name.chars().count() / 3).max(1);
+
+    let closest = known
+        .iter()
+        .map(|&(k, id)| (edit_distance(name, k), k, id))
+        .filter(|&(d, _, _)| d <= max_distance)
+        .min_by_key(|&(d, _, _)| d);
+
+    match closest {
+        None => format!(r#"id "{}" not found"#, name),
+        Some((_, k, id)) if k == id => {
+            format!(r#"id "{}" not found, did you mean "{}"?"#, name, id)
+        }
+        Some((_, k, id)) => format!(
+            r#"id "{}" not found, did you mean "{}" (the block with text "{}")?"#,
+            name, id, k
+        ),
+    }
+}
+
+/// The Levenshtein distance between `a` and `b`, that is the minimum number of characters to
+/// insert, remove or replace to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let replace = prev[j] + usize::from(ca != *cb);
+            cur[j + 1] = replace.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+
+    prev[b.len()]
+}
+
+/// Describe the geometry of a diagram as JSON, `ids` are the ids of the blocks.
+fn layout_json(layout: &Layout, ids: &[&String]) -> serde_json::Value {
+    let point = |pt: CanvasPoint| json!({ "x": pt.x, "y": pt.y });
+
+    let blocks = layout
+        .blocks
+        .iter()
+        .zip(ids)
+        .map(|(r, id)| {
+            json!({
+                "id": id,
+                "x": r.x,
+                "y": r.y,
+                "width": r.width,
+                "height": r.height,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let edges = layout
+        .edges
+        .iter()
+        .map(|e| {
+            let labels = e
+                .labels
+                .iter()
+                .map(|(pt, text)| {
+                    json!({ "x": pt.x, "y": pt.y, "text": String::from_utf8_lossy(text) })
+                })
+                .collect::<Vec<_>>();
+
+            json!({
+                "from": ids[e.from],
+                "to": ids[e.to],
+                "src": point(e.src),
+                "dst": point(e.dst),
+                "points": e.points.iter().copied().map(point).collect::<Vec<_>>(),
+                "labels": labels,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "width": layout.width,
+        "height": layout.height,
+        "column_gutters": layout.column_gutters,
+        "row_gutters": layout.row_gutters,
+        "blocks": blocks,
+        "edges": edges,
+    })
+}
+
+/// Parse a layout emitted by `layout_json`, `ids` are the ids of the blocks.
+fn parse_layout_json(input: &str, ids: &[&String]) -> Result<Layout, String> {
+    #[derive(Deserialize)]
+    struct SpecLayout {
+        width: usize,
+        height: usize,
+        column_gutters: Vec<usize>,
+        row_gutters: Vec<usize>,
+        blocks: Vec<SpecRect>,
+        edges: Vec<SpecRoute>,
+    }
+
+    #[derive(Deserialize)]
+    struct SpecRoute {
+        from: String,
+        to: String,
+        src: SpecPoint,
+        dst: SpecPoint,
+        points: Vec<SpecPoint>,
+        labels: Vec<SpecLabel>,
+    }
+
+    #[derive(Deserialize)]
+    struct SpecLabel {
+        x: usize,
+        y: usize,
+        text: String,
+    }
+
+    let layout: SpecLayout = serde_json::from_str(input).map_err(|e| e.to_string())?;
+    let index = |id: &String| {
+        ids.iter()
+            .position(|i| *i == id)
+            .ok_or_else(|| format!(r#"the layout references the unknown id "{}""#, id))
+    };
+    let point = CanvasPoint::from;
+
+    let mut edges = Vec::with_capacity(layout.edges.len());
+    for e in layout.edges {
+        edges.push(EdgeRoute {
+            from: index(&e.from)?,
+            to: index(&e.to)?,
+            src: point(e.src),
+            dst: point(e.dst),
+            points: e.points.into_iter().map(point).collect(),
+            labels: e
+                .labels
+                .into_iter()
+                .map(|l| (CanvasPoint::new(l.x, l.y), l.text.into_bytes()))
+                .collect(),
+        });
+    }
+
+    Ok(Layout {
+        width: layout.width,
+        height: layout.height,
+        column_gutters: layout.column_gutters,
+        row_gutters: layout.row_gutters,
+        blocks: layout.blocks.into_iter().map(BlockRect::from).collect(),
+        edges,
+    })
+}
+
+/// The routes of the edges of a layout emitted by `layout_json`, by the ids of their blocks.
+/// Unlike `parse_layout_json` the layout doesn't need to match the diagram.
+fn previous_routes(input: &str) -> Result<HashMap<(String, String), Vec<CanvasPoint>>, String> {
+    #[derive(Deserialize)]
+    struct SpecLayout {
+        edges: Vec<SpecRoute>,
+    }
+
+    #[derive(Deserialize)]
+    struct SpecRoute {
+        from: String,
+        to: String,
+        points: Vec<SpecPoint>,
+    }
+
+    let layout: SpecLayout = serde_json::from_str(input).map_err(|e| e.to_string())?;
+    Ok(layout
+        .edges
+        .into_iter()
+        .map(|e| {
+            let points = e.points.into_iter().map(CanvasPoint::from).collect();
+            ((e.from, e.to), points)
+        })
+        .collect())
+}
+
+/// The 64 bits FNV-1a hash of `data`, which unlike the hashers in std is stable across Rust
+/// versions and so it can be used for keys stored on disk.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+        (h ^ u64::from(*b)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Whether an edge from `from` to `to` duplicates one of the `existing` edges, which map the
+/// endpoints to whether the edge is directed. Edges between the same blocks in opposite
+/// directions are distinct only if both are directed.
+fn is_duplicate_edge(
+    existing: &BTreeMap<(usize, usize), bool>,
+    (from, to): (usize, usize),
+    directed: bool,
+) -> bool {
+    existing.contains_key(&(from, to))
+        || existing
+            .get(&(to, from))
+            .is_some_and(|&reverse_directed| !(directed && reverse_directed))
+}
+
+/// Describe the given suggestion in terms of the diagram spec, `ids` are the ids of the blocks.
+fn describe_overlap(o: &Overlap, ids: &[&String]) -> String {
+    let rect = |r: &BlockRect| format!("{}x{} at {},{}", r.width, r.height, r.x, r.y);
+    let (a, b) = o.blocks;
+    format!(
+        r#"blocks "{}" ({}) and "{}" ({}) overlap, "{}" is drawn over "{}""#,
+        ids[a],
+        rect(&o.rects.0),
+        ids[b],
+        rect(&o.rects.1),
+        ids[b],
+        ids[a]
+    )
+}
+
+fn describe_suggestion(s: &Suggestion, ids: &[&String]) -> String {
+    let plural = |n: usize| if n == 1 { "" } else { "s" };
+
+    match *s {
+        Suggestion::SwapBlocks {
+            a,
+            b,
+            crossings_removed,
+        } => format!(
+            r#"swap blocks "{}" and "{}" to remove {} crossing{}"#,
+            ids[a],
+            ids[b],
+            crossings_removed,
+            plural(crossings_removed)
+        ),
+        Suggestion::IncreaseHorizontalMargin {
+            margin,
+            hugging_removed,
+        } => format!(
+            "increase horizontal_margin to {} to avoid line hugging in {} cell{}",
+            margin,
+            hugging_removed,
+            plural(hugging_removed)
+        ),
+        Suggestion::IncreaseVerticalMargin {
+            margin,
+            hugging_removed,
+        } => format!(
+            "increase vertical_margin to {} to avoid line hugging in {} cell{}",
+            margin,
+            hugging_removed,
+            plural(hugging_removed)
+        ),
+    }
+}
+
+/// What's left to do after laying out a diagram whose kind was lowered to blocks and edges by
+/// `lower_kind`.
+#[derive(Default)]
+struct Lowered {
+    warnings: Vec<String>,
+
+    // lines of the legend, like the one of C4 diagrams.
+    legend: Vec<String>,
+
+    routes: ComputedRoutes,
+
+    // the addresses drawn next to the ranges of memory maps.
+    memmap: Option<MemmapAddresses>,
+}
+
+/// Add the blocks and edges that the matrix and the nodes, ranges, days and the like of the kind
+/// of `spec` stand for to the spec itself, so that it can be rendered like any other diagram.
+/// What belongs to other kinds is ignored with a warning.
+fn lower_kind(spec: &mut Spec) -> Result<Lowered, String> {
+    if let Some(matrix) = spec.matrix.take() {
+        expand_matrix(spec, matrix)?;
+    }
+
+    let mut lowered = Lowered::default();
+    match spec.kind {
+        SpecDiagramKind::Memmap => lowered.memmap = Some(expand_memmap(spec)?),
+        SpecDiagramKind::Calendar => lowered.warnings = expand_calendar(spec)?,
+        SpecDiagramKind::Tree => lowered.routes = expand_tree(spec)?,
+        SpecDiagramKind::Layered | SpecDiagramKind::C4 => {
+            if spec.kind == SpecDiagramKind::C4 {
+                lowered.legend = expand_c4(spec);
+            }
+            lowered.routes = expand_layered(spec)?;
+            lowered
+                .warnings
+                .extend(describe_loops(&lowered.routes.loops));
+        }
+        SpecDiagramKind::Graph | SpecDiagramKind::Packet => {}
+    }
+
+    let warnings = &mut lowered.warnings;
+    if spec.kind != SpecDiagramKind::Memmap && !spec.ranges.is_empty() {
+        warnings.push(r#"ranges are ignored, the kind of the diagram is not "memmap""#.to_string());
+    }
+    if !matches!(spec.kind, SpecDiagramKind::Tree | SpecDiagramKind::Layered)
+        && !spec.nodes.is_empty()
+    {
+        warnings.push(
+            r#"nodes are ignored, the kind of the diagram is neither "tree" nor "layered""#
+                .to_string(),
+        );
+    }
+    if spec.kind != SpecDiagramKind::Calendar
+        && (spec.month.is_some() || spec.week.is_some() || !spec.events.is_empty())
+    {
+        warnings.push(
+            r#"month, week and events are ignored, the kind of the diagram is not "calendar""#
+                .to_string(),
+        );
+    }
+    if !spec.diagrams.is_empty() {
+        warnings.push("diagrams are ignored when rendering a single diagram".to_string());
+    }
+    if spec.kind != SpecDiagramKind::C4
+        && (!spec.persons.is_empty()
+            || !spec.systems.is_empty()
+            || !spec.containers.is_empty()
+            || !spec.relationships.is_empty())
+    {
+        warnings.push(
+            r#"persons, systems, containers and relationships are ignored, the kind of the diagram is not "c4""#
+                .to_string(),
+        );
+    }
+    if spec.kind != SpecDiagramKind::Packet && !spec.fields.is_empty() {
+        warnings.push(r#"fields are ignored, the kind of the diagram is not "packet""#.to_string());
+    }
+    Ok(lowered)
+}
+
+/// Render a diagram `Spec` applying the given overrides.
+fn render_diagram(mut spec: Spec, opts: &Overrides) -> Result<RenderedDiagram, Error> {
+    check_limits(&spec, &opts.limits)?;
+
+    let Lowered {
+        mut warnings,
+        legend: kind_legend,
+        routes: computed_routes,
+        memmap,
+    } = lower_kind(&mut spec).map_err(Error::Invalid)?;
+    check_limits(&spec, &opts.limits)?;
+
+    let mut id_to_block_id = HashMap::with_capacity(spec.blocks.len());
+    let mut occupied_positions = HashSet::with_capacity(spec.blocks.len());
+    let mut blocks = Vec::with_capacity(spec.blocks.len());
+    let mut ids = Vec::with_capacity(spec.blocks.len());
+    let mut block_spans = Vec::with_capacity(spec.blocks.len());
+    let mut block_steps = Vec::with_capacity(spec.blocks.len());
+    let mut texts: Vec<&String> = Vec::with_capacity(spec.blocks.len());
+    let mut known = Vec::with_capacity(spec.blocks.len());
+    let mut loose_ids = HashMap::new();
+    let mut loose_texts = HashMap::new();
+    let mut legend = kind_legend
+        .into_iter()
+        .map(String::into_bytes)
+        .collect::<Vec<_>>();
+    let mut external = vec![];
+    let mut resolved_blocks = vec![];
+    let mut resolved_edges = BTreeMap::new();
+    let mut resolved_stubs = vec![];
+    let mut stubs = vec![];
+
+    let mut transliterated = BTreeMap::new();
+    let mut normalize = |warnings: &mut Vec<String>, what: &str, t: &str| {
+        let (mut t, control_chars) = normalize_text(t, spec.tab_width);
+        if !control_chars.is_empty() {
+            warnings.push(format!(
+                "{} contains control characters that are ignored: {:?}",
+                what, control_chars
+            ));
+        }
+        if opts.ascii_only {
+            let (ascii, table) = transliterate(&t);
+            t = ascii;
+            transliterated.extend(table);
+        }
+        t
+    };
+
+    for b in &spec.blocks {
+        let id = b.id.as_ref().unwrap_or(&b.text);
+        if id_to_block_id.insert(id, blocks.len()).is_some() {
+            warnings.push(format!(r#"duplicate id found: "{}""#, id));
+            continue;
+        }
+
+        let pos = (b.position.row, b.position.column);
+        if !occupied_positions.insert((pos, b.z.unwrap_or(0))) {
+            warnings.push(format!(
+                r#"more than one cell present at row {} and column {}"#,
+                b.position.row, b.position.column
+            ));
+            continue;
+        }
+
+        let what = format!(r#"block "{}""#, id);
+        let text = normalize(&mut warnings, &what, &b.text);
+        let footer = b
+            .footer
+            .as_ref()
+            .map(|f| normalize(&mut warnings, &what, f));
+        let mut body = match b.kind {
+            SpecKind::Text => {
+                if !b.bars.is_empty() {
+                    warnings.push(format!(
+                        r#"the bars of block "{}" are ignored, its kind is not "bar""#,
+                        id
+                    ));
+                }
+                text.clone()
+            }
+            SpecKind::Bar => {
+                let bars = b
+                    .bars
+                    .iter()
+                    .map(|bar| (normalize(&mut warnings, &what, &bar.label), bar.value))
+                    .collect::<Vec<_>>();
+                let inner_w = b.width.map(|w| w.saturating_sub(2 + 2 * spec.padding));
+                let chart = bar_chart(&bars, inner_w);
+                if text.is_empty() {
+                    chart
+                } else {
+                    format!("{}\n{}", text, chart)
+                }
+            }
+        };
+        if !b.sparkline.is_empty() {
+            if !body.is_empty() {
+                body.push('\n');
+            }
+            body.push_str(&sparkline(&b.sparkline));
+        }
+        let mut block = Block::from_str(pos, &body);
+        if let Some(footer) = &footer {
+            block = block.with_footer(footer.as_bytes());
+        }
+        if let Some(w) = b.width {
+            block = block.with_width(w);
+        }
+        if let Some(h) = b.height {
+            block = block.with_height(h);
+        }
+        block = block.with_overflow(match b.overflow {
+            SpecOverflow::Wrap | SpecOverflow::Error => Overflow::Wrap,
+            SpecOverflow::Truncate => Overflow::Truncate,
+        });
+        if b.overflow == SpecOverflow::Error && block.overflows() {
+            return Err(Error::Invalid(format!(
+                r#"the text of block "{}" does not fit in its size"#,
+                id
+            )));
+        }
+        block = block.with_shape(match (b.shape, b.external) {
+            (_, true) | (SpecShape::External, _) => Shape::External,
+            (SpecShape::Rectangle, _) => Shape::Rectangle,
+            (SpecShape::Note, _) => Shape::Note,
+        });
+        block = block
+            .with_text_direction(match b.text_direction {
+                SpecTextDirection::Ltr => TextDirection::LeftToRight,
+                SpecTextDirection::Rtl => TextDirection::RightToLeft,
+            })
+            .with_vertical_text(b.vertical)
+            .with_pinned(b.pinned || b.external)
+            .with_shadow(b.shadow)
+            .with_dashed(b.dashed);
+        match b.fill {
+            Some(c) if c.is_ascii_graphic() => block = block.with_fill(c as u8),
+            Some(c) => warnings.push(format!(
+                r#"the fill of block "{}" is ignored, {:?} is not a printable ASCII character"#,
+                id, c
+            )),
+            None => {}
+        }
+        if let Some(o) = b.order {
+            block = block.with_order(o);
+        }
+        if let Some(z) = b.z {
+            block = block.with_z(z);
+        }
+        if let Some(r) = b.rect {
+            block = block.with_rect(r.into());
+        }
+        if spec.number_blocks {
+            block = block.with_corner_text(format!("[{}]", blocks.len() + 1).as_bytes());
+            // the legend has one row per block, ids taken from multi-line texts would break it
+            let entry = format!("[{}] {}", blocks.len() + 1, single_line(id));
+            legend.push(normalize(&mut warnings, "legend", &entry).into_bytes());
+        }
+
+        resolved_blocks.push(json!({
+            "id": id,
+            "text": text,
+            "footer": footer,
+            "width": b.width,
+            "height": b.height,
+            "overflow": b.overflow,
+            "shape": b.shape,
+            "text_direction": b.text_direction,
+            "vertical": b.vertical,
+            "pinned": b.pinned,
+            "external": b.external,
+            "details": b.details,
+            "order": b.order,
+            "z": b.z,
+            "shadow": b.shadow,
+            "fill": b.fill,
+            "kind": b.kind,
+            "bars": b.bars,
+            "sparkline": b.sparkline,
+            "rect": b.rect,
+            "dashed": b.dashed,
+            "step": b.step,
+        }));
+        if b.external {
+            external.push(blocks.len());
+        }
+        if let Some(d) = &b.details {
+            let entry = format!(
+                r#""{}" is detailed in "{}""#,
+                single_line(id),
+                single_line(d)
+            );
+            legend.push(normalize(&mut warnings, "legend", &entry).into_bytes());
+        }
+        blocks.push(block);
+        ids.push(id);
+        block_spans.push(b.span);
+        block_steps.push(b.step);
+        texts.push(&b.text);
+        loose_ids.entry(slugify(id)).or_insert(blocks.len() - 1);
+        loose_texts
+            .entry(slugify(&b.text))
+            .or_insert(blocks.len() - 1);
+        known.push((id.as_str(), id.as_str()));
+        if b.text != *id {
+            known.push((b.text.as_str(), id.as_str()));
+        }
+    }
+
+    let mut edges = BTreeMap::new();
+    let mut edge_spans = BTreeMap::new();
+    let mut edge_steps = BTreeMap::new();
+    let mut directions = BTreeMap::new();
+    let mut routed_edges = HashSet::new();
+    // ids are preferred over texts when matching loosely
+    let resolve = |r: &String| match spec.id_matching {
+        SpecIdMatching::Exact => id_to_block_id.get(r).copied(),
+        SpecIdMatching::Loose => {
+            let slug = slugify(r);
+            loose_ids
+                .get(&slug)
+                .or_else(|| loose_texts.get(&slug))
+                .copied()
+        }
+    };
+
+    for e in &spec.edges {
+        let from = match resolve(&e.from) {
+            Some(i) => i,
+            None => {
+                warnings.push(describe_unknown_id(&e.from, &known));
+                continue;
+            }
+        };
+
+        if let Some(external) = &e.external {
+            if e.to.is_empty() {
+                if !is_edge_selected(&e.tags, opts) {
+                    continue;
+                }
+                let what = format!(r#"edge from "{}" to the external "{}""#, e.from, external);
+                let name = normalize(&mut warnings, &what, external);
+                resolved_stubs.push(json!({
+                    "from": ids[from],
+                    "external": name,
+                    "tags": e.tags,
+                }));
+                stubs.push((from, name));
+                continue;
+            }
+            warnings.push(format!(
+                r#"the edge from "{}" goes both to "{}" and to the external "{}", the external one is ignored"#,
+                e.from, e.to, external
+            ));
+        } else if e.to.is_empty() {
+            warnings.push(format!(
+                r#"the edge from "{}" has neither "to" nor "external", it's ignored"#,
+                e.from
+            ));
+            continue;
+        }
+
+        let to = match resolve(&e.to) {
+            Some(i) => i,
+            None => {
+                warnings.push(describe_unknown_id(&e.to, &known));
+                continue;
+            }
+        };
+
+        // the endpoints of filtered out edges are checked too, the filters don't hide typos
+        if !is_edge_selected(&e.tags, opts) {
+            continue;
+        }
+
+        let directed = e.directed.unwrap_or(spec.directed);
+        // bidirectional edges go both ways like undirected ones
+        let one_way = directed && !e.bidirectional;
+        if is_duplicate_edge(&directions, (from, to), one_way) {
+            warnings.push(format!(
+                r#"duplicate edges from "{}" to "{}""#,
+                e.from, e.to
+            ));
+            continue;
+        }
+
+        let what = format!(r#"edge from "{}" to "{}""#, e.from, e.to);
+        let from_label = e
+            .from_label
+            .as_ref()
+            .map(|l| normalize(&mut warnings, &what, l));
+        let to_label = e
+            .to_label
+            .as_ref()
+            .map(|l| normalize(&mut warnings, &what, l));
+        let dashed = e.dashed || e.tags.iter().any(|t| opts.dashed_edge_tags.contains(t));
+        let mut edge = Edge::new(from, to)
+            .with_directed(directed)
+            .with_bidirectional(e.bidirectional)
+            .with_dashed(dashed);
+        if let Some(l) = &from_label {
+            edge = edge.with_from_label(l.as_bytes());
+        }
+        if let Some(l) = &to_label {
+            edge = edge.with_to_label(l.as_bytes());
+        }
+        if let Some(g) = &e.group {
+            edge = edge.with_group(g.as_bytes());
+        }
+        if !e.route.is_empty() {
+            edge = edge.with_route(e.route.iter().copied().map(CanvasPoint::from));
+            routed_edges.insert((from, to));
+        }
+
+        resolved_edges.insert(
+            (from, to),
+            json!({
+                "from": ids[from],
+                "to": ids[to],
+                "from_label": from_label,
+                "to_label": to_label,
+                "tags": e.tags,
+                "directed": directed,
+                "bidirectional": e.bidirectional,
+                "group": e.group,
+                "route": e.route,
+                "dashed": dashed,
+                "step": e.step,
+            }),
+        );
+        edges.insert((from, to), edge);
+        edge_spans.insert((from, to), e.span);
+        edge_steps.insert((from, to), e.step);
+        directions.insert((from, to), one_way);
+    }
+
+    if spec.infer_edges && is_edge_selected(&[], opts) {
+        for (from, text) in texts.iter().enumerate() {
+            for r in references(text) {
+                let to = match resolve(&r) {
+                    Some(to) if to != from => to,
+                    _ => continue,
+                };
+
+                if is_duplicate_edge(&directions, (from, to), spec.directed) {
+                    continue;
+                }
+
+                warnings.push(format!(
+                    r#"inferred edge from "{}" to "{}""#,
+                    ids[from], ids[to]
+                ));
+                resolved_edges.insert(
+                    (from, to),
+                    json!({
+                        "from": ids[from],
+                        "to": ids[to],
+                        "directed": spec.directed,
+                        "inferred": true,
+                    }),
+                );
+                edges.insert((from, to), Edge::new(from, to).with_directed(spec.directed));
+                edge_spans.insert((from, to), block_spans[from]);
+                edge_steps.insert((from, to), None);
+                directions.insert((from, to), spec.directed);
+            }
+        }
+    }
+
+    // edges without a route of their own prefer the one they had in the previous layout
+    let mut prev_layout = String::new();
+    if let Some(path) = &opts.prev_layout {
+        prev_layout = std::fs::read_to_string(path).map_err(|e| Error::Io(e.to_string()))?;
+        let routes = previous_routes(&prev_layout).map_err(Error::Parse)?;
+        for (&(from, to), edge) in edges.iter_mut() {
+            let key = (ids[from].clone(), ids[to].clone());
+            match routes.get(&key) {
+                Some(points) if edge.route().is_empty() => {
+                    *edge = edge.clone().with_route(points.iter().copied());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let column_labels = spec
+        .column_labels
+        .iter()
+        .map(|l| normalize(&mut warnings, "column label", l).into_bytes())
+        .collect::<Vec<_>>();
+    let row_labels = spec
+        .row_labels
+        .iter()
+        .map(|l| normalize(&mut warnings, "row label", l).into_bytes())
+        .collect::<Vec<_>>();
+    let fields = spec
+        .fields
+        .iter()
+        .map(|f| (normalize(&mut warnings, "field", &f.name), f.bits))
+        .collect::<Vec<_>>();
+
+    if !transliterated.is_empty() {
+        let table = transliterated
+            .iter()
+            .map(|(c, ascii)| format!("\n  {} -> {}", c, ascii))
+            .collect::<String>();
+        warnings.push(format!("transliterated non-ASCII characters:{}", table));
+    }
+
+    let hmargin = opts.horizontal_margin.unwrap_or(spec.horizontal_margin);
+    let vmargin = match (
+        opts.vertical_margin.or(spec.vertical_margin),
+        spec.cell_aspect,
+    ) {
+        (Some(m), _) => m,
+        (None, Some(aspect)) if aspect.is_finite() && aspect > 0.0 => {
+            (hmargin as f64 / aspect).round() as usize
+        }
+        (None, Some(aspect)) => {
+            return Err(Error::Invalid(format!(
+                "cell_aspect must be a positive number, not {}",
+                aspect
+            )))
+        }
+        (None, None) => default_vmargin(),
+    };
+    let (hmargin, vmargin) = if spec.compact {
+        (0, 0)
+    } else {
+        (hmargin, vmargin)
+    };
+
+    let mut config = RenderOptions {
+        hmargin,
+        vmargin,
+        padding: spec.padding,
+        seed: opts.seed,
+        max_tweaks: opts.max_tweaks,
+        max_tweak_time: opts.limits.max_tweak_time,
+        gutter_threshold: spec.gutter_threshold,
+        symmetric: spec.symmetric,
+        block_aspect: spec.block_aspect,
+        route_failure: match spec.route_failure {
+            SpecRouteFailure::Error => RouteFailure::Error,
+            SpecRouteFailure::Skip => RouteFailure::Skip,
+            SpecRouteFailure::Force => RouteFailure::Force,
+        },
+        attachment: match spec.attachment {
+            SpecAttachment::VerticalFirst => Attachment::VerticalFirst,
+            SpecAttachment::HorizontalFirst => Attachment::HorizontalFirst,
+            SpecAttachment::Shortest => Attachment::Shortest,
+        },
+    };
+
+    if opts.optimize_layout {
+        blocks = optimize_layout(&blocks, edges.values().cloned(), config.clone());
+    }
+
+    if opts.auto_margins && spec.compact {
+        warnings.push("margins are not chosen automatically in compact diagrams".to_string());
+    } else if opts.auto_margins {
+        config = auto_margins(&blocks, edges.values().cloned(), config);
+        warnings.push(format!(
+            "chosen margins: horizontal_margin = {}, vertical_margin = {}",
+            config.hmargin, config.vmargin
+        ));
+    }
+
+    let mut fit_width = None;
+    if let Some(tw) = opts.terminal_width {
+        let (width, _) = estimate_size(&blocks, config.clone());
+        if width > tw && opts.fit {
+            let (fitted, cfg) = fit_to_width(&blocks, config, tw);
+            blocks = fitted;
+            config = cfg;
+            fit_width = Some(tw);
+
+            let (width, _) = estimate_size(&blocks, config.clone());
+            if width > tw {
+                warnings.push(format!(
+                    "the diagram is {} characters wide even after shrinking it, but the terminal is only {}",
+                    width, tw
+                ));
+            }
+        } else if width > tw {
+            warnings.push(format!(
+                "the diagram is {} characters wide, but the terminal is only {}, use --fit to shrink it",
+                width, tw
+            ));
+        }
+    }
+
+    let (width, height) = estimate_size(&blocks, config.clone());
+    if width.saturating_mul(height) > opts.limits.max_canvas_cells {
+        return Err(Error::Limit(Limit::CanvasCells(
+            opts.limits.max_canvas_cells,
+        )));
+    }
+
+    let rows = blocks.iter().map(|b| b.position().row);
+    let columns = blocks.iter().map(|b| b.position().col);
+    let (min_row, max_row) = (rows.clone().min(), rows.max());
+    let (min_col, max_col) = (columns.clone().min(), columns.max());
+    for &i in &external {
+        let pos = blocks[i].position();
+        let periphery = [min_row, max_row].contains(&Some(pos.row))
+            || [min_col, max_col].contains(&Some(pos.col));
+        if !periphery {
+            warnings.push(format!(
+                r#"external block "{}" is not on the periphery of the diagram"#,
+                ids[i]
+            ));
+        }
+    }
+
+    for o in overlapping_blocks(&blocks, config.clone()) {
+        warnings.push(describe_overlap(&o, &ids));
+    }
+
+    // labels are indexed from the first column or row with blocks, see `add_headers`
+    let unused_labels = |what: &str, labels: &[String], coord: fn(&Block) -> LogicalCoord| {
+        let first = blocks.iter().map(coord).min().unwrap_or(0);
+        labels
+            .iter()
+            .zip((0..).map(|i: LogicalCoord| first.saturating_add(i)))
+            .filter(|&(_, c)| !blocks.iter().any(|b| coord(b) == c))
+            .map(|(l, c)| {
+                format!(
+                    r#"{} label "{}" is ignored, {} {} has no blocks"#,
+                    what, l, what, c
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    warnings.extend(unused_labels("column", &spec.column_labels, |b| {
+        b.position().col
+    }));
+    warnings.extend(unused_labels("row", &spec.row_labels, |b| b.position().row));
+
+    if opts.suggest {
+        for s in suggest(&blocks, edges.values().cloned(), config.clone()) {
+            warnings.push(format!("suggestion: {}", describe_suggestion(&s, &ids)));
+        }
+    }
+
+    for (resolved, b) in resolved_blocks.iter_mut().zip(&blocks) {
+        let pos = b.position();
+        resolved["position"] = json!({ "row": pos.row, "column": pos.col });
+    }
+
+    let resolved = json!({
+        "blocks": resolved_blocks,
+        "edges": resolved_edges.into_values().chain(resolved_stubs).collect::<Vec<_>>(),
+        "horizontal_margin": config.hmargin,
+        "vertical_margin": config.vmargin,
+        "padding": config.padding,
+        "compact": spec.compact,
+        "number_blocks": spec.number_blocks,
+        "tab_width": spec.tab_width,
+        "gutter_threshold": config.gutter_threshold,
+        "symmetric": config.symmetric,
+        "cell_aspect": spec.cell_aspect,
+        "block_aspect": config.block_aspect,
+        "route_failure": spec.route_failure,
+        "attachment": spec.attachment,
+        "directed": spec.directed,
+        "id_matching": spec.id_matching,
+        "infer_edges": spec.infer_edges,
+        "lines": spec.lines,
+        "column_labels": spec.column_labels,
+        "row_labels": spec.row_labels,
+        "seed": config.seed,
+        "max_tweaks": config.max_tweaks,
+        "fit_width": fit_width,
+    });
+
+    let json_rows = |json: &serde_json::Value| {
+        let json = serde_json::to_string_pretty(json).unwrap();
+        json.lines().map(|l| l.as_bytes().to_vec()).collect()
+    };
+
+    if spec.kind == SpecDiagramKind::Packet {
+        let has_elements = !blocks.is_empty() || !edges.is_empty();
+        return render_packet(&fields, spec.bits_per_row, has_elements, warnings, config);
+    }
+
+    if opts.dump_resolved {
+        return Ok(RenderedDiagram {
+            rows: json_rows(&resolved),
+            warnings,
+            config,
+            ids: ids.into_iter().cloned().collect(),
+            block_spans,
+            edge_spans: edge_spans.into_values().collect(),
+            layout: None,
+            score: None,
+            grid: None,
+        });
+    }
+
+    add_computed_routes(computed_routes, &blocks, &ids, &mut edges, &config);
+
+    let unroutable = |e: RouteError| {
+        Error::Invalid(format!(
+            r#"the edge from "{}" to "{}" can't be routed without running over a block, set route_failure to "skip" or "force" to render the diagram anyway"#,
+            ids[e.from], ids[e.to]
+        ))
+    };
+
+    let grid = logical_grid(&blocks, config.clone());
+    if opts.layout_json {
+        let edges = edges.into_values().collect::<Vec<_>>();
+        let layout =
+            try_render_layout(&blocks, edges.clone(), config.clone()).map_err(unroutable)?;
+        let score = score_layout(&blocks, edges, &layout, config.clone()).ok();
+
+        return Ok(RenderedDiagram {
+            rows: json_rows(&layout_json(&layout, &ids)),
+            warnings,
+            config,
+            ids: ids.into_iter().cloned().collect(),
+            block_spans,
+            edge_spans: edge_spans.into_values().collect(),
+            layout: Some(layout),
+            score,
+            grid: Some(grid),
+        });
+    }
+
+    let edges = edges.into_values().collect::<Vec<_>>();
+    let layout = match (&opts.from_layout, &opts.cache_dir) {
+        (Some(path), _) => {
+            let layout = std::fs::read_to_string(path).map_err(|e| Error::Io(e.to_string()))?;
+            parse_layout_json(&layout, &ids).map_err(Error::Parse)?
+        }
+        (None, Some(cache_dir)) => {
+            // the version is part of the key because routing might change between versions
+            let key = format!("{}\n{}{}", env!("CARGO_PKG_VERSION"), resolved, prev_layout);
+            let path = cache_dir.join(format!("{:016x}.json", fnv1a(key.as_bytes())));
+
+            let cached = std::fs::read_to_string(&path)
+                .ok()
+                .and_then(|l| parse_layout_json(&l, &ids).ok())
+                .filter(|l| score_layout(&blocks, edges.clone(), l, config.clone()).is_ok());
+
+            match cached {
+                Some(layout) => layout,
+                None => {
+                    let layout = try_render_layout(&blocks, edges.clone(), config.clone())
+                        .map_err(unroutable)?;
+                    let stored = std::fs::create_dir_all(cache_dir).and_then(|_| {
+                        std::fs::write(&path, layout_json(&layout, &ids).to_string())
+                    });
+                    if let Err(e) = stored {
+                        warnings.push(format!(
+                            "cannot write the cache at {}: {}",
+                            path.display(),
+                            e
+                        ));
+                    }
+                    layout
+                }
+            }
+        }
+        (None, None) => {
+            try_render_layout(&blocks, edges.clone(), config.clone()).map_err(unroutable)?
+        }
+    };
+
+    let mismatch = |e| Error::Invalid(format!("the layout does not match the diagram: {}", e));
+    let mut canvas =
+        render_with_layout(&blocks, edges.clone(), &layout, config.clone()).map_err(mismatch)?;
+    let score = score_layout(&blocks, edges.clone(), &layout, config.clone()).map_err(mismatch)?;
+
+    for (b, (r, id)) in blocks.iter().zip(layout.blocks.iter().zip(&ids)) {
+        if b.rect().is_some_and(|rect| rect != *r) {
+            warnings.push(format!(
+                r#"block "{}" can't be kept at its rect, it's drawn at x = {}, y = {}, width = {}, height = {}"#,
+                id, r.x, r.y, r.width, r.height
+            ));
+        }
+    }
+    let intrusions = edge_intrusions(&layout);
+    for (i, r) in layout.edges.iter().enumerate() {
+        if r.points.is_empty() {
+            warnings.push(format!(
+                r#"the edge from "{}" to "{}" can't be routed, it was left out"#,
+                ids[r.from], ids[r.to]
+            ));
+        } else if intrusions.iter().any(|x| x.edge == i) {
+            warnings.push(format!(
+                r#"the edge from "{}" to "{}" can't be routed, it's drawn over the blocks in its way where marked with "!""#,
+                ids[r.from], ids[r.to]
+            ));
+        }
+    }
+    for c in collisions(&layout) {
+        let edge = |i: usize| {
+            let r = &layout.edges[i];
+            format!(r#"the edge from "{}" to "{}""#, ids[r.from], ids[r.to])
+        };
+        let (over, under) = (edge(c.over), edge(c.under));
+        let what = match c.kind {
+            CollisionKind::LabelOverLabel => {
+                format!("a label of {} is drawn over a label of {}", over, under)
+            }
+            CollisionKind::LabelOverLine => {
+                format!("a label of {} is drawn over the line of {}", over, under)
+            }
+            CollisionKind::LineOverEnd => format!("{} runs over the end of {}", over, under),
+        };
+        warnings.push(format!("{} at x = {}, y = {}", what, c.point.x, c.point.y));
+    }
+    // edges with a route of their own, like the loops of layered diagrams, go the long way on
+    // purpose
+    for d in detours(&layout, DETOUR_FACTOR) {
+        let r = &layout.edges[d.edge];
+        if !edges[d.edge].route().is_empty() {
+            continue;
+        }
+        warnings.push(format!(
+            r#"the edge from "{}" to "{}" is {} characters long but its blocks are only {} apart, move them closer or increase the margins"#,
+            ids[r.from], ids[r.to], d.length, d.distance
+        ));
+    }
+    for (e, r) in edges.iter().zip(&layout.edges) {
+        if routed_edges.contains(&(r.from, r.to)) && e.route() != r.points {
+            warnings.push(format!(
+                r#"the route of the edge from "{}" to "{}" doesn't fit the diagram anymore, it was routed again"#,
+                ids[r.from], ids[r.to]
+            ));
+        }
+    }
+
+    let mut layout = if opts.compact_margins {
+        let (rows, compacted) = compact_margins(canvas, &layout);
+        canvas = rows;
+        compacted
+    } else {
+        layout
+    };
+
+    if !spec.lines.is_empty() {
+        let lines = spec
+            .lines
+            .iter()
+            .map(|l| Polyline {
+                points: l.points.iter().copied().map(PolylinePoint::from).collect(),
+                arrow: l.arrow,
+            })
+            .collect::<Vec<_>>();
+        let (rows, failed) = add_polylines(canvas, &layout, &blocks, &lines);
+        canvas = rows;
+        for (i, e) in failed {
+            let why = match e {
+                PolylineError::Block(b) => format!(r#"it goes through "{}""#, ids[b]),
+                e => e.to_string(),
+            };
+            warnings.push(format!("line {} can't be drawn, {}", i, why));
+        }
+    }
+
+    if !stubs.is_empty() {
+        let (rows, stubbed, failed) = add_stubs(canvas, &layout, &stubs);
+        canvas = rows;
+        layout = stubbed;
+        for i in failed {
+            let (from, name) = &stubs[i];
+            warnings.push(format!(
+                r#"the edge from "{}" to the external "{}" can't be drawn, every side of its block is taken"#,
+                ids[*from], name
+            ));
+        }
+    }
+
+    if opts.guides {
+        canvas = add_guides(canvas, &layout, &blocks);
+    }
+
+    if !column_labels.is_empty() || !row_labels.is_empty() {
+        let (rows, headers) = add_headers(canvas, &layout, &blocks, &column_labels, &row_labels);
+        canvas = rows;
+        layout = headers;
+    }
+
+    if let Some(addresses) = &memmap {
+        let (rows, offset) = add_addresses(canvas, &layout, addresses, &id_to_block_id);
+        canvas = rows;
+        layout = offset;
+    }
+
+    let frames = if opts.frames {
+        let steps = frame_steps(&block_steps, &edge_steps);
+        let mut sorted = steps.clone();
+        sorted.sort_unstable();
+        sorted.dedup();
+
+        sorted
+            .into_iter()
+            .map(|step| {
+                let shown = |el: Element| match el {
+                    Element::Block(i) => steps[i] <= step,
+                    Element::Edge(i) => steps[block_steps.len() + i] <= step,
+                };
+                hide_elements(canvas.clone(), &layout, &blocks, |el| !shown(el))
+            })
+            .collect()
+    } else {
+        vec![canvas]
+    };
+
+    let mut canvas = vec![];
+    for (i, mut frame) in frames.into_iter().enumerate() {
+        if let Some(v) = opts.viewport {
+            frame = Canvas::from_rows(frame)
+                .crop(v.x, v.y, v.width, v.height)
+                .into_rows();
+        }
+        if let Some(w) = opts.split_width {
+            frame = split_pages(&Canvas::from_rows(frame), w).map_err(Error::Invalid)?;
+        }
+
+        if i > 0 {
+            canvas.push(FRAME_SEPARATOR.to_vec());
+        }
+        canvas.extend(frame);
+    }
+
+    canvas.extend(legend);
+    Ok(RenderedDiagram {
+        rows: canvas,
+        warnings,
+        config,
+        ids: ids.into_iter().cloned().collect(),
+        block_spans,
+        edge_spans: edge_spans.into_values().collect(),
+        layout: Some(layout),
+        score: Some(score),
+        grid: Some(grid),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::assert_diagram_eq;
+    use crate::Element;
+
+    fn render_to_string(text: &str, format: Format, opts: &Overrides) -> Result<String, Error> {
+        Ok(render_spec_str(text, format, opts)?.to_string())
+    }
+
+    #[test]
+    fn test_basic_toml_diagram() {
+        let diagram = br#"
+edges = [ {from = "one", to = "four"}
+        , {from = "one", to = "0000"}
+        , {from = "two", to = "zero"}
+        , {from = "oooo", to = "zero"}
+        ]
+
+[[blocks]]
+text = "zero"
+position = { row = -1, column = -1 }
+
+[[blocks]]
+text = "one"
+position = { row = -1, column = 0 }
+
+[[blocks]]
+text = "two"
+position = { row = -1, column = 1 }
+
+[[blocks]]
+text = "0000"
+position = { row = 0, column = -1 }
+
+[[blocks]]
+text = "four"
+position = { row = 0, column = 1 }
+
+[[blocks]]
+text = "oooo"
+position = { row = 1, column = -1 }
+
+"#;
+        let diagram = toml::from_slice(diagram).unwrap();
+
+        assert_diagram_eq!(
+            render_diagram(
+                diagram,
+                &Overrides {
+                    seed: Some(42),
+                    max_tweaks: 0,
+                    ..Overrides::default()
+                }
+            )
+            .unwrap()
+            .rows,
+            br#"                                           
+         +------------------------+        
+         |                        |        
+     +---+--+     +-----+     +---+--+     
+     |      |     |     |     |      |     
+   +-+ zero |     | one +--+  | two  |     
+   | |      |     |     |  |  |      |     
+   | +------+     +--+--+  |  +------+     
+   |                 |     |               
+   |                 |     +------+        
+   |                 |            |        
+   | +------+        |        +---+--+     
+   | |      |        |        |      |     
+   | | 0000 +--------+        | four |     
+   | |      |                 |      |     
+   | +------+                 +------+     
+   |                                       
+   |                                       
+   |                                       
+   | +------+                              
+   | |      |                              
+   +-+ oooo |                              
+     |      |                              
+     +------+                              
+                                           
+                                           
+                                           "#
+        );
+    }
+
+    #[test]
+    fn test_overflow_error() {
+        let opts = Overrides {
+            seed: Some(42),
+            max_tweaks: 0,
+            ..Overrides::default()
+        };
+        let diagram = |overflow| {
+            format!(
+                r#"
+edges = []
+
+[[blocks]]
+text = "a long description"
+width = 10
+overflow = "{}"
+position = {{ row = 0, column = 0 }}
+"#,
+                overflow
+            )
+        };
+
+        assert!(render_to_string(&diagram("wrap"), Format::Toml, &opts).is_ok());
+        assert!(render_to_string(&diagram("truncate"), Format::Toml, &opts).is_ok());
+        assert_eq!(
+            render_to_string(&diagram("error"), Format::Toml, &opts),
+            Err(Error::Invalid(
+                r#"the text of block "a long description" does not fit in its size"#.to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_normalize_text() {
+        let no_control_chars = BTreeSet::new();
+
+        assert_eq!(
+            normalize_text("a\tb\r\n\tab\tc", 4),
+            ("a   b\n    ab  c".to_string(), no_control_chars.clone())
+        );
+        assert_eq!(
+            normalize_text("a\tb", 0),
+            ("ab".to_string(), no_control_chars)
+        );
+        assert_eq!(
+            normalize_text("a\x07b\rc\r\n", 4),
+            (
+                "abc\n".to_string(),
+                ['\x07', '\r'].iter().copied().collect()
+            )
+        );
+    }
+
+    #[test]
+    fn test_transliterate() {
+        assert_eq!(
+            transliterate("plain"),
+            ("plain".to_string(), BTreeMap::new())
+        );
+        assert_eq!(
+            transliterate("café → Straße"),
+            (
+                "cafe - Strasse".to_string(),
+                [('é', "e"), ('→', "-"), ('ß', "ss")]
+                    .iter()
+                    .map(|(c, s)| (*c, s.to_string()))
+                    .collect()
+            )
+        );
+    }
+
+    #[test]
+    fn test_describe_suggestion() {
+        let db = "db".to_string();
+        let cache = "cache".to_string();
+        let ids = [&db, &cache];
+
+        assert_eq!(
+            describe_suggestion(
+                &Suggestion::SwapBlocks {
+                    a: 0,
+                    b: 1,
+                    crossings_removed: 2
+                },
+                &ids
+            ),
+            r#"swap blocks "db" and "cache" to remove 2 crossings"#
+        );
+        assert_eq!(
+            describe_suggestion(
+                &Suggestion::IncreaseVerticalMargin {
+                    margin: 4,
+                    hugging_removed: 1
+                },
+                &ids
+            ),
+            "increase vertical_margin to 4 to avoid line hugging in 1 cell"
+        );
+    }
+
+    #[test]
+    fn test_edge_tags_filters() {
+        let tags = |t: &[&str]| t.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        let mut opts = Overrides::default();
+
+        assert!(is_edge_selected(&tags(&[]), &opts));
+        assert!(is_edge_selected(&tags(&["async"]), &opts));
+
+        opts.edge_tags = tags(&["sync", "legacy"]);
+        assert!(!is_edge_selected(&tags(&[]), &opts));
+        assert!(!is_edge_selected(&tags(&["async"]), &opts));
+        assert!(is_edge_selected(&tags(&["async", "legacy"]), &opts));
+
+        opts.exclude_edge_tags = tags(&["async"]);
+        assert!(!is_edge_selected(&tags(&["async", "legacy"]), &opts));
+        assert!(is_edge_selected(&tags(&["sync"]), &opts));
+    }
+
+    #[test]
+    fn test_edge_tags() {
+        let diagram = r#"
+blocks = [
+    { text = "a", position = { row = 0, column = 0 } },
+    { text = "b", position = { row = 0, column = 1 } },
+]
+edges = [
+    { from = "a", to = "b", tags = ["legacy"] },
+    { from = "a", to = "c", tags = ["async"] },
+]
+"#;
+        let opts = Overrides {
+            exclude_edge_tags: vec!["async".to_string()],
+            dashed_edge_tags: vec!["legacy".to_string()],
+            dump_resolved: true,
+            ..Overrides::default()
+        };
+        let rendered = render_spec_str(diagram, Format::Toml, &opts).unwrap();
+
+        // edges are checked even if they're filtered out
+        assert_eq!(
+            rendered.warnings,
+            vec![r#"id "c" not found, did you mean "a"?"#]
+        );
+        let dump = String::from_utf8(rendered.rows.concat()).unwrap();
+        let dump = serde_json::from_str::<serde_json::Value>(&dump).unwrap();
+        assert_eq!(dump["edges"][0]["dashed"], true);
+    }
+
+    #[test]
+    fn test_directed_spec() {
+        let diagram = r#"
+directed = true
+edges = [ {from = "a", to = "b"}
+        , {from = "b", to = "c", directed = false}
+        ]
+
+[[blocks]]
+text = "a"
+position = { row = 0, column = 0 }
+
+[[blocks]]
+text = "b"
+position = { row = 0, column = 1 }
+
+[[blocks]]
+text = "c"
+position = { row = 0, column = 2 }
+"#;
+
+        let rendered = render_to_string(diagram, Format::Toml, &Overrides::default());
+        assert!(rendered.unwrap().contains("| a +---->+ b +-----+ c |"));
+    }
+
+    #[test]
+    fn test_is_duplicate_edge() {
+        // (existing a -> b directed, new edge, new directed, duplicate)
+        let matrix = [
+            (false, (0, 1), false, true),
+            (false, (0, 1), true, true),
+            (true, (0, 1), false, true),
+            (true, (0, 1), true, true),
+            (false, (1, 0), false, true),
+            (false, (1, 0), true, true),
+            (true, (1, 0), false, true),
+            (true, (1, 0), true, false),
+            (true, (1, 2), true, false),
+            (false, (2, 1), false, false),
+        ];
+
+        for &(existing_directed, edge, directed, duplicate) in &matrix {
+            let existing = std::iter::once(((0, 1), existing_directed)).collect();
+            assert_eq!(
+                is_duplicate_edge(&existing, edge, directed),
+                duplicate,
+                "{:?}",
+                (existing_directed, edge, directed)
+            );
+        }
+    }
+
+    #[test]
+    fn test_directed_edges_in_both_directions() {
+        let diagram = r#"
+edges = [ {from = "a", to = "b", directed = true}
+        , {from = "b", to = "a", directed = true}
+        , {from = "b", to = "c"}
+        , {from = "c", to = "b", directed = true}
+        ]
+
+[[blocks]]
+id = "a"
+text = "a"
+position = { row = 0, column = 0 }
+
+[[blocks]]
+id = "b"
+text = "b"
+position = { row = 0, column = 1 }
+
+[[blocks]]
+id = "c"
+text = "c"
+position = { row = 0, column = 2 }
+"#;
+
+        let spec = parse_spec(diagram, Format::Toml).unwrap();
+        let rendered = render_diagram(spec, &Overrides::default()).unwrap().rows;
+        let rendered = rendered
+            .iter()
+            .map(|l| String::from_utf8_lossy(l).into_owned())
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        // a and b are connected twice, the directed edge from c to b is a duplicate
+        let arrowheads = rendered.matches(|c| "<>^v".contains(c)).count();
+        assert_eq!(arrowheads, 2);
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("abc", ""), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("hull breach", "hull breach"), 0);
+        assert_eq!(edit_distance("hul breach", "hull breach"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("café", "cafe"), 1);
+    }
+
+    #[test]
+    fn test_describe_unknown_id() {
+        let known = [
+            ("hull breach", "hull breach"),
+            ("eng", "eng"),
+            ("engine room", "eng"),
+        ];
+
+        assert_eq!(
+            describe_unknown_id("hul breach", &known),
+            r#"id "hul breach" not found, did you mean "hull breach"?"#
+        );
+        assert_eq!(
+            describe_unknown_id("engine rom", &known),
+            r#"id "engine rom" not found, did you mean "eng" (the block with text "engine room")?"#
+        );
+        assert_eq!(
+            describe_unknown_id("bridge", &known),
+            r#"id "bridge" not found"#
+        );
+    }
+
+    #[test]
+    fn test_slugify() {
+        assert_eq!(slugify("Hull Breach"), "hull breach");
+        assert_eq!(slugify("  hull-breach!! "), "hull breach");
+        assert_eq!(slugify("Hull_Breach (2)"), "hull breach 2");
+        assert_eq!(slugify("Ünïcode"), "ünïcode");
+        assert_eq!(slugify("--"), "");
+    }
+
+    #[test]
+    fn test_loose_id_matching() {
+        let blocks = r#"
+[[blocks]]
+text = "hull breach"
+position = { row = 0, column = 0 }
+
+[[blocks]]
+id = "eng"
+text = "Engine Room"
+position = { row = 0, column = 1 }
+
+[[blocks]]
+text = "bridge"
+position = { row = 0, column = 2 }
+"#;
+        let edges = r#"edges = [ {from = "Hull-Breach", to = "engine room!"}
+        , {from = "HULL BREACH", to = "Bridge"}
+        ]"#;
+
+        let render = |header: &str| {
+            let diagram = format!("{}\n{}", header, blocks);
+            let spec = parse_spec(&diagram, Format::Toml).unwrap();
+            render_diagram(spec, &Overrides::default()).unwrap().rows
+        };
+
+        let edgeless = render("edges = []");
+
+        // none of the references match exactly
+        assert_eq!(render(edges), edgeless);
+        assert_eq!(
+            render(&format!("id_matching = \"exact\"\n{}", edges)),
+            edgeless
+        );
+        assert_ne!(
+            render(&format!("id_matching = \"loose\"\n{}", edges)),
+            edgeless
+        );
+    }
+
+    #[test]
+    fn test_references() {
+        assert_eq!(references("see [db]"), vec!["db"]);
+        assert_eq!(references("[a] and [b c], not [] or [ ]"), vec!["a", "b c"]);
+        assert_eq!(references("[[a]] [b"), vec!["a"]);
+        assert_eq!(references("[a\nb] [c\n]"), Vec::<String>::new());
+        assert_eq!(references("no references"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_infer_edges() {
+        let blocks = r#"
+[[blocks]]
+text = "api, see [db]"
+position = { row = 0, column = 0 }
+
+[[blocks]]
+id = "db"
+text = "database\nsee [api, see [db]] and [nope]"
+position = { row = 0, column = 1 }
+"#;
+
+        let render = |header: &str| {
+            let diagram = format!("{}\n{}", header, blocks);
+            let spec = parse_spec(&diagram, Format::Toml).unwrap();
+            render_diagram(spec, &Overrides::default()).unwrap().rows
+        };
+
+        let explicit = render(r#"edges = [{from = "api, see [db]", to = "db"}]"#);
+
+        assert_ne!(render("edges = []"), explicit);
+        assert_eq!(render("edges = []\ninfer_edges = true"), explicit);
+    }
+
+    #[test]
+    fn test_dump_resolved() {
+        let diagram = r#"
+infer_edges = true
+edges = [{from = "a", to = "B", from_label = "1", tags = ["x"]}]
+id_matching = "loose"
+
+[[blocks]]
+text = "a\tsee [c]"
+id = "a"
+position = { row = 0, column = 0 }
+
+[[blocks]]
+text = "b"
+position = { row = 0, column = 1 }
+
+[[blocks]]
+text = "c"
+position = { row = 1, column = 0 }
+shape = "note"
+"#;
+
+        let mut opts = Overrides {
+            dump_resolved: true,
+            ..Overrides::default()
+        };
+        let dump = render_to_string(diagram, Format::Toml, &opts).unwrap();
+        let dump: serde_json::Value = serde_json::from_str(&dump).unwrap();
+
+        assert_eq!(dump["horizontal_margin"], 5);
+        assert_eq!(dump["tab_width"], 4);
+        assert_eq!(dump["id_matching"], "loose");
+
+        assert_eq!(dump["blocks"][0]["text"], "a   see [c]");
+        assert_eq!(dump["blocks"][1]["id"], "b");
+        assert_eq!(dump["blocks"][2]["shape"], "note");
+        assert_eq!(dump["blocks"][2]["overflow"], "wrap");
+        assert_eq!(
+            dump["blocks"][2]["position"],
+            json!({"row": 1, "column": 0})
+        );
+
+        assert_eq!(
+            dump["edges"],
+            json!([
+                {
+                    "from": "a",
+                    "to": "b",
+                    "from_label": "1",
+                    "to_label": null,
+                    "tags": ["x"],
+                    "directed": false,
+                    "bidirectional": false,
+                    "group": null,
+                    "route": [],
+                    "dashed": false,
+                    "step": null,
+                },
+                {"from": "a", "to": "c", "directed": false, "inferred": true},
+            ])
+        );
+
+        // the dump is a valid spec itself
+        let resolved = render_to_string(&dump.to_string(), Format::Json, &opts).unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&resolved).unwrap()["blocks"],
+            dump["blocks"]
+        );
+
+        opts.dump_resolved = false;
+        assert!(render_to_string(diagram, Format::Toml, &opts)
+            .unwrap()
+            .contains("+---+"));
+    }
+
+    #[test]
+    fn test_layout_json() {
+        let diagram = r#"
+edges = [{from = "a", to = "b", to_label = "1"}]
+
+[[blocks]]
+text = "a"
+position = { row = 0, column = 0 }
+
+[[blocks]]
+text = "b"
+position = { row = 0, column = 1 }
+"#;
+
+        let opts = Overrides {
+            layout_json: true,
+            ..Overrides::default()
+        };
+        let layout = render_to_string(diagram, Format::Toml, &opts).unwrap();
+        let layout: serde_json::Value = serde_json::from_str(&layout).unwrap();
+
+        assert_eq!(layout["width"], 25);
+        assert_eq!(layout["height"], 11);
+        assert_eq!(
+            layout["blocks"],
+            json!([
+                {"id": "a", "x": 5, "y": 3, "width": 5, "height": 5},
+                {"id": "b", "x": 15, "y": 3, "width": 5, "height": 5},
+            ])
+        );
+        assert_eq!(layout["edges"][0]["from"], "a");
+        assert_eq!(layout["edges"][0]["to"], "b");
+        assert_eq!(
+            layout["edges"][0]["points"],
+            json!([{"x": 9, "y": 5}, {"x": 15, "y": 5}])
+        );
+        assert_eq!(layout["edges"][0]["labels"][0]["text"], "1");
+        assert_eq!(layout["column_gutters"], json!([0, 0, 0]));
+    }
+
+    #[test]
+    fn test_from_layout() {
+        let diagram = r#"
+edges = [{from = "a", to = "b"}, {from = "c", to = "a", to_label = "x"}]
+
+[[blocks]]
+text = "a"
+position = { row = 0, column = 0 }
+
+[[blocks]]
+text = "b"
+position = { row = 0, column = 1 }
+
+[[blocks]]
+id = "c"
+text = "c"
+position = { row = 1, column = 1 }
+"#;
+
+        let mut opts = Overrides {
+            layout_json: true,
+            ..Overrides::default()
+        };
+        let layout = render_to_string(diagram, Format::Toml, &opts).unwrap();
+
+        let path = std::env::temp_dir().join(format!("ascii-diagrams-{}.json", std::process::id()));
+        std::fs::write(&path, layout).unwrap();
+
+        opts.layout_json = false;
+        let rendered = render_to_string(diagram, Format::Toml, &opts);
+        opts.from_layout = Some(path.clone());
+        assert_eq!(render_to_string(diagram, Format::Toml, &opts), rendered);
+
+        let changed = diagram.replace(r#"text = "c""#, r#"text = "cc""#);
+        let err = render_to_string(&changed, Format::Toml, &opts);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            err,
+            Err(Error::Invalid(
+                "the layout does not match the diagram: the size of the canvas is different"
+                    .to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_render_spec_diff() {
+        let old = r#"
+horizontal_margin = 3
+vertical_margin = 1
+padding = 0
+edges = [{from = "api", to = "db"}, {from = "web", to = "api"}]
+
+[[blocks]]
+text = "web"
+position = { row = 0, column = 0 }
+
+[[blocks]]
+text = "api"
+position = { row = 0, column = 1 }
+
+[[blocks]]
+text = "db"
+position = { row = 1, column = 1 }
+"#;
+        let new = old
+            .replace(
+                r#"{from = "api", to = "db"}"#,
+                r#"{from = "api", to = "cache"}"#,
+            )
+            .replace(
+                "[[blocks]]\ntext = \"db\"\nposition = { row = 1, column = 1 }",
+                "[[blocks]]\ntext = \"cache\"\nposition = { row = 1, column = 0 }",
+            );
+        let opts = Overrides {
+            seed: Some(0),
+            max_tweaks: 0,
+            ..Overrides::default()
+        };
+
+        let diff = render_spec_diff(old, Format::Toml, &new, Format::Toml, &opts).unwrap();
+        assert!(diff.warnings.is_empty());
+        assert_eq!(diff.ids, vec!["web", "api", "cache", "db"]);
+        assert_diagram_eq!(
+            diff.rows,
+            br#"                     
+   +-----+   +---+   
+   | web +--++api|   
+   +-----+  |+-+-+   
+            |  :     
+   +-----+  |+-+-+   
+   |cache+--+:db :   
+   +-----+   +- -+   
+                     
+added: cache, api -> cache
+removed (dashed): db, api -> db"#
+        );
+    }
+
+    #[test]
+    fn test_prev_layout() {
+        let diagram = r#"
+horizontal_margin = 4
+vertical_margin = 2
+padding = 0
+edges = [{from = "a", to = "b"}]
+
+[[blocks]]
+text = "a"
+position = { row = 0, column = 0 }
+
+[[blocks]]
+text = "b"
+position = { row = 0, column = 1 }
+"#;
+        let mut opts = Overrides {
+            seed: Some(0),
+            layout_json: true,
+            ..Overrides::default()
+        };
+        let layout = render_to_string(diagram, Format::Toml, &opts).unwrap();
+        let mut layout: serde_json::Value = serde_json::from_str(&layout).unwrap();
+
+        // the edge was moved above the blocks in the previous layout
+        let route =
+            json!([{"x": 5, "y": 2}, {"x": 5, "y": 0}, {"x": 12, "y": 0}, {"x": 12, "y": 2}]);
+        layout["edges"][0]["points"] = route.clone();
+        let path =
+            std::env::temp_dir().join(format!("ascii-diagrams-prev-{}.json", std::process::id()));
+        std::fs::write(&path, layout.to_string()).unwrap();
+
+        let changed = format!(
+            "{}\n{}",
+            diagram.replace(
+                r#"edges = [{from = "a", to = "b"}]"#,
+                r#"edges = [{from = "a", to = "b"}, {from = "c", to = "b"}]"#
+            ),
+            "[[blocks]]\ntext = \"c\"\nposition = { row = 1, column = 0 }\n"
+        );
+        opts.prev_layout = Some(path.clone());
+        let stable = render_spec_str(&changed, Format::Toml, &opts);
+        std::fs::remove_file(&path).unwrap();
+
+        let stable = stable.unwrap().layout.unwrap();
+        let points = stable.edges[0]
+            .points
+            .iter()
+            .map(|p| json!({"x": p.x, "y": p.y}))
+            .collect::<Vec<_>>();
+        assert_eq!((stable.edges[0].from, stable.edges[0].to), (0, 1));
+        assert_eq!(json!(points), route);
+    }
+
+    #[test]
+    fn test_fnv1a() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn test_cache_dir() {
+        let diagram = r#"
+edges = [{from = "a", to = "b"}, {from = "c", to = "a"}]
+
+[[blocks]]
+text = "a"
+position = { row = 0, column = 0 }
+
+[[blocks]]
+text = "b"
+position = { row = 0, column = 1 }
+
+[[blocks]]
+text = "c"
+position = { row = 1, column = 1 }
+"#;
+
+        let cache_dir =
+            std::env::temp_dir().join(format!("ascii-diagrams-cache-{}", std::process::id()));
+        let mut opts = Overrides {
+            seed: Some(0),
+            ..Overrides::default()
+        };
+        let rendered = render_to_string(diagram, Format::Toml, &opts).unwrap();
+
+        opts.cache_dir = Some(cache_dir.clone());
+        let entries = || std::fs::read_dir(&cache_dir).unwrap().count();
+
+        assert_eq!(
+            render_to_string(diagram, Format::Toml, &opts).unwrap(),
+            rendered
+        );
+        assert_eq!(entries(), 1);
+        assert_eq!(
+            render_to_string(diagram, Format::Toml, &opts).unwrap(),
+            rendered
+        );
+        assert_eq!(entries(), 1);
+
+        // different options are cached separately
+        opts.seed = Some(1);
+        render_to_string(diagram, Format::Toml, &opts).unwrap();
+        assert_eq!(entries(), 2);
+
+        // corrupted entries are replaced
+        for e in std::fs::read_dir(&cache_dir).unwrap() {
+            std::fs::write(e.unwrap().path(), "{}").unwrap();
+        }
+        opts.seed = Some(0);
+        assert_eq!(
+            render_to_string(diagram, Format::Toml, &opts).unwrap(),
+            rendered
+        );
+        assert_eq!(entries(), 2);
+
+        std::fs::remove_dir_all(&cache_dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_viewport() {
+        assert_eq!(
+            "1, 2,3,4".parse::<Viewport>(),
+            Ok(Viewport {
+                x: 1,
+                y: 2,
+                width: 3,
+                height: 4
+            })
+        );
+        assert!("1,2,3".parse::<Viewport>().is_err());
+        assert!("1,2,3,-4".parse::<Viewport>().is_err());
+    }
+
+    #[test]
+    fn test_viewport() {
+        let diagram = r#"
+edges = [{from = "a", to = "b"}]
+number_blocks = true
+
+[[blocks]]
+text = "a"
+position = { row = 0, column = 0 }
+
+[[blocks]]
+text = "b"
+position = { row = 0, column = 1 }
+"#;
+
+        let opts = Overrides {
+            viewport: Some("11,3,6,4".parse().unwrap()),
+            ..Overrides::default()
+        };
+        assert_eq!(
+            render_to_string(diagram, Format::Toml, &opts).unwrap(),
+            "~-+   \n  |   \n  +--~\n  ~   \n[1] a\n[2] b"
+        );
+    }
+
+    #[test]
+    fn test_legend_multi_line_ids() {
+        let diagram = r#"
+number_blocks = true
+blocks = [
+    { text = "web\nserver", position = { row = 0, column = 0 } },
+    { text = "db", position = { row = 0, column = 1 }, details = "storage\nlayer" },
+]
+"#;
+        let rendered = render_to_string(diagram, Format::Toml, &Overrides::default()).unwrap();
+        let legend = rendered.lines().rev().take(3).collect::<Vec<_>>();
+        assert_eq!(
+            legend,
+            vec![
+                r#""db" is detailed in "storage layer""#,
+                "[2] db",
+                "[1] web server"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_pages() {
+        let canvas = Canvas::from_rows(vec![
+            b"+---+      ".to_vec(),
+            b"| a +-----+".to_vec(),
+            b"+---+     |".to_vec(),
+        ]);
+
+        let pages = split_pages(&canvas, 4).unwrap();
+        let pages = pages
+            .iter()
+            .map(|l| String::from_utf8_lossy(l))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pages.join("\n"),
+            r#"[page 1/3, columns 1-4]
++--~
+| a 
++--~
+continues on page 2 at rows 1, 3
+
+[page 2/3, columns 5-8]
+~   
++--~
+~   
+continued from page 1 at rows 1, 3
+continues on page 3 at rows 2
+
+[page 3/3, columns 9-11]
+   
+~-+
+  |
+continued from page 2 at rows 2"#
+        );
+
+        assert_eq!(split_pages(&canvas, 11).unwrap(), canvas.into_rows());
+    }
+
+    #[test]
+    fn test_fit_terminal_width() {
+        let diagram = r#"
+edges = [{from = "a", to = "b"}]
+
+[[blocks]]
+text = "a block with quite a lot of text"
+position = { row = 0, column = 0 }
+
+[[blocks]]
+text = "b"
+position = { row = 0, column = 1 }
+"#;
+
+        let width = |opts: &Overrides| {
+            let rendered = render_to_string(diagram, Format::Toml, opts).unwrap();
+            rendered.lines().map(str::len).max().unwrap()
+        };
+
+        let mut opts = Overrides {
+            fit: true,
+            ..Overrides::default()
+        };
+        assert_eq!(width(&opts), 56);
+
+        opts.terminal_width = Some(30);
+        assert!(width(&opts) <= 30);
+
+        opts.fit = false;
+        assert_eq!(width(&opts), 56);
+    }
+
+    #[test]
+    fn test_rendered_diagram() {
+        let diagram = r#"
+edges = [ {from = "a", to = "b"}
+        , {from = "a", to = "missing"}
+        ]
+
+[[blocks]]
+text = "a"
+position = { row = 0, column = 0 }
+
+[[blocks]]
+id = "b"
+text = "bb"
+position = { row = 0, column = 1 }
+"#;
+
+        let rendered = render_spec_str(diagram, Format::Toml, &Overrides::default()).unwrap();
+        assert_eq!(
+            rendered.warnings,
+            vec![r#"id "missing" not found"#.to_string()]
+        );
+        assert_eq!(rendered.ids, vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(
+            rendered.to_string().lines().nth(5),
+            Some("     | a +-----+ bb |     ")
+        );
+
+        let layout = rendered.layout.unwrap();
+        assert_eq!(layout.blocks[1].x, 15);
+        assert_eq!(
+            layout.edges[0].points,
+            [(9, 5), (15, 5)].map(CanvasPoint::from)
+        );
+
+        let score = rendered.score.unwrap();
+        assert_eq!((score.crossings, score.length), (0, 6));
+
+        let dump = Overrides {
+            dump_resolved: true,
+            ..Overrides::default()
+        };
+        let rendered = render_spec_str(diagram, Format::Toml, &dump).unwrap();
+        assert_eq!((rendered.layout, rendered.score), (None, None));
+    }
+
+    #[test]
+    fn test_source_spans() {
+        let diagram = r#"{
+  "blocks": [
+    {"id": "a", "text": "a [b]", "position": {"row": 0, "column": 0}},
+    {"id": "c", "text": "c", "position": {"row": 0, "column": 0}},
+    {"id": "b", "text": "b", "position": {"row": 0, "column": 1}}
+  ],
+  "edges": [{"from": "b", "to": "a"}, {"from": "b", "to": "missing"}],
+  "infer_edges": true,
+  "directed": true
+}"#;
+
+        let rendered = render_spec_str(diagram, Format::Json, &Overrides::default()).unwrap();
+        let lines =
+            |spans: &[Option<Span>]| spans.iter().map(|s| s.map(|s| s.line)).collect::<Vec<_>>();
+
+        // the block at an occupied position is skipped, the inferred edge points to its block
+        assert_eq!(lines(&rendered.block_spans), vec![Some(3), Some(5)]);
+        assert_eq!(lines(&rendered.edge_spans), vec![Some(3), Some(7)]);
+
+        let span = rendered.edge_spans[1].unwrap();
+        assert_eq!(
+            &diagram[span.start..span.end],
+            r#"{"from": "b", "to": "a"}"#
+        );
+
+        // spans can be looked up from what is on screen
+        let layout = rendered.layout.unwrap();
+        let b = &layout.blocks[1];
+        match layout.hit_test(b.x + 1, b.y + 1) {
+            Some(Element::Block(i)) => assert_eq!(rendered.block_spans[i].unwrap().line, 5),
+            e => panic!("unexpected element {:?}", e),
+        }
+
+        // escaped keys aren't understood, no spans rather than wrong ones
+        let diagram = r#"{
+  "bl\u006fcks": [{"text": "a", "position": {"row": 0, "column": 0}}],
+  "edges": []
+}"#;
+        let rendered = render_spec_str(diagram, Format::Json, &Overrides::default()).unwrap();
+        assert_eq!(rendered.block_spans, vec![None]);
+    }
+
+    #[test]
+    fn test_edge_groups() {
+        let diagram = r#"
+horizontal_margin = 3
+directed = true
+edges = [ {from = "worker", to = "sink", group = "events"}
+        , {from = "cron", to = "sink", group = "events"}
+        , {from = "web", to = "sink", group = "events"}
+        ]
+
+[[blocks]]
+text = "sink"
+position = { row = 2, column = 0 }
+
+[[blocks]]
+text = "worker"
+position = { row = 0, column = 0 }
+
+[[blocks]]
+text = "cron"
+position = { row = 0, column = 1 }
+
+[[blocks]]
+text = "web"
+position = { row = 0, column = 2 }
+"#;
+        let opts = Overrides {
+            seed: Some(0),
+            max_tweaks: 0,
+            ..Overrides::default()
+        };
+
+        let rendered = render_spec_str(diagram, Format::Toml, &opts).unwrap();
+        assert!(rendered.warnings.is_empty());
+        assert_eq!(rendered.to_string().matches('*').count(), 1);
+
+        let ungrouped = diagram.replace(r#", group = "events""#, "");
+        let rendered = render_spec_str(&ungrouped, Format::Toml, &opts).unwrap();
+        assert!(!rendered.to_string().contains('*'));
+    }
+
+    #[test]
+    fn test_external_edges() {
+        let diagram = r#"
+horizontal_margin = 3
+vertical_margin = 1
+edges = [ {from = "web", to = "api"}
+        , {from = "api", external = "internet"}
+        , {from = "web"}
+        ]
+
+[[blocks]]
+text = "web"
+position = { row = 0, column = 0 }
+
+[[blocks]]
+text = "api"
+position = { row = 0, column = 1 }
+"#;
+        let opts = Overrides {
+            seed: Some(0),
+            max_tweaks: 0,
+            ..Overrides::default()
+        };
+
+        let rendered = render_spec_str(diagram, Format::Toml, &opts).unwrap();
+        assert_eq!(
+            rendered.warnings,
+            vec![r#"the edge from "web" has neither "to" nor "external", it's ignored"#]
+        );
+        assert_diagram_eq!(
+            rendered.rows,
+            br#"                       
+   +-----+   +-----+   
+   |     |   |     |   
+   | web +---+ api |   
+   |     |   |     |   
+   +-----+   +--+--+   
+                |      
+                |      
+                v      
+             internet  "#
+        );
+    }
+
+    #[test]
+    fn test_lines() {
+        let diagram = r#"
+horizontal_margin = 3
+vertical_margin = 1
+edges = [{from = "web", to = "db"}]
+lines = [ {points = [{row = 1, column = 0}, {row = 1, column = 2}]}
+        , {points = [{x = 0, y = 2}, {x = 8, y = 2}], arrow = true}
+        , {points = [{x = 1, y = 0}, {x = 1, y = 0}]}
+        ]
+
+[[blocks]]
+text = "web"
+position = { row = 0, column = 0 }
+
+[[blocks]]
+text = "db"
+position = { row = 1, column = 1 }
+"#;
+        let opts = Overrides {
+            seed: Some(0),
+            max_tweaks: 0,
+            ..Overrides::default()
+        };
+
+        let rendered = render_spec_str(diagram, Format::Toml, &opts).unwrap();
+        assert_eq!(
+            rendered.warnings,
+            vec![
+                r#"line 1 can't be drawn, it goes through "web""#,
+                "line 2 can't be drawn, the segment from point 0 has no length"
+            ]
+        );
+        assert_diagram_eq!(
+            rendered.rows,
+            br#"                      
+   +-----+            
+   |     |            
+   | web |            
+   |     |            
+   +--+--+            
+ -----+-------------- 
+      |      +----+   
+      |      |    |   
+      +------+ db |   
+             |    |   
+             +----+   
+                      "#
+        );
+    }
+
+    #[test]
+    fn test_toml_nesting_limit() {
+        let too_deep =
+            Error::Parse("arrays and tables are nested more than 128 levels deep".into());
+        for diagram in [
+            format!("a = {}", "[".repeat(40_000)),
+            format!("a = {}", "{a = ".repeat(40_000)),
+        ] {
+            let rendered = render_spec_str(&diagram, Format::Toml, &Overrides::default());
+            assert_eq!(rendered.err(), Some(too_deep.clone()));
+        }
+
+        let nested = format!("a = {}1{}\nblocks = []", "[".repeat(100), "]".repeat(100));
+        assert!(render_spec_str(&nested, Format::Toml, &Overrides::default()).is_ok());
+    }
+
+    #[test]
+    fn test_compact_margins() {
+        let diagram = r#"
+horizontal_margin = 8
+vertical_margin = 4
+edges = [{from = "a", to = "b"}]
+
+[[blocks]]
+text = "a"
+position = { row = 0, column = 0 }
+
+[[blocks]]
+text = "b"
+position = { row = 1, column = 1 }
+"#;
+        let mut opts = Overrides {
+            seed: Some(0),
+            max_tweaks: 0,
+            ..Overrides::default()
+        };
+
+        let loose = render_spec_str(diagram, Format::Toml, &opts).unwrap();
+        opts.compact_margins = true;
+        let compacted = render_spec_str(diagram, Format::Toml, &opts).unwrap();
+
+        // the layout describes what is drawn
+        let layout = compacted.layout.unwrap();
+        assert_eq!(layout.width, compacted.rows[0].len());
+        assert_eq!(layout.height, compacted.rows.len());
+        assert!(layout.width < loose.rows[0].len());
+        assert!(layout.height < loose.rows.len());
+        assert_eq!(
+            layout.hit_test(layout.blocks[1].x, layout.blocks[1].y),
+            Some(Element::Block(1))
+        );
+        assert_eq!(compacted.score, loose.score);
+    }
+
+    #[test]
+    fn test_block_z() {
+        let diagram = r#"
+edges = []
+
+[[blocks]]
+text = "storage"
+position = { row = 0, column = 0 }
+height = 5
+
+[[blocks]]
+text = "v2"
+position = { row = 0, column = 0 }
+z = 1
+"#;
+        let rendered = render_spec_str(diagram, Format::Toml, &Overrides::default()).unwrap();
+        assert!(rendered.warnings.is_empty());
+        assert!(rendered.to_string().contains("v2"));
+
+        let same_z = diagram.replace("z = 1", "");
+        let rendered = render_spec_str(&same_z, Format::Toml, &Overrides::default()).unwrap();
+        assert_eq!(
+            rendered.warnings,
+            vec!["more than one cell present at row 0 and column 0"]
+        );
+    }
+
+    #[test]
+    fn test_headers() {
+        let diagram = r#"
+column_labels = ["frontend", "backend", "data"]
+row_labels = ["public"]
+edges = [{from = "web", to = "api"}]
+
+[[blocks]]
+text = "web"
+position = { row = 0, column = 0 }
+
+[[blocks]]
+text = "api"
+position = { row = 0, column = 1 }
+"#;
+        let opts = Overrides {
+            seed: Some(0),
+            max_tweaks: 0,
+            ..Overrides::default()
+        };
+
+        let rendered = render_spec_str(diagram, Format::Toml, &opts).unwrap();
+        assert_eq!(
+            rendered.warnings,
+            vec![r#"column label "data" is ignored, column 2 has no blocks"#]
+        );
+        assert_diagram_eq!(
+            rendered.rows,
+            br#"        |    frontend  |  backend     
+--------+--------------+--------------
+        |                             
+        |                             
+        |                             
+        |     +-----+     +-----+     
+        |     |     |     |     |     
+ public |     | web +-----+ api |     
+        |     |     |     |     |     
+        |     +-----+     +-----+     
+        |                             
+        |                             
+        |                             "#
+        );
+
+        let layout = rendered.layout.unwrap();
+        let b = &layout.blocks[1];
+        assert_eq!(rendered.rows[b.y][b.x], b'+');
+        assert_eq!(layout.hit_test(b.x, b.y), Some(Element::Block(1)));
+    }
+
+    #[test]
+    fn test_headers_negative_coordinates() {
+        let diagram = r#"
+column_labels = ["left", "middle", "right"]
+blocks = [
+    { text = "a", position = { row = -2, column = -1 } },
+    { text = "b", position = { row = -2, column = 1 } },
+]
+"#;
+        let rendered = render_spec_str(diagram, Format::Toml, &Overrides::default()).unwrap();
+        assert_eq!(
+            rendered.warnings,
+            vec![r#"column label "middle" is ignored, column 0 has no blocks"#]
+        );
+        assert_eq!(rendered.rows[0], b"     left      |    right     ".to_vec());
+    }
+
+    #[test]
+    fn test_matrix() {
+        let diagram = r#"
+horizontal_margin = 2
+vertical_margin = 1
+
+[matrix]
+columns = ["api", "db"]
+rows = ["api", "db"]
+cells = [["", "r/w"], ["r", ""]]
+heat = [[0, 10], [5, 1]]
+"#;
+        let rendered = render_spec_str(diagram, Format::Toml, &Overrides::default()).unwrap();
+        assert!(rendered.warnings.is_empty());
+        assert_diagram_eq!(
+            rendered.rows,
+            br#"     |   api  |  db     
+-----+--------+---------
+     |                  
+     |  +---+  +-----+  
+     |  |   |  |@@@@@|  
+ api |  |   |  |@r/w@|  
+     |  |   |  |@@@@@|  
+     |  +---+  +-----+  
+-----+                  
+     |  +---+  +-----+  
+     |  |+++|  |.....|  
+ db  |  |+r+|  |.....|  
+     |  |+++|  |.....|  
+     |  +---+  +-----+  
+     |                  "#
+        );
+
+        // emphasis takes precedence over heat
+        let emphasized = diagram.replace("heat", "emphasis = [[\"\", \"!\"], [\"\", \"\"]]\nheat");
+        let rendered = render_spec_str(&emphasized, Format::Toml, &Overrides::default()).unwrap();
+        assert!(rendered.to_string().contains("|!r/w!|"));
+
+        let ragged = diagram.replace(r#"["r", ""]]"#, r#"["r"]]"#);
+        assert_eq!(
+            render_spec_str(&ragged, Format::Toml, &Overrides::default()),
+            Err(Error::Invalid(
+                "the matrix has 2 columns but row 1 of cells has 1".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_bar_blocks() {
+        let diagram = r#"
+edges = [{from = "api", to = "load"}]
+
+[[blocks]]
+text = "api"
+position = { row = 0, column = 0 }
+
+[[blocks]]
+id = "load"
+text = "load"
+kind = "bar"
+bars = [{label = "cpu", value = 75}, {label = "memory", value = 30}, {label = "disk", value = 0.5}]
+position = { row = 0, column = 1 }
+width = 28
+"#;
+        let opts = Overrides {
+            seed: Some(0),
+            max_tweaks: 0,
+            ..Overrides::default()
+        };
+
+        let rendered = render_spec_str(diagram, Format::Toml, &opts).unwrap();
+        assert!(rendered.warnings.is_empty());
+        assert_diagram_eq!(
+            rendered.rows,
+            br#"                                                  
+                                                  
+                                                  
+     +-----+     +--------------------------+     
+     |     |     |                          |     
+     |     |     | load                     |     
+     | api |     | cpu    #############  75 |     
+     |     +-----+ memory #####          30 |     
+     |     |     | disk                 0.5 |     
+     |     |     |                          |     
+     +-----+     +--------------------------+     
+                                                  
+                                                  
+                                                  "#
+        );
+
+        assert_eq!(
+            bar_chart(&[("a".to_string(), 2.0), ("b".to_string(), -1.0)], None),
+            format!("a {}  2\nb {} -1", "#".repeat(20), " ".repeat(20))
+        );
+    }
+
+    #[test]
+    fn test_sparkline() {
+        let diagram = r#"
+edges = []
+horizontal_margin = 1
+vertical_margin = 0
+padding = 0
+
+[[blocks]]
+text = "load"
+sparkline = [1, 4, 2, 8]
+position = { row = 0, column = 0 }
+"#;
+        let rendered = render_spec_str(diagram, Format::Toml, &Overrides::default()).unwrap();
+        assert!(rendered.warnings.is_empty());
+        assert_diagram_eq!(
+            rendered.rows,
+            br#" +----+ 
+ |load| 
+ |_-.'| 
+ +----+ "#
+        );
+    }
+
+    #[test]
+    fn test_cell_aspect() {
+        let render = |settings: &str| {
+            let diagram = format!(
+                "{}\nedges = []\nblocks = [{{text = \"a\", position = {{row = 0, column = 0}}}}]",
+                settings
+            );
+            render_spec_str(&diagram, Format::Toml, &Overrides::default()).map(|r| r.config)
+        };
+
+        // the defaults already look square with the usual aspect ratio
+        let config = render("").unwrap();
+        assert_eq!((config.hmargin, config.vmargin), (5, 3));
+        let config = render("cell_aspect = 2.0").unwrap();
+        assert_eq!((config.hmargin, config.vmargin), (5, 3));
+
+        let config = render("horizontal_margin = 8\ncell_aspect = 2.0").unwrap();
+        assert_eq!((config.hmargin, config.vmargin), (8, 4));
+        let config = render("horizontal_margin = 8\ncell_aspect = 2.5").unwrap();
+        assert_eq!(config.vmargin, 3);
+
+        // explicit margins win
+        let config = render("vertical_margin = 1\ncell_aspect = 2.0").unwrap();
+        assert_eq!(config.vmargin, 1);
+
+        assert_eq!(
+            render("cell_aspect = 0.0"),
+            Err(Error::Invalid(
+                "cell_aspect must be a positive number, not 0".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_guides() {
+        let diagram = r#"
+edges = [{from = "a", to = "b"}]
+
+[[blocks]]
+text = "a"
+position = { row = 0, column = 0 }
+
+[[blocks]]
+text = "b"
+position = { row = 1, column = 1 }
+"#;
+        let mut opts = Overrides {
+            seed: Some(0),
+            max_tweaks: 0,
+            ..Overrides::default()
+        };
+
+        let plain = render_spec_str(diagram, Format::Toml, &opts).unwrap();
+        opts.guides = true;
+        let guided = render_spec_str(diagram, Format::Toml, &opts).unwrap();
+
+        // guides are easily stripped and the diagram stays the same otherwise
+        let marks = guided.to_string().matches('.').count();
+        assert_eq!(marks, 9);
+        assert_eq!(guided.to_string().replace('.', " "), plain.to_string());
+        assert_eq!(guided.layout, plain.layout);
+    }
+
+    #[test]
+    fn test_frames() {
+        let diagram = r#"
+horizontal_margin = 4
+vertical_margin = 1
+padding = 0
+
+[[blocks]]
+text = "a"
+position = { row = 0, column = 0 }
+
+[[blocks]]
+text = "b"
+position = { row = 0, column = 1 }
+step = 2
+
+[[blocks]]
+text = "c"
+position = { row = 1, column = 1 }
+step = 1
+
+[[edges]]
+from = "a"
+to = "b"
+
+[[edges]]
+from = "a"
+to = "c"
+step = 3
+"#;
+        let mut opts = Overrides {
+            seed: Some(0),
+            max_tweaks: 0,
+            ..Overrides::default()
+        };
+
+        let plain = render_spec_str(diagram, Format::Toml, &opts).unwrap();
+        opts.frames = true;
+        let rendered = render_spec_str(diagram, Format::Toml, &opts).unwrap();
+
+        // every frame has the layout of the whole diagram and the last one is the whole diagram
+        let frames = rendered
+            .rows
+            .split(|r| r == FRAME_SEPARATOR)
+            .collect::<Vec<_>>();
+        assert_eq!(frames.len(), 4);
+        assert_eq!(frames[3], &plain.rows[..]);
+        assert_diagram_eq!(
+            frames[1],
+            br#"                  
+    +-+           
+    |a|           
+    +-+           
+                  
+           +-+    
+           |c|    
+           +-+    
+                  "#
+        );
+        assert_diagram_eq!(
+            frames[2],
+            br#"                  
+    +-+    +-+    
+    |a+----+b|    
+    +-+    +-+    
+                  
+           +-+    
+           |c|    
+           +-+    
+                  "#
+        );
+
+        // without steps blocks appear in the order they're declared
+        let diagram = diagram.replace("step = ", "# step = ");
+        let rendered = render_spec_str(&diagram, Format::Toml, &opts).unwrap();
+        assert_eq!(rendered.rows.split(|r| r == FRAME_SEPARATOR).count(), 3);
+    }
+
+    #[test]
+    fn test_pinned_rects_and_routes() {
+        let diagram = r#"
+horizontal_margin = 4
+vertical_margin = 2
+padding = 0
+
+[[blocks]]
+text = "a"
+position = { row = 0, column = 0 }
+
+[[blocks]]
+text = "b"
+position = { row = 0, column = 1 }
+rect = { x = 13, y = 2, width = 3, height = 3 }
+
+[[edges]]
+from = "a"
+to = "b"
+route = [{ x = 5, y = 2 }, { x = 5, y = 0 }, { x = 14, y = 0 }, { x = 14, y = 2 }]
+"#;
+        let opts = Overrides {
+            seed: Some(0),
+            max_tweaks: 0,
+            ..Overrides::default()
+        };
+
+        let rendered = render_spec_str(diagram, Format::Toml, &opts).unwrap();
+        assert!(rendered.warnings.is_empty());
+        assert_diagram_eq!(
+            rendered.rows,
+            br#"     +--------+     
+     |        |     
+    +++      +++    
+    |a|      |b|    
+    +-+      +-+    
+                    
+                    "#
+        );
+
+        // the rect of b can't be kept once a grows and the route doesn't reach b anymore
+        let grown = diagram.replace(r#"text = "a""#, "id = \"a\"\ntext = \"a wider block\"");
+        let rendered = render_spec_str(&grown, Format::Toml, &opts).unwrap();
+        assert_eq!(
+            rendered.warnings,
+            vec![
+                r#"block "b" can't be kept at its rect, it's drawn at x = 23, y = 2, width = 3, height = 3"#,
+                r#"the route of the edge from "a" to "b" doesn't fit the diagram anymore, it was routed again"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_route_failure() {
+        let mut diagram = String::from(
+            "horizontal_margin = 0\nvertical_margin = 0\npadding = 0\nedges = [{from = \"4\", to = \"0\"}]\n",
+        );
+        for i in 0..9 {
+            diagram.push_str(&format!(
+                "\n[[blocks]]\ntext = \"{}\"\nposition = {{ row = {}, column = {} }}\n",
+                i,
+                i / 3,
+                i % 3
+            ));
+        }
+        let opts = Overrides {
+            seed: Some(0),
+            max_tweaks: 0,
+            ..Overrides::default()
+        };
+
+        assert_eq!(
+            render_spec_str(&diagram, Format::Toml, &opts).map(|r| r.rows),
+            Err(Error::Invalid(
+                r#"the edge from "4" to "0" can't be routed without running over a block, set route_failure to "skip" or "force" to render the diagram anyway"#
+                    .to_string()
+            ))
+        );
+
+        let skip = format!("route_failure = \"skip\"\n{}", diagram);
+        let rendered = render_spec_str(&skip, Format::Toml, &opts).unwrap();
+        assert_eq!(
+            rendered.warnings,
+            vec![r#"the edge from "4" to "0" can't be routed, it was left out"#]
+        );
+
+        let force = format!("route_failure = \"force\"\n{}", diagram);
+        let rendered = render_spec_str(&force, Format::Toml, &opts).unwrap();
+        assert!(rendered.to_string().contains('!'));
+        assert_eq!(rendered.warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_compact() {
+        let diagram = r#"
+compact = true
+horizontal_margin = 4
+padding = 0
+edges = [{from = "q", to = "w"}]
+
+[[blocks]]
+text = "q"
+position = { row = 0, column = 0 }
+
+[[blocks]]
+text = "w"
+position = { row = 0, column = 1 }
+
+[[blocks]]
+text = "e"
+position = { row = 0, column = 2 }
+
+[[blocks]]
+text = "shift"
+position = { row = 1, column = 0 }
+
+[[blocks]]
+text = "space"
+position = { row = 1, column = 1 }
+"#;
+        let opts = Overrides {
+            seed: Some(0),
+            max_tweaks: 0,
+            ..Overrides::default()
+        };
+
+        let rendered = render_spec_str(diagram, Format::Toml, &opts).unwrap();
+        assert!(rendered.warnings.is_empty());
+        assert_diagram_eq!(
+            rendered.rows,
+            br#"+-----+-----+-+
+|  q  +  w  |e|
++-----+-----+-+
+|shift|space|  
++-----+-----+  "#
+        );
+
+        let opts = Overrides {
+            dump_resolved: true,
+            ..opts
+        };
+        let dump = render_to_string(diagram, Format::Toml, &opts).unwrap();
+        let dump: serde_json::Value = serde_json::from_str(&dump).unwrap();
+        assert_eq!(dump["compact"], true);
+        assert_eq!(dump["horizontal_margin"], 0);
+    }
+
+    #[test]
+    fn test_memmap() {
+        let diagram = r#"
+kind = "memmap"
+padding = 0
+ranges = [
+    { name = "kernel", start = 0x1000, end = 0x8000 },
+    { name = "boot", start = 0, end = 0x1000 },
+    { name = "heap", start = 0x10000, end = 0x20000 },
+]
+"#;
+        let rendered = render_spec_str(diagram, Format::Toml, &Overrides::default()).unwrap();
+        assert!(rendered.warnings.is_empty());
+        assert_diagram_eq!(
+            rendered.rows,
+            br#"0x00000 +------+
+        | boot |
+0x01000 +------+
+        |kernel|
+0x08000 +------+
+        |......|
+0x10000 +------+
+        | heap |
+0x20000 +------+"#
+        );
+
+        let overlapping = diagram.replace("end = 0x1000", "end = 0x2000");
+        assert_eq!(
+            render_spec_str(&overlapping, Format::Toml, &Overrides::default()).map(|r| r.rows),
+            Err(Error::Invalid(
+                r#"ranges "boot" and "kernel" overlap"#.to_string()
+            ))
+        );
+
+        let graph = diagram.replace(r#"kind = "memmap""#, "");
+        let rendered = render_spec_str(&graph, Format::Toml, &Overrides::default()).unwrap();
+        assert_eq!(
+            rendered.warnings,
+            vec![r#"ranges are ignored, the kind of the diagram is not "memmap""#]
+        );
+    }
+
+    #[test]
+    fn test_packet() {
+        let diagram = r#"
+kind = "packet"
+bits_per_row = 16
+fields = [
+    { name = "Version", bits = 4 },
+    { name = "IHL", bits = 4 },
+    { name = "Type of Service", bits = 8 },
+    { name = "Total Length", bits = 16 },
+]
+"#;
+        let rendered = render_spec_str(diagram, Format::Toml, &Overrides::default()).unwrap();
+        assert!(rendered.warnings.is_empty());
+        assert_eq!(rendered.layout, None);
+        assert_diagram_eq!(
+            rendered.rows,
+            br#" 0                   1           
+ 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 
++-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+|Version|  IHL  |Type of Service|
++-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+|         Total Length          |
++-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+"#
+        );
+
+        let zero = diagram.replace("bits_per_row = 16", "bits_per_row = 0");
+        assert_eq!(
+            render_spec_str(&zero, Format::Toml, &Overrides::default()).map(|r| r.rows),
+            Err(Error::Invalid(
+                "bits_per_row must be a positive number".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        let opts = Overrides {
+            limits: Limits {
+                max_blocks: 2,
+                max_edges: 1,
+                max_text_length: 8,
+                max_canvas_cells: 10_000,
+                max_tweak_time: None,
+            },
+            ..Overrides::default()
+        };
+        let render =
+            |diagram: &str| render_spec_str(diagram, Format::Toml, &opts).map(|r| r.warnings);
+
+        let diagram = r#"
+blocks = [ { text = "a", position = { row = 0, column = 0 } }
+         , { text = "b", position = { row = 0, column = 1 } } ]
+edges = [ { from = "a", to = "b" } ]
+"#;
+        assert_eq!(render(diagram), Ok(vec![]));
+
+        let blocks = diagram.replace(
+            "]\nedges",
+            r#", { text = "c", position = { row = 1, column = 0 } } ]
+edges"#,
+        );
+        assert_eq!(render(&blocks), Err(Error::Limit(Limit::Blocks(2))));
+
+        let edges = diagram.replace(r#""b" }"#, r#""b" }, { from = "b", to = "a" }"#);
+        assert_eq!(render(&edges), Err(Error::Limit(Limit::Edges(1))));
+
+        // tabs count as the columns they're expanded to
+        let text = diagram.replace(r#""a""#, r#""a\t\tb""#);
+        assert_eq!(render(&text), Err(Error::Limit(Limit::TextLength(8))));
+
+        // nodes are checked before adding their blocks
+        let nodes = r#"
+kind = "tree"
+nodes = [ { text = "a" }, { text = "b", parent = "a" }, { text = "c", parent = "b" } ]
+"#;
+        assert_eq!(render(nodes), Err(Error::Limit(Limit::Blocks(2))));
+
+        // neither huge sizes nor blocks far apart are allocated
+        let cells = Err(Error::Limit(Limit::CanvasCells(10_000)));
+        let width = diagram.replace(r#"text = "a","#, r#"text = "a", width = 1000000000,"#);
+        assert_eq!(render(&width), cells);
+        let far = diagram.replace("column = 1", "column = 1000000000");
+        assert_eq!(render(&far), cells);
+        let margin = format!("horizontal_margin = 1000000000\n{}", diagram);
+        assert_eq!(render(&margin), cells);
+        let packet = r#"
+kind = "packet"
+bits_per_row = 16
+fields = [ { name = "payload", bits = 1000000000 } ]
+"#;
+        assert_eq!(render(packet), cells);
+        let wide = diagram.replace(r#"text = "a","#, r#"text = "a", width = 5000,"#);
+        assert_eq!(render(&wide), cells);
+
+        assert_eq!(
+            Error::Limit(Limit::CanvasCells(10_000)).to_string(),
+            "the diagram would be drawn on more than 10000 characters"
+        );
+    }
+
+    #[test]
+    fn test_tree() {
+        let diagram = r#"
+kind = "tree"
+vertical_margin = 3
+nodes = [ { text = "ceo" }
+        , { text = "cto", parent = "ceo" }
+        , { text = "cfo", parent = "ceo" }
+        , { text = "dev", parent = "cto" }
+        , { text = "ops", parent = "cto" }
+        ]
+"#;
+        let opts = Overrides {
+            seed: Some(0),
+            max_tweaks: 0,
+            ..Overrides::default()
+        };
+
+        let rendered = render_spec_str(diagram, Format::Toml, &opts).unwrap();
+        assert!(rendered.warnings.is_empty());
+        assert_diagram_eq!(
+            rendered.rows,
+            br#"                                         
+                                         
+                                         
+     +-----+                             
+     |     |                             
+     | ceo |                             
+     |     |                             
+     +--+--+                             
+        |                                
+        *-----------------------+        
+        |                       |        
+     +--+--+                 +--+--+     
+     |     |                 |     |     
+     | cto |                 | cfo |     
+     |     |                 |     |     
+     +--+--+                 +-----+     
+        |                                
+        *-----------+                    
+        |           |                    
+     +--+--+     +--+--+                 
+     |     |     |     |                 
+     | dev |     | ops |                 
+     |     |     |     |                 
+     +-----+     +-----+                 
+                                         
+                                         
+                                         "#
+        );
+
+        let straight = format!("connector = \"straight\"{}", diagram);
+        let rendered = render_spec_str(&straight, Format::Toml, &opts).unwrap();
+        assert!(!rendered.to_string().contains('*'));
+
+        let horizontal = format!(
+            "orientation = \"horizontal\"\nsibling_order = \"text\"{}",
+            diagram
+        );
+        let rendered = render_spec_str(&horizontal, Format::Toml, &opts).unwrap();
+        assert_eq!(rendered.ids, vec!["ceo", "cto", "cfo", "dev", "ops"]);
+        let rect = |i: usize| rendered.layout.as_ref().unwrap().blocks[i];
+        assert_eq!(rect(0).y, rect(2).y);
+        assert!(rect(2).y < rect(1).y && rect(0).x < rect(1).x);
+
+        let err = |nodes: &str| {
+            let diagram = format!("kind = \"tree\"\nnodes = {}", nodes);
+            match render_spec_str(&diagram, Format::Toml, &opts) {
+                Err(Error::Invalid(e)) => e,
+                _ => panic!("the tree is invalid"),
+            }
+        };
+        assert_eq!(
+            err(r#"[{ text = "a", parent = "b" }]"#),
+            r#"the parent "b" of node "a" doesn't exist"#
+        );
+        assert_eq!(
+            err(r#"[{ text = "a", parent = "b" }, { text = "b", parent = "a" }]"#),
+            r#"node "a" is its own ancestor"#
+        );
+    }
+
+    #[test]
+    fn test_layered() {
+        let diagram = r#"
+kind = "layered"
+directed = true
+nodes = [ { text = "parse" }
+        , { text = "check", parent = "parse" }
+        , { text = "emit", parent = "check" }
+        , { text = "report", parent = "check" }
+        ]
+edges = [ { from = "emit", to = "parse" } ]
+"#;
+        let opts = Overrides {
+            seed: Some(0),
+            max_tweaks: 0,
+            ..Overrides::default()
+        };
+
+        let rendered = render_spec_str(diagram, Format::Toml, &opts).unwrap();
+        assert_eq!(
+            rendered.warnings,
+            vec![r#"feedback edges drawn around the diagram to break cycles: "emit" -> "parse""#]
+        );
+        assert_diagram_eq!(
+            rendered.rows,
+            br#"                                  
+            +-----------------+   
+            v                 |   
+     +------++                |   
+     |       |                |   
+     | parse |                |   
+     |       |                |   
+     +---+---+                |   
+         |                    |   
+         |                    |   
+         v                    |   
+     +---+---+                |   
+     |       |                |   
+     | check +----------+     |   
+     |       |          |     |   
+     +---+---+          |     |   
+         |              |     |   
+         |              |     |   
+         v              v     |   
+     +---+---+     +----+---+ |   
+     |       |     |        | |   
+     | emit  |     | report | |   
+     |       |     |        | |   
+     +------++     +--------+ |   
+            |                 |   
+            +-----------------+   
+                                  "#
+        );
+
+        let acyclic = diagram.replace(r#"edges = [ { from = "emit", to = "parse" } ]"#, "");
+        let rendered = render_spec_str(&acyclic, Format::Toml, &opts).unwrap();
+        assert!(rendered.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_external() {
+        let diagram = r#"
+horizontal_margin = 3
+vertical_margin = 1
+edges = [{from = "user", to = "shop"}, {from = "shop", to = "bank"}]
+
+[[blocks]]
+text = "user"
+position = { row = 0, column = 0 }
+external = true
+
+[[blocks]]
+text = "shop"
+position = { row = 0, column = 1 }
+
+[[blocks]]
+text = "bank"
+position = { row = 0, column = 2 }
+shape = "external"
+"#;
+        let opts = Overrides {
+            seed: Some(0),
+            max_tweaks: 0,
+            ..Overrides::default()
+        };
+
+        let rendered = render_spec_str(diagram, Format::Toml, &opts).unwrap();
+        assert!(rendered.warnings.is_empty());
+        assert_diagram_eq!(
+            rendered.rows,
+            br#"                                    
+   +------+   +------+   +------+   
+   [      ]   |      |   [      ]   
+   [ user +---+ shop +---+ bank ]   
+   [      ]   |      |   [      ]   
+   +------+   +------+   +------+   
+                                    "#
+        );
+
+        let inner = r#"
+blocks = [ { text = "user", position = { row = 1, column = 1 }, external = true }
+         , { text = "a", position = { row = 0, column = 0 } }
+         , { text = "b", position = { row = 2, column = 2 } }
+         ]
+"#;
+        let rendered = render_spec_str(inner, Format::Toml, &opts).unwrap();
+        assert_eq!(
+            rendered.warnings,
+            vec![r#"external block "user" is not on the periphery of the diagram"#]
+        );
+    }
+
+    #[test]
+    fn test_c4() {
+        let diagram = r#"
+kind = "c4"
+persons = [{ name = "Customer", description = "Buys things" }]
+systems = [{ name = "Shop" }, { name = "Bank", external = true }]
+relationships = [ { from = "Customer", to = "Shop", description = "Orders", technology = "HTTPS" }
+                , { from = "Shop", to = "Bank", description = "Charges" }
+                ]
+"#;
+        let opts = Overrides {
+            seed: Some(0),
+            max_tweaks: 0,
+            ..Overrides::default()
+        };
+
+        let rendered = render_spec_str(diagram, Format::Toml, &opts).unwrap();
+        assert!(rendered.warnings.is_empty());
+        assert_diagram_eq!(
+            rendered.rows,
+            br#"                               
+                               
+                               
+     +-------------------+     
+     [                   ]     
+     [    Customer       ]     
+     [    [Person]       ]     
+     [                   ]     
+     [    Buys things    ]     
+     [                   ]     
+     +---------+---------+     
+               |Orders [HTTPS] 
+               |               
+               v               
+     +---------+---------+     
+     |                   |     
+     | Shop              |     
+     | [Software System] |     
+     |                   |     
+     +---------+---------+     
+               |Charges        
+               |               
+               v               
+     +---------+---------+     
+     [                   ]     
+     [ Bank              ]     
+     [ [Software System] ]     
+     [                   ]     
+     +-------------------+     
+                               
+                               
+                               
+[    ] person or external software system
+-->    relationship, labelled with its description [technology]"#
+        );
+
+        let graph = diagram.replace(r#"kind = "c4""#, "");
+        let rendered = render_spec_str(&graph, Format::Toml, &opts).unwrap();
+        assert_eq!(
+            rendered.warnings,
+            vec![
+                r#"persons, systems, containers and relationships are ignored, the kind of the diagram is not "c4""#
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_all() {
+        let diagram = r#"
+title = "Shop"
+horizontal_margin = 3
+blocks = [ { text = "user", position = { row = 0, column = 0 } }
+         , { text = "shop", position = { row = 0, column = 1 }, details = "containers" }
+         , { text = "bank", position = { row = 0, column = 2 }, details = "bank" }
+         ]
+
+[diagrams.containers]
+title = "Shop containers"
+horizontal_margin = 5
+blocks = [ { text = "api", position = { row = 0, column = 0 }, details = "api" } ]
+
+[diagrams.api]
+blocks = [ { text = "orders", position = { row = 0, column = 0 } } ]
+
+[diagrams.orphan]
+blocks = [ { text = "x", position = { row = 0, column = 0 } } ]
+"#;
+        let opts = Overrides {
+            seed: Some(0),
+            ..Overrides::default()
+        };
+
+        let all = render_spec_all(diagram, Format::Toml, &opts).unwrap();
+        let names = all.iter().map(|d| d.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["", "containers", "api", "orphan"]);
+        assert_eq!(all[2].breadcrumbs, vec!["Shop", "Shop containers", "api"]);
+        assert_eq!(all[3].breadcrumbs, vec!["orphan"]);
+
+        assert_eq!(
+            all[0].rendered.warnings,
+            vec![r#"block "bank" is detailed in unknown diagram "bank""#]
+        );
+        assert_eq!(
+            all[3].rendered.warnings,
+            vec![r#"diagram "orphan" doesn't detail any block"#]
+        );
+        assert!(all[0].rendered.to_string().ends_with(
+            r#""shop" is detailed in "containers"
+"bank" is detailed in "bank""#
+        ));
+
+        // the margins of the top diagram are used unless they're set
+        let margins = all
+            .iter()
+            .map(|d| d.rendered.config.hmargin)
+            .collect::<Vec<_>>();
+        assert_eq!(margins, vec![3, 5, 3, 3]);
+
+        let rendered = render_spec_str(diagram, Format::Toml, &opts).unwrap();
+        assert_eq!(
+            rendered.warnings,
+            vec!["diagrams are ignored when rendering a single diagram"]
+        );
+    }
+
+    #[test]
+    fn test_calendar() {
+        let diagram = r#"
+kind = "calendar"
+month = "2024-02"
+padding = 0
+events = { "2024-02-14" = "demo", "2024-03-01" = "retro" }
+"#;
+        let rendered = render_spec_str(diagram, Format::Toml, &Overrides::default()).unwrap();
+        assert_eq!(
+            rendered.warnings,
+            vec!["the event on 2024-03-01 is ignored, the day is not in the calendar"]
+        );
+        assert_diagram_eq!(
+            rendered.rows,
+            br#" Mon  Tue  Wed  Thu  Fri  Sat  Sun  
+------------------------------------
+               +----+----+----+----+
+               |1   |2   |3   |4   |
+               |    |    |    |    |
++----+----+----+----+----+----+----+
+|5   |6   |7   |8   |9   |10  |11  |
+|    |    |    |    |    |    |    |
++----+----+----+----+----+----+----+
+|12  |13  |14  |15  |16  |17  |18  |
+|    |    |demo|    |    |    |    |
++----+----+----+----+----+----+----+
+|19  |20  |21  |22  |23  |24  |25  |
+|    |    |    |    |    |    |    |
++----+----+----+----+----+----+----+
+|26  |27  |28  |29  |               
+|    |    |    |    |               
++----+----+----+----+               "#
+        );
+
+        let week = diagram.replace(r#"month = "2024-02""#, r#"week = "2024-02-29""#);
+        let rendered = render_spec_str(&week, Format::Toml, &Overrides::default()).unwrap();
+        assert_eq!(
+            rendered.warnings,
+            vec!["the event on 2024-02-14 is ignored, the day is not in the calendar"]
+        );
+        assert_eq!(rendered.ids[0], "2024-02-26");
+        assert_eq!(rendered.ids[6], "2024-03-03");
+
+        let invalid = diagram.replace("2024-02-14", "2023-02-29");
+        assert_eq!(
+            render_spec_str(&invalid, Format::Toml, &Overrides::default()).map(|r| r.rows),
+            Err(Error::Invalid(
+                r#"invalid date "2023-02-29", it must be YYYY-MM-DD"#.to_string()
+            ))
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+
+use crate::render::{render_layout, RenderOptions};
+use crate::spec::layered::loop_route;
+use crate::spec::tree::elbow_route;
+use crate::spec::{Spec, SpecBlock, SpecNode, SpecOrientation, SpecPosition};
+use crate::{Block, Edge, LogicalCoord};
+
+/// The ids of the given nodes, which must be unique, alongside the index of each id.
+pub fn node_ids(nodes: &[SpecNode]) -> Result<(Vec<String>, HashMap<String, usize>), String> {
+    let ids = nodes
+        .iter()
+        .map(|n| n.id.as_ref().unwrap_or(&n.text).clone())
+        .collect::<Vec<_>>();
+
+    let mut index = HashMap::with_capacity(nodes.len());
+    for (i, id) in ids.iter().enumerate() {
+        if index.insert(id.clone(), i).is_some() {
+            return Err(format!(r#"duplicate node "{}""#, id));
+        }
+    }
+    Ok((ids, index))
+}
+
+/// Add a block for each node at the given (depth, slot), below the blocks already in the spec.
+/// The depth is the row and the slot the column, or viceversa with `SpecOrientation::Horizontal`.
+pub fn push_nodes(
+    spec: &mut Spec,
+    nodes: Vec<SpecNode>,
+    ids: &[String],
+    placed: impl Iterator<Item = (usize, usize)>,
+) -> Result<(), String> {
+    let first_row = spec
+        .blocks
+        .iter()
+        .map(|b| b.position.row + 1)
+        .max()
+        .unwrap_or(0);
+    for ((n, id), (depth, slot)) in nodes.into_iter().zip(ids).zip(placed) {
+        let depth = LogicalCoord::try_from(depth).map_err(|e| e.to_string())?;
+        let slot = LogicalCoord::try_from(slot).map_err(|e| e.to_string())?;
+        let (row, column) = match spec.orientation {
+            SpecOrientation::Vertical => (depth, slot),
+            SpecOrientation::Horizontal => (slot, depth),
+        };
+        spec.blocks.push(SpecBlock {
+            id: Some(id.clone()),
+            text: n.text,
+            position: SpecPosition {
+                row: first_row + row,
+                column,
+            },
+            external: n.external,
+            ..SpecBlock::default()
+        });
+    }
+    Ok(())
+}
+
+/// The edges of a tree or layered diagram routed from where their blocks are, each from the id
+/// of the block it starts at to the id of the one it ends at.
+#[derive(Default)]
+pub struct ComputedRoutes {
+    pub orientation: SpecOrientation,
+
+    // see `elbow_route`
+    pub elbows: Vec<(String, String)>,
+
+    // see `loop_route`
+    pub loops: Vec<(String, String)>,
+}
+
+/// Give the edges in `routes` without a route of their own the one computed from where their
+/// blocks are, see `elbow_route` and `loop_route`.
+pub fn add_computed_routes(
+    routes: ComputedRoutes,
+    blocks: &[Block],
+    ids: &[&String],
+    edges: &mut BTreeMap<(usize, usize), Edge>,
+    config: &RenderOptions,
+) {
+    let ComputedRoutes {
+        orientation,
+        elbows,
+        loops,
+    } = routes;
+    if elbows.is_empty() && loops.is_empty() {
+        return;
+    }
+
+    let layout = render_layout(blocks, Vec::<Edge>::new(), config.clone());
+    let index = ids
+        .iter()
+        .enumerate()
+        .map(|(i, id)| (id.as_str(), i))
+        .collect::<HashMap<_, _>>();
+    let key =
+        |from: &String, to: &String| Some((*index.get(from.as_str())?, *index.get(to.as_str())?));
+
+    let routes = elbows
+        .iter()
+        .filter_map(|(from, to)| {
+            let (f, t) = key(from, to)?;
+            let route = elbow_route(&layout.blocks[f], &layout.blocks[t], orientation);
+            Some(((f, t), route))
+        })
+        .chain(loops.iter().enumerate().filter_map(|(lane, (from, to))| {
+            let (f, t) = key(from, to)?;
+            let size = (layout.width, layout.height);
+            let (a, b) = (&layout.blocks[f], &layout.blocks[t]);
+            let route = loop_route(a, b, &layout.blocks, lane, orientation, size)?;
+            Some(((f, t), route))
+        }))
+        .collect::<Vec<_>>();
+    for (key, route) in routes {
+        if let Some(edge) = edges.get_mut(&key).filter(|e| e.route().is_empty()) {
+            *edge = edge.clone().with_route(route);
+        }
+    }
+}
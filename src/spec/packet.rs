@@ -0,0 +1,32 @@
+use crate::render::{packet, RenderOptions};
+use crate::spec::{Error, RenderedDiagram};
+
+/// Draw the fields of a packet diagram, `bits_per_row` bits per row, see `packet`. Packets are
+/// drawn on their own, the blocks and edges of the diagram are ignored if it has any.
+pub fn render_packet(
+    fields: &[(String, usize)],
+    bits_per_row: usize,
+    has_elements: bool,
+    mut warnings: Vec<String>,
+    config: RenderOptions,
+) -> Result<RenderedDiagram, Error> {
+    if bits_per_row == 0 {
+        return Err(Error::Invalid(
+            "bits_per_row must be a positive number".to_string(),
+        ));
+    }
+    if has_elements {
+        warnings.push("blocks and edges are ignored in packet diagrams".to_string());
+    }
+    Ok(RenderedDiagram {
+        rows: packet(fields, bits_per_row),
+        warnings,
+        config,
+        ids: vec![],
+        block_spans: vec![],
+        edge_spans: vec![],
+        layout: None,
+        score: None,
+        grid: None,
+    })
+}
@@ -0,0 +1,123 @@
+use crate::render::{BlockRect, CanvasPoint};
+use crate::spec::nodes::{node_ids, push_nodes, ComputedRoutes};
+use crate::spec::{Spec, SpecConnector, SpecEdge, SpecOrientation, SpecSiblingOrder};
+
+/// Add a block for each node of the tree to the spec and an edge from each node to each of its
+/// children. Each leaf is in a column of its own, or in a row with `SpecOrientation::Horizontal`,
+/// and each parent is aligned with its middle child.
+///
+/// With `SpecConnector::Elbow` the edges are routed as elbows, see `elbow_route`.
+pub fn expand_tree(spec: &mut Spec) -> Result<ComputedRoutes, String> {
+    let nodes = std::mem::take(&mut spec.nodes);
+    let (ids, index) = node_ids(&nodes)?;
+
+    let mut roots = vec![];
+    let mut children = vec![vec![]; nodes.len()];
+    for (i, n) in nodes.iter().enumerate() {
+        match &n.parent {
+            Some(p) => match index.get(p) {
+                Some(&parent) => children[parent].push(i),
+                None => {
+                    return Err(format!(
+                        r#"the parent "{}" of node "{}" doesn't exist"#,
+                        p, ids[i]
+                    ))
+                }
+            },
+            None => roots.push(i),
+        }
+    }
+    if spec.sibling_order == SpecSiblingOrder::Text {
+        for siblings in children.iter_mut().chain(std::iter::once(&mut roots)) {
+            siblings.sort_by(|&a, &b| nodes[a].text.cmp(&nodes[b].text));
+        }
+    }
+
+    let mut placed = vec![None; nodes.len()];
+    let mut next_slot = 0;
+    for &r in &roots {
+        place_subtree(r, 0, &children, &mut next_slot, &mut placed);
+    }
+    if let Some(i) = placed.iter().position(Option::is_none) {
+        return Err(format!(r#"node "{}" is its own ancestor"#, ids[i]));
+    }
+
+    push_nodes(spec, nodes, &ids, placed.into_iter().flatten())?;
+
+    let elbow = spec.connector == SpecConnector::Elbow;
+    let mut routes = ComputedRoutes {
+        orientation: spec.orientation,
+        ..ComputedRoutes::default()
+    };
+    for (parent, children) in children.iter().enumerate() {
+        for &c in children {
+            spec.edges.push(SpecEdge {
+                from: ids[parent].clone(),
+                to: ids[c].clone(),
+                group: Some(ids[parent].clone()).filter(|_| elbow),
+                ..SpecEdge::default()
+            });
+            if elbow {
+                routes.elbows.push((ids[parent].clone(), ids[c].clone()));
+            }
+        }
+    }
+    Ok(routes)
+}
+
+/// The route of an edge from a parent to its child that leaves the parent from the middle of the
+/// side facing the child, turns halfway between them and enters the child from the middle of the
+/// side facing the parent. Siblings share the part of their route up to the turn.
+pub fn elbow_route(
+    parent: &BlockRect,
+    child: &BlockRect,
+    orientation: SpecOrientation,
+) -> Vec<CanvasPoint> {
+    let (src, dst) = match orientation {
+        SpecOrientation::Vertical => (
+            CanvasPoint::new(parent.x + parent.width / 2, parent.y + parent.height - 1),
+            CanvasPoint::new(child.x + child.width / 2, child.y),
+        ),
+        SpecOrientation::Horizontal => (
+            CanvasPoint::new(parent.x + parent.width - 1, parent.y + parent.height / 2),
+            CanvasPoint::new(child.x, child.y + child.height / 2),
+        ),
+    };
+
+    let (a, b) = match orientation {
+        SpecOrientation::Vertical if src.x != dst.x => {
+            let y = (src.y + dst.y) / 2;
+            (CanvasPoint::new(src.x, y), CanvasPoint::new(dst.x, y))
+        }
+        SpecOrientation::Horizontal if src.y != dst.y => {
+            let x = (src.x + dst.x) / 2;
+            (CanvasPoint::new(x, src.y), CanvasPoint::new(x, dst.y))
+        }
+        _ => return vec![src, dst],
+    };
+    vec![src, a, b, dst]
+}
+
+/// Assign the given node and its descendants a (depth, slot), where leaves take the next free slot
+/// and parents the slot of their middle child.
+fn place_subtree(
+    node: usize,
+    depth: usize,
+    children: &[Vec<usize>],
+    next_slot: &mut usize,
+    placed: &mut [Option<(usize, usize)>],
+) {
+    let slot = match children[node].get((children[node].len().max(1) - 1) / 2) {
+        Some(&middle) => {
+            for &c in &children[node] {
+                place_subtree(c, depth + 1, children, next_slot, placed);
+            }
+            placed[middle].map_or(0, |(_, slot)| slot)
+        }
+        None => {
+            *next_slot += 1;
+            *next_slot - 1
+        }
+    };
+    placed[node] = Some((depth, slot));
+}
@@ -0,0 +1,314 @@
+//! Bring the positions of the blocks of a diagram in line with a rendered copy of it where some
+//! blocks were moved by hand.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+
+use crate::render::{BlockRect, Layout};
+use crate::spans::find_position;
+use crate::spec::{render_spec_str, Error, Format, Overrides};
+use crate::{LogicalCoord, LogicalPos};
+
+/// Update the positions of the blocks of the diagram `input` written in `format` to match
+/// `rendered`, a diagram previously rendered from it with the same `overrides` where some blocks
+/// were moved by hand. Return the updated source alongside what was changed and what couldn't
+/// be.
+///
+/// Blocks are found in `rendered` by their text and their corners, so they must be moved without
+/// changing them, while edges can be left dangling or redrawn freely. A moved block is assigned
+/// to the column and the row with blocks whose middle is the closest to its own, so blocks can
+/// only be moved to columns and rows that already have blocks. Only the `row` and the `column`
+/// of the blocks are rewritten, the rest of the source is kept as is.
+pub fn sync_spec_str(
+    rendered: &str,
+    input: &str,
+    format: Format,
+    overrides: &Overrides,
+) -> Result<(String, Vec<String>), Error> {
+    // the whole diagram must be rendered exactly where the blocks are in the source
+    let opts = Overrides {
+        suggest: false,
+        optimize_layout: false,
+        dump_resolved: false,
+        layout_json: false,
+        viewport: None,
+        split_width: None,
+        ..overrides.clone()
+    };
+    let diagram = render_spec_str(input, format, &opts)?;
+    let layout = diagram.layout.as_ref().ok_or_else(|| {
+        Error::Invalid("the diagram has no blocks to sync, like packet diagrams".to_string())
+    })?;
+    let positions = positions(input, format, &opts)?;
+
+    let edited = rendered
+        .lines()
+        .map(|l| l.as_bytes().to_vec())
+        .collect::<Vec<_>>();
+    let found = layout
+        .blocks
+        .iter()
+        .map(|r| find_block(&edited, &diagram.rows, r))
+        .collect::<Vec<_>>();
+
+    // the rendered diagram can be shifted as a whole, by labels for example, the blocks that
+    // weren't moved tell by how much
+    let mut shifts = BTreeMap::new();
+    for (r, candidates) in layout.blocks.iter().zip(&found) {
+        for &(x, y) in candidates {
+            *shifts
+                .entry((x - r.x as isize, y - r.y as isize))
+                .or_insert(0) += 1;
+        }
+    }
+    let (dx, dy) = shifts
+        .into_iter()
+        .max_by_key(|&((dx, dy), n)| (n, -(dx.abs() + dy.abs())))
+        .map(|(shift, _)| shift)
+        .ok_or_else(|| {
+            Error::Invalid("none of the blocks were found in the rendered diagram".to_string())
+        })?;
+
+    let columns = centers(layout, &positions, |p| p.col, |r| (r.x, r.width));
+    let rows = centers(layout, &positions, |p| p.row, |r| (r.y, r.height));
+
+    let mut edits = vec![];
+    let mut messages = vec![];
+    for (i, (r, candidates)) in layout.blocks.iter().zip(&found).enumerate() {
+        let id = &diagram.ids[i];
+        let (x, y) = match candidates[..] {
+            [] => {
+                messages.push(format!(
+                    r#"block "{}" was not found in the rendered diagram, its position is unchanged"#,
+                    id
+                ));
+                continue;
+            }
+            _ if candidates.contains(&(r.x as isize + dx, r.y as isize + dy)) => continue,
+            [(x, y)] => (x - dx, y - dy),
+            _ => {
+                messages.push(format!(
+                    r#"block "{}" was found {} times in the rendered diagram, its position is unchanged"#,
+                    id,
+                    candidates.len()
+                ));
+                continue;
+            }
+        };
+
+        let LogicalPos { row, col: column } = positions[i];
+        let new_row = nearest(&rows, 2 * y + r.height as isize).unwrap_or(row);
+        let new_column = nearest(&columns, 2 * x + r.width as isize).unwrap_or(column);
+        if (new_row, new_column) == (row, column) {
+            continue;
+        }
+
+        match diagram.block_spans[i].and_then(|s| find_position(input, s)) {
+            Some((row_range, column_range)) => {
+                edits.push((row_range, new_row));
+                edits.push((column_range, new_column));
+                messages.push(format!(
+                    r#"moved block "{}" from row {}, column {} to row {}, column {}"#,
+                    id, row, column, new_row, new_column
+                ));
+            }
+            None => messages.push(format!(
+                r#"block "{}" was moved to row {}, column {} but its position can't be updated in the diagram"#,
+                id, new_row, new_column
+            )),
+        }
+    }
+
+    Ok((apply(input, edits), messages))
+}
+
+/// The logical positions of the blocks of the diagram, in the same order as
+/// `RenderedDiagram::ids`.
+fn positions(input: &str, format: Format, opts: &Overrides) -> Result<Vec<LogicalPos>, Error> {
+    let resolved = Overrides {
+        dump_resolved: true,
+        ..opts.clone()
+    };
+    let json = render_spec_str(input, format, &resolved)?.to_string();
+    let json: serde_json::Value =
+        serde_json::from_str(&json).map_err(|e| Error::Parse(e.to_string()))?;
+
+    let blocks = json["blocks"].as_array().cloned().unwrap_or_default();
+    Ok(blocks
+        .iter()
+        .map(|b| {
+            let coord = |k: &str| b["position"][k].as_i64().unwrap_or(0) as LogicalCoord;
+            LogicalPos::new(coord("row"), coord("column"))
+        })
+        .collect())
+}
+
+/// Where the top left corner of the block drawn in `rect` of `rows` is in `edited`, every place
+/// it's found if it was copied.
+fn find_block(edited: &[Vec<u8>], rows: &[Vec<u8>], rect: &BlockRect) -> Vec<(isize, isize)> {
+    let at = |rows: &[Vec<u8>], x: usize, y: usize| {
+        rows.get(y).and_then(|r| r.get(x)).copied().unwrap_or(b' ')
+    };
+
+    // the sides are not compared since edges can be attached to them
+    let (w, h) = (rect.width, rect.height);
+    let cells = (0..h)
+        .flat_map(|y| (0..w).map(move |x| (x, y)))
+        .filter(|&(x, y)| {
+            let corner = (x == 0 || x + 1 == w) && (y == 0 || y + 1 == h);
+            let inside = x > 0 && x + 1 < w && y > 0 && y + 1 < h;
+            corner || inside
+        })
+        .map(|(x, y)| (x, y, at(rows, rect.x + x, rect.y + y)))
+        .collect::<Vec<_>>();
+
+    // blank blocks can be found anywhere
+    if cells.iter().all(|&(_, _, c)| c == b' ') {
+        return vec![];
+    }
+
+    let height = edited.len();
+    let width = edited.iter().map(Vec::len).max().unwrap_or(0);
+    let mut found = vec![];
+    for y in 0..height.saturating_sub(h - 1) {
+        for x in 0..width.saturating_sub(w - 1) {
+            if cells
+                .iter()
+                .all(|&(cx, cy, c)| at(edited, x + cx, y + cy) == c)
+            {
+                found.push((x as isize, y as isize));
+            }
+        }
+    }
+    found
+}
+
+/// The middle of each column or row with blocks, doubled to keep it an integer.
+fn centers(
+    layout: &Layout,
+    positions: &[LogicalPos],
+    coord: impl Fn(&LogicalPos) -> LogicalCoord,
+    range: impl Fn(&BlockRect) -> (usize, usize),
+) -> HashMap<LogicalCoord, isize> {
+    let mut spans = HashMap::new();
+    for (p, r) in positions.iter().zip(&layout.blocks) {
+        let (start, len) = range(r);
+        let span = spans.entry(coord(p)).or_insert((start, start + len));
+        *span = (span.0.min(start), span.1.max(start + len));
+    }
+
+    spans
+        .into_iter()
+        .map(|(c, (start, end))| (c, (start + end) as isize))
+        .collect()
+}
+
+/// The column or row whose middle is the closest to `middle`, both doubled.
+fn nearest(centers: &HashMap<LogicalCoord, isize>, middle: isize) -> Option<LogicalCoord> {
+    centers
+        .iter()
+        .min_by_key(|&(&c, &m)| ((m - middle).abs(), c))
+        .map(|(&c, _)| c)
+}
+
+/// Replace each range of `input` with the given value.
+fn apply(input: &str, mut edits: Vec<(Range<usize>, LogicalCoord)>) -> String {
+    edits.sort_by_key(|(r, _)| r.start);
+
+    let mut out = String::with_capacity(input.len());
+    let mut last = 0;
+    for (range, value) in edits {
+        out.push_str(&input[last..range.start]);
+        out.push_str(&value.to_string());
+        last = range.end;
+    }
+    out.push_str(&input[last..]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync() {
+        let input = r#"
+horizontal_margin = 4
+vertical_margin = 1
+padding = 0
+
+[[blocks]]
+text = "a"
+position = { row = 0, column = 0 }
+
+[[blocks]]
+text = "b"
+# b is right of a
+position = { row = 0, column = 1 }
+
+[[blocks]]
+text = "c"
+[blocks.position]
+row = 1
+column = 1
+
+[[edges]]
+from = "a"
+to = "b"
+"#;
+        let opts = Overrides {
+            seed: Some(0),
+            ..Overrides::default()
+        };
+        let rendered = render_spec_str(input, Format::Toml, &opts)
+            .unwrap()
+            .to_string();
+        let (synced, messages) = sync_spec_str(&rendered, input, Format::Toml, &opts).unwrap();
+        assert_eq!(synced, input);
+        assert!(messages.is_empty());
+
+        // the whole diagram is shifted right by two, a is moved below its column and c is gone
+        let edited = r#"
+             +-+
+      +------+b|
+      |      +-+
+      |
+     +++
+     |a|
+     +-+
+"#;
+        let (synced, messages) = sync_spec_str(edited, input, Format::Toml, &opts).unwrap();
+        assert_eq!(
+            messages,
+            vec![
+                r#"moved block "a" from row 0, column 0 to row 1, column 0"#,
+                r#"block "c" was not found in the rendered diagram, its position is unchanged"#,
+            ]
+        );
+        assert_eq!(
+            synced,
+            input.replace(
+                "position = { row = 0, column = 0 }",
+                "position = { row = 1, column = 0 }"
+            )
+        );
+    }
+
+    #[test]
+    fn test_sync_packet() {
+        let input = r#"
+kind = "packet"
+fields = [{ name = "Version", bits = 4 }]
+"#;
+        let opts = Overrides::default();
+        let rendered = render_spec_str(input, Format::Toml, &opts)
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            sync_spec_str(&rendered, input, Format::Toml, &opts),
+            Err(Error::Invalid(
+                "the diagram has no blocks to sync, like packet diagrams".to_string()
+            ))
+        );
+    }
+}
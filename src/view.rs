@@ -0,0 +1,427 @@
+use std::io::{self, Read, Write};
+
+use ascii_diagrams::Metrics;
+
+/// A key press the viewer reacts to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Left,
+    Right,
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Home,
+
+    /// Increase the margins by one.
+    Grow,
+
+    /// Decrease the margins by one.
+    Shrink,
+
+    NextSeed,
+    PreviousSeed,
+
+    /// Double the maximum number of tweaks.
+    MoreTweaks,
+
+    /// Halve the maximum number of tweaks.
+    FewerTweaks,
+
+    /// Quit and report the current seed, tweaks and margins.
+    Write,
+
+    Quit,
+}
+
+/// The state of the viewer: the top left corner of the visible region of the diagram and the
+/// options the diagram is rendered with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct View {
+    pub x: usize,
+    pub y: usize,
+    pub hmargin: usize,
+    pub vmargin: usize,
+    pub seed: u64,
+    pub max_tweaks: usize,
+}
+
+impl View {
+    /// Apply `key` given the size of the diagram and the size of the region of the screen it's
+    /// shown in. Return whether the diagram has to be rendered again.
+    pub fn apply(
+        &mut self,
+        key: Key,
+        size: (usize, usize),
+        (screen_width, screen_height): (usize, usize),
+    ) -> bool {
+        let mut render = false;
+
+        match key {
+            Key::Left => self.x = self.x.saturating_sub(1),
+            Key::Right => self.x += 1,
+            Key::Up => self.y = self.y.saturating_sub(1),
+            Key::Down => self.y += 1,
+            Key::PageUp => self.y = self.y.saturating_sub(screen_height),
+            Key::PageDown => self.y += screen_height,
+            Key::Home => {
+                self.x = 0;
+                self.y = 0;
+            }
+            Key::Grow => {
+                self.hmargin += 1;
+                self.vmargin += 1;
+                render = true;
+            }
+            Key::Shrink => {
+                render = self.hmargin > 1 || self.vmargin > 1;
+                self.hmargin = self.hmargin.saturating_sub(1).max(1);
+                self.vmargin = self.vmargin.saturating_sub(1).max(1);
+            }
+            Key::NextSeed => {
+                self.seed = self.seed.wrapping_add(1);
+                render = true;
+            }
+            Key::PreviousSeed => {
+                self.seed = self.seed.wrapping_sub(1);
+                render = true;
+            }
+            Key::MoreTweaks => {
+                self.max_tweaks = (self.max_tweaks * 2).max(1);
+                render = true;
+            }
+            Key::FewerTweaks => {
+                render = self.max_tweaks > 0;
+                self.max_tweaks /= 2;
+            }
+            Key::Write | Key::Quit => {}
+        }
+
+        self.clamp(size, (screen_width, screen_height));
+        render
+    }
+
+    /// Make sure the visible region does not go past the right and bottom sides of the
+    /// diagram, as long as it fits the screen.
+    pub fn clamp(&mut self, (width, height): (usize, usize), (sw, sh): (usize, usize)) {
+        self.x = self.x.min(width.saturating_sub(sw));
+        self.y = self.y.min(height.saturating_sub(sh));
+    }
+}
+
+/// Decode the keys in `input` as read from a terminal in raw mode, unknown keys are skipped.
+pub fn parse_keys(input: &[u8]) -> Vec<Key> {
+    let mut keys = vec![];
+
+    let mut i = 0;
+    while i < input.len() {
+        let (key, len) = match input[i..] {
+            [0x1b, b'[', b'A', ..] => (Some(Key::Up), 3),
+            [0x1b, b'[', b'B', ..] => (Some(Key::Down), 3),
+            [0x1b, b'[', b'C', ..] => (Some(Key::Right), 3),
+            [0x1b, b'[', b'D', ..] => (Some(Key::Left), 3),
+            [0x1b, b'[', b'H', ..] => (Some(Key::Home), 3),
+            [0x1b, b'[', b'5', b'~', ..] => (Some(Key::PageUp), 4),
+            [0x1b, b'[', b'6', b'~', ..] => (Some(Key::PageDown), 4),
+
+            // a lone escape is the escape key itself rather than the start of a sequence
+            [0x1b] | [b'q', ..] | [0x03, ..] => (Some(Key::Quit), 1),
+
+            [b'h', ..] => (Some(Key::Left), 1),
+            [b'l', ..] => (Some(Key::Right), 1),
+            [b'k', ..] => (Some(Key::Up), 1),
+            [b'j', ..] => (Some(Key::Down), 1),
+            [b'+', ..] | [b'=', ..] => (Some(Key::Grow), 1),
+            [b'-', ..] => (Some(Key::Shrink), 1),
+            [b's', ..] => (Some(Key::NextSeed), 1),
+            [b'S', ..] => (Some(Key::PreviousSeed), 1),
+            [b't', ..] => (Some(Key::MoreTweaks), 1),
+            [b'T', ..] => (Some(Key::FewerTweaks), 1),
+            [b'w', ..] => (Some(Key::Write), 1),
+            _ => (None, 1),
+        };
+
+        keys.extend(key);
+        i += len;
+    }
+
+    keys
+}
+
+/// Compose the escape sequences that draw the region of `rows` visible from `view` on a screen
+/// of the given size. The last row of the screen is a status line showing `status`.
+pub fn frame(rows: &[Vec<u8>], view: &View, (sw, sh): (usize, usize), status: &str) -> Vec<u8> {
+    let mut out = b"\x1b[H".to_vec();
+
+    for r in 0..sh.saturating_sub(1) {
+        if let Some(row) = rows.get(view.y + r) {
+            let start = view.x.min(row.len());
+            let end = (view.x + sw).min(row.len());
+            out.extend_from_slice(&row[start..end]);
+        }
+        out.extend_from_slice(b"\x1b[K\r\n");
+    }
+
+    let mut status = status.as_bytes()[..status.len().min(sw)].to_vec();
+    status.resize(sw, b' ');
+    out.extend_from_slice(b"\x1b[7m");
+    out.extend_from_slice(&status);
+    out.extend_from_slice(b"\x1b[0m");
+
+    out
+}
+
+/// Show the diagram rendered by `render` in the alternate screen of the terminal and let the
+/// user pan it with the arrow keys, change its margins with `+` and `-`, cycle the seeds with
+/// `s` and `S` and change the tweaks with `t` and `T`, until `q` or `w` is pressed. `render` is
+/// called with the current `view` and returns the diagram alongside its score.
+///
+/// Return the final `view` if the user asked to keep it with `w`.
+pub fn run(
+    mut view: View,
+    mut render: impl FnMut(&View) -> Result<(Vec<Vec<u8>>, Metrics), String>,
+) -> Result<Option<View>, String> {
+    let (mut rows, mut score) = render(&view)?;
+
+    let terminal = Terminal::enter().map_err(|e| format!("cannot set up the terminal: {}", e))?;
+
+    let mut error = None;
+    let mut screen = (0, 0);
+    let mut redraw = true;
+    let mut input = [0; 64];
+    loop {
+        let (sw, sh) = terminal.size();
+        if (sw, sh) != screen {
+            screen = (sw, sh);
+            redraw = true;
+        }
+
+        let size = diagram_size(&rows);
+        if redraw {
+            view.clamp(size, (sw, sh - 1));
+
+            let status = match &error {
+                Some(e) => format!(" error: {}", e),
+                None => format!(
+                    " seed {}, tweaks {}: {} crossings, {} hugging, length {} | margins {}x{} | columns {}-{} of {}, rows {}-{} of {} | s/S: seed, t/T: tweaks, +/-: margins, w: keep, q: quit",
+                    view.seed,
+                    view.max_tweaks,
+                    score.crossings,
+                    score.hugging,
+                    score.length,
+                    view.hmargin,
+                    view.vmargin,
+                    view.x + 1,
+                    (view.x + sw).min(size.0),
+                    size.0,
+                    view.y + 1,
+                    (view.y + sh - 1).min(size.1),
+                    size.1
+                ),
+            };
+
+            terminal
+                .write(&frame(&rows, &view, (sw, sh), &status))
+                .map_err(|e| e.to_string())?;
+            redraw = false;
+        }
+
+        // reads time out so that resizes of the terminal are picked up
+        let n = io::stdin().read(&mut input).map_err(|e| e.to_string())?;
+        for key in parse_keys(&input[..n]) {
+            match key {
+                Key::Quit => return Ok(None),
+                Key::Write => return Ok(Some(view)),
+                _ => {}
+            }
+
+            if view.apply(key, size, (sw, sh - 1)) {
+                match render(&view) {
+                    Ok((r, s)) => {
+                        rows = r;
+                        score = s;
+                        error = None;
+                    }
+                    Err(e) => error = Some(e),
+                }
+            }
+            redraw = true;
+        }
+    }
+}
+
+fn diagram_size(rows: &[Vec<u8>]) -> (usize, usize) {
+    let width = rows.iter().map(Vec::len).max().unwrap_or(0);
+    (width, rows.len())
+}
+
+/// The terminal in raw mode showing the alternate screen, the original state is restored on
+/// drop.
+struct Terminal {
+    #[cfg(unix)]
+    original: libc::termios,
+}
+
+#[cfg(unix)]
+impl Terminal {
+    fn enter() -> io::Result<Self> {
+        use std::io::IsTerminal;
+
+        if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+            return Err(io::Error::other("the viewer needs an interactive terminal"));
+        }
+
+        // SAFETY: tcgetattr and tcsetattr only read and fill the given termios struct
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut raw = original;
+        unsafe { libc::cfmakeraw(&mut raw) };
+        // wait at most 100ms for input
+        raw.c_cc[libc::VMIN] = 0;
+        raw.c_cc[libc::VTIME] = 1;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let terminal = Terminal { original };
+        terminal.write(b"\x1b[?1049h\x1b[?25l")?;
+        Ok(terminal)
+    }
+
+    /// The size of the terminal as (columns, rows), falling back to 80x24 if it cannot be
+    /// queried.
+    fn size(&self) -> (usize, usize) {
+        // SAFETY: TIOCGWINSZ only fills the given winsize struct
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        let res = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+        if res != 0 || size.ws_col == 0 || size.ws_row < 2 {
+            return (80, 24);
+        }
+
+        (usize::from(size.ws_col), usize::from(size.ws_row))
+    }
+}
+
+#[cfg(not(unix))]
+impl Terminal {
+    fn enter() -> io::Result<Self> {
+        Err(io::Error::other(
+            "the viewer is only supported on unix terminals",
+        ))
+    }
+
+    fn size(&self) -> (usize, usize) {
+        (80, 24)
+    }
+}
+
+impl Terminal {
+    fn write(&self, data: &[u8]) -> io::Result<()> {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(data)?;
+        stdout.flush()
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let _ = self.write(b"\x1b[?25h\x1b[?1049l");
+
+        #[cfg(unix)]
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &self.original);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_keys() {
+        assert_eq!(
+            parse_keys(b"\x1b[A\x1b[Dlx+-\x1b[6~"),
+            vec![
+                Key::Up,
+                Key::Left,
+                Key::Right,
+                Key::Grow,
+                Key::Shrink,
+                Key::PageDown
+            ]
+        );
+        assert_eq!(parse_keys(b"\x1b"), vec![Key::Quit]);
+        assert_eq!(parse_keys(b"jq"), vec![Key::Down, Key::Quit]);
+        assert_eq!(
+            parse_keys(b"sStTw"),
+            vec![
+                Key::NextSeed,
+                Key::PreviousSeed,
+                Key::MoreTweaks,
+                Key::FewerTweaks,
+                Key::Write
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut view = View {
+            x: 0,
+            y: 0,
+            hmargin: 1,
+            vmargin: 2,
+            seed: 0,
+            max_tweaks: 0,
+        };
+
+        // the visible region stops at the right and bottom sides of the diagram
+        for _ in 0..10 {
+            assert!(!view.apply(Key::Right, (30, 10), (25, 8)));
+        }
+        assert!(!view.apply(Key::PageDown, (30, 10), (25, 8)));
+        assert_eq!((view.x, view.y), (5, 2));
+
+        assert!(!view.apply(Key::Right, (20, 10), (25, 8)));
+        assert_eq!(view.x, 0);
+
+        assert!(view.apply(Key::Shrink, (20, 10), (25, 8)));
+        assert_eq!((view.hmargin, view.vmargin), (1, 1));
+        assert!(!view.apply(Key::Shrink, (20, 10), (25, 8)));
+
+        assert!(view.apply(Key::Grow, (20, 10), (25, 8)));
+        assert_eq!((view.hmargin, view.vmargin), (2, 2));
+
+        assert!(view.apply(Key::PreviousSeed, (20, 10), (25, 8)));
+        assert_eq!(view.seed, u64::MAX);
+        assert!(view.apply(Key::NextSeed, (20, 10), (25, 8)));
+        assert_eq!(view.seed, 0);
+
+        assert!(!view.apply(Key::FewerTweaks, (20, 10), (25, 8)));
+        assert!(view.apply(Key::MoreTweaks, (20, 10), (25, 8)));
+        assert!(view.apply(Key::MoreTweaks, (20, 10), (25, 8)));
+        assert_eq!(view.max_tweaks, 2);
+    }
+
+    #[test]
+    fn test_frame() {
+        let rows = vec![b"abcdef".to_vec(), b"ghi".to_vec(), b"jklmno".to_vec()];
+        let view = View {
+            x: 2,
+            y: 1,
+            hmargin: 1,
+            vmargin: 1,
+            seed: 0,
+            max_tweaks: 0,
+        };
+
+        assert_eq!(
+            String::from_utf8(frame(&rows, &view, (3, 4), "status")).unwrap(),
+            "\x1b[Hi\x1b[K\r\nlmn\x1b[K\r\n\x1b[K\r\n\x1b[7msta\x1b[0m"
+        );
+    }
+}
@@ -0,0 +1,31 @@
+//! Render every diagram in `tests/corpus` and compare it to its `.expected.txt`. Run with
+//! `ASCII_DIAGRAMS_BLESS=1` to write the expected outputs of new diagrams or after intended
+//! changes.
+
+use std::path::Path;
+
+use ascii_diagrams::{check_corpus, Overrides};
+
+#[test]
+fn test_corpus() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+    let bless = std::env::var_os("ASCII_DIAGRAMS_BLESS").is_some();
+
+    let mismatches = check_corpus(&dir, &Overrides::default(), bless).unwrap();
+    if bless {
+        return;
+    }
+    for m in &mismatches {
+        eprintln!(
+            "{}:\n--- expected\n{}\n--- actual\n{}",
+            m.diagram.display(),
+            m.expected.as_deref().unwrap_or("(missing)"),
+            m.actual
+        );
+    }
+    assert!(
+        mismatches.is_empty(),
+        "{} diagrams don't match, set ASCII_DIAGRAMS_BLESS=1 to update them",
+        mismatches.len()
+    );
+}